@@ -1,18 +1,31 @@
 //! Actually performs a backup.
 
-use super::{btrfs, config};
+use super::borg_log::{LogLevel, MessageId, Size, StderrLine};
+use super::command_line::CommandLine;
+use super::json_error::{fields, Structured};
+use super::tail::{Lines, Tail};
+use super::{btrfs, check, config, profile, progress, restic, usage, verbosity};
+use chrono::format::StrftimeItems;
 use nix::libc;
-use std::ffi::{c_int, CStr, CString, OsStr};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{c_int, CStr, CString, OsStr, OsString};
 use std::fmt::{Display, Formatter, LowerHex};
 use std::fs::File;
+use std::io::{BufRead, BufReader, Read as _, Write};
 use std::os::unix::ffi::OsStrExt as _;
 use std::os::unix::fs::OpenOptionsExt as _;
 use std::os::unix::io::{AsFd as _, AsRawFd as _};
 use std::os::unix::prelude::*;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant, SystemTime};
 
 /// The errors that can occur.
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)] // Borg calls exit code 2 an “error” status.
 pub enum Error {
 	/// The archive root location cannot be opened.
 	OpenArchiveRoot(std::io::Error),
@@ -23,28 +36,87 @@ pub enum Error {
 	/// The created snapshot cannot be opened.
 	OpenSnapshot(std::io::Error),
 
+	/// A snapshot cannot be locked, either to mark it as in use or to delete it.
+	LockSnapshot(std::io::Error),
+
+	/// The parent directory of the archive root cannot be locked.
+	LockArchiveRootParent(std::io::Error),
+
+	/// The directory under `snapshot_dir` to hold an archive’s snapshots cannot be created or
+	/// opened.
+	SnapshotDir(PathBuf, std::io::Error),
+
 	/// An error occurred creating a btrfs snapshot.
 	SnapshotCreate(btrfs::Error),
 
+	/// A btrfs snapshot could not be created because the filesystem was out of space, even after
+	/// the given number of retries.
+	SnapshotNoSpace(u32, std::io::Error),
+
 	/// An error occurred deleting a btrfs snapshot.
 	SnapshotDelete(btrfs::Error),
 
+	/// The subvolume information of an archive root cannot be read.
+	SubvolumeInfo(btrfs::Error),
+
+	/// A directory that may hold snapshots cannot be read.
+	ListSnapshots(PathBuf, std::io::Error),
+
 	/// There was an error spawning or communicating with the `borg` executable.
 	Spawn(std::io::Error),
 
-	/// The `borg` executable terminated with exit code 2, indicating an error.
-	ErrorStatus,
+	/// The `borg` executable could not be launched with the given command line.
+	Launch(CommandLine, std::io::Error),
+
+	/// The `borg` executable terminated with exit code 2 and reported the given error message.
+	Borg(String),
+
+	/// The `borg` executable reported that an archive with the name being created already exists,
+	/// most likely because Borgify was run twice within one second.
+	ArchiveExists,
+
+	/// The `borg` executable terminated with exit code 2, indicating an error, but did not report
+	/// an error message. The last few lines it printed, if any, are included.
+	ErrorStatus(Vec<String>),
 
 	/// The `borg` executable terminated with an exit code other than 0, 1, or 2, which is not
 	/// documented as being possible, and did not print an error message.
 	UnknownExitCode(i32),
 
+	/// The `restic` executable terminated with the given exit code, indicating an error. The last
+	/// few lines it printed, if any, are included.
+	Restic(i32, Vec<String>),
+
 	/// The `borg` executable terminated due to a fatal signal.
 	Signal(i32),
 
 	/// The `borg` executable terminated due to an unknown reason (neither normal termination nor a
 	/// signal).
 	Unknown,
+
+	/// An error occurred backing up to one of the archive’s repositories.
+	Target(String, Box<Error>),
+
+	/// The archive was created, but verifying it afterwards failed.
+	Verify(Box<Error>),
+
+	/// The archive was created, but listing what changed since the previous archive failed.
+	ListChanged(Box<Error>),
+
+	/// The `borg` executable produced output that is not valid JSON, or not of the expected form.
+	Json(serde_json::Error),
+
+	/// The archive was created, but flushing the repository’s filesystem to disk afterwards
+	/// failed.
+	Sync(std::io::Error),
+
+	/// The archive root contains fewer files, or a smaller total size, than the configured
+	/// minimum; the number of files and total size found are included.
+	TooSmall(u64, u64),
+
+	/// The pattern selecting an archive’s Borg archives for pruning also matches Borg archives
+	/// that belong to another archive; the pattern and those archives’ names are included.
+	ForeignArchives(String, Vec<String>),
 }
 
 impl Display for Error {
@@ -55,15 +127,65 @@ impl Display for Error {
 				"error opening archive root’s parent directory".fmt(f)
 			}
 			Self::OpenSnapshot(_) => "error opening created btrfs snapshot".fmt(f),
+			Self::LockSnapshot(_) => "error locking btrfs snapshot".fmt(f),
+			Self::LockArchiveRootParent(_) => {
+				"error locking archive root’s parent directory".fmt(f)
+			}
+			Self::SnapshotDir(p, _) => {
+				write!(f, "error preparing snapshot directory {}", p.display())
+			}
 			Self::SnapshotCreate(_) => "error creating btrfs snapshot".fmt(f),
+			Self::SnapshotNoSpace(retries, _) => write!(
+				f,
+				"not enough space to create btrfs snapshot (tried {} time{})",
+				retries + 1,
+				if *retries == 0 { "" } else { "s" }
+			),
 			Self::SnapshotDelete(_) => "error deleting btrfs snapshot".fmt(f),
+			Self::SubvolumeInfo(_) => "error reading archive root’s subvolume information".fmt(f),
+			Self::ListSnapshots(p, _) => {
+				write!(f, "error reading snapshot directory {}", p.display())
+			}
 			Self::Spawn(_) => "failed to spawn Borg executable".fmt(f),
-			Self::ErrorStatus => {
+			Self::Launch(command_line, _) => write!(f, "failed to launch {command_line}"),
+			Self::Borg(message) => write!(f, "borg reported an error: {message}"),
+			Self::ArchiveExists => {
+				"an archive with this name already exists in the repository (if Borgify was just run, wait a second and run it again)".fmt(f)
+			}
+			Self::ErrorStatus(output) if output.is_empty() => {
 				"borg returned exit code 2 (error) without an error message".fmt(f)
 			}
+			Self::ErrorStatus(output) => write!(
+				f,
+				"borg returned exit code 2 (error) without an error message; its last output was:{}",
+				Lines(output)
+			),
 			Self::UnknownExitCode(code) => write!(f, "borg returned unknown exit code {code}"),
+			Self::Restic(code, output) if output.is_empty() => {
+				write!(f, "restic returned exit code {code}")
+			}
+			Self::Restic(code, output) => write!(
+				f,
+				"restic returned exit code {code}; its last output was:{}",
+				Lines(output)
+			),
 			Self::Signal(signal) => write!(f, "borg terminated due to signal {signal}"),
 			Self::Unknown => write!(f, "borg terminated due to unknown reason"),
+			Self::Target(url, _) => write!(f, "error backing up to repository {url}"),
+			Self::Verify(_) => "error verifying newly created archive".fmt(f),
+			Self::ListChanged(_) => "error listing changes in newly created archive".fmt(f),
+			Self::Json(_) => "Borg output is invalid JSON".fmt(f),
+			Self::Sync(_) => "error flushing repository to disk".fmt(f),
+			Self::TooSmall(files, bytes) => write!(
+				f,
+				"archive root contains only {files} files totalling {}, less than the configured min_files or min_bytes (it may be an incompletely mounted disk)",
+				Size(*bytes)
+			),
+			Self::ForeignArchives(glob, names) => write!(
+				f,
+				"refusing to prune because the pattern {glob} also matches archives of another name: {}",
+				names.join(", ")
+			),
 		}
 	}
 }
@@ -71,13 +193,94 @@ impl Display for Error {
 impl std::error::Error for Error {
 	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
 		match self {
-			Self::ErrorStatus | Self::UnknownExitCode(_) | Self::Signal(_) | Self::Unknown => None,
+			Self::Borg(_)
+			| Self::ArchiveExists
+			| Self::ErrorStatus(_)
+			| Self::UnknownExitCode(_)
+			| Self::Restic(..)
+			| Self::Signal(_)
+			| Self::Unknown
+			| Self::TooSmall(..)
+			| Self::ForeignArchives(..) => None,
 			Self::OpenArchiveRoot(e) => Some(e),
 			Self::OpenArchiveRootParent(e) => Some(e),
 			Self::OpenSnapshot(e) => Some(e),
+			Self::LockSnapshot(e) => Some(e),
+			Self::LockArchiveRootParent(e) => Some(e),
+			Self::SnapshotDir(_, e) => Some(e),
 			Self::SnapshotCreate(e) => Some(e),
+			Self::SnapshotNoSpace(_, e) => Some(e),
 			Self::SnapshotDelete(e) => Some(e),
+			Self::SubvolumeInfo(e) => Some(e),
+			Self::ListSnapshots(_, e) => Some(e),
 			Self::Spawn(e) => Some(e),
+			Self::Launch(_, e) => Some(e),
+			Self::Target(_, e) => Some(e),
+			Self::Verify(e) => Some(e),
+			Self::ListChanged(e) => Some(e),
+			Self::Json(e) => Some(e),
+			Self::Sync(e) => Some(e),
+		}
+	}
+}
+
+impl Structured for Error {
+	fn variant(&self) -> &'static str {
+		match self {
+			Self::OpenArchiveRoot(_) => "OpenArchiveRoot",
+			Self::OpenArchiveRootParent(_) => "OpenArchiveRootParent",
+			Self::OpenSnapshot(_) => "OpenSnapshot",
+			Self::LockSnapshot(_) => "LockSnapshot",
+			Self::LockArchiveRootParent(_) => "LockArchiveRootParent",
+			Self::SnapshotDir(..) => "SnapshotDir",
+			Self::SnapshotCreate(_) => "SnapshotCreate",
+			Self::SnapshotNoSpace(..) => "SnapshotNoSpace",
+			Self::SnapshotDelete(_) => "SnapshotDelete",
+			Self::SubvolumeInfo(_) => "SubvolumeInfo",
+			Self::ListSnapshots(..) => "ListSnapshots",
+			Self::Spawn(_) => "Spawn",
+			Self::Launch(..) => "Launch",
+			Self::Borg(_) => "Borg",
+			Self::ArchiveExists => "ArchiveExists",
+			Self::ErrorStatus(_) => "ErrorStatus",
+			Self::UnknownExitCode(_) => "UnknownExitCode",
+			Self::Restic(..) => "Restic",
+			Self::Signal(_) => "Signal",
+			Self::Unknown => "Unknown",
+			Self::Target(..) => "Target",
+			Self::Verify(_) => "Verify",
+			Self::ListChanged(_) => "ListChanged",
+			Self::Json(_) => "Json",
+			Self::Sync(_) => "Sync",
+			Self::TooSmall(..) => "TooSmall",
+			Self::ForeignArchives(..) => "ForeignArchives",
+		}
+	}
+
+	fn fields(&self) -> Map<String, Value> {
+		match self {
+			Self::Launch(command_line, _) => fields([("command", command_line.to_json())]),
+			Self::SnapshotDir(p, _) | Self::ListSnapshots(p, _) => {
+				fields([("path", p.to_string_lossy().into())])
+			}
+			Self::SnapshotNoSpace(retries, _) => fields([("retries", (*retries).into())]),
+			Self::Borg(message) => fields([("message", message.as_str().into())]),
+			Self::ErrorStatus(output) => fields([("output", output.as_slice().into())]),
+			Self::UnknownExitCode(code) => fields([("code", (*code).into())]),
+			Self::Restic(code, output) => fields([
+				("code", (*code).into()),
+				("output", output.as_slice().into()),
+			]),
+			Self::Signal(signal) => fields([("signal", (*signal).into())]),
+			Self::Target(url, _) => fields([("repository", url.as_str().into())]),
+			Self::TooSmall(files, bytes) => {
+				fields([("files", (*files).into()), ("bytes", (*bytes).into())])
+			}
+			Self::ForeignArchives(glob, names) => fields([
+				("pattern", glob.as_str().into()),
+				("archives", names.as_slice().into()),
+			]),
+			_ => Map::new(),
 		}
 	}
 }
@@ -119,58 +322,277 @@ fn openat(
 	}
 }
 
-/// Performs a backup, given a snapshot if applicable.
+/// Settings which are shared by every backup performed in one run.
+pub struct Context<'a> {
+	/// The run timestamp in UTC, recorded by Borg as the archive creation time unless an archive’s
+	/// `timestamp_source` says otherwise.
+	pub timestamp_utc: &'a str,
+
+	/// Whether the run timestamp was given on the command line, in which case it is recorded for
+	/// every archive whatever its `timestamp_source`.
+	pub timestamp_given: bool,
+
+	/// The run timestamp as written in each archive’s name, in the archive’s time zone, keyed by
+	/// archive name.
+	pub name_timestamps: &'a HashMap<&'a str, String>,
+
+	/// The passphrase, if any, for each repository.
+	pub passphrases: &'a HashMap<&'a str, Option<Vec<u8>>>,
+
+	/// The umask.
+	pub umask: u16,
+
+	/// The paths, relative to the archive root, of Borg’s own directories to exclude from each
+	/// archive that has any within its root.
+	pub excludes: &'a HashMap<&'a str, Vec<PathBuf>>,
+
+	/// Where to send Borg’s progress reports, in addition to the terminal, if anywhere.
+	pub progress: Option<&'a progress::Sink>,
+
+	/// The settings of the repositories that have any, keyed by URL.
+	pub repositories: &'a BTreeMap<Cow<'a, str>, config::Repository<'a>>,
+
+	/// The `cgroup.procs` file of the cgroup into which to move each `borg create` process, if
+	/// any, opened for writing.
+	pub cgroup: Option<&'a File>,
+
+	/// Whether to read each archive’s statistics from `borg create --json` to account for the use
+	/// of each repository, in place of the statistics Borg prints with `--stats`.
+	pub usage: bool,
+}
+
+impl Context<'_> {
+	/// Returns the run timestamp as written in an archive’s name.
+	fn name_timestamp(&self, archive_name: &str) -> &str {
+		self.name_timestamps
+			.get(archive_name)
+			.expect("name timestamp missing from map, but we computed one for every archive")
+	}
+
+	/// Returns the additional environment variables to set for Borg, or restic, for a repository.
+	pub fn borg_env(&self, repository: &str) -> impl Iterator<Item = (&str, &str)> {
+		self.repositories
+			.get(repository)
+			.into_iter()
+			.flat_map(|i| &i.borg_env)
+			.map(|(k, v)| (&**k, &**v))
+	}
+
+	/// Returns the `--remote-path` option to pass to Borg for a repository, if it needs one.
+	fn remote_path_arg(&self, repository: &str) -> Option<String> {
+		self.repositories
+			.get(repository)
+			.and_then(|i| i.remote_path.as_deref())
+			.map(|i| format!("--remote-path={i}"))
+	}
+}
+
+/// Returns the directories where Borg keeps its cache and its configuration (including keys), as
+/// Borg itself would determine them from the environment variables returned by `env`.
+fn borg_dirs_from(env: impl Fn(&str) -> Option<OsString>) -> Vec<PathBuf> {
+	let env = |name: &str| env(name).filter(|i| !i.is_empty()).map(PathBuf::from);
+	let base = env("BORG_BASE_DIR");
+	let home = env("HOME");
+	let dir = |specific: &str, xdg: &str, under_home: &str| {
+		env(specific).or_else(|| match &base {
+			Some(base) => Some(base.join(under_home)),
+			None => env(xdg)
+				.map(|i| i.join("borg"))
+				.or_else(|| home.as_ref().map(|i| i.join(under_home))),
+		})
+	};
+	[
+		dir("BORG_CACHE_DIR", "XDG_CACHE_HOME", ".cache/borg"),
+		dir("BORG_CONFIG_DIR", "XDG_CONFIG_HOME", ".config/borg"),
+	]
+	.into_iter()
+	.flatten()
+	.collect()
+}
+
+/// Returns the directories where Borg keeps its cache and its configuration.
+pub fn borg_dirs() -> Vec<PathBuf> {
+	borg_dirs_from(|name| std::env::var_os(name))
+}
+
+/// Returns the paths, relative to `root`, of those of `dirs` that lie strictly within it.
 ///
-/// On success, returns whether any warnings were generated.
-fn run_with_root(
-	archive_name: &str,
-	archive: &config::Archive,
-	timestamp_utc: &str,
-	timestamp_local: &str,
-	passphrase: Option<&str>,
-	root: impl AsFd,
-	umask: u16,
-) -> Result<bool, Error> {
-	// Launch Borg.
-	let mut child = Command::new("borg");
-	let root = root.as_fd().as_raw_fd();
-	// SAFETY: The lambda just calls fchdir, which is documented as signal-safe.
-	unsafe {
-		child.pre_exec(move || {
-			// SAFETY: The root parameter (of type impl AsFd) lives for the duration of
-			// run_with_root, which, if it successfully spawns the child, has created a new process
-			// in which the descriptor remains valid even if closed in the parent.
-			let ret = libc::fchdir(root);
-			if ret < 0 {
-				Err(std::io::Error::last_os_error())
-			} else {
-				Ok(())
+/// All paths should be canonical, so that symbolic links do not hide an overlap.
+pub fn excludes_within(root: &Path, dirs: &[PathBuf]) -> Vec<PathBuf> {
+	dirs.iter()
+		.filter_map(|i| i.strip_prefix(root).ok())
+		.filter(|i| !i.as_os_str().is_empty())
+		.map(Path::to_owned)
+		.collect()
+}
+
+/// What was learned from a Borg process’s standard error.
+#[derive(Debug, Default, Eq, PartialEq)]
+struct Output {
+	/// The first error message, if any.
+	error: Option<String>,
+
+	/// Whether Borg reported that the archive already exists.
+	archive_exists: bool,
+
+	/// The number of files Borg reported as having changed while it read them.
+	changed_files: u64,
+
+	/// The last few lower-level messages and non-JSON lines.
+	tail: Vec<String>,
+}
+
+/// Passes the JSON lines that Borg writes to standard error with `--log-json` through to `out` in
+/// human-readable form, as Borg itself would have printed them, while collecting the information
+/// needed to report a failure.
+///
+/// Progress reports overwrite each other on a single line, unless `show_progress` is false, in
+/// which case they are not displayed at all. Either way, each progress report’s JSON line is also
+/// passed to `on_progress`. Any lines that are not valid JSON are passed through unmodified.
+fn handle_output(
+	mut stderr: impl BufRead,
+	mut out: impl Write,
+	show_progress: bool,
+	mut on_progress: impl FnMut(&str),
+) -> std::io::Result<Output> {
+	let mut line_buffer = Vec::new();
+	let mut error = None;
+	let mut archive_exists = false;
+	let mut changed_files = 0;
+	let mut tail = Tail::default();
+	// The length of the progress report currently displayed, if there is one.
+	let mut progress_len: Option<usize> = None;
+	loop {
+		line_buffer.clear();
+		if stderr.read_until(b'\n', &mut line_buffer)? == 0 {
+			break;
+		}
+		let text = String::from_utf8_lossy(&line_buffer);
+		let text = text.trim_end();
+		let line = serde_json::from_str::<StderrLine>(text);
+		if let Ok(StderrLine::ArchiveProgress { .. } | StderrLine::Progress { .. }) = line {
+			on_progress(text);
+		}
+		let (progress, message) = match line {
+			Ok(StderrLine::ArchiveProgress {
+				original_size,
+				compressed_size,
+				deduplicated_size,
+				nfiles,
+				path,
+				finished: false,
+			}) => (
+				Some(format!(
+					"{} O {} C {} D {nfiles} N {path}",
+					Size(original_size),
+					Size(compressed_size),
+					Size(deduplicated_size)
+				)),
+				None,
+			),
+			Ok(StderrLine::Progress {
+				message: Some(message),
+				finished: false,
+			}) => (Some(message.into_owned()), None),
+			Ok(StderrLine::LogMessage {
+				level,
+				message,
+				message_id,
+			}) => {
+				archive_exists |= message_id == Some(MessageId::ArchiveAlreadyExists);
+				if is_file_changed(message_id, &message) {
+					changed_files += 1;
+				}
+				if level >= LogLevel::Error {
+					error.get_or_insert_with(|| message.clone().into_owned());
+				} else {
+					tail.push_line(&message);
+				}
+				(None, Some(message))
 			}
-		});
+			Ok(_) => (None, None),
+			Err(_) => {
+				tail.push_line(text);
+				(None, Some(text.into()))
+			}
+		};
+		match progress.filter(|_| show_progress) {
+			Some(progress) => {
+				// Overwrite the previous progress report, padding to erase any leftover characters.
+				let width = progress_len.unwrap_or(0);
+				write!(out, "\r{progress:width$}")?;
+				progress_len = Some(progress.len());
+			}
+			None => {
+				// A progress report has finished or a message needs to be printed, so end any
+				// progress report on display.
+				if progress_len.take().is_some() {
+					writeln!(out)?;
+				}
+				if let Some(message) = message {
+					writeln!(out, "{message}")?;
+				}
+			}
+		}
+		out.flush()?;
 	}
-	child
-		.args([
-			"--verbose",
-			"--progress",
-			"--iec",
-			"--umask",
-			&format!("0{umask:o}"),
-			"create",
-			"--stats",
-			"--exclude-caches",
-			"--timestamp",
-			timestamp_utc,
-			"--compression",
-			&archive.compression,
-		])
-		.args(archive.patterns.iter().map(|i| format!("--pattern={i}")))
-		.arg(format!("::{archive_name}-{timestamp_local}"))
-		.arg(".")
-		.env("BORG_REPO", OsStr::new(archive.repository.as_ref()))
-		.env("BORG_FILES_CACHE_SUFFIX", archive_name);
+	if progress_len.is_some() {
+		writeln!(out)?;
+	}
+	Ok(Output {
+		error,
+		archive_exists,
+		changed_files,
+		tail: tail.into_lines(),
+	})
+}
+
+/// Returns whether a log message reports that a file changed while Borg was reading it.
+///
+/// Borg 1.4 and later identify this warning by its message ID; earlier versions give it none, so
+/// it is recognized by its text instead.
+fn is_file_changed(message_id: Option<MessageId>, message: &str) -> bool {
+	match message_id {
+		Some(id) => id == MessageId::FileChanged,
+		None => message.ends_with(": file changed while we backed it up"),
+	}
+}
+
+/// The result of a Borg command that did not fail.
+#[derive(Debug, Default, Eq, PartialEq)]
+struct Completion {
+	/// Whether Borg reported any warnings.
+	warnings: bool,
+
+	/// The number of files Borg reported as having changed while it read them.
+	changed_files: u64,
+
+	/// Borg’s standard output, if the command was set up to capture it.
+	stdout: Vec<u8>,
+}
+
+/// Runs a Borg command to completion, passing it the repository passphrase if there is one.
+///
+/// The command must include `--log-json`. Borg’s standard error is decoded and passed through to
+/// ours as it arrives, so that the specific error message can be reported if Borg fails.
+///
+/// Progress reports are shown only if not in quiet mode, but are passed to `on_progress`
+/// regardless. If the command’s standard output is piped, it is collected concurrently and
+/// returned.
+///
+/// On success, returns whether Borg reported any warnings and how many files it reported as having
+/// changed while it read them.
+fn spawn_and_wait(
+	mut child: Command,
+	passphrase: Option<&[u8]>,
+	on_progress: impl FnMut(&str),
+) -> Result<Completion, Error> {
 	let passphrase_pipe_reader = if let Some(passphrase) = passphrase {
 		let passphrase_pipe_reader =
 			super::passphrase::send_to_inheritable_pipe(passphrase).map_err(Error::Spawn)?;
+		for var in super::passphrase::OVERRIDING_ENV {
+			child.env_remove(var);
+		}
 		child.env(
 			"BORG_PASSPHRASE_FD",
 			format!("{}", passphrase_pipe_reader.as_fd().as_raw_fd()),
@@ -179,20 +601,63 @@ fn run_with_root(
 	} else {
 		None
 	};
-	let mut child = child.spawn().map_err(Error::Spawn)?;
+	let mut child = child
+		.stderr(Stdio::piped())
+		.spawn()
+		.map_err(|e| Error::Launch(CommandLine::of(&child), e))?;
 
 	// Drop the pipe reader now that the child has a copy of it, ensuring we don’t keep open FDs
 	// around longer than necessary.
 	drop(passphrase_pipe_reader);
 
+	// Collect standard output, if it is piped, on another thread, so that neither pipe can fill up
+	// and block Borg while the other is being read.
+	let stdout = child.stdout.take().map(|mut stdout| {
+		std::thread::spawn(move || {
+			let mut buffer = Vec::new();
+			stdout.read_to_end(&mut buffer).map(|_| buffer)
+		})
+	});
+
+	// Pass through standard error until the child closes it. If that fails, try to clean up by
+	// killing the child, which may not have finished yet.
+	let output = match handle_output(
+		BufReader::new(child.stderr.take().unwrap()),
+		std::io::stderr().lock(),
+		!verbosity::quiet(),
+		on_progress,
+	) {
+		Ok(output) => output,
+		Err(e) => {
+			// Best effort; the child may already have died, in which case there is nothing to do.
+			let _ = child.kill();
+			let _ = child.wait();
+			return Err(Error::Spawn(e));
+		}
+	};
+
 	// Wait and collect exit status.
 	let status = child.wait().map_err(Error::Spawn)?;
+	let stdout = match stdout {
+		Some(thread) => thread
+			.join()
+			.expect("standard output reader panicked")
+			.map_err(Error::Spawn)?,
+		None => Vec::new(),
+	};
 	if let Some(code) = status.code() {
 		// The process terminated normally.
 		match code {
-			0 => Ok(false),                         // Borg returned success.
-			1 => Ok(true),                          // Borg returned success with a warning.
-			2 => Err(Error::ErrorStatus),           // Borg returned error.
+			0 | 1 => Ok(Completion {
+				warnings: code == 1, // Borg returned success, possibly with a warning.
+				changed_files: output.changed_files,
+				stdout,
+			}),
+			2 if output.archive_exists => Err(Error::ArchiveExists), // The archive name is taken.
+			2 => Err(match output.error {
+				Some(message) => Error::Borg(message), // Borg returned error with a message.
+				None => Error::ErrorStatus(output.tail), // Borg returned error without one.
+			}),
 			_ => Err(Error::UnknownExitCode(code)), // Borg returned an exit code it is not documented as being able to return.
 		}
 	} else if let Some(signal) = status.signal() {
@@ -204,160 +669,1982 @@ fn run_with_root(
 	}
 }
 
-/// Information about an existent snapshot.
-struct Snapshot {
-	/// Whether any warnings were generated while creating the snapshot.
-	pub warnings: bool,
+/// The files cache mode used for archives backed up from a btrfs snapshot if none is configured.
+///
+/// Each snapshot presents the same files with different inode numbers, so Borg’s default mode,
+/// which includes the inode number, would treat every file as changed on every run.
+const SNAPSHOT_FILES_CACHE: &str = "ctime,size";
 
-	/// The file descriptor of the parent directory containing the snapshot and its source.
-	pub parent: File,
+/// Returns the files cache mode to pass to Borg for an archive, or `None` to use Borg’s default.
+fn files_cache<'a>(archive: &'a config::Archive<'_>) -> Option<&'a str> {
+	archive
+		.files_cache
+		.as_deref()
+		.or(archive.btrfs_snapshot.then_some(SNAPSHOT_FILES_CACHE))
+}
 
-	/// The file descriptor of the snapshot itself.
-	pub snapshot_fd: File,
+/// Expands Borgify’s own placeholders in an archive comment.
+///
+/// `{archive}` becomes the archive name, `{tags}` the archive’s tags separated by commas, and
+/// `{borgify_version}` the version of Borgify. Anything else, including Borg’s own placeholders
+/// such as `{hostname}` and escaped braces (`{{` and `}}`), is left for Borg to expand.
+fn expand_comment(comment: &str, archive_name: &str, archive: &config::Archive<'_>) -> String {
+	let mut ret = String::with_capacity(comment.len());
+	let mut rest = comment;
+	while let Some(start) = rest.find('{') {
+		ret.push_str(&rest[..start]);
+		rest = &rest[start..];
+		if let Some(after) = rest.strip_prefix("{{") {
+			ret.push_str("{{");
+			rest = after;
+			continue;
+		}
+		let Some(end) = rest.find('}') else {
+			break;
+		};
+		match &rest[1..end] {
+			"archive" => ret.push_str(archive_name),
+			"tags" => ret.push_str(&archive.tags.join(",")),
+			"borgify_version" => ret.push_str(env!("CARGO_PKG_VERSION")),
+			_ => ret.push_str(&rest[..=end]),
+		}
+		rest = &rest[end + 1..];
+	}
+	ret.push_str(rest);
+	ret
 }
 
-impl Snapshot {
-	/// Creates a btrfs snapshot at a sibling location to the source path, with a generated name.
-	///
-	/// On success, returns whether any warnings were generated, and the path to the snapshot.
-	fn create(source: &File, hash_seed: &[u8]) -> Result<Self, Error> {
-		// Open the parent directory of the archive root.
-		let parent = openat(
-			source,
-			CStr::from_bytes_with_nul(b"..\0").unwrap(),
-			libc::O_DIRECTORY,
-			0,
+/// Formats a time in UTC as Borg’s `--timestamp` option expects.
+fn format_timestamp(time: SystemTime) -> String {
+	chrono::DateTime::<chrono::Utc>::from(time)
+		.format("%FT%T")
+		.to_string()
+}
+
+/// Builds the `borg create` command that backs up an archive to one repository, without arranging
+/// for it to run in the archive root.
+///
+/// `timestamp` is the creation time to record, and `source_excludes` lists any further paths,
+/// relative to the archive root, to exclude, such as nested subvolumes missing from a snapshot.
+pub fn create_command(
+	context: &Context<'_>,
+	archive_name: &str,
+	archive: &config::Archive,
+	repository: &str,
+	timestamp: &str,
+	source_excludes: &[PathBuf],
+) -> Command {
+	let timestamp_local = context.name_timestamp(archive_name);
+
+	// For relative paths, Borg runs in the archive root and is told to back up “.”; for absolute
+	// paths, it is given the archive root’s path.
+	let (path, exclude_base) = match archive.path_style {
+		config::PathStyle::Relative => (Path::new("."), Path::new("")),
+		config::PathStyle::Absolute => (&*archive.root, &*archive.root),
+	};
+	let quiet = verbosity::quiet();
+	let mut child = Command::new("borg");
+	child
+		.args((!quiet).then_some("--verbose"))
+		.args((!quiet || context.progress.is_some()).then_some("--progress"))
+		.args([
+			"--log-json",
+			"--iec",
+			"--umask",
+			&format!("0{:o}", context.umask),
+		])
+		.args(context.remote_path_arg(repository))
+		.args(
+			archive
+				.upload_buffer
+				.map(|i| format!("--upload-buffer={i}")),
+		)
+		.arg("create")
+		.args(context.usage.then_some("--json"))
+		.args((!quiet && !context.usage).then_some("--stats"))
+		.args([
+			"--exclude-caches",
+			"--timestamp",
+			timestamp,
+			"--compression",
+			&archive.compression.to_string(),
+		])
+		.args(files_cache(archive).map(|i| format!("--files-cache={i}")))
+		.args(
+			archive
+				.checkpoint_interval
+				.map(|i| format!("--checkpoint-interval={i}")),
 		)
-		.map_err(Error::OpenArchiveRootParent)?;
+		.args(archive.patterns.iter().map(|i| format!("--pattern={i}")))
+		.args(
+			context
+				.excludes
+				.get(archive_name)
+				.into_iter()
+				.flatten()
+				.chain(source_excludes)
+				.map(|i| format!("--exclude=pp:{}", exclude_base.join(i).display())),
+		)
+		.args(
+			archive
+				.comment
+				.as_deref()
+				.map(|i| format!("--comment={}", expand_comment(i, archive_name, archive))),
+		)
+		.arg(format!("::{archive_name}-{timestamp_local}"))
+		.arg(path)
+		.envs(context.borg_env(repository))
+		.env("BORG_REPO", OsStr::new(repository))
+		.env("BORG_FILES_CACHE_SUFFIX", archive_name);
+	child
+}
 
-		// Try to create a “randomly” (actually an SHA256 of a seed value and a counter) named
-		// subvolume, repeatedly, until we don’t collide with an existing name.
-		let mut any_warnings = false;
-		let mut hash_base = hmac_sha256::Hash::new();
-		hash_base.update(hash_seed);
-		let hash_base = hash_base;
-		for i in u64::MIN..=u64::MAX {
-			let mut hash = hash_base;
-			hash.update(i.to_le_bytes());
-			let hash = hash.finalize();
-			let snapshot_name = format!("{:x}", FormattableSlice(&hash));
-			match btrfs::create_snapshot(source, &parent, &snapshot_name) {
-				Ok(()) => {
-					let snapshot_fd = openat(
-						&parent,
-						CString::new(snapshot_name)
-							.expect("hex-encoded hash contains embedded NUL"),
-						libc::O_DIRECTORY | libc::O_NOFOLLOW,
-						0,
-					)
-					.map_err(Error::OpenSnapshot)?;
-					return Ok(Self {
-						warnings: any_warnings,
-						parent,
-						snapshot_fd,
-					});
+/// The parts of the output of `borg create --json` that Borgify uses.
+#[derive(Debug, Deserialize)]
+struct CreateOutput {
+	/// The archive created.
+	archive: CreateArchive,
+}
+
+/// The archive in the output of `borg create --json`.
+#[derive(Debug, Deserialize)]
+struct CreateArchive {
+	/// The archive’s statistics.
+	stats: usage::Stats,
+}
+
+/// Returns the statistics of the archive created, from the standard output of
+/// `borg create --json`.
+fn create_stats(stdout: &[u8]) -> Result<usage::Stats, serde_json::Error> {
+	serde_json::from_slice::<CreateOutput>(stdout).map(|i| i.archive.stats)
+}
+
+/// Performs a backup to one repository, given a snapshot if applicable.
+///
+/// On success, returns whether any warnings were generated and how many files changed while being
+/// read.
+fn run_with_root(
+	context: &Context<'_>,
+	archive_name: &str,
+	archive: &config::Archive,
+	repository: &str,
+	source: &Source,
+) -> Result<Completion, Error> {
+	let timestamp: Cow<'_, str> = match archive.timestamp_source {
+		config::TimestampSource::Run => context.timestamp_utc.into(),
+		_ if context.timestamp_given => context.timestamp_utc.into(),
+		config::TimestampSource::Snapshot => {
+			format_timestamp(source.snapshot_time().unwrap_or_else(SystemTime::now)).into()
+		}
+		config::TimestampSource::Start => format_timestamp(SystemTime::now()).into(),
+	};
+	let passphrase = context
+		.passphrases
+		.get(repository)
+		.expect("passphrase missing from map, but we already examined every repository")
+		.as_deref();
+
+	// Launch Borg or restic, in the archive root if it is given relative paths, and in the cgroup
+	// if there is one.
+	let mut child = match archive.backend {
+		config::Backend::Borg => create_command(
+			context,
+			archive_name,
+			archive,
+			repository,
+			&timestamp,
+			&source.excludes,
+		),
+		config::Backend::Restic => restic::backup_command(
+			context,
+			archive_name,
+			archive,
+			repository,
+			&timestamp,
+			source.snapshot_path().unwrap_or(&archive.root),
+			&source.excludes,
+		),
+	};
+	if let Some(cgroup) = context.cgroup {
+		let procs = cgroup.as_raw_fd();
+		// SAFETY: The lambda just calls write, which is documented as signal-safe.
+		unsafe {
+			child.pre_exec(move || {
+				// SAFETY: The procs descriptor belongs to the context, which outlives
+				// run_with_root, and the buffer is a valid one-byte slice. Writing “0” moves the
+				// writing process, which is the child about to execute Borg.
+				let ret = libc::write(procs, b"0".as_ptr().cast(), 1);
+				if ret < 0 {
+					Err(std::io::Error::last_os_error())
+				} else {
+					Ok(())
 				}
-				Err(btrfs::Error::Syscall(e)) if e.kind() == std::io::ErrorKind::AlreadyExists => {
-					// A subvolume with this name already exists. Given how we generate snapshot
-					// subvolume paths, that’s unlikely to be something the user legitimately
-					// created (more likely something created by a previous invocation of this tool
-					// that failed to delete it), so we should probably warn about it, but we
-					// shouldn’t do anything else to it; instead, just increment “i” and try
-					// generating a new name.
-					eprintln!(
-						"WARNING: Snapshot {} already exists; trying another name",
-						snapshot_name
-					);
-					any_warnings = true;
+			});
+		}
+	}
+	if archive.path_style == config::PathStyle::Relative {
+		let root = source.fd().as_raw_fd();
+		// SAFETY: The lambda just calls fchdir, which is documented as signal-safe.
+		unsafe {
+			child.pre_exec(move || {
+				// SAFETY: The root parameter (of type BorrowedFd) lives for the duration of
+				// run_with_root, which, if it successfully spawns the child, has created a new
+				// process in which the descriptor remains valid even if closed in the parent.
+				let ret = libc::fchdir(root);
+				if ret < 0 {
+					Err(std::io::Error::last_os_error())
+				} else {
+					Ok(())
 				}
-				Err(e) => return Err(Error::SnapshotCreate(e)),
-			}
+			});
 		}
-		panic!("tried 2⁶⁴ filenames without finding a nonexistent one, which is impossible");
 	}
+	let started = Instant::now();
+	let on_progress = |line: &str| {
+		if let Some(progress) = context.progress {
+			progress.send(line, archive_name, repository);
+		}
+	};
+	let (mut completion, stats) = match archive.backend {
+		config::Backend::Borg if context.usage => {
+			child.stdout(Stdio::piped());
+			let completion = spawn_and_wait(child, passphrase, on_progress)?;
+			let stats = create_stats(&completion.stdout);
+			(completion, Some(stats))
+		}
+		config::Backend::Borg => (spawn_and_wait(child, passphrase, on_progress)?, None),
+		config::Backend::Restic => {
+			let backup = restic::run_backup(child, passphrase, on_progress)?;
+			let completion = Completion {
+				warnings: backup.warnings,
+				..Completion::default()
+			};
+			(completion, Some(backup.stats))
+		}
+	};
 
-	/// Deletes a snapshot.
-	fn delete(self) -> Result<(), Error> {
-		btrfs::delete_subvolume(self.parent, self.snapshot_fd).map_err(Error::SnapshotDelete)
+	// Report the archive’s statistics, if they were asked for, and account them to the repository.
+	// The archive has been created by now, so statistics that cannot be read only warrant a
+	// warning.
+	match stats {
+		None => (),
+		Some(Ok(stats)) => {
+			if !verbosity::quiet() {
+				println!("Archive {archive_name}: {}", stats.describe());
+			}
+			usage::record(repository, &stats, started.elapsed());
+		}
+		Some(Err(e)) => {
+			eprintln!(
+				"WARNING: Cannot read the statistics {} reported for archive {archive_name}: {e}",
+				archive.backend.program()
+			);
+			completion.warnings = true;
+		}
 	}
+	Ok(completion)
 }
 
-/// Creates a btrfs snapshot, performs the backup, and deletes the snapshot.
+/// Verifies that an archive just created in a repository can be read back, by checking its
+/// metadata and the presence of all its chunks.
 ///
 /// On success, returns whether any warnings were generated.
-fn do_snapshot(
-	archive_name: &str,
-	archive: &config::Archive,
-	timestamp_utc: &str,
-	timestamp_local: &str,
-	passphrase: Option<&str>,
-	archive_root: &File,
-	umask: u16,
-) -> Result<bool, Error> {
-	// Create a snapshot at a unique path which is a sibling to the root.
-	let snapshot = Snapshot::create(archive_root, archive.root.as_os_str().as_bytes())?;
-	let snapshot_warnings = snapshot.warnings;
+fn verify(context: &Context<'_>, archive_name: &str, repository: &str) -> Result<bool, Error> {
+	let passphrase = context
+		.passphrases
+		.get(repository)
+		.expect("passphrase missing from map, but we already examined every repository")
+		.as_deref();
+	let mut child = Command::new("borg");
+	child
+		.args((!verbosity::quiet()).then_some("--verbose"))
+		.args(["--log-json", "--umask", &format!("0{:o}", context.umask)])
+		.args(context.remote_path_arg(repository))
+		.args([
+			"check",
+			"--archives-only",
+			&format!("::{archive_name}-{}", context.name_timestamp(archive_name)),
+		])
+		.envs(context.borg_env(repository))
+		.env("BORG_REPO", OsStr::new(repository));
+	spawn_and_wait(child, passphrase, |_| ())
+		.map(|i| i.warnings)
+		.map_err(|e| Error::Verify(Box::new(e)))
+}
 
-	// Run the backup using the snapshot as the archive root.
-	let backup_result = run_with_root(
-		archive_name,
-		archive,
-		timestamp_utc,
-		timestamp_local,
-		passphrase,
-		&snapshot.snapshot_fd,
-		umask,
-	);
+/// The parts of the output of `borg list --json` that Borgify uses.
+#[derive(Debug, Deserialize)]
+struct ListOutput {
+	/// The archives listed, oldest first.
+	archives: Vec<ListArchive>,
+}
+
+/// An archive in the output of `borg list --json`.
+#[derive(Debug, Deserialize)]
+struct ListArchive {
+	/// The archive’s name.
+	name: String,
+
+	/// The time the archive was started, in local time without an offset, as Borg 1.2 writes it,
+	/// or with one, as later versions do.
+	#[serde(default)]
+	time: Option<String>,
+}
+
+/// Returns the name of the archive that a checkpoint archive was left behind by, if `name` is that
+/// of a checkpoint archive, which Borg leaves behind when creating an archive is interrupted, named
+/// after the archive with `.checkpoint` appended, optionally followed by a dot and a number.
+fn checkpoint_base(name: &str) -> Option<&str> {
+	let (base, suffix) = name.rsplit_once(".checkpoint")?;
+	let numbered = || {
+		suffix
+			.strip_prefix('.')
+			.is_some_and(|i| !i.is_empty() && i.bytes().all(|b| b.is_ascii_digit()))
+	};
+	(!base.is_empty() && (suffix.is_empty() || numbered())).then_some(base)
+}
 
-	// Delete the snapshot.
-	let delete_snapshot_result = snapshot.delete();
+/// Returns the times at which a timestamp format is tried to find out what its output looks like.
+///
+/// The times include every month and weekday, one- and two-digit fields, years differing in every
+/// digit and on either side of a change in the number of digits of a Unix timestamp, and offsets
+/// on either side of UTC.
+fn sample_times() -> impl Iterator<Item = chrono::DateTime<chrono::FixedOffset>> {
+	use chrono::TimeZone as _;
+	let offsets = [-9 * 3600 - 1800, 3600, 5 * 3600 + 2700, 12 * 3600 + 2700]
+		.map(|i| chrono::FixedOffset::east_opt(i).expect("offset is valid"));
+	[1987, 2001, 2099, 2110].into_iter().flat_map(move |year| {
+		(1..=12).flat_map(move |month| {
+			[1, 9, 10, 22, 23, 24, 25, 26, 27, 28]
+				.into_iter()
+				.zip(
+					[(0, 0, 0), (9, 5, 7), (12, 30, 30), (23, 59, 59)]
+						.into_iter()
+						.cycle(),
+				)
+				.zip(offsets.into_iter().cycle())
+				.filter_map(move |((day, (hour, minute, second)), offset)| {
+					offset
+						.with_ymd_and_hms(year, month, day, hour, minute, second)
+						.single()
+				})
+		})
+	})
+}
+
+/// Appends text to a pattern for Borg’s `--glob-archives` option, bracketing the characters that
+/// are special in patterns so that they match only themselves.
+fn push_glob_literal(glob: &mut String, text: &str) {
+	for c in text.chars() {
+		if matches!(c, '*' | '?' | '[') {
+			glob.push('[');
+			glob.push(c);
+			glob.push(']');
+		} else {
+			glob.push(c);
+		}
+	}
+}
 
-	match (backup_result, delete_snapshot_result) {
-		(Ok(any_warnings_running_backup), Ok(())) => {
-			Ok(snapshot_warnings || any_warnings_running_backup)
+/// Returns a pattern for Borg’s `--glob-archives` option that matches the names of the Borg
+/// archives of the archive `name`: the name, a hyphen, and a timestamp in the strftime `format`.
+///
+/// Each part of the timestamp that has the same length at every sample time becomes a literal or a
+/// digit class at each position, as appropriate, but a part whose length varies, such as a month
+/// name, can only become a wildcard. The names that a pattern matches should therefore still be
+/// checked with [`is_archive_of`](is_archive_of).
+fn archive_glob(name: &str, format: &str) -> String {
+	let mut glob = String::new();
+	push_glob_literal(&mut glob, name);
+	glob.push('-');
+	for item in StrftimeItems::new(format) {
+		let samples: Vec<Vec<char>> = sample_times()
+			.map(|time| {
+				time.format_with_items(std::iter::once(&item))
+					.to_string()
+					.chars()
+					.collect()
+			})
+			.collect();
+		if samples.iter().any(|i| i.len() != samples[0].len()) {
+			if !glob.ends_with('*') {
+				glob.push('*');
+			}
+			continue;
 		}
-		(Ok(_), Err(e)) => Err(e),
-		(Err(e), Ok(())) => Err(e),
-		// If both failed, the error from doing the backup is more important.
-		(Err(backup_error), Err(_)) => Err(backup_error),
+		for position in 0..samples[0].len() {
+			let first = samples[0][position];
+			if samples.iter().all(|i| i[position] == first) {
+				push_glob_literal(&mut glob, first.encode_utf8(&mut [0; 4]));
+			} else if samples.iter().all(|i| i[position].is_ascii_digit()) {
+				glob.push_str("[0-9]");
+			} else {
+				glob.push('?');
+			}
+		}
+	}
+	glob
+}
+
+/// Returns whether `borg_name` is the name of one of the Borg archives of the archive `name`: the
+/// name, a hyphen, and a timestamp in the strftime `format`.
+fn is_archive_of(borg_name: &str, name: &str, format: &str) -> bool {
+	borg_name
+		.strip_prefix(name)
+		.and_then(|i| i.strip_prefix('-'))
+		.is_some_and(|timestamp| {
+			chrono::format::parse(
+				&mut chrono::format::Parsed::new(),
+				timestamp,
+				StrftimeItems::new(format),
+			)
+			.is_ok()
+		})
+}
+
+/// Returns the name of the most recent Borg archive of the archive `name`, with timestamps in
+/// `format`, other than `current` and checkpoint archives, in the standard output of
+/// `borg list --json`, if there is one.
+fn previous_archive(
+	stdout: &[u8],
+	current: &str,
+	name: &str,
+	format: &str,
+) -> Result<Option<String>, serde_json::Error> {
+	let output: ListOutput = serde_json::from_slice(stdout)?;
+	Ok(output
+		.archives
+		.into_iter()
+		.rev()
+		.map(|i| i.name)
+		.find(|i| i != current && is_archive_of(i, name, format)))
+}
+
+/// Parses the time of an archive in the output of `borg list --json`.
+fn parse_archive_time(time: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+	use chrono::TimeZone as _;
+	if let Ok(time) = chrono::DateTime::parse_from_rfc3339(time) {
+		return Some(time.to_utc());
 	}
+	let time = chrono::NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M:%S%.f").ok()?;
+	chrono::Local
+		.from_local_datetime(&time)
+		.earliest()
+		.map(|i| i.to_utc())
+}
+
+/// Returns the names of the checkpoint archives of the archive `name`, with timestamps in `format`,
+/// started before `cutoff` in the standard output of `borg list --json --consider-checkpoints`.
+fn stale_checkpoints(
+	stdout: &[u8],
+	cutoff: chrono::DateTime<chrono::Utc>,
+	name: &str,
+	format: &str,
+) -> Result<Vec<String>, serde_json::Error> {
+	let output: ListOutput = serde_json::from_slice(stdout)?;
+	Ok(output
+		.archives
+		.into_iter()
+		.filter(|i| checkpoint_base(&i.name).is_some_and(|i| is_archive_of(i, name, format)))
+		.filter(|i| {
+			i.time
+				.as_deref()
+				.and_then(parse_archive_time)
+				.is_some_and(|time| time < cutoff)
+		})
+		.map(|i| i.name)
+		.collect())
 }
 
-/// Performs a backup.
+/// Deletes the checkpoint archives, left by interrupted backups of an archive in a repository, that
+/// were started longer than `max_age` ago.
 ///
 /// On success, returns whether any warnings were generated.
-pub fn run(
+pub fn delete_stale_checkpoints(
+	context: &Context<'_>,
 	archive_name: &str,
-	archive: &config::Archive,
-	timestamp_utc: &str,
-	timestamp_local: &str,
-	passphrase: Option<&str>,
-	umask: u16,
+	archive: &config::Archive<'_>,
+	repository: &str,
+	max_age: chrono::Duration,
 ) -> Result<bool, Error> {
-	let archive_root = File::options()
-		.read(true)
-		.custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
-		.open(&archive.root)
-		.map_err(Error::OpenArchiveRoot)?;
-	if archive.btrfs_snapshot {
-		do_snapshot(
-			archive_name,
-			archive,
-			timestamp_utc,
-			timestamp_local,
-			passphrase,
-			&archive_root,
-			umask,
-		)
+	let passphrase = context
+		.passphrases
+		.get(repository)
+		.expect("passphrase missing from map, but we already examined every repository")
+		.as_deref();
+	let umask = format!("0{:o}", context.umask);
+	let mut child = Command::new("borg");
+	child
+		.args(["--log-json", "--umask", &umask])
+		.args(context.remote_path_arg(repository))
+		.args(["list", "--json", "--consider-checkpoints"])
+		.arg(format!(
+			"--glob-archives={}.checkpoint*",
+			archive_glob(archive_name, &archive.name_date_format)
+		))
+		.envs(context.borg_env(repository))
+		.env("BORG_REPO", OsStr::new(repository))
+		.stdout(Stdio::piped());
+	let listed = spawn_and_wait(child, passphrase, |_| ())?;
+	let cutoff = chrono::Utc::now() - max_age;
+	let stale = stale_checkpoints(
+		&listed.stdout,
+		cutoff,
+		archive_name,
+		&archive.name_date_format,
+	)
+	.map_err(Error::Json)?;
+	let mut warnings = listed.warnings;
+	for name in &stale {
+		if !verbosity::quiet() {
+			println!("Deleting stale checkpoint archive {name} in repository {repository}");
+		}
+		let mut child = Command::new("borg");
+		child
+			.args(["--log-json", "--umask", &umask])
+			.args(context.remote_path_arg(repository))
+			.args(["delete", &format!("::{name}")])
+			.envs(context.borg_env(repository))
+			.env("BORG_REPO", OsStr::new(repository));
+		warnings |= spawn_and_wait(child, passphrase, |_| ())?.warnings;
+	}
+	Ok(warnings)
+}
+
+/// How a path differs between two archives.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum Change {
+	/// The path exists only in the newer archive.
+	Added,
+
+	/// The path exists only in the older archive.
+	Removed,
+
+	/// The path exists in both archives, with different contents or metadata.
+	Modified,
+}
+
+impl Display for Change {
+	fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+		match self {
+			Self::Added => "added".fmt(f),
+			Self::Removed => "removed".fmt(f),
+			Self::Modified => "modified".fmt(f),
+		}
+	}
+}
+
+/// A line of the output of `borg diff --json-lines`, describing one changed path.
+#[derive(Debug, Deserialize)]
+struct DiffLine {
+	/// The path.
+	path: String,
+
+	/// The ways in which the path changed.
+	changes: Vec<DiffChange>,
+}
+
+/// One way in which a path changed, in the output of `borg diff --json-lines`.
+#[derive(Debug, Deserialize)]
+struct DiffChange {
+	/// The kind of change, such as `added`, `removed directory`, `modified`, or `mode`.
+	#[serde(rename = "type")]
+	kind: String,
+}
+
+/// Classifies each path in the standard output of `borg diff --json-lines` as added, removed, or
+/// modified.
+fn parse_diff(stdout: &[u8]) -> Result<Vec<(Change, String)>, serde_json::Error> {
+	stdout
+		.split(|&i| i == b'\n')
+		.filter(|i| !i.is_empty())
+		.map(|line| {
+			let line: DiffLine = serde_json::from_slice(line)?;
+			let kind = |prefix| line.changes.iter().any(|i| i.kind.starts_with(prefix));
+			let change = if kind("added") {
+				Change::Added
+			} else if kind("removed") {
+				Change::Removed
+			} else {
+				Change::Modified
+			};
+			Ok((change, line.path))
+		})
+		.collect()
+}
+
+/// Formats the number of paths of each kind of change, such as `3 added, 0 removed, 12 modified`.
+fn summarize_changes(changes: &[(Change, String)]) -> String {
+	let count = |change| changes.iter().filter(|(i, _)| *i == change).count();
+	format!(
+		"{} added, {} removed, {} modified",
+		count(Change::Added),
+		count(Change::Removed),
+		count(Change::Modified)
+	)
+}
+
+/// Prints the paths that changed between the archive just created in a repository and the
+/// previous archive of the same name there, followed by how many changed in each way.
+///
+/// On success, returns whether any warnings were generated.
+fn list_changed(
+	context: &Context<'_>,
+	archive_name: &str,
+	archive: &config::Archive<'_>,
+	repository: &str,
+) -> Result<bool, Error> {
+	let passphrase = context
+		.passphrases
+		.get(repository)
+		.expect("passphrase missing from map, but we already examined every repository")
+		.as_deref();
+	let quiet = verbosity::quiet();
+	let umask = format!("0{:o}", context.umask);
+	let current = format!("{archive_name}-{}", context.name_timestamp(archive_name));
+	let mut child = Command::new("borg");
+	child
+		.args(["--log-json", "--umask", &umask])
+		.args(context.remote_path_arg(repository))
+		.args(["list", "--json"])
+		.arg(format!(
+			"--glob-archives={}",
+			archive_glob(archive_name, &archive.name_date_format)
+		))
+		.envs(context.borg_env(repository))
+		.env("BORG_REPO", OsStr::new(repository))
+		.stdout(Stdio::piped());
+	let listed = spawn_and_wait(child, passphrase, |_| ())?;
+	let Some(previous) = previous_archive(
+		&listed.stdout,
+		&current,
+		archive_name,
+		&archive.name_date_format,
+	)
+	.map_err(Error::Json)?
+	else {
+		if !quiet {
+			println!("No earlier archive of {archive_name} to compare with");
+		}
+		return Ok(listed.warnings);
+	};
+	let mut child = Command::new("borg");
+	child
+		.args(["--log-json", "--umask", &umask])
+		.args(context.remote_path_arg(repository))
+		.args(["diff", "--json-lines", &format!("::{previous}"), &current])
+		.envs(context.borg_env(repository))
+		.env("BORG_REPO", OsStr::new(repository))
+		.stdout(Stdio::piped());
+	let diffed = spawn_and_wait(child, passphrase, |_| ())?;
+	let changes = parse_diff(&diffed.stdout).map_err(Error::Json)?;
+	if !quiet {
+		for (change, path) in &changes {
+			println!("{change:<8} {path}");
+		}
+		println!("Changes since {previous}: {}", summarize_changes(&changes));
+	}
+	Ok(listed.warnings || diffed.warnings)
+}
+
+/// Returns the names in the standard output of `borg list --json --consider-checkpoints` that are
+/// neither Borg archives of the archive `name`, with timestamps in `format`, nor checkpoints of
+/// them.
+fn foreign_archives(
+	stdout: &[u8],
+	name: &str,
+	format: &str,
+) -> Result<Vec<String>, serde_json::Error> {
+	let output: ListOutput = serde_json::from_slice(stdout)?;
+	Ok(output
+		.archives
+		.into_iter()
+		.map(|i| i.name)
+		.filter(|i| !is_archive_of(checkpoint_base(i).unwrap_or(i), name, format))
+		.collect())
+}
+
+/// Deletes those of an archive’s Borg archives in a repository that its retention rules do not
+/// keep, printing what Borg reports it has deleted and the space freed.
+///
+/// Borg selects the archives to prune by a pattern, which cannot always be made to match exactly
+/// the archive’s own Borg archives, so the pattern is first used to list them, and nothing is
+/// pruned if it matches any Borg archive of another archive.
+///
+/// On success, returns whether any warnings were generated.
+///
+/// # Panics
+/// This function panics if the archive has no retention rules.
+pub fn prune(
+	archive_name: &str,
+	archive: &config::Archive<'_>,
+	repository: &str,
+	passphrase: Option<&[u8]>,
+	remote_path: Option<&str>,
+	borg_env: Option<&config::BorgEnv<'_>>,
+	umask: u16,
+) -> Result<bool, Error> {
+	let retention = archive
+		.prune
+		.as_ref()
+		.expect("archive to prune has retention rules");
+	let quiet = verbosity::quiet();
+	let umask = format!("0{umask:o}");
+	// Checkpoints are included, so that Borg can remove those superseded by a complete archive.
+	let glob = format!("{}*", archive_glob(archive_name, &archive.name_date_format));
+	let mut child = Command::new("borg");
+	child
+		.args(["--log-json", "--umask", &umask])
+		.args(remote_path.map(|i| format!("--remote-path={i}")))
+		.args(["list", "--json", "--consider-checkpoints"])
+		.arg(format!("--glob-archives={glob}"))
+		.envs(borg_env.into_iter().flatten().map(|(k, v)| (&**k, &**v)))
+		.env("BORG_REPO", OsStr::new(repository))
+		.stdout(Stdio::piped());
+	let listed = spawn_and_wait(child, passphrase, |_| ())?;
+	let foreign = foreign_archives(&listed.stdout, archive_name, &archive.name_date_format)
+		.map_err(Error::Json)?;
+	if !foreign.is_empty() {
+		return Err(Error::ForeignArchives(glob, foreign));
+	}
+	let mut child = Command::new("borg");
+	child
+		.args((!quiet).then_some("--verbose"))
+		.args(["--log-json", "--iec", "--umask", &umask])
+		.args(remote_path.map(|i| format!("--remote-path={i}")))
+		.arg("prune")
+		.args((!quiet).then_some("--list"))
+		.args((!quiet).then_some("--stats"))
+		.arg(format!("--glob-archives={glob}"))
+		.args(retention.args())
+		.envs(borg_env.into_iter().flatten().map(|(k, v)| (&**k, &**v)))
+		.env("BORG_REPO", OsStr::new(repository));
+	spawn_and_wait(child, passphrase, |_| ()).map(|i| listed.warnings || i.warnings)
+}
+
+/// Performs a backup to each of the archive’s repositories in turn, given a snapshot if
+/// applicable.
+///
+/// Every repository is attempted even if an earlier one fails. On success, returns whether any
+/// warnings were generated for any repository; on failure, returns the first error.
+fn run_targets(
+	context: &Context<'_>,
+	archive_name: &str,
+	archive: &config::Archive,
+	source: &Source,
+) -> Result<bool, Error> {
+	let multiple = archive.repositories.len() > 1;
+	let quiet = verbosity::quiet();
+	let mut result = Ok(false);
+	let mut changed_files = 0;
+	for repository in &archive.repositories {
+		if multiple && !quiet {
+			println!("----- Repository {repository} -----");
+		}
+		let target_result = profile::time(archive_name, profile::Phase::Create, || {
+			run_with_root(context, archive_name, archive, repository, source)
+		})
+		.map(|completion| {
+			changed_files += completion.changed_files;
+			completion.warnings
+		})
+		.and_then(|warnings| {
+			if archive.verify_after {
+				if !quiet {
+					println!("Verifying archive in repository {repository}");
+				}
+				let verified = profile::time(archive_name, profile::Phase::Verify, || {
+					verify(context, archive_name, repository)
+				})?;
+				Ok(verified || warnings)
+			} else {
+				Ok(warnings)
+			}
+		})
+		.and_then(|warnings| {
+			if archive.list_changed {
+				let listed = list_changed(context, archive_name, archive, repository)
+					.map_err(|e| Error::ListChanged(Box::new(e)))?;
+				Ok(listed || warnings)
+			} else {
+				Ok(warnings)
+			}
+		})
+		.and_then(|warnings| {
+			let sync = context
+				.repositories
+				.get(&**repository)
+				.is_some_and(|i| i.sync_repo);
+			if let Some(path) = check::local_path(repository).filter(|_| sync) {
+				if !quiet {
+					println!("Flushing repository {repository} to disk");
+				}
+				profile::time(archive_name, profile::Phase::Sync, || sync_filesystem(path))
+					.map_err(Error::Sync)?;
+			}
+			Ok(warnings)
+		});
+		if multiple {
+			match &target_result {
+				Ok(_) if quiet => (),
+				Ok(false) => println!("Repository {repository}: succeeded"),
+				Ok(true) => println!("Repository {repository}: succeeded with warnings"),
+				Err(e) => println!("Repository {repository}: failed: {e}"),
+			}
+		}
+		result = match (result, target_result) {
+			(Ok(any_warnings), Ok(warnings)) => Ok(any_warnings || warnings),
+			(Ok(_), Err(e)) => Err(Error::Target(repository.clone().into_owned(), Box::new(e))),
+			(Err(e), _) => Err(e),
+		};
+	}
+	if changed_files != 0 {
+		if source.snapshot_time().is_some() {
+			// Nothing should be writing to a snapshot, so this suggests a misconfiguration, such as
+			// a writable snapshot being modified.
+			eprintln!(
+				"WARNING: Borg reported {changed_files} files in archive {archive_name} as changed while being read, although they were read from a snapshot"
+			);
+		} else if !quiet {
+			println!("Archive {archive_name}: {changed_files} files changed while being read");
+		}
+	}
+	result
+}
+
+/// Flushes all data written to the filesystem containing `path` to disk, such as before unplugging
+/// a removable drive.
+fn sync_filesystem(path: &Path) -> std::io::Result<()> {
+	let file = File::open(path)?;
+	// SAFETY: The file descriptor is valid for the duration of the call.
+	if unsafe { libc::syncfs(file.as_raw_fd()) } == 0 {
+		Ok(())
 	} else {
-		run_with_root(
-			archive_name,
+		Err(std::io::Error::last_os_error())
+	}
+}
+
+/// Information about an existent snapshot.
+struct Snapshot {
+	/// Whether any warnings were generated while creating the snapshot.
+	pub warnings: bool,
+
+	/// The file descriptor of the parent directory containing the snapshot and its source.
+	pub parent: File,
+
+	/// The file descriptor of the snapshot itself.
+	pub snapshot_fd: File,
+
+	/// The time the snapshot was created.
+	pub created: SystemTime,
+
+	/// The path of the snapshot, for messages.
+	pub path: PathBuf,
+}
+
+/// Returns the seed from which snapshot names are generated for a group of archives sharing a
+/// root.
+///
+/// The seed includes the archive names as well as the root, so that different groups of archives
+/// backed up from the same root (for example with different snapshot options) try different
+/// sequences of names rather than colliding with each other.
+fn snapshot_seed(root: &Path, archive_names: &[&str]) -> Vec<u8> {
+	let mut seed = root.as_os_str().as_bytes().to_vec();
+	for name in archive_names {
+		seed.push(0);
+		seed.extend_from_slice(name.as_bytes());
+	}
+	seed
+}
+
+/// Returns the `counter`th candidate snapshot name for a seed: the SHA256 of the seed and the
+/// counter, in hex.
+fn snapshot_name(seed: &[u8], counter: u64) -> String {
+	let mut hash = hmac_sha256::Hash::new();
+	hash.update(seed);
+	hash.update(counter.to_le_bytes());
+	format!("{:x}", FormattableSlice(&hash.finalize()))
+}
+
+/// Opens a directory under `snapshot_dir` to hold snapshots, creating it first if it does not
+/// exist.
+///
+/// Only the last component is created, so that a mistyped or unmounted `snapshot_dir` is reported
+/// rather than silently created on the wrong filesystem.
+fn open_snapshot_dir(dir: &Path) -> std::io::Result<File> {
+	match std::fs::create_dir(dir) {
+		Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(e),
+		_ => (),
+	}
+	File::options()
+		.read(true)
+		.custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+		.open(dir)
+}
+
+/// How long to wait before retrying to create a snapshot when the filesystem is out of space.
+const SNAPSHOT_SPACE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Deletes the subvolume named `name` within `parent` if it is a read-only snapshot of `source`,
+/// and therefore most likely one that an earlier run failed to delete, returning whether it did.
+fn delete_leftover_snapshot(
+	source: &File,
+	parent: &File,
+	name: &str,
+) -> Result<bool, btrfs::Error> {
+	let snapshot_fd = openat(
+		parent,
+		CString::new(name).expect("hex-encoded hash contains embedded NUL"),
+		libc::O_DIRECTORY | libc::O_NOFOLLOW,
+		0,
+	)?;
+	if snapshot_fd.metadata()?.ino() != SUBVOLUME_ROOT_INODE {
+		return Ok(false);
+	}
+	let info = btrfs::subvolume_info(&snapshot_fd)?;
+	if !info.readonly || info.parent_uuid != btrfs::subvolume_info(source)?.uuid {
+		return Ok(false);
+	}
+	btrfs::delete_subvolume(parent, snapshot_fd)?;
+	Ok(true)
+}
+
+impl Snapshot {
+	/// Creates a btrfs snapshot of `source`, whose path is `root`, with a generated name, at a
+	/// sibling location to the source path or, if `dir` is given, within that directory, creating
+	/// it if needed.
+	fn create(
+		source: &File,
+		root: &Path,
+		dir: Option<&Path>,
+		hash_seed: &[u8],
+		options: &config::SnapshotOptions<'_>,
+	) -> Result<Self, Error> {
+		// Open the directory to hold the snapshot.
+		let parent = match dir {
+			Some(dir) => {
+				open_snapshot_dir(dir).map_err(|e| Error::SnapshotDir(dir.to_owned(), e))?
+			}
+			None => {
+				openat(source, c"..", libc::O_DIRECTORY, 0).map_err(Error::OpenArchiveRootParent)?
+			}
+		};
+
+		// Hold an exclusive lock on the parent directory while choosing a name and creating the
+		// snapshot, so that concurrent invocations snapshotting the same root take turns rather
+		// than racing for the same names. The lock is taken on a separate descriptor so that it is
+		// released when that descriptor is dropped at the end of this function.
+		let lock =
+			openat(&parent, c".", libc::O_DIRECTORY, 0).map_err(Error::OpenArchiveRootParent)?;
+		// SAFETY: lock.as_raw_fd() is a valid file descriptor, as lock is an open File.
+		if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } < 0 {
+			return Err(Error::LockArchiveRootParent(std::io::Error::last_os_error()));
+		}
+
+		// Try to create a “randomly” named subvolume, repeatedly, until we don’t collide with an
+		// existing name. Running out of space may be transient, as other space is reclaimed, so
+		// that is retried with the same name a limited number of times, after a delay.
+		let mut any_warnings = false;
+		let mut space_retries = 0;
+		for i in u64::MIN..=u64::MAX {
+			let snapshot_name = snapshot_name(hash_seed, i);
+			let result = loop {
+				let mut result = btrfs::create_snapshot(source, &parent, &snapshot_name, true);
+				if options.allow_writable {
+					if let Err(btrfs::Error::SetReadonly(e)) = &result {
+						// The kernel or filesystem would only create a writable snapshot, which
+						// could not be made read-only, and the user has opted to accept a writable
+						// one instead.
+						eprintln!(
+							"WARNING: Cannot create read-only snapshot ({e}); creating a writable snapshot instead, which may be modified while the backup runs"
+						);
+						any_warnings = true;
+						result = btrfs::create_snapshot(source, &parent, &snapshot_name, false);
+					}
+				}
+				match &result {
+					Err(btrfs::Error::Syscall(e))
+						if e.raw_os_error() == Some(libc::ENOSPC)
+							&& space_retries < options.space_retries =>
+					{
+						space_retries += 1;
+						eprintln!(
+							"WARNING: Not enough space to create snapshot; retrying in {} seconds (retry {space_retries} of {})",
+							SNAPSHOT_SPACE_RETRY_DELAY.as_secs(),
+							options.space_retries
+						);
+						any_warnings = true;
+						std::thread::sleep(SNAPSHOT_SPACE_RETRY_DELAY);
+					}
+					_ => break result,
+				}
+			};
+			match result {
+				Ok(()) => {
+					let snapshot_fd = openat(
+						&parent,
+						CString::new(snapshot_name.as_str())
+							.expect("hex-encoded hash contains embedded NUL"),
+						libc::O_DIRECTORY | libc::O_NOFOLLOW,
+						0,
+					)
+					.map_err(Error::OpenSnapshot)?;
+					// Hold a shared lock on the snapshot for as long as it is open, so that
+					// --delete-snapshots can tell that it is in use. It is taken while the parent
+					// directory is still locked, so the snapshot is never found unlocked.
+					// SAFETY: snapshot_fd.as_raw_fd() is a valid file descriptor, as snapshot_fd is
+					// an open File.
+					if unsafe { libc::flock(snapshot_fd.as_raw_fd(), libc::LOCK_SH) } < 0 {
+						return Err(Error::LockSnapshot(std::io::Error::last_os_error()));
+					}
+					let info = btrfs::subvolume_info(&snapshot_fd);
+					if verbosity::verbose() {
+						match &info {
+							Ok(info) => println!(
+								"Created snapshot {snapshot_name}: subvolume ID {}, generation {}, UUID {}, parent UUID {}",
+								info.treeid, info.generation, info.uuid, info.parent_uuid
+							),
+							Err(e) => println!(
+								"Created snapshot {snapshot_name}, but cannot read its details: {e}"
+							),
+						}
+					}
+					// The snapshot was only just created, so if its creation time cannot be read,
+					// the current time is a close substitute.
+					let created = info.map_or_else(|_| SystemTime::now(), |i| i.otime);
+					let path = dir
+						.unwrap_or_else(|| root.parent().unwrap_or(root))
+						.join(&snapshot_name);
+					return Ok(Self {
+						warnings: any_warnings,
+						parent,
+						snapshot_fd,
+						created,
+						path,
+					});
+				}
+				Err(btrfs::Error::Syscall(e)) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+					// A subvolume with this name already exists. Given how we generate snapshot
+					// subvolume paths, that’s unlikely to be something the user legitimately
+					// created (more likely something created by a previous invocation of this tool
+					// that failed to delete it). Unless the user has opted to have such leftovers
+					// deleted, or to treat them as routine, we should warn about it, but we
+					// shouldn’t do anything else to it; instead, just increment “i” and try
+					// generating a new name.
+					let path = dir
+						.unwrap_or_else(|| root.parent().unwrap_or(root))
+						.join(&snapshot_name);
+					let warning = match options.collision {
+						config::SnapshotCollision::Warn => Some("already exists".to_owned()),
+						config::SnapshotCollision::Info => {
+							if verbosity::verbose() {
+								println!(
+									"Snapshot {} already exists; trying another name",
+									path.display()
+								);
+							}
+							None
+						}
+						config::SnapshotCollision::Delete => {
+							match delete_leftover_snapshot(source, &parent, &snapshot_name) {
+								Ok(true) => {
+									if !verbosity::quiet() {
+										println!(
+											"Deleted leftover snapshot {}; trying another name",
+											path.display()
+										);
+									}
+									None
+								}
+								Ok(false) => Some(
+									"already exists and is not a read-only snapshot of the archive root, so was not deleted"
+										.to_owned(),
+								),
+								Err(e) => Some(format!("already exists and cannot be deleted ({e})")),
+							}
+						}
+					};
+					if let Some(warning) = warning {
+						eprintln!(
+							"WARNING: Snapshot {} {warning}; trying another name",
+							path.display()
+						);
+						any_warnings = true;
+					}
+				}
+				Err(btrfs::Error::Syscall(e)) if e.raw_os_error() == Some(libc::ENOSPC) => {
+					return Err(Error::SnapshotNoSpace(space_retries, e));
+				}
+				Err(e) => return Err(Error::SnapshotCreate(e)),
+			}
+		}
+		panic!("tried 2⁶⁴ filenames without finding a nonexistent one, which is impossible");
+	}
+
+	/// Deletes a snapshot.
+	fn delete(self) -> Result<(), Error> {
+		btrfs::delete_subvolume(self.parent, self.snapshot_fd).map_err(Error::SnapshotDelete)
+	}
+}
+
+/// The inode number that a snapshot reports for the empty directory standing in for a subvolume
+/// that was nested within its source.
+const EMPTY_SUBVOL_DIR_INODE: u64 = 2;
+
+/// The inode number of the root directory of every subvolume.
+const SUBVOLUME_ROOT_INODE: u64 = 256;
+
+/// Walks a snapshot looking for the directories where subvolumes nested within its source would
+/// be, returning their paths relative to the snapshot root in sorted order.
+///
+/// Directories that cannot be read are skipped; Borg will report them itself.
+fn find_nested_subvolumes(snapshot: BorrowedFd<'_>) -> Vec<PathBuf> {
+	let base = PathBuf::from(format!("/proc/self/fd/{}", snapshot.as_raw_fd()));
+	let mut found = Vec::new();
+	let mut pending = vec![base.clone()];
+	while let Some(dir) = pending.pop() {
+		let Ok(entries) = std::fs::read_dir(&dir) else {
+			continue;
+		};
+		for entry in entries.flatten() {
+			let Ok(metadata) = entry.metadata() else {
+				continue;
+			};
+			if !metadata.is_dir() {
+				continue;
+			}
+			let path = entry.path();
+			if matches!(
+				metadata.ino(),
+				EMPTY_SUBVOL_DIR_INODE | SUBVOLUME_ROOT_INODE
+			) {
+				found.push(
+					path.strip_prefix(&base)
+						.expect("directory entry is within its parent")
+						.to_owned(),
+				);
+			} else {
+				pending.push(path);
+			}
+		}
+	}
+	found.sort();
+	found
+}
+
+/// Walks a directory counting the files within it, other than directories, and the total size of
+/// the regular files among them, stopping early once both counts reach the given minimums.
+///
+/// The walk stays on the directory’s own filesystem, not descending into directories with a
+/// different device number, such as mountpoints, so that a large filesystem mounted somewhere
+/// below the root cannot make up for a missing one. Patterns and excludes are not applied, so the
+/// counts are an upper bound on what is backed up. Entries that cannot be examined are skipped.
+fn count_files(dir: BorrowedFd<'_>, min_files: u64, min_bytes: u64) -> (u64, u64) {
+	let mut files = 0_u64;
+	let mut bytes = 0_u64;
+	let root = PathBuf::from(format!("/proc/self/fd/{}", dir.as_raw_fd()));
+	let Ok(device) = std::fs::metadata(&root).map(|metadata| metadata.dev()) else {
+		return (files, bytes);
+	};
+	let mut pending = vec![root];
+	while let Some(dir) = pending.pop() {
+		let Ok(entries) = std::fs::read_dir(&dir) else {
+			continue;
+		};
+		for entry in entries.flatten() {
+			let Ok(metadata) = entry.metadata() else {
+				continue;
+			};
+			if metadata.is_dir() {
+				if metadata.dev() == device {
+					pending.push(entry.path());
+				}
+				continue;
+			}
+			files += 1;
+			if metadata.is_file() {
+				bytes = bytes.saturating_add(metadata.len());
+			}
+			if files >= min_files && bytes >= min_bytes {
+				return (files, bytes);
+			}
+		}
+	}
+	(files, bytes)
+}
+
+/// A directory from which one or more archives are backed up.
+pub struct Source {
+	/// The archive root directory.
+	root: File,
+
+	/// The snapshot of the archive root, if one was taken.
+	snapshot: Option<Snapshot>,
+
+	/// The paths, relative to the archive root, of nested subvolumes to exclude from archives.
+	excludes: Vec<PathBuf>,
+
+	/// Whether any warnings were generated about nested subvolumes.
+	nested_warnings: bool,
+}
+
+impl Source {
+	/// Opens an archive root and, if `snapshot_options` is provided, takes a btrfs snapshot of it
+	/// on behalf of the named archives.
+	pub fn open(
+		root: &Path,
+		snapshot_options: Option<&config::SnapshotOptions<'_>>,
+		archive_names: &[&str],
+	) -> Result<Self, Error> {
+		let root_fd = File::options()
+			.read(true)
+			.custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+			.open(root)
+			.map_err(Error::OpenArchiveRoot)?;
+		let snapshot = match snapshot_options {
+			// Create a snapshot at a unique path which is a sibling to the root or, if so
+			// configured, under the snapshot directory in a subdirectory named after the archive.
+			// Archives sharing a snapshot all have the same options, so the first one’s name is
+			// used.
+			Some(options) => {
+				let dir = options.snapshot_dir.as_deref().map(|dir| {
+					dir.join(
+						archive_names
+							.first()
+							.expect("a source is opened for at least one archive"),
+					)
+				});
+				Some(Snapshot::create(
+					&root_fd,
+					root,
+					dir.as_deref(),
+					&snapshot_seed(root, archive_names),
+					options,
+				)?)
+			}
+			None => None,
+		};
+
+		// Look for nested subvolumes, which are missing from the snapshot.
+		let mut excludes = Vec::new();
+		let mut nested_warnings = false;
+		if let (Some(snapshot), Some(options)) = (&snapshot, snapshot_options) {
+			let action = options.nested_subvolumes;
+			if action != config::NestedSubvolumes::Ignore {
+				let nested = find_nested_subvolumes(snapshot.snapshot_fd.as_fd());
+				for path in &nested {
+					let path = root.join(path);
+					if action == config::NestedSubvolumes::Exclude {
+						if !verbosity::quiet() {
+							println!(
+								"Excluding nested subvolume {}, which is not included in the snapshot",
+								path.display()
+							);
+						}
+					} else {
+						eprintln!(
+							"WARNING: {} is a nested subvolume, which is not included in the snapshot and will be backed up as an empty directory",
+							path.display()
+						);
+						nested_warnings = true;
+					}
+				}
+				if action == config::NestedSubvolumes::Exclude {
+					excludes = nested;
+				}
+			}
+		}
+
+		Ok(Self {
+			root: root_fd,
+			snapshot,
+			excludes,
+			nested_warnings,
+		})
+	}
+
+	/// Returns whether any warnings were generated while preparing the source.
+	pub fn warnings(&self) -> bool {
+		self.nested_warnings || self.snapshot.as_ref().is_some_and(|i| i.warnings)
+	}
+
+	/// Returns the time the snapshot was created, if one was taken.
+	fn snapshot_time(&self) -> Option<SystemTime> {
+		self.snapshot.as_ref().map(|i| i.created)
+	}
+
+	/// Returns the directory that Borg should actually read from: the snapshot if one was taken,
+	/// otherwise the archive root itself.
+	fn fd(&self) -> BorrowedFd<'_> {
+		match &self.snapshot {
+			Some(snapshot) => snapshot.snapshot_fd.as_fd(),
+			None => self.root.as_fd(),
+		}
+	}
+
+	/// Returns the path of the snapshot, if one was taken.
+	pub fn snapshot_path(&self) -> Option<&Path> {
+		self.snapshot.as_ref().map(|i| &*i.path)
+	}
+
+	/// Releases the source without deleting its snapshot, returning the snapshot’s path if one was
+	/// taken, so that something else can use the snapshot.
+	pub fn keep(self) -> Option<PathBuf> {
+		self.snapshot.map(|i| i.path)
+	}
+
+	/// Releases the source, deleting its snapshot if one was taken.
+	pub fn close(self) -> Result<(), Error> {
+		match self.snapshot {
+			Some(snapshot) => snapshot.delete(),
+			None => Ok(()),
+		}
+	}
+}
+
+/// A snapshot of an archive root that Borgify created and that still exists.
+pub struct FoundSnapshot {
+	/// The path of the snapshot.
+	pub path: PathBuf,
+
+	/// The time the snapshot was created.
+	pub created: SystemTime,
+
+	/// The file descriptor of the directory containing the snapshot.
+	parent: File,
+
+	/// The file descriptor of the snapshot itself.
+	snapshot_fd: File,
+}
+
+impl FoundSnapshot {
+	/// Deletes the snapshot, unless a backup running concurrently is using it, returning whether
+	/// it was deleted.
+	pub fn delete(self) -> Result<bool, Error> {
+		// Take the lock that a run holds on the directory while creating a snapshot and locking
+		// it, so that a snapshot just created cannot be caught before its run has locked it.
+		// SAFETY: self.parent.as_raw_fd() is a valid file descriptor, as self.parent is an open
+		// File.
+		if unsafe { libc::flock(self.parent.as_raw_fd(), libc::LOCK_EX) } < 0 {
+			return Err(Error::LockArchiveRootParent(std::io::Error::last_os_error()));
+		}
+
+		// A run holds a shared lock on its snapshot until it has finished with it.
+		// SAFETY: self.snapshot_fd.as_raw_fd() is a valid file descriptor, as self.snapshot_fd is
+		// an open File.
+		if unsafe { libc::flock(self.snapshot_fd.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } < 0 {
+			let e = std::io::Error::last_os_error();
+			return if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+				Ok(false)
+			} else {
+				Err(Error::LockSnapshot(e))
+			};
+		}
+		btrfs::delete_subvolume(self.parent, self.snapshot_fd).map_err(Error::SnapshotDelete)?;
+		Ok(true)
+	}
+}
+
+/// Returns whether a file name could be that of a snapshot Borgify created, which is a SHA256 hash
+/// in lowercase hex.
+fn is_snapshot_name(name: &OsStr) -> bool {
+	name.len() == 64
+		&& name
+			.as_bytes()
+			.iter()
+			.all(|&i| i.is_ascii_digit() || (b'a'..=b'f').contains(&i))
+}
+
+/// Finds the snapshots of an archive root that Borgify created and that still exist, oldest first.
+///
+/// Snapshots are looked for next to the root or, if `dir` is given, within that directory, where
+/// [`Source::open`](Source::open) would put them. A snapshot is recognized by its name following
+/// Borgify’s naming scheme and by its being a snapshot of the root’s subvolume.
+pub fn find_snapshots(root: &Path, dir: Option<&Path>) -> Result<Vec<FoundSnapshot>, Error> {
+	let root_fd = File::options()
+		.read(true)
+		.custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+		.open(root)
+		.map_err(Error::OpenArchiveRoot)?;
+	let root_uuid = btrfs::subvolume_info(&root_fd)
+		.map_err(Error::SubvolumeInfo)?
+		.uuid;
+	let dir = dir.unwrap_or_else(|| root.parent().unwrap_or(root));
+	let entries = match std::fs::read_dir(dir) {
+		// A snapshot directory is only created when first needed, so if it does not exist, there
+		// are no snapshots in it.
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+		result => result.map_err(|e| Error::ListSnapshots(dir.to_owned(), e))?,
+	};
+	let parent = File::options()
+		.read(true)
+		.custom_flags(libc::O_DIRECTORY)
+		.open(dir)
+		.map_err(|e| Error::ListSnapshots(dir.to_owned(), e))?;
+	let mut found = Vec::new();
+	for entry in entries {
+		let entry = entry.map_err(|e| Error::ListSnapshots(dir.to_owned(), e))?;
+		if !is_snapshot_name(&entry.file_name()) {
+			continue;
+		}
+		// Anything that cannot be opened or examined is not a snapshot Borgify could delete.
+		let Ok(snapshot_fd) = File::options()
+			.read(true)
+			.custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+			.open(entry.path())
+		else {
+			continue;
+		};
+		if !snapshot_fd
+			.metadata()
+			.is_ok_and(|i| i.ino() == SUBVOLUME_ROOT_INODE)
+		{
+			continue;
+		}
+		let Ok(info) = btrfs::subvolume_info(&snapshot_fd) else {
+			continue;
+		};
+		if info.parent_uuid != root_uuid {
+			continue;
+		}
+		found.push(FoundSnapshot {
+			path: entry.path(),
+			created: info.otime,
+			parent: parent
+				.try_clone()
+				.map_err(|e| Error::ListSnapshots(dir.to_owned(), e))?,
+			snapshot_fd,
+		});
+	}
+	found.sort_by_key(|i| i.created);
+	Ok(found)
+}
+
+/// Performs a backup of a source to each of the archive’s repositories.
+///
+/// `source` must have been opened from the archive’s root, with a snapshot taken if the archive
+/// requests one; the same source may be shared by several archives.
+///
+/// On success, returns whether any warnings were generated.
+pub fn run(
+	context: &Context<'_>,
+	archive_name: &str,
+	archive: &config::Archive,
+	source: &Source,
+) -> Result<bool, Error> {
+	if archive.backend == config::Backend::Borg
+		&& archive.btrfs_snapshot
+		&& archive.files_cache.is_none()
+		&& !verbosity::quiet()
+	{
+		println!(
+			"Using files cache mode {SNAPSHOT_FILES_CACHE}, which ignores inode numbers, because they change with every snapshot; set files_cache to override"
+		);
+	}
+	if archive.min_files.is_some() || archive.min_bytes.is_some() {
+		let min_files = archive.min_files.unwrap_or(0);
+		let min_bytes = archive.min_bytes.unwrap_or(0);
+		let (files, bytes) = count_files(source.fd(), min_files, min_bytes);
+		if files < min_files || bytes < min_bytes {
+			return Err(Error::TooSmall(files, bytes));
+		}
+	}
+	run_targets(context, archive_name, archive, source)
+}
+
+/// Tests expanding placeholders in an archive comment.
+#[test]
+fn test_expand_comment() {
+	let archive = config::Archive {
+		tags: vec!["db".into(), "nightly".into()],
+		..config::Archive::default()
+	};
+	assert_eq!(
+		expand_comment("{archive} [{tags}] on {hostname}", "foo", &archive),
+		"foo [db,nightly] on {hostname}"
+	);
+	assert_eq!(
+		expand_comment("{{archive}} {borgify_version", "foo", &archive),
+		"{{archive}} {borgify_version"
+	);
+}
+
+/// Tests flushing a filesystem to disk, and failing for a path that does not exist.
+#[test]
+fn test_sync_filesystem() {
+	let dir = super::temp_dir::TempDir::new("sync");
+	sync_filesystem(dir.path()).unwrap();
+	let missing = dir.join("missing");
+	assert_eq!(
+		sync_filesystem(&missing).unwrap_err().kind(),
+		std::io::ErrorKind::NotFound
+	);
+}
+
+/// Tests creating a directory under `snapshot_dir` on first use, reusing it afterwards, and
+/// refusing to create missing ancestors.
+#[test]
+fn test_open_snapshot_dir() {
+	let base = super::temp_dir::TempDir::new("snapshot-dir");
+	let dir = base.join("home");
+	open_snapshot_dir(&dir).unwrap();
+	assert!(dir.is_dir());
+	open_snapshot_dir(&dir).unwrap();
+	assert_eq!(
+		open_snapshot_dir(&base.join("missing/home"))
+			.unwrap_err()
+			.kind(),
+		std::io::ErrorKind::NotFound
+	);
+}
+
+/// Tests formatting a time for Borg’s `--timestamp` option.
+#[test]
+fn test_format_timestamp() {
+	let time = SystemTime::UNIX_EPOCH + std::time::Duration::new(1_700_000_000, 999_000_000);
+	assert_eq!(format_timestamp(time), "2023-11-14T22:13:20");
+}
+
+/// Tests that archives sharing a root do not generate the same snapshot names.
+#[test]
+fn test_snapshot_name_distinct_archives() {
+	let root = Path::new("/home");
+	let foo = snapshot_seed(root, &["foo"]);
+	let bar = snapshot_seed(root, &["bar"]);
+	assert_ne!(snapshot_name(&foo, 0), snapshot_name(&bar, 0));
+	assert_ne!(snapshot_name(&foo, 0), snapshot_name(&foo, 1));
+	assert_eq!(snapshot_name(&foo, 0), snapshot_name(&foo, 0));
+	assert_eq!(snapshot_name(&foo, 0).len(), 64);
+}
+
+/// Tests passing through Borg’s JSON output, with progress reports overwriting each other and
+/// messages on lines of their own.
+#[test]
+fn test_handle_output_passthrough() {
+	const INPUT: &[u8] = br#"{"type": "log_message", "levelname": "INFO", "message": "Creating archive", "name": "borg.archiver"}
+{"type": "archive_progress", "original_size": 2048, "compressed_size": 1024, "deduplicated_size": 0, "nfiles": 12, "path": "home/user/long/path"}
+{"type": "archive_progress", "original_size": 4096, "compressed_size": 2048, "deduplicated_size": 0, "nfiles": 20, "path": "home"}
+{"type": "archive_progress", "finished": true}
+not json
+"#;
+	let mut out = Vec::new();
+	let mut progress = Vec::new();
+	let output = handle_output(INPUT, &mut out, true, |i| progress.push(i.to_owned())).unwrap();
+	assert_eq!(
+		String::from_utf8(out).unwrap(),
+		"Creating archive\n\r2.00 KiB O 1.00 KiB C 0 B D 12 N home/user/long/path\r4.00 KiB O 2.00 KiB C 0 B D 20 N home               \nnot json\n"
+	);
+	assert_eq!(
+		output,
+		Output {
+			error: None,
+			archive_exists: false,
+			changed_files: 0,
+			tail: vec!["Creating archive".to_owned(), "not json".to_owned()],
+		}
+	);
+	assert_eq!(progress.len(), 3);
+	assert!(progress[2].contains(r#""finished": true"#));
+}
+
+/// Tests that progress reports are passed on but not displayed when `show_progress` is false.
+#[test]
+fn test_handle_output_hidden_progress() {
+	const INPUT: &[u8] =
+		br#"{"type": "progress_percent", "message": " 50% Syncing chunks cache", "finished": false}
+{"type": "log_message", "levelname": "WARNING", "message": "a warning", "name": "borg.archiver"}
+"#;
+	let mut out = Vec::new();
+	let mut progress = 0;
+	handle_output(INPUT, &mut out, false, |_| progress += 1).unwrap();
+	assert_eq!(String::from_utf8(out).unwrap(), "a warning\n");
+	assert_eq!(progress, 1);
+}
+
+/// Tests collecting the first error message from Borg’s JSON output.
+#[test]
+fn test_handle_output_error() {
+	const INPUT: &[u8] = br#"{"type": "log_message", "levelname": "WARNING", "message": "a warning", "name": "borg.archiver"}
+{"type": "log_message", "levelname": "ERROR", "message": "first error", "name": "borg.archiver"}
+{"type": "log_message", "levelname": "CRITICAL", "message": "second error", "name": "borg.archiver"}
+"#;
+	let output = handle_output(INPUT, std::io::sink(), true, |_| ()).unwrap();
+	assert_eq!(output.error.as_deref(), Some("first error"));
+	assert_eq!(output.tail, ["a warning"]);
+}
+
+/// Tests counting the files Borg reports as changed while being read, identified by message ID or,
+/// for older versions of Borg, by message text.
+#[test]
+fn test_handle_output_changed_files() {
+	const INPUT: &[u8] = br#"{"type": "log_message", "levelname": "WARNING", "message": "home/a.log: file changed while we backed it up", "name": "borg.archive", "msgid": "FileChangedWarning"}
+{"type": "log_message", "levelname": "WARNING", "message": "home/b.log: file changed while we backed it up", "name": "borg.archiver"}
+{"type": "log_message", "levelname": "WARNING", "message": "home/c.log: file changed while we backed it up", "name": "borg.archiver", "msgid": "BackupOSError"}
+"#;
+	let output = handle_output(INPUT, std::io::sink(), true, |_| ()).unwrap();
+	assert_eq!(output.changed_files, 2);
+	assert_eq!(output.error, None);
+}
+
+/// Tests finding the previous archive in the output of `borg list --json`.
+#[test]
+fn test_previous_archive() {
+	const TWO: &[u8] = br#"{"archives": [{"archive": "home-2023-01-07T10:00:00", "id": "01", "name": "home-2023-01-07T10:00:00", "start": "2023-01-07T10:00:01.000000", "time": "2023-01-07T10:00:01.000000"}, {"archive": "home-2023-01-08T10:00:00", "id": "02", "name": "home-2023-01-08T10:00:00", "start": "2023-01-08T10:00:01.000000", "time": "2023-01-08T10:00:01.000000"}], "encryption": {"mode": "none"}, "repository": {"id": "0123", "last_modified": "2023-01-08T10:00:02.000000", "location": "/r"}}"#;
+	assert_eq!(
+		previous_archive(TWO, "home-2023-01-08T10:00:00", "home", "%FT%T")
+			.unwrap()
+			.as_deref(),
+		Some("home-2023-01-07T10:00:00")
+	);
+	const CHECKPOINT: &[u8] = br#"{"archives": [{"name": "home-2023-01-07T10:00:00"}, {"name": "home-2023-01-08T09:00:00.checkpoint"}, {"name": "home-2023-01-08T10:00:00"}]}"#;
+	assert_eq!(
+		previous_archive(CHECKPOINT, "home-2023-01-08T10:00:00", "home", "%FT%T")
+			.unwrap()
+			.as_deref(),
+		Some("home-2023-01-07T10:00:00")
+	);
+	const ONE: &[u8] = br#"{"archives": [{"name": "home-2023-01-08T10:00:00"}]}"#;
+	assert_eq!(
+		previous_archive(ONE, "home-2023-01-08T10:00:00", "home", "%FT%T").unwrap(),
+		None
+	);
+	assert!(previous_archive(b"{}", "home-2023-01-08T10:00:00", "home", "%FT%T").is_err());
+}
+
+/// Tests recognizing the names of checkpoint archives.
+#[test]
+fn test_checkpoint_base() {
+	assert_eq!(
+		checkpoint_base("home-2023-01-08T10:00:00.checkpoint"),
+		Some("home-2023-01-08T10:00:00")
+	);
+	assert_eq!(
+		checkpoint_base("home-2023-01-08T10:00:00.checkpoint.2"),
+		Some("home-2023-01-08T10:00:00")
+	);
+	assert_eq!(checkpoint_base("home-2023-01-08T10:00:00"), None);
+	assert_eq!(checkpoint_base(".checkpoint"), None);
+	assert_eq!(checkpoint_base("home.checkpoint.x"), None);
+	assert_eq!(checkpoint_base("home.checkpoint."), None);
+	assert_eq!(checkpoint_base("home.checkpointed"), None);
+}
+
+/// Tests building the pattern that selects an archive’s Borg archives, which must not match those
+/// of an archive whose name starts with the same text.
+#[test]
+fn test_archive_glob() {
+	assert_eq!(
+		archive_glob("web", "%FT%T"),
+		"web-[0-9][0-9][0-9][0-9]-[0-9][0-9]-[0-9][0-9]T[0-9][0-9]:[0-9][0-9]:[0-9][0-9]"
+	);
+	assert_eq!(
+		archive_glob("a*b?[c]", "%Y%m%d-%H%M%S%z"),
+		"a[*]b[?][[]c]-[0-9][0-9][0-9][0-9][0-9][0-9][0-9][0-9]-[0-9][0-9][0-9][0-9][0-9][0-9]?[0-9][0-9][0-9][0-9]"
+	);
+	assert_eq!(
+		archive_glob("web", "%d %B %Y"),
+		"web-[0-9][0-9] * [0-9][0-9][0-9][0-9]"
+	);
+	assert_eq!(archive_glob("web", "%s_%-d"), "web-*_*");
+}
+
+/// Tests recognizing the names of an archive’s Borg archives.
+#[test]
+fn test_is_archive_of() {
+	assert!(is_archive_of("web-2023-01-08T10:00:00", "web", "%FT%T"));
+	assert!(!is_archive_of("web-db-2023-01-08T10:00:00", "web", "%FT%T"));
+	assert!(!is_archive_of("web-2023-01-08T10:00:00x", "web", "%FT%T"));
+	assert!(!is_archive_of("web2023-01-08T10:00:00", "web", "%FT%T"));
+	assert!(!is_archive_of(
+		"web-2023-01-08T10:00:00.checkpoint",
+		"web",
+		"%FT%T"
+	));
+	assert!(is_archive_of("web-08 January 2023", "web", "%d %B %Y"));
+	assert!(is_archive_of(
+		"web-20230108-100000+0100",
+		"web",
+		"%Y%m%d-%H%M%S%z"
+	));
+	assert!(is_archive_of("web-1673172000_8", "web", "%s_%-d"));
+}
+
+/// Tests finding the names matched when pruning that belong to another archive.
+#[test]
+fn test_foreign_archives() {
+	const INPUT: &[u8] = br#"{"archives": [
+		{"name": "web-2023-01-07T10:00:00"},
+		{"name": "web-2023-01-08T09:00:00.checkpoint"},
+		{"name": "web-db-2023-01-08T10:00:00"},
+		{"name": "web-2023-01-08T10:00:00"}
+	]}"#;
+	assert_eq!(
+		foreign_archives(INPUT, "web", "%FT%T").unwrap(),
+		["web-db-2023-01-08T10:00:00"]
+	);
+	assert!(foreign_archives(INPUT, "web-db", "%FT%T").unwrap().len() == 3);
+}
+
+/// Tests finding the checkpoint archives older than a cutoff in the output of `borg list --json`.
+#[test]
+fn test_stale_checkpoints() {
+	const INPUT: &[u8] = br#"{"archives": [
+		{"name": "home-2023-01-01T10:00:00.checkpoint", "time": "2023-01-01T10:00:01.000000+00:00"},
+		{"name": "home-2023-01-01T10:00:00", "time": "2023-01-01T10:30:00.000000+00:00"},
+		{"name": "home-2023-01-07T10:00:00.checkpoint.1", "time": "2023-01-07T10:00:01.000000+00:00"},
+		{"name": "home-2023-01-08T09:00:00.checkpoint", "time": "2023-01-08T09:00:01.000000+00:00"},
+		{"name": "home-2023-01-08T09:30:00.checkpoint"}
+	]}"#;
+	let cutoff = chrono::DateTime::parse_from_rfc3339("2023-01-08T00:00:00Z")
+		.unwrap()
+		.to_utc();
+	assert_eq!(
+		stale_checkpoints(INPUT, cutoff, "home", "%FT%T").unwrap(),
+		[
+			"home-2023-01-01T10:00:00.checkpoint",
+			"home-2023-01-07T10:00:00.checkpoint.1"
+		]
+	);
+	assert_eq!(
+		parse_archive_time("2023-01-08T09:00:01.000000"),
+		chrono::NaiveDateTime::parse_from_str("2023-01-08T09:00:01", "%FT%T")
+			.ok()
+			.and_then(|i| {
+				use chrono::TimeZone as _;
+				chrono::Local.from_local_datetime(&i).earliest()
+			})
+			.map(|i| i.to_utc())
+	);
+}
+
+/// Tests reading the statistics of a new archive from the output of `borg create --json`.
+#[test]
+fn test_create_stats() {
+	const OUTPUT: &[u8] = br#"{"archive": {"command_line": ["borg", "create"], "duration": 1.5, "id": "01", "name": "home-2023-01-08T10:00:00", "stats": {"compressed_size": 2048, "deduplicated_size": 512, "nfiles": 12, "original_size": 4096}}, "cache": {"path": "/root/.cache/borg/0123", "stats": {"total_chunks": 12}}, "encryption": {"mode": "none"}, "repository": {"id": "0123", "location": "/r"}}"#;
+	assert_eq!(
+		create_stats(OUTPUT).unwrap(),
+		usage::Stats {
+			original_size: 4096,
+			compressed_size: 2048,
+			deduplicated_size: 512,
+			nfiles: 12,
+		}
+	);
+	assert!(create_stats(b"").is_err());
+}
+
+/// Tests classifying the paths in the output of `borg diff --json-lines` and counting them.
+#[test]
+fn test_parse_diff() {
+	const OUTPUT: &[u8] = br#"{"path": "home/new.txt", "changes": [{"type": "added", "size": 12}]}
+{"path": "home/old", "changes": [{"type": "removed directory"}]}
+{"path": "home/notes.txt", "changes": [{"type": "modified", "added": 10, "removed": 4}, {"type": "mtime", "old_mtime": "a", "new_mtime": "b"}]}
+{"path": "home/script", "changes": [{"type": "mode", "old_mode": "-rw-r--r--", "new_mode": "-rwxr-xr-x"}]}
+"#;
+	let changes = parse_diff(OUTPUT).unwrap();
+	assert_eq!(
+		changes,
+		[
+			(Change::Added, "home/new.txt".to_owned()),
+			(Change::Removed, "home/old".to_owned()),
+			(Change::Modified, "home/notes.txt".to_owned()),
+			(Change::Modified, "home/script".to_owned()),
+		]
+	);
+	assert_eq!(
+		summarize_changes(&changes),
+		"1 added, 1 removed, 2 modified"
+	);
+	assert_eq!(parse_diff(b"").unwrap(), []);
+	assert!(parse_diff(b"not json\n").is_err());
+}
+
+/// Tests locating Borg’s directories from various combinations of environment variables.
+#[test]
+fn test_borg_dirs_from() {
+	let dirs = |vars: &[(&str, &str)]| {
+		borg_dirs_from(|name| {
+			vars.iter()
+				.find(|(k, _)| *k == name)
+				.map(|(_, v)| OsString::from(v))
+		})
+	};
+	assert_eq!(
+		dirs(&[("HOME", "/root")]),
+		[
+			PathBuf::from("/root/.cache/borg"),
+			PathBuf::from("/root/.config/borg")
+		]
+	);
+	assert_eq!(
+		dirs(&[("HOME", "/root"), ("XDG_CACHE_HOME", "/var/cache")]),
+		[
+			PathBuf::from("/var/cache/borg"),
+			PathBuf::from("/root/.config/borg")
+		]
+	);
+	assert_eq!(
+		dirs(&[
+			("HOME", "/root"),
+			("XDG_CACHE_HOME", "/var/cache"),
+			("BORG_BASE_DIR", "/srv"),
+			("BORG_CONFIG_DIR", "/etc/borg"),
+		]),
+		[
+			PathBuf::from("/srv/.cache/borg"),
+			PathBuf::from("/etc/borg")
+		]
+	);
+	assert_eq!(dirs(&[]), Vec::<PathBuf>::new());
+}
+
+/// Tests finding Borg’s directories within an archive root.
+#[test]
+fn test_excludes_within() {
+	let dirs = [
+		PathBuf::from("/root/.cache/borg"),
+		PathBuf::from("/etc/borg"),
+	];
+	assert_eq!(
+		excludes_within(Path::new("/root"), &dirs),
+		[PathBuf::from(".cache/borg")]
+	);
+	assert_eq!(
+		excludes_within(Path::new("/home"), &dirs),
+		Vec::<PathBuf>::new()
+	);
+	assert_eq!(
+		excludes_within(Path::new("/etc/borg"), &dirs),
+		Vec::<PathBuf>::new()
+	);
+}
+
+/// Tests choosing the files cache mode, which ignores inode numbers by default for snapshots.
+#[test]
+fn test_files_cache() {
+	let mut archive = config::Archive::default();
+	assert_eq!(files_cache(&archive), None);
+	archive.btrfs_snapshot = true;
+	assert_eq!(files_cache(&archive), Some("ctime,size"));
+	archive.files_cache = Some("mtime,size,inode".into());
+	assert_eq!(files_cache(&archive), Some("mtime,size,inode"));
+}
+
+/// Tests building the `borg create` command, whose excludes are relative to the working directory
+/// for relative paths and absolute otherwise, whose only path to back up is the archive root, and
+/// which prints Borg’s statistics unless Borgify is to read them.
+#[test]
+fn test_create_command() {
+	let name_timestamps = HashMap::from([("foo", "2023-01-08T10:00:00".to_owned())]);
+	let excludes = HashMap::from([("foo", vec![PathBuf::from(".cache/borg")])]);
+	let context = Context {
+		timestamp_utc: "2023-01-08T09:00:00",
+		timestamp_given: false,
+		name_timestamps: &name_timestamps,
+		passphrases: &HashMap::new(),
+		umask: 0o077,
+		excludes: &excludes,
+		progress: None,
+		repositories: &BTreeMap::new(),
+		cgroup: None,
+		usage: false,
+	};
+	let mut archive = config::Archive {
+		root: Path::new("/home").into(),
+		patterns: vec!["- sh:*.tmp".into()],
+		..config::Archive::default()
+	};
+	let args = |archive: &config::Archive<'_>| -> Vec<String> {
+		create_command(
+			&context,
+			"foo",
 			archive,
-			timestamp_utc,
-			timestamp_local,
-			passphrase,
-			archive_root,
-			umask,
+			"/repo",
+			"2023-01-08T09:00:00",
+			&[PathBuf::from("nested")],
 		)
-	}
+		.get_args()
+		.map(|i| i.to_str().unwrap().to_owned())
+		.collect()
+	};
+	let relative = args(&archive);
+	assert!(relative.contains(&"--pattern=- sh:*.tmp".to_owned()));
+	assert!(relative.contains(&"--exclude=pp:.cache/borg".to_owned()));
+	assert!(relative.contains(&"--exclude=pp:nested".to_owned()));
+	assert!(!relative
+		.iter()
+		.any(|i| i.starts_with("--checkpoint-interval")));
+	assert_eq!(
+		relative[relative.len() - 2..],
+		["::foo-2023-01-08T10:00:00", "."]
+	);
+	archive.path_style = config::PathStyle::Absolute;
+	let absolute = args(&archive);
+	assert!(absolute.contains(&"--exclude=pp:/home/.cache/borg".to_owned()));
+	assert!(absolute.contains(&"--exclude=pp:/home/nested".to_owned()));
+	assert_eq!(absolute.last().unwrap(), "/home");
+	archive.checkpoint_interval = std::num::NonZeroU32::new(600);
+	assert!(args(&archive).contains(&"--checkpoint-interval=600".to_owned()));
+	assert!(absolute.contains(&"--stats".to_owned()));
+	assert!(!absolute.contains(&"--json".to_owned()));
+	let context = Context {
+		usage: true,
+		..context
+	};
+	let usage: Vec<_> = create_command(&context, "foo", &archive, "/repo", "", &[])
+		.get_args()
+		.map(|i| i.to_str().unwrap().to_owned())
+		.collect();
+	assert!(usage.contains(&"--json".to_owned()));
+	assert!(!usage.contains(&"--stats".to_owned()));
+}
+
+/// Tests recognizing the names Borgify gives snapshots.
+#[test]
+fn test_is_snapshot_name() {
+	let name = snapshot_name(b"/home", 0);
+	assert!(is_snapshot_name(OsStr::new(&name)));
+	assert!(!is_snapshot_name(OsStr::new(&name[1..])));
+	assert!(!is_snapshot_name(OsStr::new(&name.to_uppercase())));
+	assert!(!is_snapshot_name(OsStr::new("home")));
+}
+
+/// Tests recognizing Borg’s report that the archive being created already exists.
+#[test]
+fn test_handle_output_archive_exists() {
+	const INPUT: &[u8] = br#"{"type": "log_message", "time": 1673159749.4641619, "message": "Archive home-2023-01-08T10:00:00 already exists", "levelname": "ERROR", "name": "borg.archiver", "msgid": "Archive.AlreadyExists"}
+"#;
+	let output = handle_output(INPUT, std::io::sink(), true, |_| ()).unwrap();
+	assert!(output.archive_exists);
+	assert_eq!(
+		output.error.as_deref(),
+		Some("Archive home-2023-01-08T10:00:00 already exists")
+	);
+}
+
+/// Tests that walking an ordinary directory tree finds no nested subvolumes.
+#[test]
+fn test_find_nested_subvolumes_none() {
+	let dir = super::temp_dir::TempDir::new("nested");
+	std::fs::create_dir_all(dir.join("a/b")).unwrap();
+	std::fs::write(dir.join("a/file"), b"").unwrap();
+	assert_eq!(
+		find_nested_subvolumes(File::open(dir.path()).unwrap().as_fd()),
+		Vec::<PathBuf>::new()
+	);
+}
+
+/// Tests counting files, including those in nested subdirectories and stopping early once the
+/// minimums are reached.
+#[test]
+fn test_count_files() {
+	let path = super::temp_dir::TempDir::new("count");
+	std::fs::create_dir_all(path.join("sub/deeper/empty")).unwrap();
+	std::fs::write(path.join("a"), b"12345").unwrap();
+	std::fs::write(path.join("sub/b"), b"123").unwrap();
+	std::fs::write(path.join("sub/deeper/c"), b"12").unwrap();
+	std::os::unix::fs::symlink("a", path.join("sub/link")).unwrap();
+	let dir = File::open(path.path()).unwrap();
+	assert_eq!(count_files(dir.as_fd(), u64::MAX, u64::MAX), (4, 10));
+	assert_eq!(count_files(dir.as_fd(), 1, 0).0, 1);
 }
@@ -0,0 +1,181 @@
+//! Decoding of the JSON lines that Borg writes to standard error when given `--log-json`.
+
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
+
+/// A line of output in JSON format that Borg sends to standard error.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq)]
+#[serde(tag = "type")]
+pub enum StderrLine<'data> {
+	#[serde(rename = "log_message")]
+	LogMessage {
+		/// The severity of the event.
+		#[serde(rename = "levelname")]
+		level: LogLevel,
+
+		/// The formatted message text.
+		#[serde(borrow)]
+		message: Cow<'data, str>,
+
+		/// The message ID.
+		#[serde(rename = "msgid")]
+		message_id: Option<MessageId>,
+	},
+
+	/// The progress of creating an archive, emitted repeatedly with `--progress`.
+	#[serde(rename = "archive_progress")]
+	ArchiveProgress {
+		/// The total size of the files processed so far.
+		#[serde(default)]
+		original_size: u64,
+
+		/// The size of the files processed so far, after compression.
+		#[serde(default)]
+		compressed_size: u64,
+
+		/// The size of the files processed so far, after compression and deduplication.
+		#[serde(default)]
+		deduplicated_size: u64,
+
+		/// The number of files processed so far.
+		#[serde(default)]
+		nfiles: u64,
+
+		/// The path currently being processed.
+		#[serde(borrow, default)]
+		path: Cow<'data, str>,
+
+		/// Whether this is the last progress report for the archive.
+		#[serde(default)]
+		finished: bool,
+	},
+
+	/// The progress of some other operation, as a message or a percentage, emitted with
+	/// `--progress`.
+	#[serde(rename = "progress_message", alias = "progress_percent")]
+	Progress {
+		/// The formatted progress text, absent in the final report.
+		#[serde(borrow, default)]
+		message: Option<Cow<'data, str>>,
+
+		/// Whether this is the last progress report for the operation.
+		#[serde(default)]
+		finished: bool,
+	},
+
+	#[serde(other)]
+	Unknown,
+}
+
+/// A severity level of a log event.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum LogLevel {
+	#[serde(rename = "DEBUG")]
+	Debug,
+
+	#[serde(rename = "INFO")]
+	Info,
+
+	#[serde(rename = "WARNING")]
+	Warning,
+
+	#[serde(rename = "ERROR")]
+	Error,
+
+	#[serde(rename = "CRITICAL")]
+	Critical,
+
+	#[serde(other)]
+	Unknown,
+}
+
+/// A message ID.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq)]
+pub enum MessageId {
+	/// The repository is encrypted and the passphrase is incorrect.
+	PassphraseWrong,
+
+	/// An archive with the requested name already exists in the repository.
+	#[serde(rename = "Archive.AlreadyExists")]
+	ArchiveAlreadyExists,
+
+	/// A file changed while Borg was reading it, so its contents in the archive may be
+	/// inconsistent.
+	#[serde(rename = "FileChangedWarning")]
+	FileChanged,
+
+	/// Any other message.
+	#[serde(other)]
+	Unknown,
+}
+
+/// A size in bytes, which formats with IEC binary prefixes as Borg does with `--iec`.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Size(pub u64);
+
+impl Display for Size {
+	fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+		const PREFIXES: [&str; 6] = ["", "Ki", "Mi", "Gi", "Ti", "Pi"];
+		if self.0 < 1024 {
+			return write!(f, "{} B", self.0);
+		}
+		let mut value = self.0 as f64;
+		let mut prefix = 0;
+		while value >= 1024.0 && prefix < PREFIXES.len() - 1 {
+			value /= 1024.0;
+			prefix += 1;
+		}
+		write!(f, "{value:.2} {}B", PREFIXES[prefix])
+	}
+}
+
+/// Tests decoding an archive progress line, including the final one which omits the counters.
+#[test]
+fn test_decode_archive_progress() {
+	let line: StderrLine = serde_json::from_str(r#"{"type": "archive_progress", "original_size": 2048, "compressed_size": 1024, "deduplicated_size": 512, "nfiles": 3, "path": "home/user", "time": 1673159749.46}"#).unwrap();
+	assert_eq!(
+		line,
+		StderrLine::ArchiveProgress {
+			original_size: 2048,
+			compressed_size: 1024,
+			deduplicated_size: 512,
+			nfiles: 3,
+			path: "home/user".into(),
+			finished: false,
+		}
+	);
+	let line: StderrLine = serde_json::from_str(
+		r#"{"type": "archive_progress", "finished": true, "time": 1673159750.0}"#,
+	)
+	.unwrap();
+	assert!(matches!(
+		line,
+		StderrLine::ArchiveProgress { finished: true, .. }
+	));
+}
+
+/// Tests decoding percentage progress lines and unknown line types.
+#[test]
+fn test_decode_progress_and_unknown() {
+	let line: StderrLine = serde_json::from_str(r#"{"type": "progress_percent", "operation": 1, "msgid": "cache.sync", "finished": false, "message": " 50% Syncing chunks cache", "current": 1, "total": 2, "time": 1.0}"#).unwrap();
+	assert_eq!(
+		line,
+		StderrLine::Progress {
+			message: Some(" 50% Syncing chunks cache".into()),
+			finished: false,
+		}
+	);
+	let line: StderrLine =
+		serde_json::from_str(r#"{"type": "file_status", "status": "A", "path": "foo"}"#).unwrap();
+	assert_eq!(line, StderrLine::Unknown);
+}
+
+/// Tests formatting sizes.
+#[test]
+fn test_size_display() {
+	assert_eq!(Size(0).to_string(), "0 B");
+	assert_eq!(Size(1023).to_string(), "1023 B");
+	assert_eq!(Size(1536).to_string(), "1.50 KiB");
+	assert_eq!(Size(3 << 30).to_string(), "3.00 GiB");
+}
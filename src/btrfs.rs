@@ -1,11 +1,13 @@
 //! Creation and deletion of btrfs snapshots.
 
+use super::json_error::Structured;
 use nix::libc;
 use std::ffi::OsStr;
 use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::mem::MaybeUninit;
 use std::os::unix::prelude::*;
+use std::time::{Duration, SystemTime};
 
 /// The raw btrfs ioctls.
 mod ioctl {
@@ -18,10 +20,14 @@ mod ioctl {
 	/// The maximum length of the name of a subvolume used in another place.
 	pub const VOL_NAME_MAX: usize = 255;
 
+	/// The subvolume ID of the top-level subvolume of every btrfs filesystem.
+	pub const FS_TREE_OBJECTID: u64 = 5;
+
 	/// The size of a UUID used with btrfs ioctls.
 	pub const UUID_SIZE: usize = 16;
 
-	/// A flag to [`snap_create_v2`](snap_create_v2) to make the new subvolume read-only.
+	/// A flag to [`snap_create_v2`](snap_create_v2) to make the new subvolume read-only, which is
+	/// also the read-only flag reported by [`subvol_get_flags`](subvol_get_flags).
 	pub const SUBVOL_RDONLY: u64 = 1 << 1;
 
 	/// A flag to [`snap_destroy_v2`](snap_destroy_v2) to find the subvolume to destroy by
@@ -93,8 +99,15 @@ pub enum Error {
 	/// A specified path is not the root directory of a subvolume.
 	NotSubvolumeRoot,
 
+	/// A specified path is the root directory of the filesystem’s top-level subvolume.
+	TopLevelSubvolume,
+
 	/// An error was returned by a syscall.
 	Syscall(std::io::Error),
+
+	/// A snapshot that the kernel would only create writable was created, but could not then be
+	/// made read-only, so it was deleted again.
+	SetReadonly(std::io::Error),
 }
 
 impl Display for Error {
@@ -102,7 +115,11 @@ impl Display for Error {
 		match self {
 			Self::NotBtrfs => "not a btrfs filesystem",
 			Self::NotSubvolumeRoot => "not the root of a subvolume",
+			Self::TopLevelSubvolume => {
+				"the top-level subvolume of the filesystem, whose snapshot would not include any nested subvolumes and would be created outside it or within it; snapshot a nested subvolume instead"
+			}
 			Self::Syscall(_) => "syscall failed",
+			Self::SetReadonly(_) => "cannot make the snapshot read-only",
 		}
 		.fmt(f)
 	}
@@ -111,8 +128,20 @@ impl Display for Error {
 impl std::error::Error for Error {
 	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
 		match self {
-			Self::NotBtrfs | Self::NotSubvolumeRoot => None,
-			Self::Syscall(e) => Some(e),
+			Self::NotBtrfs | Self::NotSubvolumeRoot | Self::TopLevelSubvolume => None,
+			Self::Syscall(e) | Self::SetReadonly(e) => Some(e),
+		}
+	}
+}
+
+impl Structured for Error {
+	fn variant(&self) -> &'static str {
+		match self {
+			Self::NotBtrfs => "NotBtrfs",
+			Self::NotSubvolumeRoot => "NotSubvolumeRoot",
+			Self::TopLevelSubvolume => "TopLevelSubvolume",
+			Self::Syscall(_) => "Syscall",
+			Self::SetReadonly(_) => "SetReadonly",
 		}
 	}
 }
@@ -132,6 +161,45 @@ impl From<std::io::Error> for Error {
 /// A result type whose error type is [`Error`](Error).
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// A btrfs UUID, which formats in the usual hyphenated hexadecimal form.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Uuid(pub [u8; ioctl::UUID_SIZE]);
+
+impl Display for Uuid {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+		for (i, b) in self.0.iter().enumerate() {
+			if matches!(i, 4 | 6 | 8 | 10) {
+				"-".fmt(f)?;
+			}
+			write!(f, "{b:02x}")?;
+		}
+		Ok(())
+	}
+}
+
+/// Information about a subvolume.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SubvolInfo {
+	/// The subvolume ID.
+	pub treeid: u64,
+
+	/// The generation of the subvolume, i.e. the ID of the transaction that last modified it.
+	pub generation: u64,
+
+	/// The UUID of the subvolume.
+	pub uuid: Uuid,
+
+	/// The UUID of the subvolume that this subvolume is a snapshot of, or nil if it is not a
+	/// snapshot.
+	pub parent_uuid: Uuid,
+
+	/// The time the subvolume was created.
+	pub otime: SystemTime,
+
+	/// Whether the subvolume is read-only.
+	pub readonly: bool,
+}
+
 /// Checks whether a given file handle refers to a something on a Btrfs filesystem.
 fn is_btrfs(f: impl AsFd) -> Result<bool> {
 	const BTRFS_SUPER_MAGIC: libc::__fsword_t = 0x9123683e;
@@ -157,11 +225,16 @@ fn is_subvolume(f: &File) -> Result<bool> {
 	Ok(metadata.is_dir() && metadata.ino() == BTRFS_FIRST_FREE_OBJECTID)
 }
 
-/// Creates a snapshot.
+/// Creates a snapshot, which is read-only if `readonly` is set and writable otherwise.
+///
+/// If the kernel refuses to create a read-only snapshot directly, a writable one is created and
+/// then made read-only; if that fails too, the writable snapshot is deleted again and
+/// [`Error::SetReadonly`](Error::SetReadonly) is returned.
 pub fn create_snapshot(
 	source: &File,
 	dest_parent: impl AsFd,
 	dest_name: impl AsRef<OsStr>,
+	readonly: bool,
 ) -> Result<()> {
 	let dest_name = dest_name.as_ref();
 
@@ -178,11 +251,18 @@ pub fn create_snapshot(
 		return Err(Error::NotSubvolumeRoot);
 	}
 
+	// The source must not be the top-level subvolume. That subvolume’s root is the root of the
+	// mount, so the destination, a sibling, would be on another filesystem or, for a mount at /,
+	// inside the source itself. Kernels too old to report subvolume information skip this check.
+	if matches!(subvolume_info(source), Ok(info) if info.treeid == ioctl::FS_TREE_OBJECTID) {
+		return Err(Error::TopLevelSubvolume);
+	}
+
 	// Perform the ioctl.
 	let mut args = ioctl::ArgsV2 {
 		fd: source.as_fd().as_raw_fd().into(),
 		transid: 0,
-		flags: ioctl::SUBVOL_RDONLY,
+		flags: if readonly { ioctl::SUBVOL_RDONLY } else { 0 },
 		unused: [0; 4],
 		identifier: ioctl::ArgsV2Identifier {
 			name: [0; ioctl::SUBVOL_NAME_MAX + 1],
@@ -191,30 +271,103 @@ pub fn create_snapshot(
 	// SAFETY: name is the active union member.
 	unsafe { &mut args.identifier.name[..dest_name.len()] }.copy_from_slice(dest_name.as_bytes());
 	// SAFETY: The passed-in parameter is locally constructed properly.
-	unsafe { ioctl::snap_create_v2(dest_parent.as_fd().as_raw_fd(), &args as *const _) }?;
+	let result =
+		unsafe { ioctl::snap_create_v2(dest_parent.as_fd().as_raw_fd(), &args as *const _) };
+	match result {
+		Err(nix::errno::Errno::EINVAL | nix::errno::Errno::EOPNOTSUPP) if readonly => {
+			// Some kernels reject creating a read-only snapshot directly under certain mount
+			// options, but can still create a writable one and then make it read-only.
+			args.flags = 0;
+			// SAFETY: The passed-in parameter is locally constructed properly.
+			unsafe { ioctl::snap_create_v2(dest_parent.as_fd().as_raw_fd(), &args as *const _) }?;
+			let snapshot = open_subvolume(dest_parent.as_fd(), dest_name)?;
+			if let Err(e) = set_readonly(&snapshot, true) {
+				// Do not leave behind a writable snapshot that the caller does not know about.
+				// Deleting it is best effort; the error worth reporting is the original one.
+				let _ = delete_subvolume(dest_parent, snapshot);
+				return Err(match e {
+					Error::Syscall(e) => Error::SetReadonly(e),
+					e => e,
+				});
+			}
+		}
+		result => {
+			result?;
+		}
+	}
 
 	Ok(())
 }
 
-/// Deletes a subvolume.
-pub fn delete_subvolume(parent: impl AsFd, subvolume: impl AsFd) -> Result<()> {
-	let parent = parent.as_fd();
-	let subvolume = subvolume.as_fd();
+/// Opens the root directory of a subvolume named `name` within `parent`.
+fn open_subvolume(parent: BorrowedFd<'_>, name: &OsStr) -> Result<File> {
+	let name = std::ffi::CString::new(name.as_bytes())
+		.map_err(|_| std::io::Error::from_raw_os_error(libc::EINVAL))?;
+	// SAFETY: parent is a valid file descriptor, as proven by it being of type BorrowedFd, and name
+	// is a NUL-terminated string.
+	let ret = unsafe {
+		libc::openat(
+			parent.as_raw_fd(),
+			name.as_ptr(),
+			libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+		)
+	};
+	if ret < 0 {
+		Err(Error::Syscall(std::io::Error::last_os_error()))
+	} else {
+		// SAFETY: openat promises to return a brand new file descriptor.
+		Ok(unsafe { File::from_raw_fd(ret) })
+	}
+}
 
-	// Make the subvolume writeable, which is a prerequisite for a non-root user to delete it.
+/// Returns subvolume flags with the read-only flag set or cleared, and the others unchanged.
+fn with_readonly(flags: u64, readonly: bool) -> u64 {
+	if readonly {
+		flags | ioctl::SUBVOL_RDONLY
+	} else {
+		flags & !ioctl::SUBVOL_RDONLY
+	}
+}
+
+/// Makes a subvolume read-only or writable, leaving its other flags unchanged.
+pub fn set_readonly(subvolume: impl AsFd, readonly: bool) -> Result<()> {
+	let subvolume = subvolume.as_fd();
 	let mut flags = 0_u64;
 	// SAFETY: This is a read-only ioctl.
 	unsafe { ioctl::subvol_get_flags(subvolume.as_raw_fd(), &mut flags as *mut _) }?;
-	flags &= !ioctl::SUBVOL_RDONLY;
-	// SAFETY: The flags are exactly the old flags, minus the read-only flag.
+	let flags = with_readonly(flags, readonly);
+	// SAFETY: The flags are exactly the old flags, with only the read-only flag changed.
 	unsafe { ioctl::subvol_set_flags(subvolume.as_raw_fd(), &flags as *const _) }?;
+	Ok(())
+}
 
-	// Get subvolume info.
+/// Returns information about the subvolume whose root directory is `subvolume`.
+pub fn subvolume_info(subvolume: impl AsFd) -> Result<SubvolInfo> {
 	let mut info = MaybeUninit::<ioctl::GetSubvolInfoArgs>::uninit();
 	// SAFETY: This is a read-only ioctl and points at the right parameter type.
-	unsafe { ioctl::get_subvol_info(subvolume.as_raw_fd(), info.as_mut_ptr()) }?;
+	unsafe { ioctl::get_subvol_info(subvolume.as_fd().as_raw_fd(), info.as_mut_ptr()) }?;
 	// SAFETY: The ioctl promises to fill the struct on success.
 	let info = unsafe { info.assume_init() };
+	Ok(SubvolInfo {
+		treeid: info.treeid,
+		generation: info.generation,
+		uuid: Uuid(info.uuid),
+		parent_uuid: Uuid(info.parent_uuid),
+		otime: SystemTime::UNIX_EPOCH + Duration::new(info.otime.sec, info.otime.nsec),
+		readonly: info.flags & ioctl::SUBVOL_RDONLY != 0,
+	})
+}
+
+/// Deletes a subvolume.
+pub fn delete_subvolume(parent: impl AsFd, subvolume: impl AsFd) -> Result<()> {
+	let parent = parent.as_fd();
+	let subvolume = subvolume.as_fd();
+
+	// Make the subvolume writeable, which is a prerequisite for a non-root user to delete it.
+	set_readonly(subvolume, false)?;
+
+	// Get subvolume info.
+	let info = subvolume_info(subvolume)?;
 
 	// Delete subvolume.
 	let args = ioctl::ArgsV2 {
@@ -231,3 +384,27 @@ pub fn delete_subvolume(parent: impl AsFd, subvolume: impl AsFd) -> Result<()> {
 
 	Ok(())
 }
+
+/// Tests formatting a UUID.
+#[test]
+fn test_uuid_display() {
+	let uuid = Uuid([
+		0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd,
+		0xef,
+	]);
+	assert_eq!(uuid.to_string(), "12345678-9abc-def0-0123-456789abcdef");
+}
+
+/// Tests setting and clearing the read-only flag while leaving other flags alone.
+#[test]
+fn test_with_readonly() {
+	const OTHER: u64 = 1 << 0;
+	assert_eq!(with_readonly(0, true), ioctl::SUBVOL_RDONLY);
+	assert_eq!(with_readonly(OTHER, true), OTHER | ioctl::SUBVOL_RDONLY);
+	assert_eq!(
+		with_readonly(OTHER | ioctl::SUBVOL_RDONLY, true),
+		OTHER | ioctl::SUBVOL_RDONLY
+	);
+	assert_eq!(with_readonly(OTHER | ioctl::SUBVOL_RDONLY, false), OTHER);
+	assert_eq!(with_readonly(0, false), 0);
+}
@@ -1,16 +1,27 @@
 //! A lightweight check for whether a repository is properly initialized and a proper passphrase
 //! has been provided.
 
+use super::borg_log::{LogLevel, MessageId, StderrLine};
+use super::command_line::CommandLine;
+use super::config::{BorgEnv, CheckProbe};
+use super::json_error::{fields, Structured};
+use super::tail::{Lines, Tail};
 use serde::Deserialize;
-use std::borrow::Cow;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Read as _};
+use std::net::{TcpStream, ToSocketAddrs as _};
 use std::os::unix::io::{AsFd as _, AsRawFd as _};
 use std::os::unix::process::ExitStatusExt as _;
+use std::path::Path;
 use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 
 /// The possible errors from checking a repository.
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)] // Borg calls exit code 2 an “error” status.
 pub enum Error {
 	/// A passphrase is needed and was not provided, or the provided passphrase was incorrect.
 	Passphrase,
@@ -22,12 +33,15 @@ pub enum Error {
 	/// There was an error spawning or communicating with the `borg` executable.
 	Spawn(std::io::Error),
 
+	/// The `borg` executable could not be launched with the given command line.
+	Launch(CommandLine, std::io::Error),
+
 	/// The `borg` executable produced a line of output that is not valid JSON.
 	Json(serde_json::Error),
 
 	/// The `borg` executable terminated with exit code 2, indicating an error, but did not print
-	/// an error message.
-	ErrorStatusWithoutMessage,
+	/// an error message. The last few lower-level messages it printed, if any, are included.
+	ErrorStatusWithoutMessage(Vec<String>),
 
 	/// The `borg` executable terminated with an exit code other than 0, 1, or 2, which is not
 	/// documented as being possible, and did not print an error message.
@@ -39,6 +53,12 @@ pub enum Error {
 	/// The `borg` executable terminated due to an unknown reason (neither normal termination nor a
 	/// signal).
 	Unknown,
+
+	/// The host of a remote repository could not be resolved to an address.
+	Resolve(String, std::io::Error),
+
+	/// No TCP connection could be made to the SSH port of the host of a remote repository.
+	Connect(String, std::io::Error),
 }
 
 impl Display for Error {
@@ -47,14 +67,22 @@ impl Display for Error {
 			Self::Passphrase => write!(f, "incorrect passphrase"),
 			Self::Repository(e) => write!(f, "{e}"),
 			Self::Spawn(_) => write!(f, "failed to spawn Borg executable"),
+			Self::Launch(command_line, _) => write!(f, "failed to launch {command_line}"),
 			Self::Json(_) => write!(f, "Borg output is invalid JSON"),
-			Self::ErrorStatusWithoutMessage => write!(
+			Self::ErrorStatusWithoutMessage(output) if output.is_empty() => write!(
 				f,
 				"borg returned exit code 2 (error) without an error message"
 			),
+			Self::ErrorStatusWithoutMessage(output) => write!(
+				f,
+				"borg returned exit code 2 (error) without an error message; its last output was:{}",
+				Lines(output)
+			),
 			Self::UnknownExitCode(code) => write!(f, "borg returned unknown exit code {code}"),
 			Self::Signal(signal) => write!(f, "borg terminated due to signal {signal}"),
 			Self::Unknown => write!(f, "borg terminated due to unknown reason"),
+			Self::Resolve(host, _) => write!(f, "cannot resolve host {host}"),
+			Self::Connect(host, _) => write!(f, "cannot connect to host {host}"),
 		}
 	}
 }
@@ -64,12 +92,48 @@ impl std::error::Error for Error {
 		match self {
 			Self::Passphrase
 			| Self::Repository(_)
-			| Self::ErrorStatusWithoutMessage
+			| Self::ErrorStatusWithoutMessage(_)
 			| Self::UnknownExitCode(_)
 			| Self::Signal(_)
 			| Self::Unknown => None,
 			Self::Spawn(e) => Some(e),
+			Self::Launch(_, e) => Some(e),
 			Self::Json(e) => Some(e),
+			Self::Resolve(_, e) | Self::Connect(_, e) => Some(e),
+		}
+	}
+}
+
+impl Structured for Error {
+	fn variant(&self) -> &'static str {
+		match self {
+			Self::Passphrase => "Passphrase",
+			Self::Repository(_) => "Repository",
+			Self::Spawn(_) => "Spawn",
+			Self::Launch(..) => "Launch",
+			Self::Json(_) => "Json",
+			Self::ErrorStatusWithoutMessage(_) => "ErrorStatusWithoutMessage",
+			Self::UnknownExitCode(_) => "UnknownExitCode",
+			Self::Signal(_) => "Signal",
+			Self::Unknown => "Unknown",
+			Self::Resolve(..) => "Resolve",
+			Self::Connect(..) => "Connect",
+		}
+	}
+
+	fn fields(&self) -> Map<String, Value> {
+		match self {
+			Self::Repository(message) => fields([("message", message.as_str().into())]),
+			Self::Launch(command_line, _) => fields([("command", command_line.to_json())]),
+			Self::UnknownExitCode(code) => fields([("code", (*code).into())]),
+			Self::Signal(signal) => fields([("signal", (*signal).into())]),
+			Self::ErrorStatusWithoutMessage(output) => {
+				fields([("output", output.as_slice().into())])
+			}
+			Self::Resolve(host, _) | Self::Connect(host, _) => {
+				fields([("host", host.as_str().into())])
+			}
+			_ => Map::new(),
 		}
 	}
 }
@@ -86,65 +150,13 @@ impl From<serde_json::Error> for Error {
 	}
 }
 
-/// A line of output in JSON format that Borg sends to standard error.
-#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq)]
-#[serde(tag = "type")]
-enum StderrLine<'data> {
-	#[serde(rename = "log_message")]
-	LogMessage {
-		/// The severity of the event.
-		#[serde(rename = "levelname")]
-		level: LogLevel,
-
-		/// The formatted message text.
-		#[serde(borrow)]
-		message: Cow<'data, str>,
-
-		/// The message ID.
-		#[serde(rename = "msgid")]
-		message_id: Option<MessageId>,
-	},
-
-	#[serde(other)]
-	Unknown,
-}
-
-/// A severity level of a log event.
-#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd)]
-enum LogLevel {
-	#[serde(rename = "DEBUG")]
-	Debug,
-
-	#[serde(rename = "INFO")]
-	Info,
-
-	#[serde(rename = "WARNING")]
-	Warning,
-
-	#[serde(rename = "ERROR")]
-	Error,
-
-	#[serde(rename = "CRITICAL")]
-	Critical,
-
-	#[serde(other)]
-	Unknown,
-}
-
-/// A message ID.
-#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq)]
-enum MessageId {
-	/// The repository is encrypted and the passphrase is incorrect.
-	PassphraseWrong,
-
-	/// Any other message.
-	#[serde(other)]
-	Unknown,
-}
-
 /// Handles output generated by a child process.
-fn handle_output(mut stderr: impl BufRead) -> Result<(), Error> {
+///
+/// If no error was reported, returns the last few lower-level messages, for use in case the
+/// process nonetheless fails.
+fn handle_output(mut stderr: impl BufRead) -> Result<Vec<String>, Error> {
 	let mut line_buffer = String::new();
+	let mut tail = Tail::default();
 	let mut first_non_passphrase_error: Option<String> = None;
 	let mut seen_passphrase_wrong_error = false;
 	loop {
@@ -163,6 +175,7 @@ fn handle_output(mut stderr: impl BufRead) -> Result<(), Error> {
 			StderrLine::LogMessage { level, message, .. } if level >= LogLevel::Error => {
 				first_non_passphrase_error.get_or_insert(message.into_owned());
 			}
+			StderrLine::LogMessage { message, .. } => tail.push_line(&message),
 			_ => (),
 		}
 	}
@@ -171,7 +184,7 @@ fn handle_output(mut stderr: impl BufRead) -> Result<(), Error> {
 	} else if seen_passphrase_wrong_error {
 		Err(Error::Passphrase)
 	} else {
-		Ok(())
+		Ok(tail.into_lines())
 	}
 }
 
@@ -180,19 +193,20 @@ fn handle_output(mut stderr: impl BufRead) -> Result<(), Error> {
 fn test_handle_output_empty() {
 	const OUTPUT: &[u8] = b"";
 	match handle_output(OUTPUT) {
-		Ok(()) => (),
+		Ok(_) => (),
 		Err(e) => panic!("unexpected error {e}"),
 	}
 }
 
 /// Tests `handle_output` with a debug-level log message.
 ///
-/// The message should not affect the result; the check should pass.
+/// The message should not affect the result; the check should pass, retaining the message in case
+/// the exit status indicates an error.
 #[test]
 fn test_handle_output_debug() {
 	const OUTPUT: &[u8] = br#"{"message": "35 self tests completed in 0.08 seconds", "type": "log_message", "created": 1488278449.5575905, "levelname": "DEBUG", "name": "borg.archiver"}"#;
 	match handle_output(OUTPUT) {
-		Ok(()) => (),
+		Ok(output) => assert_eq!(output, ["35 self tests completed in 0.08 seconds"]),
 		Err(e) => panic!("unexpected error {e}"),
 	}
 }
@@ -202,7 +216,7 @@ fn test_handle_output_debug() {
 fn test_handle_output_passphrase() {
 	const OUTPUT: &[u8] = br#"{"type": "log_message", "time": 1673159674.6615226, "message": "passphrase supplied in BORG_PASSPHRASE, by BORG_PASSCOMMAND or via BORG_PASSPHRASE_FD is incorrect.", "levelname": "ERROR", "name": "borg.archiver", "msgid": "PassphraseWrong"}"#;
 	match handle_output(OUTPUT) {
-		Ok(()) => panic!("unexpected success"),
+		Ok(_) => panic!("unexpected success"),
 		Err(Error::Passphrase) => (),
 		Err(e) => panic!("unexpected error {e}"),
 	}
@@ -213,7 +227,7 @@ fn test_handle_output_passphrase() {
 fn test_handle_output_error() {
 	const OUTPUT: &[u8] = br#"{"type": "log_message", "time": 1673159749.4641619, "message": "Repository /some/path does not exist.", "levelname": "ERROR", "name": "borg.archiver", "msgid": "Repository.DoesNotExist"}"#;
 	match handle_output(OUTPUT) {
-		Ok(()) => panic!("unexpected success"),
+		Ok(_) => panic!("unexpected success"),
 		Err(Error::Repository(msg)) if msg == "Repository /some/path does not exist." => (),
 		Err(e) => panic!("unexpected error {e}"),
 	}
@@ -225,7 +239,7 @@ fn test_handle_output_debug_and_error() {
 	const OUTPUT: &[u8] = br#"{"message": "35 self tests completed in 0.08 seconds", "type": "log_message", "created": 1488278449.5575905, "levelname": "DEBUG", "name": "borg.archiver"}
 {"type": "log_message", "time": 1673159749.4641619, "message": "Repository /some/path does not exist.", "levelname": "ERROR", "name": "borg.archiver", "msgid": "Repository.DoesNotExist"}"#;
 	match handle_output(OUTPUT) {
-		Ok(()) => panic!("unexpected success"),
+		Ok(_) => panic!("unexpected success"),
 		Err(Error::Repository(msg)) if msg == "Repository /some/path does not exist." => (),
 		Err(e) => panic!("unexpected error {e}"),
 	}
@@ -237,7 +251,7 @@ fn test_handle_output_two_errors() {
 	const OUTPUT: &[u8] = br#"{"type": "log_message", "time": 1673159749.4641619, "message": "The first message", "levelname": "ERROR", "name": "borg.archiver"}
 {"type": "log_message", "time": 1673159749.4641619, "message": "The second message", "levelname": "ERROR", "name": "borg.archiver"}"#;
 	match handle_output(OUTPUT) {
-		Ok(()) => panic!("unexpected success"),
+		Ok(_) => panic!("unexpected success"),
 		Err(Error::Repository(msg)) if msg == "The first message" => (),
 		Err(e) => panic!("unexpected error {e}"),
 	}
@@ -251,7 +265,7 @@ fn test_handle_output_passphrase_and_other_error() {
 	const OUTPUT: &[u8] = br#"{"type": "log_message", "time": 1673159674.6615226, "message": "passphrase supplied in BORG_PASSPHRASE, by BORG_PASSCOMMAND or via BORG_PASSPHRASE_FD is incorrect.", "levelname": "ERROR", "name": "borg.archiver", "msgid": "PassphraseWrong"}
 {"type": "log_message", "time": 1673159749.4641619, "message": "The second message", "levelname": "ERROR", "name": "borg.archiver"}"#;
 	match handle_output(OUTPUT) {
-		Ok(()) => panic!("unexpected success"),
+		Ok(_) => panic!("unexpected success"),
 		Err(Error::Repository(msg)) if msg == "The second message" => (),
 		Err(e) => panic!("unexpected error {e}"),
 	}
@@ -262,45 +276,121 @@ fn test_handle_output_passphrase_and_other_error() {
 fn test_handle_output_invalid_json() {
 	const OUTPUT: &[u8] = b"{";
 	match handle_output(OUTPUT) {
-		Ok(()) => panic!("unexpected success"),
+		Ok(_) => panic!("unexpected success"),
 		Err(Error::Json(_)) => (),
 		Err(e) => panic!("unexpected error {e}"),
 	}
 }
 
+/// The parts of the output of `borg info --json` or `borg list --json` that Borgify uses.
+#[derive(Debug, Deserialize)]
+struct InfoOutput {
+	/// The repository’s encryption settings.
+	encryption: InfoEncryption,
+}
+
+/// The encryption section of the output of `borg info --json` or `borg list --json`.
+#[derive(Debug, Deserialize)]
+struct InfoEncryption {
+	/// The encryption mode, such as `none` or `repokey-blake2`.
+	mode: String,
+}
+
+/// Information about a repository learned while checking it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Info {
+	/// The encryption mode, such as `none` or `repokey-blake2`.
+	pub encryption_mode: String,
+}
+
+/// Extracts the repository information from the standard output of `borg info --json` or `borg
+/// list --json`.
+fn parse_info(stdout: &[u8]) -> Result<Info, serde_json::Error> {
+	let output: InfoOutput = serde_json::from_slice(stdout)?;
+	Ok(Info {
+		encryption_mode: output.encryption.mode,
+	})
+}
+
+/// Returns the Borg subcommand and its arguments for a check probe.
+///
+/// `borg list` is limited to the most recent archive, since only the repository-wide parts of its
+/// output are needed; Borg rejects a limit of zero.
+fn probe_args(probe: CheckProbe) -> &'static [&'static str] {
+	match probe {
+		CheckProbe::Info => &["info", "--json"],
+		CheckProbe::List => &["list", "--json", "--last", "1"],
+	}
+}
+
 /// Tries to examine a repository and verify that it exists and is accessible with a given
+/// passphrase, returning its encryption mode.
+///
+/// If `manage_passphrase` is false, no passphrase is passed at all, leaving Borg to obtain one
+/// itself. The variables in `borg_env`, if any, are set for Borg. `probe` selects the Borg
+/// subcommand; both report the encryption mode and fail the same way for a missing or incorrect
 /// passphrase.
-pub fn run(repository: &str, passphrase: Option<&str>, umask: u16) -> Result<(), Error> {
+pub fn run(
+	repository: &str,
+	passphrase: Option<&[u8]>,
+	manage_passphrase: bool,
+	remote_path: Option<&str>,
+	borg_env: Option<&BorgEnv<'_>>,
+	probe: CheckProbe,
+	umask: u16,
+) -> Result<Info, Error> {
 	// If no passphrase is provided, then use an arbitrary passphrase. If it fails, it will fail
 	// with an “incorrect passphrase” error, which is exactly what we want when a passphrase is
 	// required and was not given. If the repository is unencrypted, then it will succeed because
 	// the passphrase is entirely ignored. This is weird, but is actually the Borg-recommended way
-	// to check whether a repository is encrypted or not.
-	let passphrase = passphrase.unwrap_or("f1ba7f94-7bb5-4a55-8877-7afe3b280f4b");
-	let passphrase_pipe_reader = super::passphrase::send_to_inheritable_pipe(passphrase)?;
+	// to check whether a repository is encrypted or not: borg info cannot report the encryption
+	// mode of an encrypted repository without its key, so there is no way to learn that a
+	// passphrase is needed without trying one.
+	let passphrase_pipe_reader = if manage_passphrase {
+		let passphrase = passphrase.unwrap_or(b"f1ba7f94-7bb5-4a55-8877-7afe3b280f4b");
+		Some(super::passphrase::send_to_inheritable_pipe(passphrase)?)
+	} else {
+		None
+	};
 
 	// Spawn the process.
-	let mut child = Command::new("borg")
+	let mut command = Command::new("borg");
+	command
 		.arg("--log-json")
 		.arg("--umask")
 		.arg(format!("0{umask:o}"))
-		.arg("info")
-		.env(
-			"BORG_PASSPHRASE_FD",
-			format!("{}", passphrase_pipe_reader.as_fd().as_raw_fd()),
-		)
+		.args(remote_path.map(|i| format!("--remote-path={i}")))
+		.args(probe_args(probe))
+		.envs(borg_env.into_iter().flatten().map(|(k, v)| (&**k, &**v)))
 		.env("BORG_REPO", repository)
 		.stdin(Stdio::null())
-		.stdout(Stdio::null())
-		.stderr(Stdio::piped())
-		.spawn()?;
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped());
+	if let Some(reader) = &passphrase_pipe_reader {
+		for var in super::passphrase::OVERRIDING_ENV {
+			command.env_remove(var);
+		}
+		command.env(
+			"BORG_PASSPHRASE_FD",
+			format!("{}", reader.as_fd().as_raw_fd()),
+		);
+	}
+	let mut child = command
+		.spawn()
+		.map_err(|e| Error::Launch(CommandLine::of(&command), e))?;
 
 	// Drop the pipe reader now that the child has a copy of it, ensuring we don’t keep open FDs
 	// around longer than necessary.
 	drop(passphrase_pipe_reader);
 
-	// Deal with the output.
+	// Deal with the output. The JSON on standard output is small enough to fit in the pipe buffer,
+	// so it can be read after standard error is finished without risk of deadlock.
 	let ret = handle_output(BufReader::new(child.stderr.take().unwrap()));
+	let mut stdout = Vec::new();
+	let ret = ret.and_then(|output| {
+		child.stdout.take().unwrap().read_to_end(&mut stdout)?;
+		Ok(output)
+	});
 
 	// If the result was an I/O error or invalid JSON, the child process may not have finished yet,
 	// so try to clean up by killing it.
@@ -319,19 +409,19 @@ pub fn run(repository: &str, passphrase: Option<&str>, umask: u16) -> Result<(),
 
 	// If handle_output reported an error, that is the most detailed information we can provide. If
 	// it did not, consider the exit status.
-	ret?;
+	let output = ret?;
 
 	if let Some(code) = status.code() {
 		// The process terminated normally.
 		match code {
 			0 | 1 => {
 				// Borg returned success or warning, but not error or above.
-				Ok(())
+				Ok(parse_info(&stdout)?)
 			}
 			2 => {
 				// Borg returned an error. We shouldn’t really get here; Borg should have printed
 				// an ERROR-level log message and so we should have reported that instead.
-				Err(Error::ErrorStatusWithoutMessage)
+				Err(Error::ErrorStatusWithoutMessage(output))
 			}
 			_ => {
 				// Borg returned an exit code it is not documented as being able to return.
@@ -346,3 +436,213 @@ pub fn run(repository: &str, passphrase: Option<&str>, umask: u16) -> Result<(),
 		Err(Error::Unknown)
 	}
 }
+
+/// The port used for SSH repositories that do not specify one.
+const SSH_PORT: u16 = 22;
+
+/// How long to wait for a TCP connection to the host of a remote repository.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Extracts the host and port from a remote repository URL, either of the form
+/// `ssh://[user@]host[:port]/path` or of the form `[user@]host:path`, or returns `None` if the
+/// repository is local.
+fn ssh_host(repository: &str) -> Option<(&str, u16)> {
+	let (authority, port) = if let Some(rest) = repository.strip_prefix("ssh://") {
+		let authority = &rest[..rest.find('/').unwrap_or(rest.len())];
+		let authority = authority.rsplit_once('@').map_or(authority, |(_, i)| i);
+		match authority.rsplit_once(':') {
+			Some((host, port)) if !port.contains(']') => (host, port.parse().ok()?),
+			_ => (authority, SSH_PORT),
+		}
+	} else if repository.contains("://") || repository.starts_with('/') {
+		return None;
+	} else {
+		let authority = repository.split_once('@').map_or(repository, |(_, i)| i);
+		let host = if authority.starts_with('[') {
+			&authority[..=authority.find(']')?]
+		} else {
+			let (host, _) = authority.split_once(':')?;
+			if host.contains('/') {
+				return None;
+			}
+			host
+		};
+		(host, SSH_PORT)
+	};
+	let host = authority
+		.strip_prefix('[')
+		.and_then(|i| i.strip_suffix(']'))
+		.unwrap_or(authority);
+	(!host.is_empty()).then_some((host, port))
+}
+
+/// Returns the path of a local repository, written either as a plain path or as a `file://` URL,
+/// or `None` if the repository is remote.
+pub fn local_path(repository: &str) -> Option<&Path> {
+	if let Some(path) = repository.strip_prefix("file://") {
+		Some(Path::new(path))
+	} else if repository.contains("://") || ssh_host(repository).is_some() {
+		None
+	} else {
+		Some(Path::new(repository))
+	}
+}
+
+/// The outcome of checking a host.
+#[derive(Debug)]
+enum HostOutcome {
+	/// The host passed the check.
+	Reachable,
+
+	/// The host could not be resolved to an address.
+	Unresolvable(std::io::Error),
+
+	/// No TCP connection could be made to the host’s SSH port.
+	Unreachable(std::io::Error),
+}
+
+/// The outcome of checking a host, shared between the threads that may want it.
+type SharedHostOutcome = Arc<OnceLock<HostOutcome>>;
+
+/// The outcomes of checking hosts, keyed by host and port, so that each host is checked only once
+/// however many repositories are on it, even when repositories are checked concurrently.
+///
+/// Nothing is ever evicted, so a cache should only live for one run.
+#[derive(Debug, Default)]
+pub struct HostCache(Mutex<HashMap<(String, u16), SharedHostOutcome>>);
+
+/// Makes a copy of an I/O error, so that a cached failure can be reported more than once.
+fn copy_io_error(e: &std::io::Error) -> std::io::Error {
+	match e.raw_os_error() {
+		Some(code) => std::io::Error::from_raw_os_error(code),
+		None => std::io::Error::new(e.kind(), e.to_string()),
+	}
+}
+
+/// Checks that a host resolves to an address and, if `connect` is set, that a TCP connection can
+/// be made to the given port.
+fn probe_host(host: &str, port: u16, connect: bool) -> HostOutcome {
+	let addresses: Vec<_> = match (host, port).to_socket_addrs() {
+		Ok(addresses) => addresses.collect(),
+		Err(e) => return HostOutcome::Unresolvable(e),
+	};
+	if !connect {
+		return HostOutcome::Reachable;
+	}
+	let mut last_error = std::io::Error::from(std::io::ErrorKind::AddrNotAvailable);
+	for address in addresses {
+		match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+			Ok(_) => return HostOutcome::Reachable,
+			Err(e) => last_error = e,
+		}
+	}
+	HostOutcome::Unreachable(last_error)
+}
+
+/// Checks that the host of a remote repository resolves to an address and, if `connect` is set,
+/// that a TCP connection can be made to its SSH port. Local repositories always pass.
+///
+/// If the host and port were already checked, the outcome recorded in `cache` is reused.
+pub fn check_host(repository: &str, connect: bool, cache: &HostCache) -> Result<(), Error> {
+	let Some((host, port)) = ssh_host(repository) else {
+		return Ok(());
+	};
+	let outcome = Arc::clone(
+		cache
+			.0
+			.lock()
+			.unwrap()
+			.entry((host.to_owned(), port))
+			.or_default(),
+	);
+	match outcome.get_or_init(|| probe_host(host, port, connect)) {
+		HostOutcome::Reachable => Ok(()),
+		HostOutcome::Unresolvable(e) => Err(Error::Resolve(host.to_owned(), copy_io_error(e))),
+		HostOutcome::Unreachable(e) => Err(Error::Connect(host.to_owned(), copy_io_error(e))),
+	}
+}
+
+/// Tests extracting hosts from repository URLs.
+#[test]
+fn test_ssh_host() {
+	assert_eq!(ssh_host("/path/to/repo"), None);
+	assert_eq!(ssh_host("relative/repo"), None);
+	assert_eq!(ssh_host("relative/with:colon"), None);
+	assert_eq!(ssh_host("file:///path/to/repo"), None);
+	assert_eq!(
+		ssh_host("ssh://example.com/./repo"),
+		Some(("example.com", 22))
+	);
+	assert_eq!(
+		ssh_host("ssh://user@example.com:2222/./repo"),
+		Some(("example.com", 2222))
+	);
+	assert_eq!(ssh_host("ssh://user@[::1]:2222/repo"), Some(("::1", 2222)));
+	assert_eq!(ssh_host("ssh://[::1]/repo"), Some(("::1", 22)));
+	assert_eq!(ssh_host("user@example.com:repo"), Some(("example.com", 22)));
+	assert_eq!(ssh_host("example.com:/srv/repo"), Some(("example.com", 22)));
+	assert_eq!(ssh_host("user@[fe80::1]:repo"), Some(("fe80::1", 22)));
+	assert_eq!(ssh_host("ssh://host:notaport/repo"), None);
+}
+
+/// Tests that checking hosts caches one outcome per host and port, and that local repositories
+/// need no check.
+#[test]
+fn test_check_host_cache() {
+	let cache = HostCache::default();
+	check_host("/path/to/repo", false, &cache).unwrap();
+	check_host("ssh://127.0.0.1/./a", false, &cache).unwrap();
+	check_host("user@127.0.0.1:b", false, &cache).unwrap();
+	check_host("ssh://127.0.0.1:2222/./c", false, &cache).unwrap();
+	let mut keys: Vec<_> = cache.0.lock().unwrap().keys().cloned().collect();
+	keys.sort();
+	assert_eq!(
+		keys,
+		[("127.0.0.1".to_owned(), 22), ("127.0.0.1".to_owned(), 2222)]
+	);
+}
+
+/// Tests extracting the encryption mode from the output of `borg info --json`.
+#[test]
+fn test_parse_info() {
+	const OUTPUT: &[u8] = br#"{"cache": {"path": "/root/.cache/borg/0123", "stats": {"total_chunks": 0}}, "encryption": {"mode": "repokey-blake2"}, "repository": {"id": "0123", "last_modified": "2023-01-08T01:02:03.000000", "location": "/path/to/repo"}, "security_dir": "/root/.config/borg/security/0123"}"#;
+	assert_eq!(
+		parse_info(OUTPUT).unwrap(),
+		Info {
+			encryption_mode: "repokey-blake2".to_owned()
+		}
+	);
+	assert!(parse_info(b"{}").is_err());
+}
+
+/// Tests parsing the output of `borg list --json`, which reports the encryption mode as `borg info
+/// --json` does.
+#[test]
+fn test_parse_info_list() {
+	const OUTPUT: &[u8] = br#"{"archives": [{"archive": "home-2023-01-08T01:02:03", "barchive": "home-2023-01-08T01:02:03", "id": "4567", "name": "home-2023-01-08T01:02:03", "start": "2023-01-08T01:02:04.000000", "time": "2023-01-08T01:02:04.000000"}], "encryption": {"mode": "none"}, "repository": {"id": "0123", "last_modified": "2023-01-08T01:02:05.000000", "location": "/path/to/repo"}}"#;
+	assert_eq!(
+		parse_info(OUTPUT).unwrap(),
+		Info {
+			encryption_mode: "none".to_owned()
+		}
+	);
+}
+
+/// Tests extracting the paths of local repositories.
+#[test]
+fn test_local_path() {
+	assert_eq!(
+		local_path("/path/to/repo"),
+		Some(Path::new("/path/to/repo"))
+	);
+	assert_eq!(
+		local_path("relative/repo"),
+		Some(Path::new("relative/repo"))
+	);
+	assert_eq!(
+		local_path("file:///path/to/repo"),
+		Some(Path::new("/path/to/repo"))
+	);
+	assert_eq!(local_path("ssh://example.com/./repo"), None);
+	assert_eq!(local_path("user@example.com:repo"), None);
+}
@@ -0,0 +1,572 @@
+//! Parsing of the command line.
+
+use super::json_error::{fields, Structured};
+use chrono::{DateTime, Local, NaiveDateTime, NaiveTime, TimeZone as _, Utc};
+use serde_json::{Map, Value};
+use std::ffi::OsString;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+/// The errors that can occur parsing the command line.
+#[derive(Debug)]
+pub enum Error {
+	/// An argument is not valid UTF-8.
+	NotUnicode(OsString),
+
+	/// An argument is not recognized.
+	Unknown(String),
+
+	/// An option that requires a value was given without one.
+	MissingValue(String),
+
+	/// An option was given a value it does not accept; the option and the value are included.
+	InvalidValue(String, String),
+
+	/// The timestamp given with `--timestamp` is in the future, and `--allow-future` was not
+	/// given.
+	FutureTimestamp(String),
+}
+
+impl Display for Error {
+	fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+		match self {
+			Self::NotUnicode(arg) => write!(f, "argument {arg:?} is not valid UTF-8"),
+			Self::Unknown(arg) => write!(f, "unrecognized argument {arg}"),
+			Self::MissingValue(arg) => write!(f, "option {arg} requires a value"),
+			Self::InvalidValue(arg, value) => write!(f, "invalid value {value:?} for option {arg}"),
+			Self::FutureTimestamp(value) => write!(
+				f,
+				"timestamp {value} is in the future; give --allow-future to use it anyway"
+			),
+		}
+	}
+}
+
+impl std::error::Error for Error {}
+
+impl Structured for Error {
+	fn variant(&self) -> &'static str {
+		match self {
+			Self::NotUnicode(_) => "NotUnicode",
+			Self::Unknown(_) => "Unknown",
+			Self::MissingValue(_) => "MissingValue",
+			Self::InvalidValue(..) => "InvalidValue",
+			Self::FutureTimestamp(_) => "FutureTimestamp",
+		}
+	}
+
+	fn fields(&self) -> Map<String, Value> {
+		match self {
+			Self::NotUnicode(arg) => fields([("argument", arg.to_string_lossy().into())]),
+			Self::Unknown(arg) | Self::MissingValue(arg) => {
+				fields([("argument", arg.as_str().into())])
+			}
+			Self::InvalidValue(arg, value) => fields([
+				("argument", arg.as_str().into()),
+				("value", value.as_str().into()),
+			]),
+			Self::FutureTimestamp(value) => fields([("value", value.as_str().into())]),
+		}
+	}
+}
+
+/// The options specified on the command line.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Options {
+	/// The path to the config file, if not the default.
+	pub config: Option<PathBuf>,
+
+	/// Whether to only load and validate the config file.
+	pub config_test: bool,
+
+	/// Whether to report a failure as a JSON array on standard error rather than as
+	/// human-readable text.
+	pub json_errors: bool,
+
+	/// The names of the archives to operate on, or empty to operate on all of them.
+	pub archives: Vec<String>,
+
+	/// The tags of the archives to operate on, or empty to not filter by tag.
+	pub tags: Vec<String>,
+
+	/// Whether to print the configured archives and their resolved settings, without backing up.
+	pub list: bool,
+
+	/// Whether to print output requested by other options, such as `--list`, as JSON.
+	pub json: bool,
+
+	/// Whether to warn about, rather than reject, unrecognized keys in the config file.
+	pub lenient: bool,
+
+	/// Whether to refuse, rather than warn about, a config file accessible by users other than its
+	/// owner.
+	pub strict_permissions: bool,
+
+	/// Whether to refuse, rather than warn about, an archive root containing no entries.
+	pub strict_empty_root: bool,
+
+	/// Whether to warn about literal paths in include patterns that do not exist.
+	pub check_patterns: bool,
+
+	/// Whether to accept, without a warning, archives sent uncompressed to remote repositories.
+	pub allow_uncompressed_remote: bool,
+
+	/// The verbosity level, incremented once per `-v` or `--verbose` and decremented once per
+	/// `-q` or `--quiet`.
+	pub verbosity: i8,
+
+	/// Whether to only create and delete a snapshot for each btrfs archive, without backing up.
+	pub test_snapshot: bool,
+
+	/// Whether to only create and keep a snapshot for each btrfs archive, without backing up.
+	pub only_snapshot: bool,
+
+	/// Whether to only report on the availability of Borgify’s dependencies, without backing up.
+	pub doctor: bool,
+
+	/// The maximum number of repositories to check at once.
+	pub check_jobs: usize,
+
+	/// Whether to only check each repository and print its encryption mode, without backing up.
+	pub show_encryption: bool,
+
+	/// Whether to only report which repositories need a passphrase and where it would come from,
+	/// without asking for any or backing up.
+	pub passphrase_status: bool,
+
+	/// The archive whose root’s leftover snapshots to only list, without backing up, if any.
+	pub snapshots: Option<String>,
+
+	/// The archive whose root’s leftover snapshots to only list and delete, without backing up, if
+	/// any.
+	pub delete_snapshots: Option<String>,
+
+	/// Whether to perform destructive actions, such as `--delete-snapshots`, without asking for
+	/// confirmation.
+	pub yes: bool,
+
+	/// The archive whose backup to only describe, without running Borg or taking a snapshot, if
+	/// any.
+	pub explain: Option<String>,
+
+	/// Whether to only check the repositories, passphrases, and archive roots, without backing up.
+	pub validate: bool,
+
+	/// Whether `--validate` reports every problem found, rather than stopping at the first.
+	pub keep_going: bool,
+
+	/// Whether to back up archive roots directly, even for archives configured to use snapshots.
+	pub no_snapshot: bool,
+
+	/// Whether to print how long each phase of backing up each archive took.
+	pub profile: bool,
+
+	/// Whether to only prune old Borg archives according to each archive’s retention rules,
+	/// without backing up.
+	pub prune: bool,
+
+	/// The Unix socket or named pipe to which to send Borg’s progress reports, if any.
+	pub progress_socket: Option<PathBuf>,
+
+	/// The timestamp to use for the backup instead of the current time, if any.
+	pub timestamp: Option<DateTime<Utc>>,
+
+	/// Whether to accept a timestamp in the future.
+	pub allow_future: bool,
+
+	/// The time after which no more archives are started, if any.
+	pub deadline: Option<DateTime<Utc>>,
+
+	/// Whether to only print a JSON Schema describing the config file, without backing up.
+	pub schema: bool,
+}
+
+impl Default for Options {
+	fn default() -> Self {
+		Self {
+			config: None,
+			config_test: false,
+			json_errors: false,
+			archives: Vec::new(),
+			tags: Vec::new(),
+			list: false,
+			json: false,
+			lenient: false,
+			strict_permissions: false,
+			strict_empty_root: false,
+			check_patterns: false,
+			allow_uncompressed_remote: false,
+			verbosity: 0,
+			test_snapshot: false,
+			only_snapshot: false,
+			doctor: false,
+			check_jobs: 1,
+			show_encryption: false,
+			passphrase_status: false,
+			snapshots: None,
+			delete_snapshots: None,
+			yes: false,
+			explain: None,
+			validate: false,
+			keep_going: false,
+			no_snapshot: false,
+			profile: false,
+			prune: false,
+			progress_socket: None,
+			timestamp: None,
+			allow_future: false,
+			deadline: None,
+			schema: false,
+		}
+	}
+}
+
+/// Returns the value of an option, either given inline (as in `--option=value`) or as the next
+/// argument.
+fn value(
+	name: &str,
+	inline: Option<&str>,
+	args: &mut impl Iterator<Item = OsString>,
+) -> Result<OsString, Error> {
+	inline
+		.map(OsString::from)
+		.or_else(|| args.next())
+		.ok_or_else(|| Error::MissingValue(name.to_owned()))
+}
+
+/// Returns the value of an option, as for [`value`](value), which must be valid UTF-8.
+fn string(
+	name: &str,
+	inline: Option<&str>,
+	args: &mut impl Iterator<Item = OsString>,
+) -> Result<String, Error> {
+	value(name, inline, args)?
+		.into_string()
+		.map_err(Error::NotUnicode)
+}
+
+/// Parses a timestamp given on the command line, either in RFC 3339 form with a UTC offset (such as
+/// `2023-01-08T10:00:00+01:00` or `2023-01-08T10:00:00Z`) or without one (such as
+/// `2023-01-08T10:00:00`), in which case it is in local time.
+fn parse_timestamp(value: &str) -> Option<DateTime<Utc>> {
+	if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+		return Some(timestamp.with_timezone(&Utc));
+	}
+	let naive = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S").ok()?;
+	// A local time that is skipped or repeated by a daylight saving time change is ambiguous.
+	Some(
+		Local
+			.from_local_datetime(&naive)
+			.single()?
+			.with_timezone(&Utc),
+	)
+}
+
+/// Parses a deadline given on the command line, either as a timestamp in any form accepted by
+/// [`parse_timestamp`](parse_timestamp) or as a local time of day (such as `06:00` or `06:00:00`),
+/// meaning its next occurrence after `now`.
+fn parse_deadline(value: &str, now: DateTime<Local>) -> Option<DateTime<Utc>> {
+	if let Some(deadline) = parse_timestamp(value) {
+		return Some(deadline);
+	}
+	let time = NaiveTime::parse_from_str(value, "%H:%M:%S")
+		.or_else(|_| NaiveTime::parse_from_str(value, "%H:%M"))
+		.ok()?;
+	let mut naive = now.date_naive().and_time(time);
+	if naive <= now.naive_local() {
+		naive += chrono::Duration::days(1);
+	}
+	// A local time that is skipped or repeated by a daylight saving time change is ambiguous.
+	Some(
+		Local
+			.from_local_datetime(&naive)
+			.single()?
+			.with_timezone(&Utc),
+	)
+}
+
+/// Parses the command-line arguments, not including the program name.
+pub fn parse(args: impl IntoIterator<Item = OsString>) -> Result<Options, Error> {
+	let mut options = Options::default();
+	let mut timestamp = None;
+	let mut args = args.into_iter();
+	while let Some(arg) = args.next() {
+		let arg = arg.into_string().map_err(Error::NotUnicode)?;
+		let (name, inline) = match arg.split_once('=') {
+			Some((name, value)) if name.starts_with("--") => (name, Some(value)),
+			_ => (arg.as_str(), None),
+		};
+		match (name, inline) {
+			("-a" | "--archive", _) => options.archives.push(string(name, inline, &mut args)?),
+			("-t" | "--tag", _) => options.tags.push(string(name, inline, &mut args)?),
+			("-c" | "--config", _) => {
+				options.config = Some(value(name, inline, &mut args)?.into());
+			}
+			("--check-jobs", _) => {
+				let value = string(name, inline, &mut args)?;
+				options.check_jobs = match value.parse() {
+					Ok(jobs) if jobs > 0 => jobs,
+					_ => return Err(Error::InvalidValue(name.to_owned(), value)),
+				};
+			}
+			("--allow-future", None) => options.allow_future = true,
+			("--allow-uncompressed-remote", None) => options.allow_uncompressed_remote = true,
+			("--check-patterns", None) => options.check_patterns = true,
+			("--config-test", None) => options.config_test = true,
+			("--deadline", _) => {
+				let value = string(name, inline, &mut args)?;
+				options.deadline = Some(
+					parse_deadline(&value, Local::now())
+						.ok_or_else(|| Error::InvalidValue(name.to_owned(), value))?,
+				);
+			}
+			("--delete-snapshots", _) => {
+				options.delete_snapshots = Some(string(name, inline, &mut args)?);
+			}
+			("--doctor", None) => options.doctor = true,
+			("--explain", _) => options.explain = Some(string(name, inline, &mut args)?),
+			("--json", None) => options.json = true,
+			("--json-errors", None) => options.json_errors = true,
+			("--keep-going", None) => options.keep_going = true,
+			("--list", None) => options.list = true,
+			("--no-snapshot", None) => options.no_snapshot = true,
+			("--passphrase-status", None) => options.passphrase_status = true,
+			("--lenient", None) => options.lenient = true,
+			("--profile", None) => options.profile = true,
+			("--prune", None) => options.prune = true,
+			("--progress-socket", _) => {
+				options.progress_socket = Some(value(name, inline, &mut args)?.into());
+			}
+			("--strict-permissions", None) => options.strict_permissions = true,
+			("--strict-empty-root", None) => options.strict_empty_root = true,
+			("--schema", None) => options.schema = true,
+			("--show-encryption", None) => options.show_encryption = true,
+			("--snapshots", _) => options.snapshots = Some(string(name, inline, &mut args)?),
+			("--test-snapshot", None) => options.test_snapshot = true,
+			("--only-snapshot", None) => options.only_snapshot = true,
+			("--validate", None) => options.validate = true,
+			("-y" | "--yes", None) => options.yes = true,
+			("--timestamp", _) => {
+				let value = string(name, inline, &mut args)?;
+				options.timestamp = Some(
+					parse_timestamp(&value)
+						.ok_or_else(|| Error::InvalidValue(name.to_owned(), value.clone()))?,
+				);
+				timestamp = Some(value);
+			}
+			("-q" | "--quiet", None) => options.verbosity = options.verbosity.saturating_sub(1),
+			("-v" | "--verbose", None) => options.verbosity = options.verbosity.saturating_add(1),
+			_ => return Err(Error::Unknown(arg)),
+		}
+	}
+	if let (Some(parsed), Some(value)) = (options.timestamp, timestamp) {
+		if parsed > Utc::now() && !options.allow_future {
+			return Err(Error::FutureTimestamp(value));
+		}
+	}
+	Ok(options)
+}
+
+/// Tests parsing deadlines, both as timestamps and as the next occurrence of a time of day.
+#[test]
+fn test_parse_deadline() {
+	let local = |value| {
+		Local
+			.from_local_datetime(
+				&NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S").unwrap(),
+			)
+			.unwrap()
+	};
+	let now = local("2023-01-08T22:00:00");
+	assert_eq!(
+		parse_deadline("06:00", now),
+		Some(local("2023-01-09T06:00:00").with_timezone(&Utc))
+	);
+	assert_eq!(
+		parse_deadline("23:30:15", now),
+		Some(local("2023-01-08T23:30:15").with_timezone(&Utc))
+	);
+	assert_eq!(
+		parse_deadline("2023-01-08T10:00:00Z", now),
+		Some(Utc.with_ymd_and_hms(2023, 1, 8, 10, 0, 0).unwrap())
+	);
+	assert_eq!(parse_deadline("6 am", now), None);
+	assert!(matches!(
+		parse(["--deadline", "tomorrow"].map(OsString::from)),
+		Err(Error::InvalidValue(..))
+	));
+	assert!(parse(["--deadline", "06:00"].map(OsString::from))
+		.unwrap()
+		.deadline
+		.is_some());
+}
+
+/// Tests parsing an empty command line.
+#[test]
+fn test_parse_empty() {
+	assert_eq!(parse([]).unwrap(), Options::default());
+}
+
+/// Tests parsing the `--json-errors` option.
+#[test]
+fn test_parse_json_errors() {
+	assert_eq!(
+		parse([OsString::from("--json-errors")]).unwrap(),
+		Options {
+			json_errors: true,
+			..Options::default()
+		}
+	);
+}
+
+/// Tests parsing the `--list` and `--json` options together.
+#[test]
+fn test_parse_list_json() {
+	assert_eq!(
+		parse(["--list", "--json"].map(OsString::from)).unwrap(),
+		Options {
+			list: true,
+			json: true,
+			..Options::default()
+		}
+	);
+}
+
+/// Tests parsing repeated verbosity options.
+#[test]
+fn test_parse_verbose() {
+	let options = parse(["-v", "--verbose"].map(OsString::from)).unwrap();
+	assert_eq!(options.verbosity, 2);
+}
+
+/// Tests parsing the quiet option, which lowers the verbosity level.
+#[test]
+fn test_parse_quiet() {
+	let options = parse(["-q"].map(OsString::from)).unwrap();
+	assert_eq!(options.verbosity, -1);
+	let options = parse(["--quiet", "-v", "-v"].map(OsString::from)).unwrap();
+	assert_eq!(options.verbosity, 1);
+}
+
+/// Tests parsing the config file path in both separate and inline forms.
+#[test]
+fn test_parse_config() {
+	let options = parse(["--config", "/a.json"].map(OsString::from)).unwrap();
+	assert_eq!(options.config, Some(PathBuf::from("/a.json")));
+	let options = parse(["--config=/b.json"].map(OsString::from)).unwrap();
+	assert_eq!(options.config, Some(PathBuf::from("/b.json")));
+	let options = parse(["-c", "c.json"].map(OsString::from)).unwrap();
+	assert_eq!(options.config, Some(PathBuf::from("c.json")));
+}
+
+/// Tests parsing repeated archive and tag selectors.
+#[test]
+fn test_parse_selectors() {
+	let options =
+		parse(["-a", "foo", "--archive=bar", "--tag", "db", "-t", "web"].map(OsString::from))
+			.unwrap();
+	assert_eq!(options.archives, ["foo", "bar"]);
+	assert_eq!(options.tags, ["db", "web"]);
+}
+
+/// Tests parsing an option which requires a value, without the value.
+#[test]
+fn test_parse_missing_value() {
+	match parse([OsString::from("--config")]) {
+		Err(Error::MissingValue(arg)) if arg == "--config" => (),
+		other => panic!("unexpected result {other:?}"),
+	}
+}
+
+/// Tests parsing a flag with an inline value, which is not allowed.
+#[test]
+fn test_parse_flag_with_value() {
+	assert!(parse([OsString::from("--json-errors=yes")]).is_err());
+}
+
+/// Tests parsing an unrecognized option.
+#[test]
+fn test_parse_unknown() {
+	match parse([OsString::from("--bogus")]) {
+		Err(Error::Unknown(arg)) if arg == "--bogus" => (),
+		other => panic!("unexpected result {other:?}"),
+	}
+}
+
+/// Tests parsing the `--check-jobs` option, which must be a positive integer.
+#[test]
+fn test_parse_check_jobs() {
+	assert_eq!(
+		parse(["--check-jobs", "4"].map(OsString::from))
+			.unwrap()
+			.check_jobs,
+		4
+	);
+	assert_eq!(
+		parse([OsString::from("--check-jobs=2")])
+			.unwrap()
+			.check_jobs,
+		2
+	);
+	for value in ["0", "-1", "many"] {
+		assert!(matches!(
+			parse(["--check-jobs", value].map(OsString::from)),
+			Err(Error::InvalidValue(..))
+		));
+	}
+}
+
+/// Tests parsing the `--timestamp` option, in both forms, and rejecting invalid or future values.
+#[test]
+fn test_parse_timestamp() {
+	let options = parse(["--timestamp", "2023-01-08T10:00:00+01:00"].map(OsString::from)).unwrap();
+	assert_eq!(
+		options.timestamp,
+		Some(Utc.with_ymd_and_hms(2023, 1, 8, 9, 0, 0).unwrap())
+	);
+	let options = parse([OsString::from("--timestamp=2023-01-08T10:00:00")]).unwrap();
+	assert_eq!(
+		options.timestamp,
+		Local
+			.with_ymd_and_hms(2023, 1, 8, 10, 0, 0)
+			.single()
+			.map(|i| i.with_timezone(&Utc))
+	);
+	for value in ["2023-01-08", "yesterday", "2023-13-01T00:00:00Z"] {
+		assert!(matches!(
+			parse(["--timestamp", value].map(OsString::from)),
+			Err(Error::InvalidValue(..))
+		));
+	}
+	assert!(matches!(
+		parse(["--timestamp", "2999-01-01T00:00:00Z"].map(OsString::from)),
+		Err(Error::FutureTimestamp(_))
+	));
+	assert!(
+		parse(["--allow-future", "--timestamp", "2999-01-01T00:00:00Z"].map(OsString::from))
+			.is_ok()
+	);
+}
+
+/// Tests parsing the `--explain` option, which requires an archive name.
+#[test]
+fn test_parse_explain() {
+	let options = parse(["--explain", "home"].map(OsString::from)).unwrap();
+	assert_eq!(options.explain.as_deref(), Some("home"));
+	assert!(matches!(
+		parse([OsString::from("--explain")]),
+		Err(Error::MissingValue(_))
+	));
+}
+
+/// Tests parsing `--snapshots`, `--delete-snapshots`, and `--yes`.
+#[test]
+fn test_parse_snapshots() {
+	let options = parse(["--snapshots", "home"].map(OsString::from)).unwrap();
+	assert_eq!(options.snapshots.as_deref(), Some("home"));
+	assert_eq!(options.delete_snapshots, None);
+	assert!(!options.yes);
+	let options = parse(["--delete-snapshots=home", "-y"].map(OsString::from)).unwrap();
+	assert_eq!(options.delete_snapshots.as_deref(), Some("home"));
+	assert!(options.yes);
+}
@@ -0,0 +1,105 @@
+//! Descriptions of command lines, for use in error messages.
+
+use serde_json::Value;
+use std::ffi::{OsStr, OsString};
+use std::fmt::{Display, Formatter};
+use std::os::unix::fs::PermissionsExt as _;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The program and arguments of a command.
+///
+/// Only the program and arguments are captured, never the environment, so a `CommandLine` never
+/// contains secrets passed to the child via environment variables.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CommandLine {
+	/// The program, resolved against `PATH` if possible.
+	pub program: PathBuf,
+
+	/// The arguments, not including the program name.
+	pub args: Vec<OsString>,
+}
+
+impl CommandLine {
+	/// Captures the program and arguments of a command.
+	pub fn of(command: &Command) -> Self {
+		Self {
+			program: resolve(command.get_program()),
+			args: command.get_args().map(OsStr::to_owned).collect(),
+		}
+	}
+
+	/// Returns the command line as a JSON array of strings.
+	pub fn to_json(&self) -> Value {
+		std::iter::once(self.program.as_os_str())
+			.chain(self.args.iter().map(OsString::as_os_str))
+			.map(|i| Value::from(i.to_string_lossy()))
+			.collect()
+	}
+}
+
+impl Display for CommandLine {
+	fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+		write_word(f, self.program.as_os_str())?;
+		for arg in &self.args {
+			" ".fmt(f)?;
+			write_word(f, arg)?;
+		}
+		Ok(())
+	}
+}
+
+/// Writes one word of a command line, quoting it if it contains characters special to the shell.
+fn write_word(f: &mut Formatter<'_>, word: &OsStr) -> Result<(), std::fmt::Error> {
+	let word = word.to_string_lossy();
+	let plain = !word.is_empty()
+		&& word
+			.chars()
+			.all(|c| c.is_ascii_alphanumeric() || "-_=+:,./@%".contains(c));
+	if plain {
+		word.fmt(f)
+	} else {
+		write!(f, "'{}'", word.replace('\'', r"'\''"))
+	}
+}
+
+/// Resolves a program name to the executable that would be run, by searching `PATH`.
+///
+/// If the program name contains a slash, or no matching executable is found, the name is returned
+/// unmodified.
+fn resolve(program: &OsStr) -> PathBuf {
+	let program = Path::new(program);
+	if program.components().count() != 1 {
+		return program.to_owned();
+	}
+	std::env::var_os("PATH")
+		.and_then(|path| {
+			std::env::split_paths(&path)
+				.map(|dir| dir.join(program))
+				.find(|candidate| {
+					candidate
+						.metadata()
+						.is_ok_and(|md| md.is_file() && md.permissions().mode() & 0o111 != 0)
+				})
+		})
+		.unwrap_or_else(|| program.to_owned())
+}
+
+/// Tests formatting a command line with words that need quoting.
+#[test]
+fn test_display() {
+	let command_line = CommandLine {
+		program: PathBuf::from("/usr/bin/borg"),
+		args: vec![
+			OsString::from("--umask"),
+			OsString::from("0077"),
+			OsString::from("two words"),
+			OsString::from("it's"),
+			OsString::from(""),
+		],
+	};
+	assert_eq!(
+		command_line.to_string(),
+		r"/usr/bin/borg --umask 0077 'two words' 'it'\''s' ''"
+	);
+}
@@ -1,19 +1,528 @@
 //! Loading of the configuration file.
 
+use super::check::local_path;
+use super::json_error::{fields, Structured};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{FixedOffset, TimeZone as _};
 use serde::de::Error as _;
 use serde::{Deserialize, Deserializer};
+use serde_json::{Map, Value};
 use std::borrow::Cow;
 use std::collections::BTreeMap;
-use std::path::Path;
+use std::fmt::{Display, Formatter};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+
+/// A compression algorithm supported by Borg.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionAlgorithm {
+	/// No compression.
+	None,
+
+	/// LZ4 compression.
+	Lz4,
+
+	/// Zstandard compression.
+	Zstd,
+
+	/// Zlib (gzip) compression.
+	Zlib,
+
+	/// LZMA (xz) compression.
+	Lzma,
+}
+
+/// How the paths of files are recorded in a Borg archive.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PathStyle {
+	/// Paths are recorded relative to the archive root.
+	#[default]
+	Relative,
+
+	/// Paths are recorded as the full path of the file, including the archive root.
+	Absolute,
+}
+
+impl PathStyle {
+	/// Returns the name used for the style in the config file.
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Relative => "relative",
+			Self::Absolute => "absolute",
+		}
+	}
+}
+
+/// The backup program that creates an archive’s backups.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+	/// Borg, creating Borg archives.
+	#[default]
+	Borg,
+
+	/// restic, creating restic snapshots tagged with the archive name.
+	Restic,
+}
+
+impl Backend {
+	/// Returns the name used for the backend in the config file.
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Borg => "borg",
+			Self::Restic => "restic",
+		}
+	}
+
+	/// Returns the name of the backend’s program, for messages.
+	pub fn program(self) -> &'static str {
+		match self {
+			Self::Borg => "Borg",
+			Self::Restic => "restic",
+		}
+	}
+}
+
+/// Which time Borg records as the creation time of an archive.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampSource {
+	/// The time of the run, shared by every archive in it.
+	#[default]
+	Run,
+
+	/// The time the archive root’s snapshot was created, or the time Borg is started if no
+	/// snapshot is taken.
+	Snapshot,
+
+	/// The time Borg is started to create the archive.
+	Start,
+}
+
+impl TimestampSource {
+	/// Returns the name used for the source in the config file.
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Run => "run",
+			Self::Snapshot => "snapshot",
+			Self::Start => "start",
+		}
+	}
+}
+
+impl CompressionAlgorithm {
+	/// Returns the name Borg uses for the algorithm.
+	fn name(self) -> &'static str {
+		match self {
+			Self::None => "none",
+			Self::Lz4 => "lz4",
+			Self::Zstd => "zstd",
+			Self::Zlib => "zlib",
+			Self::Lzma => "lzma",
+		}
+	}
+
+	/// Returns the range of levels the algorithm accepts, or `None` if it does not take a level.
+	fn levels(self) -> Option<std::ops::RangeInclusive<u8>> {
+		match self {
+			Self::None | Self::Lz4 => None,
+			Self::Zstd => Some(1..=22),
+			Self::Zlib | Self::Lzma => Some(0..=9),
+		}
+	}
+}
+
+/// A compression specification.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Compression<'raw> {
+	/// A specification written directly in Borg’s `--compression` syntax.
+	Raw(Cow<'raw, str>),
+
+	/// A structured specification.
+	Structured {
+		/// The compression algorithm.
+		algorithm: CompressionAlgorithm,
+
+		/// The compression level, or `None` to use the algorithm’s default.
+		level: Option<u8>,
+
+		/// Whether to let Borg skip compressing data that a quick test shows to be
+		/// incompressible.
+		auto: bool,
+
+		/// The `SPEC` of Borg’s `obfuscate` mode, which pads compressed chunks to hide their
+		/// sizes, or `None` not to pad them.
+		obfuscate: Option<u8>,
+	},
+}
+
+impl Default for Compression<'_> {
+	fn default() -> Self {
+		Self::Raw(Cow::Borrowed("lz4"))
+	}
+}
+
+impl Compression<'_> {
+	/// Returns whether the specification turns compression off entirely.
+	pub fn is_none(&self) -> bool {
+		match self {
+			Self::Raw(spec) => spec == "none",
+			Self::Structured { algorithm, .. } => *algorithm == CompressionAlgorithm::None,
+		}
+	}
+}
+
+impl Display for Compression<'_> {
+	fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+		match self {
+			Self::Raw(spec) => spec.fmt(f),
+			Self::Structured {
+				algorithm,
+				level,
+				auto,
+				obfuscate,
+			} => {
+				if let Some(spec) = obfuscate {
+					write!(f, "obfuscate,{spec},")?;
+				}
+				if *auto {
+					"auto,".fmt(f)?;
+				}
+				algorithm.name().fmt(f)?;
+				if let Some(level) = level {
+					write!(f, ",{level}")?;
+				}
+				Ok(())
+			}
+		}
+	}
+}
+
+/// How many of an archive’s Borg archives to keep when pruning, as `borg prune` options.
+#[derive(Clone, Debug, Default, Deserialize, Eq, Hash, PartialEq)]
+pub struct Retention<'raw> {
+	/// Keep every archive created within this interval, such as `7d`.
+	#[serde(borrow, default)]
+	pub keep_within: Option<Cow<'raw, str>>,
+
+	/// The number of most recent archives to keep.
+	#[serde(default)]
+	pub keep_last: Option<u32>,
+
+	/// The number of minutely archives to keep.
+	#[serde(default)]
+	pub keep_minutely: Option<u32>,
+
+	/// The number of hourly archives to keep.
+	#[serde(default)]
+	pub keep_hourly: Option<u32>,
+
+	/// The number of daily archives to keep.
+	#[serde(default)]
+	pub keep_daily: Option<u32>,
+
+	/// The number of weekly archives to keep.
+	#[serde(default)]
+	pub keep_weekly: Option<u32>,
+
+	/// The number of monthly archives to keep.
+	#[serde(default)]
+	pub keep_monthly: Option<u32>,
+
+	/// The number of yearly archives to keep.
+	#[serde(default)]
+	pub keep_yearly: Option<u32>,
+}
+
+impl Retention<'_> {
+	/// Returns the counted rules, with the names of their `borg prune` options.
+	fn counts(&self) -> [(&'static str, Option<u32>); 7] {
+		[
+			("last", self.keep_last),
+			("minutely", self.keep_minutely),
+			("hourly", self.keep_hourly),
+			("daily", self.keep_daily),
+			("weekly", self.keep_weekly),
+			("monthly", self.keep_monthly),
+			("yearly", self.keep_yearly),
+		]
+	}
+
+	/// Returns the `borg prune` options expressing the rules.
+	pub fn args(&self) -> Vec<String> {
+		self.keep_within
+			.iter()
+			.map(|i| format!("--keep-within={i}"))
+			.chain(
+				self.counts()
+					.into_iter()
+					.filter_map(|(name, count)| Some(format!("--keep-{name}={}", count?))),
+			)
+			.collect()
+	}
+
+	/// Returns the rules as a JSON object, using the same key names as the config file.
+	pub fn to_json(&self) -> Value {
+		let mut object = Map::new();
+		object.insert("keep_within".to_owned(), self.keep_within.as_deref().into());
+		for (name, count) in self.counts() {
+			object.insert(format!("keep_{name}"), count.into());
+		}
+		Value::Object(object)
+	}
+
+	/// Checks that at least one rule is given, since Borg refuses to prune without any, and that
+	/// `keep_within` is an interval Borg accepts.
+	fn check<'de, D: Deserializer<'de>>(&self) -> Result<(), D::Error> {
+		if self.keep_within.is_none() && self.counts().iter().all(|(_, i)| i.is_none()) {
+			return Err(D::Error::custom(
+				"prune must contain at least one keep_ rule",
+			));
+		}
+		if let Some(within) = &self.keep_within {
+			check_interval::<D>(within)?;
+		}
+		Ok(())
+	}
+}
+
+/// Returns the length of an interval written as Borg writes them for `--keep-within`, such as
+/// `7d`: a number followed by `H` for hours, `d` for days, `w` for weeks, `m` for months, or `y`
+/// for years, where, as for Borg, a month is 31 days and a year is 365 days. Returns `None` if the
+/// interval is not of that form or is too long to represent.
+pub fn interval_duration(interval: &str) -> Option<chrono::Duration> {
+	let unit = interval.chars().last()?;
+	let count = &interval[..interval.len() - unit.len_utf8()];
+	if count.is_empty() || !count.bytes().all(|b| b.is_ascii_digit()) {
+		return None;
+	}
+	let count: i64 = count.parse().ok()?;
+	let hours = match unit {
+		'H' => 1,
+		'd' => 24,
+		'w' => 7 * 24,
+		'm' => 31 * 24,
+		'y' => 365 * 24,
+		_ => return None,
+	};
+	chrono::Duration::try_hours(count.checked_mul(hours)?)
+}
+
+/// Checks that a string is an interval Borg accepts, as described for
+/// [`interval_duration`](interval_duration).
+fn check_interval<'de, D: Deserializer<'de>>(interval: &str) -> Result<(), D::Error> {
+	match interval_duration(interval) {
+		Some(_) => Ok(()),
+		None => Err(D::Error::invalid_value(
+			serde::de::Unexpected::Str(interval),
+			&"an interval such as 7d: a number followed by H, d, w, m, or y",
+		)),
+	}
+}
+
+/// What to do about subvolumes nested within a snapshotted archive root, which btrfs does not
+/// include in the snapshot and which therefore appear in it as empty directories.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NestedSubvolumes {
+	/// Look for nested subvolumes and warn about each one.
+	#[default]
+	Warn,
+
+	/// Look for nested subvolumes and exclude them from the archive.
+	Exclude,
+
+	/// Do not look for nested subvolumes, avoiding a walk of the whole snapshot.
+	Ignore,
+}
+
+impl NestedSubvolumes {
+	/// Returns the name used for the setting in the config file.
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Warn => "warn",
+			Self::Exclude => "exclude",
+			Self::Ignore => "ignore",
+		}
+	}
+}
+
+/// What to do if a snapshot cannot be deleted after backing up from it.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotDeleteFailure {
+	/// Fail the run, even if the backups themselves succeeded.
+	#[default]
+	Error,
+
+	/// Warn, naming the snapshot left behind, but count the backups as successful.
+	Warn,
+}
+
+impl SnapshotDeleteFailure {
+	/// Returns the name used for the setting in the config file.
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Error => "error",
+			Self::Warn => "warn",
+		}
+	}
+}
+
+/// What to do if the name generated for a snapshot is already taken, most likely by a snapshot left
+/// behind by an earlier run.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotCollision {
+	/// Warn and try another name.
+	#[default]
+	Warn,
+
+	/// Try another name, mentioning the collision only in verbose mode.
+	Info,
+
+	/// Delete the existing subvolume if it is a read-only snapshot of the same root, and otherwise
+	/// warn, then try another name.
+	Delete,
+}
+
+impl SnapshotCollision {
+	/// Returns the name used for the setting in the config file.
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Warn => "warn",
+			Self::Info => "info",
+			Self::Delete => "delete",
+		}
+	}
+}
+
+/// How thoroughly to check that the host of each remote repository is reachable before running
+/// Borg.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HostCheck {
+	/// Do not check hosts, leaving any failure to Borg and SSH.
+	#[default]
+	Off,
+
+	/// Check that each host name resolves to an address.
+	Resolve,
+
+	/// Check that each host name resolves and that a TCP connection can be made to its SSH port.
+	Connect,
+}
+
+impl HostCheck {
+	/// Returns the name used for the setting in the config file.
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Off => "off",
+			Self::Resolve => "resolve",
+			Self::Connect => "connect",
+		}
+	}
+}
+
+/// Which Borg subcommand to run when checking that a repository is accessible and finding out
+/// whether it needs a passphrase.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckProbe {
+	/// Run `borg info`.
+	#[default]
+	Info,
+
+	/// Run `borg list` for only the most recent archive, which can be faster than `borg info` for
+	/// some repositories.
+	List,
+}
+
+impl CheckProbe {
+	/// Returns the name used for the setting in the config file.
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Info => "info",
+			Self::List => "list",
+		}
+	}
+}
+
+/// What to do if Borgify’s environment holds a passphrase for Borg, which Borg would use in
+/// preference to the one Borgify passes it.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum InheritedPassphrase {
+	/// Warn, and remove the variable from Borg’s environment wherever Borgify passes a passphrase.
+	#[default]
+	Unset,
+
+	/// Refuse to run.
+	Error,
+}
+
+impl InheritedPassphrase {
+	/// Returns the name used for the setting in the config file.
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Unset => "unset",
+			Self::Error => "error",
+		}
+	}
+}
+
+/// Options controlling how a btrfs snapshot of an archive root is taken.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct SnapshotOptions<'raw> {
+	/// Whether to fall back to a writable snapshot if a read-only one cannot be created.
+	pub allow_writable: bool,
+
+	/// What to do about subvolumes nested within the archive root.
+	pub nested_subvolumes: NestedSubvolumes,
+
+	/// What to do if the snapshot cannot be deleted.
+	pub delete_failure: SnapshotDeleteFailure,
+
+	/// How many times to retry creating the snapshot if the filesystem is out of space.
+	pub space_retries: u32,
+
+	/// What to do if the name generated for the snapshot is already taken.
+	pub collision: SnapshotCollision,
+
+	/// The directory under which to place the snapshot, in a subdirectory named after the
+	/// archive, rather than next to the archive root.
+	pub snapshot_dir: Option<Cow<'raw, Path>>,
+}
+
+impl Default for SnapshotOptions<'_> {
+	fn default() -> Self {
+		Self {
+			allow_writable: false,
+			nested_subvolumes: NestedSubvolumes::default(),
+			delete_failure: SnapshotDeleteFailure::default(),
+			space_retries: DEFAULT_SNAPSHOT_SPACE_RETRIES,
+			collision: SnapshotCollision::default(),
+			snapshot_dir: None,
+		}
+	}
+}
 
 /// Information about one archive.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct Archive<'raw> {
-	/// The requested compression level.
-	pub compression: Cow<'raw, str>,
+	/// Whether the archive is backed up; a disabled archive is parsed and validated but skipped.
+	pub enabled: bool,
 
-	/// The repository URL.
-	pub repository: Cow<'raw, str>,
+	/// The requested compression.
+	pub compression: Compression<'raw>,
+
+	/// The URLs of the repositories to write the archive to.
+	pub repositories: Vec<Cow<'raw, str>>,
+
+	/// The backup program that writes to the repositories.
+	pub backend: Backend,
 
 	/// The path to the root directory of the files to add to the archive.
 	pub root: Cow<'raw, Path>,
@@ -22,8 +531,225 @@ pub struct Archive<'raw> {
 	/// snapshot thereof.
 	pub btrfs_snapshot: bool,
 
+	/// How to take the snapshot, if `btrfs_snapshot` is set.
+	pub snapshot_options: SnapshotOptions<'raw>,
+
 	/// The list of pattern strings.
 	pub patterns: Vec<Cow<'raw, str>>,
+
+	/// The tags used to select the archive from the command line.
+	pub tags: Vec<Cow<'raw, str>>,
+
+	/// The comment to record in each Borg archive, before placeholder expansion.
+	pub comment: Option<Cow<'raw, str>>,
+
+	/// Whether to verify each Borg archive after creating it.
+	pub verify_after: bool,
+
+	/// Whether to print the paths that changed since the previous Borg archive after creating each
+	/// one.
+	pub list_changed: bool,
+
+	/// The files cache mode to pass to Borg, if not Borg’s default.
+	pub files_cache: Option<Cow<'raw, str>>,
+
+	/// The time zone in which to write the timestamp in archive names, if not the system’s.
+	pub timezone: Option<Cow<'raw, str>>,
+
+	/// The `strftime`-style format of the timestamp in archive names.
+	pub name_date_format: Cow<'raw, str>,
+
+	/// Which time Borg records as the creation time of the archive.
+	pub timestamp_source: TimestampSource,
+
+	/// Which of the archive’s Borg archives to keep when pruning, if it is pruned.
+	pub prune: Option<Retention<'raw>>,
+
+	/// The size in MiB of Borg’s buffer for uploading to remote repositories, if not Borg’s
+	/// default.
+	pub upload_buffer: Option<NonZeroU32>,
+
+	/// The interval in seconds between Borg’s checkpoints while creating the archive, if not
+	/// Borg’s default.
+	pub checkpoint_interval: Option<NonZeroU32>,
+
+	/// How old, as an interval such as `7d`, a checkpoint archive left by an interrupted backup
+	/// must be for it to be deleted at the start of a backup, if such checkpoints are deleted.
+	pub checkpoint_max_age: Option<Cow<'raw, str>>,
+
+	/// Whether to exclude Borg’s own cache and config directories if they lie within `root`.
+	pub exclude_borg_dirs: bool,
+
+	/// How to record the paths of files in the archive.
+	pub path_style: PathStyle,
+
+	/// Whether `root` must be the root of a mounted filesystem, to avoid backing up an empty
+	/// mountpoint.
+	pub require_mount: bool,
+
+	/// The minimum number of files that `root` must contain to be backed up, if any.
+	pub min_files: Option<u64>,
+
+	/// The minimum total size in bytes of the files that `root` must contain to be backed up, if
+	/// any.
+	pub min_bytes: Option<u64>,
+
+	/// The file to create or update after the archive is backed up successfully, if any.
+	pub success_marker: Option<Cow<'raw, Path>>,
+
+	/// Whether to update `success_marker` even if backing up the archive produced warnings.
+	pub success_marker_on_warnings: bool,
+
+	/// The position of the archive in the order of backing up; lower numbers go first, and ties
+	/// are broken by name.
+	pub priority: i32,
+}
+
+impl Default for Archive<'_> {
+	fn default() -> Self {
+		Self {
+			enabled: true,
+			compression: Compression::default(),
+			repositories: Vec::new(),
+			backend: Backend::Borg,
+			root: Cow::Borrowed(Path::new("")),
+			btrfs_snapshot: false,
+			snapshot_options: SnapshotOptions::default(),
+			patterns: Vec::new(),
+			tags: Vec::new(),
+			comment: None,
+			verify_after: false,
+			list_changed: false,
+			files_cache: None,
+			timezone: None,
+			name_date_format: Cow::Borrowed(DEFAULT_NAME_DATE_FORMAT),
+			timestamp_source: TimestampSource::Run,
+			prune: None,
+			upload_buffer: None,
+			checkpoint_interval: None,
+			checkpoint_max_age: None,
+			exclude_borg_dirs: true,
+			path_style: PathStyle::Relative,
+			require_mount: false,
+			min_files: None,
+			min_bytes: None,
+			success_marker: None,
+			success_marker_on_warnings: false,
+			priority: 0,
+		}
+	}
+}
+
+impl Archive<'_> {
+	/// Returns the archive’s settings as a JSON object, using the same key names as the config
+	/// file.
+	pub fn to_json(&self) -> Value {
+		serde_json::json!({
+			"enabled": self.enabled,
+			"compression": self.compression.to_string(),
+			"repository": self.repositories,
+			"backend": self.backend.name(),
+			"root": self.root.to_string_lossy(),
+			"btrfs_snapshot": self.btrfs_snapshot,
+			"allow_writable_snapshot": self.snapshot_options.allow_writable,
+			"nested_subvolumes": self.snapshot_options.nested_subvolumes.name(),
+			"snapshot_delete_failure": self.snapshot_options.delete_failure.name(),
+			"snapshot_space_retries": self.snapshot_options.space_retries,
+			"snapshot_collision": self.snapshot_options.collision.name(),
+			"snapshot_dir": self.snapshot_options.snapshot_dir.as_deref().map(Path::to_string_lossy),
+			"patterns": self.patterns,
+			"tags": self.tags,
+			"comment": self.comment,
+			"verify_after": self.verify_after,
+			"list_changed": self.list_changed,
+			"files_cache": self.files_cache,
+			"timezone": self.timezone,
+			"name_date_format": self.name_date_format,
+			"timestamp_source": self.timestamp_source.name(),
+			"prune": self.prune.as_ref().map(Retention::to_json),
+			"upload_buffer": self.upload_buffer,
+			"checkpoint_interval": self.checkpoint_interval,
+			"checkpoint_max_age": self.checkpoint_max_age,
+			"exclude_borg_dirs": self.exclude_borg_dirs,
+			"path_style": self.path_style.name(),
+			"require_mount": self.require_mount,
+			"min_files": self.min_files,
+			"min_bytes": self.min_bytes,
+			"success_marker": self.success_marker.as_deref().map(Path::to_string_lossy),
+			"success_marker_on_warnings": self.success_marker_on_warnings,
+			"priority": self.priority,
+		})
+	}
+}
+
+/// Environment variables to set for Borg, by name.
+pub type BorgEnv<'raw> = BTreeMap<Cow<'raw, str>, Cow<'raw, str>>;
+
+/// The environment variables that Borgify sets for Borg itself, which `borg_env` must not set.
+const MANAGED_BORG_ENV: [&str; 3] = ["BORG_REPO", "BORG_PASSPHRASE_FD", "BORG_FILES_CACHE_SUFFIX"];
+
+/// Settings for one repository, which apply to every archive stored in it.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Repository<'raw> {
+	/// Whether Borgify checks for, asks for, and passes on the passphrase; if not, Borg is left to
+	/// obtain it itself, such as via `BORG_PASSCOMMAND`.
+	pub manage_passphrase: bool,
+
+	/// The name of an environment variable holding the passphrase, rather than asking for it.
+	pub passphrase_env: Option<Cow<'raw, str>>,
+
+	/// The path to a file holding one or more candidate passphrases, one per line, rather than
+	/// asking for it.
+	pub passphrase_file: Option<Cow<'raw, Path>>,
+
+	/// A name shared by repositories with the same passphrase, so that it is asked for only once.
+	pub passphrase_id: Option<Cow<'raw, str>>,
+
+	/// Whether to create the parent directory of a local repository if it does not exist.
+	pub create_parent: bool,
+
+	/// Whether to flush the filesystem holding a local repository to disk after each archive is
+	/// created in it.
+	pub sync_repo: bool,
+
+	/// The path of the Borg executable on the server of a remote repository, passed to Borg as
+	/// `--remote-path`.
+	pub remote_path: Option<Cow<'raw, str>>,
+
+	/// Additional environment variables to set for every Borg command using the repository.
+	pub borg_env: BorgEnv<'raw>,
+}
+
+impl Default for Repository<'_> {
+	fn default() -> Self {
+		Self {
+			manage_passphrase: true,
+			passphrase_env: None,
+			passphrase_file: None,
+			passphrase_id: None,
+			create_parent: false,
+			sync_repo: false,
+			remote_path: None,
+			borg_env: BorgEnv::new(),
+		}
+	}
+}
+
+impl Repository<'_> {
+	/// Returns the repository’s settings as a JSON object, using the same key names as the config
+	/// file.
+	pub fn to_json(&self) -> Value {
+		serde_json::json!({
+			"manage_passphrase": self.manage_passphrase,
+			"passphrase_env": self.passphrase_env,
+			"passphrase_file": self.passphrase_file.as_deref().map(Path::to_string_lossy),
+			"passphrase_id": self.passphrase_id,
+			"create_parent": self.create_parent,
+			"sync_repo": self.sync_repo,
+			"remote_path": self.remote_path,
+			"borg_env": self.borg_env,
+		})
+	}
 }
 
 /// The complete configuration.
@@ -32,8 +758,107 @@ pub struct Config<'raw> {
 	/// The requested archives.
 	pub archives: BTreeMap<Cow<'raw, str>, Archive<'raw>>,
 
+	/// The settings for repositories, keyed by URL; a repository not listed has default settings.
+	pub repositories: BTreeMap<Cow<'raw, str>, Repository<'raw>>,
+
 	/// The umask.
 	pub umask: u16,
+
+	/// The directory in which the programs Borgify runs create temporary files, if not the system
+	/// default.
+	pub work_dir: Option<Cow<'raw, Path>>,
+
+	/// The cgroup into which to move Borg when backing up, if any.
+	pub cgroup: Option<Cow<'raw, Path>>,
+
+	/// How to check the hosts of remote repositories before running Borg.
+	pub host_check: HostCheck,
+
+	/// Which Borg subcommand to run when checking repositories.
+	pub check_probe: CheckProbe,
+
+	/// What to do if the environment holds a passphrase for Borg.
+	pub inherited_passphrase: InheritedPassphrase,
+
+	/// The command, as a program and its arguments, to run once after backing up, if any.
+	pub on_complete: Option<Vec<Cow<'raw, str>>>,
+
+	/// The locations of any keys which were not recognized, such as `archives.foo.bar`.
+	pub unknown_keys: Vec<String>,
+}
+
+impl Config<'_> {
+	/// Returns the configuration as a JSON object, with its archives and repositories fully
+	/// resolved, using the same key names as the config file.
+	pub fn to_json(&self) -> Value {
+		let archives: Map<String, Value> = self
+			.archives
+			.iter()
+			.map(|(name, archive)| (name.clone().into_owned(), archive.to_json()))
+			.collect();
+		let repositories: Map<String, Value> = self
+			.repositories
+			.iter()
+			.map(|(url, repository)| (url.clone().into_owned(), repository.to_json()))
+			.collect();
+		serde_json::json!({
+			"umask": format!("0{:o}", self.umask),
+			"work_dir": self.work_dir.as_deref().map(Path::to_string_lossy),
+			"cgroup": self.cgroup.as_deref().map(Path::to_string_lossy),
+			"host_check": self.host_check.name(),
+			"check_probe": self.check_probe.name(),
+			"inherited_passphrase": self.inherited_passphrase.name(),
+			"on_complete": self.on_complete,
+			"repositories": repositories,
+			"archives": archives,
+		})
+	}
+
+	/// Returns the SHA256, in hex, of the configuration as returned by
+	/// [`to_json`](Self::to_json), to detect changes to the effective configuration between runs.
+	///
+	/// JSON objects are written with their keys sorted, so the hash does not depend on the order
+	/// of keys in the config file, nor on how it is split into included files.
+	pub fn hash(&self) -> String {
+		let hash = hmac_sha256::Hash::hash(self.to_json().to_string().as_bytes());
+		hash.iter().map(|i| format!("{i:02x}")).collect()
+	}
+
+	/// Resolves relative paths in the configuration against a base directory, normally the
+	/// directory containing the config file. Absolute paths are left untouched.
+	pub fn resolve_relative_paths(&mut self, base: &Path) {
+		for archive in self.archives.values_mut() {
+			if archive.root.is_relative() {
+				archive.root = Cow::Owned(base.join(&archive.root));
+			}
+		}
+		for archive in self.archives.values_mut() {
+			if let Some(dir) = &mut archive.snapshot_options.snapshot_dir {
+				if dir.is_relative() {
+					*dir = Cow::Owned(base.join(&dir));
+				}
+			}
+		}
+		for archive in self.archives.values_mut() {
+			if let Some(marker) = &mut archive.success_marker {
+				if marker.is_relative() {
+					*marker = Cow::Owned(base.join(&marker));
+				}
+			}
+		}
+		if let Some(work_dir) = &mut self.work_dir {
+			if work_dir.is_relative() {
+				*work_dir = Cow::Owned(base.join(&work_dir));
+			}
+		}
+		for repository in self.repositories.values_mut() {
+			if let Some(file) = &mut repository.passphrase_file {
+				if file.is_relative() {
+					*file = Cow::Owned(base.join(&file));
+				}
+			}
+		}
+	}
 }
 
 impl<'de> Deserialize<'de> for Config<'de> {
@@ -42,303 +867,2648 @@ impl<'de> Deserialize<'de> for Config<'de> {
 	}
 }
 
-/// The intermediate JSON-parsed form of the defaults section.
-#[derive(Deserialize, Default)]
-#[serde(deny_unknown_fields)]
-struct ParsedDefaults<'raw> {
-	/// The requested compression level.
-	#[serde(borrow, default)]
-	compression: Option<Cow<'raw, str>>,
+/// One repository URL or a list of them.
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+enum ParsedRepositories<'raw> {
+	/// A single repository URL.
+	One(#[serde(borrow)] Cow<'raw, str>),
 
-	/// The repository URL.
-	#[serde(borrow, default)]
-	repository: Option<Cow<'raw, str>>,
+	/// A list of repository URLs.
+	Many(#[serde(borrow)] Vec<Cow<'raw, str>>),
 }
 
-/// The intermediate JSON-parsed form of an archive.
-#[derive(Deserialize)]
-#[serde(deny_unknown_fields)]
-struct ParsedArchive<'raw> {
-	/// The requested compression level.
-	#[serde(borrow, default)]
-	compression: Option<Cow<'raw, str>>,
-
-	/// The repository URL.
-	#[serde(borrow, default)]
-	repository: Option<Cow<'raw, str>>,
+impl<'raw> ParsedRepositories<'raw> {
+	/// Converts a `ParsedRepositories` into a nonempty list of URLs.
+	fn finish<D: Deserializer<'raw>>(self) -> Result<Vec<Cow<'raw, str>>, D::Error> {
+		match self {
+			Self::One(url) => Ok(vec![url]),
+			Self::Many(urls) if urls.is_empty() => Err(D::Error::invalid_length(
+				0,
+				&"a repository URL or a nonempty list of them",
+			)),
+			Self::Many(urls) => Ok(urls),
+		}
+	}
+}
 
-	/// The path to the root directory of the files to add to the archive.
-	#[serde(borrow)]
-	root: Cow<'raw, Path>,
+/// The values of `SPEC` that Borg’s `obfuscate` compression mode accepts: relative random padding,
+/// absolute random padding, and Padmé padding.
+const OBFUSCATE_SPECS: [std::ops::RangeInclusive<u8>; 3] = [1..=6, 110..=123, 250..=250];
 
-	/// Whether to treat `root` as a Btrfs subvolume and actually create the archive from a
-	/// snapshot thereof.
-	#[serde(default)]
-	btrfs_snapshot: bool,
+/// The intermediate JSON-parsed form of a compression specification.
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+enum ParsedCompression<'raw> {
+	/// A specification written directly in Borg’s syntax.
+	Raw(#[serde(borrow)] Cow<'raw, str>),
 
-	/// The list of pattern strings.
-	#[serde(borrow, default)]
-	patterns: Vec<Cow<'raw, str>>,
+	/// A structured specification.
+	Structured(ParsedStructuredCompression),
+}
+
+/// The intermediate JSON-parsed form of a structured compression specification.
+#[derive(Clone, Deserialize)]
+struct ParsedStructuredCompression {
+	/// The compression algorithm.
+	algorithm: CompressionAlgorithm,
+
+	/// The compression level.
+	#[serde(default)]
+	level: Option<u8>,
+
+	/// Whether to enable Borg’s automatic compressibility detection.
+	#[serde(default)]
+	auto: bool,
+
+	/// The `SPEC` of Borg’s size obfuscation.
+	#[serde(default)]
+	obfuscate: Option<u8>,
+
+	/// Any unrecognized keys.
+	#[serde(flatten)]
+	unknown: UnknownKeys,
+}
+
+impl<'raw> ParsedCompression<'raw> {
+	/// Returns the unrecognized keys of a structured specification.
+	fn unknown(&self) -> Option<&UnknownKeys> {
+		match self {
+			Self::Raw(_) => None,
+			Self::Structured(i) => Some(&i.unknown),
+		}
+	}
+
+	/// Converts a `ParsedCompression` into a [`Compression`](Compression), checking that the
+	/// combination of settings is valid.
+	fn finish<D: Deserializer<'raw>>(self) -> Result<Compression<'raw>, D::Error> {
+		match self {
+			Self::Raw(spec) => Ok(Compression::Raw(spec)),
+			Self::Structured(ParsedStructuredCompression {
+				algorithm,
+				level,
+				auto,
+				obfuscate,
+				unknown: _,
+			}) => {
+				match (level, algorithm.levels()) {
+					(None, _) => (),
+					(Some(level), Some(range)) if range.contains(&level) => (),
+					(Some(level), Some(range)) => {
+						return Err(D::Error::invalid_value(
+							serde::de::Unexpected::Unsigned(level.into()),
+							&format!(
+								"a {} level from {} to {}",
+								algorithm.name(),
+								range.start(),
+								range.end()
+							)
+							.as_str(),
+						))
+					}
+					(Some(_), None) => {
+						return Err(D::Error::custom(format!(
+							"compression algorithm {} does not take a level",
+							algorithm.name()
+						)))
+					}
+				}
+				if auto && algorithm == CompressionAlgorithm::None {
+					return Err(D::Error::custom(
+						"automatic compression cannot be combined with algorithm none",
+					));
+				}
+				if let Some(spec) = obfuscate {
+					if !OBFUSCATE_SPECS.iter().any(|i| i.contains(&spec)) {
+						return Err(D::Error::invalid_value(
+							serde::de::Unexpected::Unsigned(spec.into()),
+							&"an obfuscation SPEC from 1 to 6, from 110 to 123, or 250",
+						));
+					}
+				}
+				Ok(Compression::Structured {
+					algorithm,
+					level,
+					auto,
+					obfuscate,
+				})
+			}
+		}
+	}
+}
+
+/// Any keys in a JSON object which do not correspond to known fields.
+///
+/// Rather than rejecting unknown keys during parsing (with `deny_unknown_fields`), they are
+/// collected and reported afterwards, so that the caller can choose whether they are fatal.
+type UnknownKeys = BTreeMap<String, Value>;
+
+/// Appends the locations of unknown keys, under a given prefix, to a list.
+fn push_unknown_keys(prefix: &str, unknown: &UnknownKeys, out: &mut Vec<String>) {
+	out.extend(unknown.keys().map(|key| format!("{prefix}{key}")));
+}
+
+/// The intermediate JSON-parsed form of retention rules.
+#[derive(Clone, Deserialize)]
+struct ParsedRetention<'raw> {
+	/// The rules.
+	#[serde(borrow, flatten)]
+	rules: Retention<'raw>,
+
+	/// Any unrecognized keys.
+	#[serde(flatten)]
+	unknown: UnknownKeys,
+}
+
+/// The intermediate JSON-parsed form of the defaults section.
+#[derive(Deserialize, Default)]
+struct ParsedDefaults<'raw> {
+	/// The requested compression.
+	#[serde(borrow, default)]
+	compression: Option<ParsedCompression<'raw>>,
+
+	/// The repository URL or URLs.
+	#[serde(borrow, default)]
+	repository: Option<ParsedRepositories<'raw>>,
+
+	/// The archive comment.
+	#[serde(borrow, default)]
+	comment: Option<Cow<'raw, str>>,
+
+	/// The files cache mode.
+	#[serde(borrow, default)]
+	files_cache: Option<Cow<'raw, str>>,
+
+	/// The time zone for archive names.
+	#[serde(borrow, default)]
+	timezone: Option<Cow<'raw, str>>,
+
+	/// The format of the timestamp in archive names.
+	#[serde(borrow, default)]
+	name_date_format: Option<Cow<'raw, str>>,
+
+	/// The directory under which to place snapshots.
+	#[serde(borrow, default)]
+	snapshot_dir: Option<Cow<'raw, Path>>,
+
+	/// Which time Borg records as the creation time of archives.
+	#[serde(default)]
+	timestamp_source: Option<TimestampSource>,
+
+	/// Which Borg archives to keep when pruning.
+	#[serde(borrow, default)]
+	prune: Option<ParsedRetention<'raw>>,
+
+	/// The size in MiB of Borg’s upload buffer.
+	#[serde(default)]
+	upload_buffer: Option<NonZeroU32>,
+
+	/// The interval in seconds between Borg’s checkpoints.
+	#[serde(default)]
+	checkpoint_interval: Option<NonZeroU32>,
+
+	/// The age beyond which checkpoint archives are deleted.
+	#[serde(borrow, default)]
+	checkpoint_max_age: Option<Cow<'raw, str>>,
+
+	/// The path of the Borg executable on the servers of remote repositories.
+	#[serde(borrow, default)]
+	remote_path: Option<Cow<'raw, str>>,
+
+	/// Additional environment variables to set for Borg for every repository.
+	#[serde(borrow, default)]
+	borg_env: BorgEnv<'raw>,
+
+	/// The list of pattern strings appended to every archive’s own patterns.
+	#[serde(borrow, default)]
+	patterns: Vec<Cow<'raw, str>>,
+
+	/// Any unrecognized keys.
+	#[serde(flatten)]
+	unknown: UnknownKeys,
+}
+
+/// The intermediate JSON-parsed form of a repository’s settings.
+#[derive(Deserialize)]
+struct ParsedRepository<'raw> {
+	/// Whether Borgify manages the passphrase.
+	#[serde(default = "default_manage_passphrase")]
+	manage_passphrase: bool,
+
+	/// The name of the environment variable holding the passphrase.
+	#[serde(borrow, default)]
+	passphrase_env: Option<Cow<'raw, str>>,
+
+	/// The path to the file holding the candidate passphrases.
+	#[serde(borrow, default)]
+	passphrase_file: Option<Cow<'raw, Path>>,
+
+	/// The name shared by repositories with the same passphrase.
+	#[serde(borrow, default)]
+	passphrase_id: Option<Cow<'raw, str>>,
+
+	/// Whether to create the parent directory of a local repository.
+	#[serde(default)]
+	create_parent: bool,
+
+	/// Whether to flush the filesystem holding a local repository to disk after backing up.
+	#[serde(default)]
+	sync_repo: bool,
+
+	/// The path of the Borg executable on the server of a remote repository.
+	#[serde(borrow, default)]
+	remote_path: Option<Cow<'raw, str>>,
+
+	/// Additional environment variables to set for Borg.
+	#[serde(borrow, default)]
+	borg_env: BorgEnv<'raw>,
+
+	/// Any unrecognized keys.
+	#[serde(flatten)]
+	unknown: UnknownKeys,
+}
+
+impl<'raw> ParsedRepository<'raw> {
+	/// Converts a `ParsedRepository` for the repository at `url` into a
+	/// [`Repository`](Repository).
+	fn finish<D: Deserializer<'raw>>(self, url: &str) -> Result<Repository<'raw>, D::Error> {
+		if let Some(name) = &self.passphrase_env {
+			if name.is_empty() || name.contains(['=', '\0']) {
+				return Err(D::Error::invalid_value(
+					serde::de::Unexpected::Str(name),
+					&"an environment variable name",
+				));
+			}
+		}
+		if !self.manage_passphrase && self.passphrase_env.is_some() {
+			return Err(D::Error::custom(
+				"passphrase_env cannot be combined with manage_passphrase false",
+			));
+		}
+		if !self.manage_passphrase && self.passphrase_file.is_some() {
+			return Err(D::Error::custom(
+				"passphrase_file cannot be combined with manage_passphrase false",
+			));
+		}
+		if self.passphrase_env.is_some() && self.passphrase_file.is_some() {
+			return Err(D::Error::custom(
+				"passphrase_env and passphrase_file cannot be combined",
+			));
+		}
+		if let Some(id) = &self.passphrase_id {
+			if id.is_empty() {
+				return Err(D::Error::invalid_value(
+					serde::de::Unexpected::Str(id),
+					&"a nonempty passphrase ID",
+				));
+			}
+			if !self.manage_passphrase
+				|| self.passphrase_env.is_some()
+				|| self.passphrase_file.is_some()
+			{
+				return Err(D::Error::custom(
+					"passphrase_id applies only to passphrases asked for at the terminal, so it cannot be combined with passphrase_env, passphrase_file, or manage_passphrase false",
+				));
+			}
+		}
+		if self.create_parent && local_path(url).is_none() {
+			return Err(D::Error::custom(format!(
+				"create_parent cannot be used with remote repository {url}"
+			)));
+		}
+		if self.sync_repo && local_path(url).is_none() {
+			return Err(D::Error::custom(format!(
+				"sync_repo cannot be used with remote repository {url}"
+			)));
+		}
+		if let Some(path) = &self.remote_path {
+			check_remote_path::<D>(path)?;
+			if local_path(url).is_some() {
+				return Err(D::Error::custom(format!(
+					"remote_path cannot be used with local repository {url}"
+				)));
+			}
+		}
+		check_borg_env::<D>(&self.borg_env)?;
+		Ok(Repository {
+			manage_passphrase: self.manage_passphrase,
+			passphrase_env: self.passphrase_env,
+			passphrase_file: self.passphrase_file,
+			passphrase_id: self.passphrase_id,
+			create_parent: self.create_parent,
+			sync_repo: self.sync_repo,
+			remote_path: self.remote_path,
+			borg_env: self.borg_env,
+		})
+	}
+}
+
+/// Checks that a `remote_path` setting names an executable.
+fn check_remote_path<'raw, D: Deserializer<'raw>>(path: &str) -> Result<(), D::Error> {
+	if path.is_empty() {
+		Err(D::Error::invalid_value(
+			serde::de::Unexpected::Str(path),
+			&"a nonempty path to the Borg executable",
+		))
+	} else {
+		Ok(())
+	}
+}
+
+/// Checks that a `borg_env` setting holds valid environment variables, none of which Borgify sets
+/// itself or which would override the passphrase it passes.
+fn check_borg_env<'raw, D: Deserializer<'raw>>(env: &BorgEnv<'_>) -> Result<(), D::Error> {
+	for (name, value) in env {
+		if name.is_empty() || name.contains(['=', '\0']) {
+			return Err(D::Error::invalid_value(
+				serde::de::Unexpected::Str(name),
+				&"an environment variable name",
+			));
+		}
+		if MANAGED_BORG_ENV.contains(&&**name) {
+			return Err(D::Error::custom(format!(
+				"borg_env cannot set {name}, which Borgify sets itself"
+			)));
+		}
+		if super::passphrase::OVERRIDING_ENV.contains(&&**name) {
+			return Err(D::Error::custom(format!(
+				"borg_env cannot set {name}, which would override the passphrase Borgify passes to Borg; use passphrase_env or passphrase_file instead, or set manage_passphrase to false and set {name} in Borgify’s environment"
+			)));
+		}
+		if value.contains('\0') {
+			return Err(D::Error::invalid_value(
+				serde::de::Unexpected::Str(value),
+				&"an environment variable value without NUL characters",
+			));
+		}
+	}
+	Ok(())
+}
+
+/// Returns whether Borgify manages a repository’s passphrase if this is not written in the config
+/// file.
+const fn default_manage_passphrase() -> bool {
+	true
+}
+
+/// The intermediate JSON-parsed form of an archive.
+#[derive(Deserialize)]
+struct ParsedArchive<'raw> {
+	/// Whether the archive is backed up.
+	#[serde(default = "default_enabled")]
+	enabled: bool,
+
+	/// The requested compression.
+	#[serde(borrow, default)]
+	compression: Option<ParsedCompression<'raw>>,
+
+	/// The repository URL or URLs.
+	#[serde(borrow, default)]
+	repository: Option<ParsedRepositories<'raw>>,
+
+	/// The backup program that writes to the repositories.
+	#[serde(default)]
+	backend: Backend,
+
+	/// The path to the root directory of the files to add to the archive.
+	#[serde(borrow)]
+	root: Cow<'raw, Path>,
+
+	/// Whether to treat `root` as a Btrfs subvolume and actually create the archive from a
+	/// snapshot thereof.
+	#[serde(default)]
+	btrfs_snapshot: bool,
+
+	/// Whether to fall back to a writable snapshot if a read-only one cannot be created.
+	#[serde(default)]
+	allow_writable_snapshot: bool,
+
+	/// What to do about subvolumes nested within the archive root.
+	#[serde(default)]
+	nested_subvolumes: NestedSubvolumes,
+
+	/// What to do if the snapshot cannot be deleted.
+	#[serde(default)]
+	snapshot_delete_failure: SnapshotDeleteFailure,
+
+	/// How many times to retry creating the snapshot if the filesystem is out of space.
+	#[serde(default = "default_snapshot_space_retries")]
+	snapshot_space_retries: u32,
+
+	/// What to do if the name generated for the snapshot is already taken.
+	#[serde(default)]
+	snapshot_collision: SnapshotCollision,
+
+	/// The directory under which to place snapshots.
+	#[serde(borrow, default)]
+	snapshot_dir: Option<Cow<'raw, Path>>,
+
+	/// The list of pattern strings.
+	#[serde(borrow, default)]
+	patterns: Vec<Cow<'raw, str>>,
+
+	/// The names of the pattern groups whose patterns follow the archive’s own.
+	#[serde(borrow, default)]
+	use_groups: Vec<Cow<'raw, str>>,
+
+	/// The tags used to select the archive from the command line.
+	#[serde(borrow, default)]
+	tags: Vec<Cow<'raw, str>>,
+
+	/// The archive comment.
+	#[serde(borrow, default)]
+	comment: Option<Cow<'raw, str>>,
+
+	/// Whether to verify each Borg archive after creating it.
+	#[serde(default)]
+	verify_after: bool,
+
+	/// Whether to list the paths changed since the previous Borg archive.
+	#[serde(default)]
+	list_changed: bool,
+
+	/// The files cache mode.
+	#[serde(borrow, default)]
+	files_cache: Option<Cow<'raw, str>>,
+
+	/// The time zone for archive names.
+	#[serde(borrow, default)]
+	timezone: Option<Cow<'raw, str>>,
+
+	/// The format of the timestamp in archive names.
+	#[serde(borrow, default)]
+	name_date_format: Option<Cow<'raw, str>>,
+
+	/// Which time Borg records as the creation time of the archive.
+	#[serde(default)]
+	timestamp_source: Option<TimestampSource>,
+
+	/// Which Borg archives to keep when pruning.
+	#[serde(borrow, default)]
+	prune: Option<ParsedRetention<'raw>>,
+
+	/// The size in MiB of Borg’s upload buffer.
+	#[serde(default)]
+	upload_buffer: Option<NonZeroU32>,
+
+	/// The interval in seconds between Borg’s checkpoints.
+	#[serde(default)]
+	checkpoint_interval: Option<NonZeroU32>,
+
+	/// The age beyond which checkpoint archives are deleted.
+	#[serde(borrow, default)]
+	checkpoint_max_age: Option<Cow<'raw, str>>,
+
+	/// Whether to exclude Borg’s own cache and config directories if they lie within `root`.
+	#[serde(default = "default_exclude_borg_dirs")]
+	exclude_borg_dirs: bool,
+
+	/// How to record the paths of files in the archive.
+	#[serde(default)]
+	path_style: PathStyle,
+
+	/// Whether `root` must be the root of a mounted filesystem.
+	#[serde(default)]
+	require_mount: bool,
+
+	/// The minimum number of files.
+	#[serde(default)]
+	min_files: Option<u64>,
+
+	/// The minimum total size of files.
+	#[serde(default)]
+	min_bytes: Option<u64>,
+
+	/// The file marking a successful backup.
+	#[serde(borrow, default)]
+	success_marker: Option<Cow<'raw, Path>>,
+
+	/// Whether to update the success marker despite warnings.
+	#[serde(default)]
+	success_marker_on_warnings: bool,
+
+	/// The position in the order of backing up.
+	#[serde(default)]
+	priority: i32,
+
+	/// Any unrecognized keys.
+	#[serde(flatten)]
+	unknown: UnknownKeys,
+}
+
+/// Checks that a string is a Borg pattern specification of a kind that Borgify accepts.
+fn check_pattern<'raw, D: Deserializer<'raw>>(pattern: &str) -> Result<(), D::Error> {
+	match pattern.chars().next() {
+		Some('+') | Some('-') | Some('!') | Some('P') => Ok(()),
+		_ => Err(D::Error::invalid_value(
+			serde::de::Unexpected::Str(pattern),
+			&"Borg pattern specification starting with +, -, !, or P",
+		)),
+	}
+}
+
+/// A path pattern in an archive’s list of Borg patterns, with its style resolved.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct PathPattern<'p> {
+	/// The pattern’s type: `+` to include, `-` to exclude, or `!` to exclude without recursing.
+	pub kind: char,
+
+	/// The pattern style, such as `sh` or `pp`, either given explicitly or set by a preceding `P`
+	/// pattern.
+	pub style: &'p str,
+
+	/// The pattern text, without the type or style prefix.
+	pub path: &'p str,
+}
+
+/// Returns the path patterns in a list of Borg patterns, in order, resolving each one’s style as
+/// Borg would: from its own prefix if it has one, otherwise from the last preceding `P` pattern,
+/// or `sh` if there is none.
+pub fn path_patterns<'p>(patterns: &'p [Cow<'_, str>]) -> Vec<PathPattern<'p>> {
+	const STYLES: [&str; 5] = ["fm", "sh", "re", "pp", "pf"];
+	let mut default_style = "sh";
+	let mut ret = Vec::new();
+	for pattern in patterns {
+		let Some(kind) = pattern.chars().next() else {
+			continue;
+		};
+		let rest = pattern[kind.len_utf8()..].trim_start();
+		match kind {
+			'P' => {
+				default_style = STYLES
+					.into_iter()
+					.find(|&i| i == rest)
+					.unwrap_or(default_style);
+			}
+			'+' | '-' | '!' => {
+				let (style, path) = match rest.split_once(':') {
+					Some((style, path)) if STYLES.contains(&style) => (style, path),
+					_ => (default_style, rest),
+				};
+				ret.push(PathPattern { kind, style, path });
+			}
+			_ => (),
+		}
+	}
+	ret
+}
+
+/// Checks that no pattern, other than a regular expression, has a `..` component.
+///
+/// Borg is only ever given the archive root to back up, and matches patterns against the
+/// normalized paths it finds beneath it, so such a pattern could never match anything; it most
+/// likely reflects a mistaken belief that patterns can reach outside the root, such as into a
+/// sibling snapshot.
+fn check_parent_components<'raw, D: Deserializer<'raw>>(
+	patterns: &[Cow<'raw, str>],
+) -> Result<(), D::Error> {
+	for pattern in path_patterns(patterns) {
+		if pattern.style != "re" && pattern.path.split('/').any(|i| i == "..") {
+			return Err(D::Error::custom(format!(
+				"pattern path {:?} contains a .. component, but patterns can only match paths within the archive root",
+				pattern.path
+			)));
+		}
+	}
+	Ok(())
 }
 
 impl<'raw> ParsedArchive<'raw> {
-	/// Converts a `ParsedArchive` into an [`Archive`](Archive).
+	/// Converts a `ParsedArchive` into an [`Archive`](Archive), given the archive’s name.
 	fn finish<D: Deserializer<'raw>>(
 		self,
+		name: &str,
 		defaults: &ParsedDefaults<'raw>,
+		pattern_groups: &BTreeMap<Cow<'raw, str>, Vec<Cow<'raw, str>>>,
 	) -> Result<Archive<'raw>, D::Error> {
-		for pattern in &self.patterns {
-			match pattern.chars().next() {
-				Some('+') | Some('-') | Some('!') | Some('P') => (),
-				_ => {
-					return Err(D::Error::invalid_value(
-						serde::de::Unexpected::Str(pattern),
-						&"Borg pattern specification starting with +, -, !, or P",
-					))
-				}
+		// Borg uses the first pattern that matches, so the archive’s own patterns go first, then
+		// those of its groups, and finally the defaults, letting the more specific ones override
+		// the more general ones.
+		let mut patterns = self.patterns;
+		for name in &self.use_groups {
+			let group = pattern_groups
+				.get(name)
+				.ok_or_else(|| D::Error::custom(format!("unknown pattern group {name}")))?;
+			patterns.extend(group.iter().cloned());
+		}
+		patterns.extend(defaults.patterns.iter().cloned());
+		for pattern in &patterns {
+			check_pattern::<D>(pattern)?;
+		}
+		check_parent_components::<D>(&patterns)?;
+		if self.tags.iter().any(|tag| tag.is_empty()) {
+			return Err(D::Error::invalid_value(
+				serde::de::Unexpected::Str(""),
+				&"a nonempty tag",
+			));
+		}
+		let comment = self.comment.or_else(|| defaults.comment.clone());
+		if let Some(comment) = &comment {
+			if comment.contains(['\n', '\r']) {
+				return Err(D::Error::invalid_value(
+					serde::de::Unexpected::Str(comment),
+					&"a single-line comment",
+				));
+			}
+		}
+		let files_cache = self.files_cache.or_else(|| defaults.files_cache.clone());
+		if let Some(files_cache) = &files_cache {
+			if !valid_files_cache(files_cache) {
+				return Err(D::Error::invalid_value(
+					serde::de::Unexpected::Str(files_cache),
+					&"a Borg files cache mode, such as ctime,size,inode",
+				));
+			}
+		}
+		let prune = self
+			.prune
+			.or_else(|| defaults.prune.clone())
+			.map(|i| i.rules);
+		if let Some(prune) = &prune {
+			prune.check::<D>()?;
+		}
+		let timezone = self.timezone.or_else(|| defaults.timezone.clone());
+		if let Some(timezone) = &timezone {
+			if !super::timezone::exists(timezone) {
+				return Err(D::Error::invalid_value(
+					serde::de::Unexpected::Str(timezone),
+					&"an IANA time zone name in the system’s time zone database, such as Europe/Berlin",
+				));
+			}
+		}
+		let name_date_format = self
+			.name_date_format
+			.or_else(|| defaults.name_date_format.clone())
+			.unwrap_or(Cow::Borrowed(DEFAULT_NAME_DATE_FORMAT));
+		if !valid_name_date_format(&name_date_format) {
+			return Err(D::Error::invalid_value(
+				serde::de::Unexpected::Str(&name_date_format),
+				&"a strftime format whose output is nonempty and contains no slashes or control characters",
+			));
+		}
+		let borg_name_len = name.len() + 1 + max_name_date_len(&name_date_format);
+		if borg_name_len > MAX_BORG_ARCHIVE_NAME_LEN {
+			return Err(D::Error::custom(format!(
+				"invalid archive name {name:?}: with a hyphen and a timestamp in its name_date_format appended, it can be {borg_name_len} bytes long, but Borg allows at most {MAX_BORG_ARCHIVE_NAME_LEN}"
+			)));
+		}
+		if let Some(marker) = &self.success_marker {
+			if marker.file_name().is_none() {
+				return Err(D::Error::invalid_value(
+					serde::de::Unexpected::Str(&marker.to_string_lossy()),
+					&"a path to a file",
+				));
 			}
 		}
+		if self.success_marker_on_warnings && self.success_marker.is_none() {
+			return Err(D::Error::custom(
+				"success_marker_on_warnings requires success_marker",
+			));
+		}
+		if self.btrfs_snapshot && self.path_style == PathStyle::Absolute {
+			return Err(D::Error::custom(
+				"path_style absolute cannot be combined with btrfs_snapshot, because the snapshot is not at the archive root’s path",
+			));
+		}
 		let compression = self
 			.compression
 			.or_else(|| defaults.compression.clone())
-			.ok_or_else(|| D::Error::missing_field("compression"))?;
-		let repository = self
+			.ok_or_else(|| D::Error::missing_field("compression"))?
+			.finish::<D>()?;
+		let repositories = self
 			.repository
 			.or_else(|| defaults.repository.clone())
-			.ok_or_else(|| D::Error::missing_field("repository"))?;
+			.ok_or_else(|| D::Error::missing_field("repository"))?
+			.finish::<D>()?;
+		let upload_buffer = self.upload_buffer.or(defaults.upload_buffer);
+		let checkpoint_interval = self.checkpoint_interval.or(defaults.checkpoint_interval);
+		let checkpoint_max_age = self
+			.checkpoint_max_age
+			.or_else(|| defaults.checkpoint_max_age.clone());
+		if let Some(age) = &checkpoint_max_age {
+			check_interval::<D>(age)?;
+		}
+		if self.backend == Backend::Restic {
+			let borg_only = [
+				("patterns", !patterns.is_empty()),
+				("comment", comment.is_some()),
+				("verify_after", self.verify_after),
+				("list_changed", self.list_changed),
+				("files_cache", files_cache.is_some()),
+				("prune", prune.is_some()),
+				("upload_buffer", upload_buffer.is_some()),
+				("checkpoint_interval", checkpoint_interval.is_some()),
+				("checkpoint_max_age", checkpoint_max_age.is_some()),
+			];
+			if let Some((key, _)) = borg_only.into_iter().find(|&(_, set)| set) {
+				return Err(D::Error::custom(format!(
+					"{key} is not supported by the restic backend"
+				)));
+			}
+			// The archive name is passed as a tag, and restic splits tags at commas.
+			if name.contains(',') {
+				return Err(D::Error::custom(format!(
+					"archive name {name} contains a comma, which the restic backend cannot use, since restic would split it into several tags"
+				)));
+			}
+		}
 		Ok(Archive {
+			enabled: self.enabled,
 			compression,
-			repository,
+			repositories,
+			backend: self.backend,
 			root: self.root,
 			btrfs_snapshot: self.btrfs_snapshot,
-			patterns: self.patterns,
+			snapshot_options: SnapshotOptions {
+				allow_writable: self.allow_writable_snapshot,
+				nested_subvolumes: self.nested_subvolumes,
+				delete_failure: self.snapshot_delete_failure,
+				space_retries: self.snapshot_space_retries,
+				collision: self.snapshot_collision,
+				snapshot_dir: self.snapshot_dir.or_else(|| defaults.snapshot_dir.clone()),
+			},
+			patterns,
+			tags: self.tags,
+			comment,
+			verify_after: self.verify_after,
+			list_changed: self.list_changed,
+			files_cache,
+			timezone,
+			name_date_format,
+			timestamp_source: self
+				.timestamp_source
+				.or(defaults.timestamp_source)
+				.unwrap_or_default(),
+			prune,
+			upload_buffer,
+			checkpoint_interval,
+			checkpoint_max_age,
+			exclude_borg_dirs: self.exclude_borg_dirs,
+			path_style: self.path_style,
+			require_mount: self.require_mount,
+			min_files: self.min_files,
+			min_bytes: self.min_bytes,
+			success_marker: self.success_marker,
+			success_marker_on_warnings: self.success_marker_on_warnings,
+			priority: self.priority,
+		})
+	}
+}
+
+/// Returns whether an archive is enabled if this is not written in the config file.
+const fn default_enabled() -> bool {
+	true
+}
+
+/// The maximum length, in bytes, of a Borg archive name.
+const MAX_BORG_ARCHIVE_NAME_LEN: usize = 255;
+
+/// Checks whether a key in the `archives` section can be used as the start of a Borg archive name,
+/// returning the reason if not.
+///
+/// Borg refuses names containing slashes or `::`, which separates the repository from the archive
+/// name, and expands placeholders written in braces, and names with leading or trailing whitespace
+/// are confusing in listings, as are control characters. Catching these when the config is loaded
+/// avoids a failure after a snapshot has already been taken. Whether the name is short enough
+/// depends on the archive’s `name_date_format`, so that is checked separately.
+fn check_archive_name(name: &str) -> Result<(), &'static str> {
+	if name.is_empty() {
+		Err("it is empty")
+	} else if name.contains('/') {
+		Err("Borg does not allow slashes in archive names")
+	} else if name.contains("::") {
+		Err("Borg does not allow :: in archive names")
+	} else if name.contains(['{', '}']) {
+		Err("Borg would expand braces in archive names as placeholders")
+	} else if name.contains(char::is_control) {
+		Err("it contains a control character")
+	} else if name.starts_with(char::is_whitespace) || name.ends_with(char::is_whitespace) {
+		Err("it starts or ends with whitespace")
+	} else {
+		Ok(())
+	}
+}
+
+/// The format of the timestamp in archive names if none is configured.
+pub const DEFAULT_NAME_DATE_FORMAT: &str = "%FT%T";
+
+/// Checks whether a string is a valid format for the timestamp in archive names: a `strftime`
+/// format that `chrono` understands, producing nonempty output that Borg accepts in an archive
+/// name, which must not contain slashes, `::`, or braces, which Borg would expand as placeholders,
+/// nor, for the sake of readable listings, control characters, and which can be parsed back, so
+/// that the archive’s Borg archives can be told apart from others.
+fn valid_name_date_format(format: &str) -> bool {
+	use std::fmt::Write as _;
+	let items: Vec<Item<'_>> = StrftimeItems::new(format).collect();
+	if items.contains(&Item::Error) {
+		return false;
+	}
+	// Format a sample time, with a nonzero offset, to see what the output can contain.
+	let sample = FixedOffset::east_opt(3600)
+		.and_then(|offset| offset.with_ymd_and_hms(2001, 2, 3, 4, 5, 6).single())
+		.expect("sample time is valid");
+	let mut output = String::new();
+	write!(output, "{}", sample.format_with_items(items.iter())).is_ok()
+		&& !output.is_empty()
+		&& !output.contains(|c: char| matches!(c, '/' | '{' | '}') || c.is_control())
+		&& !output.contains("::")
+		&& chrono::format::parse(&mut chrono::format::Parsed::new(), &output, items.iter()).is_ok()
+}
+
+/// Returns the greatest length, in bytes, of a timestamp in a valid archive name timestamp format.
+///
+/// The length can vary with the time, for example with the names of months and weekdays, so the
+/// format is tried on a day in each month, covering every weekday, at a time with two-digit
+/// fields.
+fn max_name_date_len(format: &str) -> usize {
+	let offset = FixedOffset::east_opt(3600).expect("offset is valid");
+	(1..=12)
+		.flat_map(|month| (22..=28).map(move |day| (month, day)))
+		.filter_map(|(month, day)| {
+			offset
+				.with_ymd_and_hms(2001, month, day, 23, 59, 59)
+				.single()
 		})
+		.map(|time| time.format(format).to_string().len())
+		.max()
+		.unwrap_or(0)
+}
+
+/// Checks whether a string is a valid Borg files cache mode: either `disabled`, or a
+/// comma-separated list of distinct elements from `ctime`, `mtime`, `size`, `inode`, and
+/// `rechunk`, not including both `ctime` and `mtime`.
+fn valid_files_cache(mode: &str) -> bool {
+	if mode == "disabled" {
+		return true;
 	}
+	let elements: Vec<&str> = mode.split(',').collect();
+	elements.iter().enumerate().all(|(i, element)| {
+		["ctime", "mtime", "size", "inode", "rechunk"].contains(element)
+			&& !elements[..i].contains(element)
+	}) && !(elements.contains(&"ctime") && elements.contains(&"mtime"))
+}
+
+/// How many times to retry creating a snapshot if the filesystem is out of space, if this is not
+/// written in the config file.
+pub const DEFAULT_SNAPSHOT_SPACE_RETRIES: u32 = 3;
+
+/// Returns how many times to retry creating a snapshot if the filesystem is out of space, if this
+/// is not written in the config file.
+const fn default_snapshot_space_retries() -> u32 {
+	DEFAULT_SNAPSHOT_SPACE_RETRIES
+}
+
+/// Returns whether to exclude Borg’s own directories if this is not written in the config file.
+const fn default_exclude_borg_dirs() -> bool {
+	true
+}
+
+/// The newest config file format version that this program understands.
+pub const VERSION: u64 = 1;
+
+/// Returns the config file format version assumed if one is not written in the config file.
+const fn default_version() -> u64 {
+	1
+}
+
+/// Decodes a config file format version, which must be at least 1 and at most
+/// [`VERSION`](VERSION).
+fn deserialize_version<'de, D: Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+	let version = u64::deserialize(d)?;
+	if (1..=VERSION).contains(&version) {
+		Ok(version)
+	} else {
+		Err(D::Error::invalid_value(
+			serde::de::Unexpected::Unsigned(version),
+			&"a supported config format version",
+		))
+	}
+}
+
+/// Returns the default umask, used if one is not written in the config file.
+const fn default_umask() -> u16 {
+	0o0077
+}
+
+/// Decodes a umask from an octal string, optionally prefixed with `0o`.
+///
+/// An integer is rejected with an error suggesting the string form: a JSON number cannot have a
+/// leading zero, so whether `77` means `0o077` or decimal 77 would be a guess.
+fn deserialize_umask<'de, D: Deserializer<'de>>(d: D) -> Result<u16, D::Error> {
+	use serde::de::{Unexpected, Visitor};
+	use std::fmt::Formatter;
+	struct Vis;
+	impl Visitor<'_> for Vis {
+		type Value = u16;
+
+		fn expecting(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+			write!(f, "an octal umask string ≤\"0777\"")
+		}
+
+		fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<u16, E> {
+			let digits = value.strip_prefix("0o").unwrap_or(value);
+			let parsed = u16::from_str_radix(digits, 8)
+				.map_err(|_| E::invalid_value(Unexpected::Str(value), &self))?;
+			if !digits.starts_with('+') && parsed <= 0o777 {
+				Ok(parsed)
+			} else {
+				Err(E::invalid_value(Unexpected::Str(value), &self))
+			}
+		}
+
+		fn visit_u64<E: serde::de::Error>(self, value: u64) -> Result<u16, E> {
+			Err(E::custom(format_args!(
+				"umask must be written as an octal string, such as \"{value:04}\", not as the number {value}"
+			)))
+		}
+
+		fn visit_i64<E: serde::de::Error>(self, value: i64) -> Result<u16, E> {
+			Err(E::invalid_type(Unexpected::Signed(value), &self))
+		}
+	}
+	d.deserialize_any(Vis)
+}
+
+/// The intermediate JSON-parsed form of the config file.
+#[derive(Deserialize)]
+struct ParsedConfig<'raw> {
+	/// The config file format version.
+	#[serde(default = "default_version", deserialize_with = "deserialize_version")]
+	#[allow(dead_code)] // Only validated; there is only one version so far.
+	version: u64,
+
+	/// The defaults section.
+	#[serde(borrow, default)]
+	defaults: ParsedDefaults<'raw>,
+
+	/// The archives section.
+	#[serde(borrow)]
+	archives: BTreeMap<Cow<'raw, str>, ParsedArchive<'raw>>,
+
+	/// The repositories section.
+	#[serde(borrow, default)]
+	repositories: BTreeMap<Cow<'raw, str>, ParsedRepository<'raw>>,
+
+	/// The pattern groups section.
+	#[serde(borrow, default)]
+	pattern_groups: BTreeMap<Cow<'raw, str>, Vec<Cow<'raw, str>>>,
+
+	/// The umask option.
+	#[serde(default = "default_umask", deserialize_with = "deserialize_umask")]
+	umask: u16,
+
+	/// The work directory option.
+	#[serde(borrow, default)]
+	work_dir: Option<Cow<'raw, Path>>,
+
+	/// The cgroup option.
+	#[serde(borrow, default)]
+	cgroup: Option<Cow<'raw, Path>>,
+
+	/// The host check option.
+	#[serde(default)]
+	host_check: HostCheck,
+
+	/// The check probe option.
+	#[serde(default)]
+	check_probe: CheckProbe,
+
+	/// The inherited passphrase option.
+	#[serde(default)]
+	inherited_passphrase: InheritedPassphrase,
+
+	/// The completion command option.
+	#[serde(borrow, default)]
+	on_complete: Option<Vec<Cow<'raw, str>>>,
+
+	/// Any unrecognized keys.
+	#[serde(flatten)]
+	unknown: UnknownKeys,
+}
+
+/// Returns the keys of the defaults section and of the top level of the config file, as named by
+/// the fields of [`ParsedDefaults`](ParsedDefaults) and [`ParsedConfig`](ParsedConfig), for
+/// checking the schema against them.
+///
+/// The structs are destructured exhaustively, so a new field fails to compile until it is listed.
+#[cfg(test)]
+pub fn parsed_keys() -> (Vec<&'static str>, Vec<&'static str>) {
+	macro_rules! keys {
+		($name:ident { $($field:ident),* $(,)? }) => {{
+			let _ = |parsed: $name<'_>| {
+				let $name { $($field: _,)* unknown: _ } = parsed;
+			};
+			vec![$(stringify!($field)),*]
+		}};
+	}
+	let defaults = keys!(ParsedDefaults {
+		compression,
+		repository,
+		comment,
+		files_cache,
+		timezone,
+		name_date_format,
+		snapshot_dir,
+		timestamp_source,
+		prune,
+		upload_buffer,
+		checkpoint_interval,
+		checkpoint_max_age,
+		remote_path,
+		borg_env,
+		patterns,
+	});
+	let config = keys!(ParsedConfig {
+		version,
+		defaults,
+		archives,
+		repositories,
+		pattern_groups,
+		umask,
+		work_dir,
+		cgroup,
+		host_check,
+		check_probe,
+		inherited_passphrase,
+		on_complete,
+	});
+	(defaults, config)
+}
+
+impl<'raw> ParsedConfig<'raw> {
+	/// Converts a `ParsedConfig` into a [`Config`](Config).
+	fn finish<D: Deserializer<'raw>>(self) -> Result<Config<'raw>, D::Error> {
+		let mut unknown_keys = Vec::new();
+		push_unknown_keys("", &self.unknown, &mut unknown_keys);
+		push_unknown_keys("defaults.", &self.defaults.unknown, &mut unknown_keys);
+		if let Some(unknown) = self.defaults.compression.as_ref().and_then(|i| i.unknown()) {
+			push_unknown_keys("defaults.compression.", unknown, &mut unknown_keys);
+		}
+		if let Some(prune) = &self.defaults.prune {
+			push_unknown_keys("defaults.prune.", &prune.unknown, &mut unknown_keys);
+		}
+		for (name, archive) in &self.archives {
+			push_unknown_keys(
+				&format!("archives.{name}."),
+				&archive.unknown,
+				&mut unknown_keys,
+			);
+			if let Some(unknown) = archive.compression.as_ref().and_then(|i| i.unknown()) {
+				push_unknown_keys(
+					&format!("archives.{name}.compression."),
+					unknown,
+					&mut unknown_keys,
+				);
+			}
+			if let Some(prune) = &archive.prune {
+				push_unknown_keys(
+					&format!("archives.{name}.prune."),
+					&prune.unknown,
+					&mut unknown_keys,
+				);
+			}
+		}
+		for (url, repository) in &self.repositories {
+			push_unknown_keys(
+				&format!("repositories.{url}."),
+				&repository.unknown,
+				&mut unknown_keys,
+			);
+		}
+		if self.on_complete.as_ref().is_some_and(Vec::is_empty) {
+			return Err(D::Error::invalid_length(
+				0,
+				&"a command with at least a program name",
+			));
+		}
+		if let Some(cgroup) = &self.cgroup {
+			if !cgroup.is_absolute() {
+				return Err(D::Error::invalid_value(
+					serde::de::Unexpected::Str(&cgroup.to_string_lossy()),
+					&"an absolute path to a cgroup directory",
+				));
+			}
+		}
+		// Check every group, including any that no archive uses.
+		for pattern in self.pattern_groups.values().flatten() {
+			check_pattern::<D>(pattern)?;
+		}
+		let archives = self
+			.archives
+			.into_iter()
+			.map(|(name, archive)| {
+				if let Err(reason) = check_archive_name(&name) {
+					return Err(D::Error::custom(format!(
+						"invalid archive name {name:?}: {reason}"
+					)));
+				}
+				let archive = ParsedArchive::finish::<D>(
+					archive,
+					&name,
+					&self.defaults,
+					&self.pattern_groups,
+				)?;
+				Ok((name, archive))
+			})
+			.collect::<Result<BTreeMap<Cow<'raw, str>, Archive<'raw>>, D::Error>>()?;
+		// A repository belongs to one backup program, so every archive using it must agree.
+		let mut backends: BTreeMap<&str, Backend> = BTreeMap::new();
+		for archive in archives.values() {
+			for url in &archive.repositories {
+				if *backends.entry(url).or_insert(archive.backend) != archive.backend {
+					return Err(D::Error::custom(format!(
+						"repository {url} is used by archives with different backends"
+					)));
+				}
+			}
+		}
+		let mut repositories = self
+			.repositories
+			.into_iter()
+			.map(|(url, repository)| {
+				let repository = repository.finish::<D>(&url)?;
+				Ok((url, repository))
+			})
+			.collect::<Result<BTreeMap<Cow<'raw, str>, Repository<'raw>>, D::Error>>()?;
+		// The default environment variables apply to every repository, including those with no
+		// settings of their own, except where a repository sets the same variable itself.
+		check_borg_env::<D>(&self.defaults.borg_env)?;
+		if !self.defaults.borg_env.is_empty() {
+			for url in archives.values().flat_map(|i| &i.repositories) {
+				let repository = repositories.entry(url.clone()).or_default();
+				for (name, value) in &self.defaults.borg_env {
+					repository
+						.borg_env
+						.entry(name.clone())
+						.or_insert_with(|| value.clone());
+				}
+			}
+		}
+		// The default remote path applies to every remote repository that does not set its own,
+		// including those with no settings of their own.
+		if let Some(path) = &self.defaults.remote_path {
+			check_remote_path::<D>(path)?;
+			for url in archives.values().flat_map(|i| &i.repositories) {
+				if local_path(url).is_none() {
+					let repository = repositories.entry(url.clone()).or_default();
+					if repository.remote_path.is_none() {
+						repository.remote_path = Some(path.clone());
+					}
+				}
+			}
+		}
+		Ok(Config {
+			archives,
+			repositories,
+			umask: self.umask,
+			work_dir: self.work_dir,
+			cgroup: self.cgroup,
+			host_check: self.host_check,
+			check_probe: self.check_probe,
+			inherited_passphrase: self.inherited_passphrase,
+			on_complete: self.on_complete,
+			unknown_keys,
+		})
+	}
+}
+
+/// The errors that can occur loading a config file and the files it includes.
+#[derive(Debug)]
+pub enum LoadError {
+	/// A file could not be read.
+	Read(PathBuf, std::io::Error),
+
+	/// A file is not valid JSON, or its `include` key is not a list of paths.
+	Parse(PathBuf, serde_json::Error),
+
+	/// A file does not contain a JSON object at the top level.
+	NotObject(PathBuf),
+
+	/// A file includes itself, directly or indirectly.
+	Cycle(PathBuf),
+
+	/// The config requires a newer format version than this program understands.
+	Version(PathBuf, u64),
+}
+
+impl Display for LoadError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+		match self {
+			Self::Read(p, _) => write!(f, "error reading {}", p.display()),
+			Self::Parse(p, _) => write!(f, "error parsing {}", p.display()),
+			Self::NotObject(p) => write!(f, "{} does not contain a JSON object", p.display()),
+			Self::Cycle(p) => write!(f, "{} includes itself", p.display()),
+			Self::Version(p, version) => write!(
+				f,
+				"{} requires config format version {version}, but this borgify only supports up to version {VERSION}; upgrade borgify",
+				p.display()
+			),
+		}
+	}
+}
+
+impl std::error::Error for LoadError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			Self::Read(_, e) => Some(e),
+			Self::Parse(_, e) => Some(e),
+			Self::NotObject(_) | Self::Cycle(_) | Self::Version(..) => None,
+		}
+	}
+}
+
+impl Structured for LoadError {
+	fn variant(&self) -> &'static str {
+		match self {
+			Self::Read(..) => "Read",
+			Self::Parse(..) => "Parse",
+			Self::NotObject(_) => "NotObject",
+			Self::Cycle(_) => "Cycle",
+			Self::Version(..) => "Version",
+		}
+	}
+
+	fn fields(&self) -> Map<String, Value> {
+		match self {
+			Self::Read(p, _) | Self::Parse(p, _) | Self::NotObject(p) | Self::Cycle(p) => {
+				fields([("path", p.to_string_lossy().into())])
+			}
+			Self::Version(p, version) => fields([
+				("path", p.to_string_lossy().into()),
+				("version", (*version).into()),
+				("supported", VERSION.into()),
+			]),
+		}
+	}
+}
+
+/// Loads a config file as a JSON object, merging in the files it includes.
+///
+/// The files named in a file’s `include` list, with relative paths resolved against the including
+/// file’s directory, are loaded first, in order, and then the including file itself is merged on
+/// top. When merging, keys in the `defaults` section and entries in the `archives`,
+/// `repositories`, and `pattern_groups` sections are overridden individually, while any other
+/// top-level key is replaced outright. Before merging, relative paths in each file are resolved
+/// against that file’s own directory, as described in [`resolve_file_paths`].
+///
+/// If the merged config declares a `version` newer than [`VERSION`](VERSION), an error is returned
+/// before any other validation, so that the user is told to upgrade rather than about whichever
+/// new key happens to be unrecognized.
+///
+/// Along with the config, the paths of all the files loaded are returned, `path` first and then
+/// the included files in the order they were loaded.
+pub fn load(path: &Path) -> Result<(Value, Vec<PathBuf>), LoadError> {
+	let mut files = Vec::new();
+	let config = load_recursive(path, &mut Vec::new(), &mut files)?;
+	match config.get("version").and_then(Value::as_u64) {
+		Some(version) if version > VERSION => Err(LoadError::Version(path.to_owned(), version)),
+		_ => Ok((Value::Object(config), files)),
+	}
+}
+
+/// Loads a config file as a JSON object, merging in the files it includes.
+///
+/// `stack` holds the canonical paths of the files currently being loaded, outermost first, for
+/// detecting cycles. The path of each file loaded is appended to `files`.
+fn load_recursive(
+	path: &Path,
+	stack: &mut Vec<PathBuf>,
+	files: &mut Vec<PathBuf>,
+) -> Result<Map<String, Value>, LoadError> {
+	let canonical = std::fs::canonicalize(path).map_err(|e| LoadError::Read(path.to_owned(), e))?;
+	if stack.contains(&canonical) {
+		return Err(LoadError::Cycle(path.to_owned()));
+	}
+	files.push(path.to_owned());
+	let raw = std::fs::read(path).map_err(|e| LoadError::Read(path.to_owned(), e))?;
+	let Value::Object(mut object) =
+		serde_json::from_slice(&raw).map_err(|e| LoadError::Parse(path.to_owned(), e))?
+	else {
+		return Err(LoadError::NotObject(path.to_owned()));
+	};
+	let includes: Vec<PathBuf> = match object.remove("include") {
+		Some(includes) => {
+			serde_json::from_value(includes).map_err(|e| LoadError::Parse(path.to_owned(), e))?
+		}
+		None => Vec::new(),
+	};
+	let dir = path.parent().unwrap_or(Path::new("."));
+	resolve_file_paths(&mut object, dir);
+
+	let mut merged = Map::new();
+	stack.push(canonical);
+	for include in includes {
+		let include = dir.join(include);
+		merge(&mut merged, load_recursive(&include, stack, files)?);
+	}
+	stack.pop();
+	merge(&mut merged, object);
+	Ok(merged)
+}
+
+/// Resolves the relative paths written in one config file, before it is merged with others,
+/// against the directory containing it, so that a file means the same whichever file includes it.
+///
+/// The paths are the `work_dir` key, `snapshot_dir` in the `defaults` section, `root`,
+/// `snapshot_dir`, and `success_marker` in each archive, and `passphrase_file` in each repository.
+/// Values that are not strings are left for deserialization to reject, and a path is left
+/// relative if the directory’s path is not valid Unicode, to be resolved later against the
+/// directory of the top-level file.
+fn resolve_file_paths(object: &mut Map<String, Value>, dir: &Path) {
+	let resolve = |value: Option<&mut Value>| {
+		if let Some(Value::String(path)) = value {
+			if Path::new(path.as_str()).is_relative() {
+				if let Some(resolved) = dir.join(path.as_str()).to_str() {
+					*path = resolved.to_owned();
+				}
+			}
+		}
+	};
+	/// Returns the objects in a section keyed by name, such as `archives`.
+	fn sections<'a>(
+		object: &'a mut Map<String, Value>,
+		key: &str,
+	) -> Vec<&'a mut Map<String, Value>> {
+		match object.get_mut(key) {
+			Some(Value::Object(section)) => section
+				.values_mut()
+				.filter_map(Value::as_object_mut)
+				.collect(),
+			_ => Vec::new(),
+		}
+	}
+	resolve(object.get_mut("work_dir"));
+	if let Some(Value::Object(defaults)) = object.get_mut("defaults") {
+		resolve(defaults.get_mut("snapshot_dir"));
+	}
+	for archive in sections(object, "archives") {
+		for key in ["root", "snapshot_dir", "success_marker"] {
+			resolve(archive.get_mut(key));
+		}
+	}
+	for repository in sections(object, "repositories") {
+		resolve(repository.get_mut("passphrase_file"));
+	}
+}
+
+/// Merges one config object on top of another.
+fn merge(base: &mut Map<String, Value>, overlay: Map<String, Value>) {
+	for (key, mut value) in overlay {
+		if ["defaults", "archives", "repositories", "pattern_groups"].contains(&key.as_str()) {
+			if let (Some(Value::Object(base_section)), Value::Object(section)) =
+				(base.get_mut(&key), &mut value)
+			{
+				base_section.append(section);
+				continue;
+			}
+		}
+		base.insert(key, value);
+	}
+}
+
+/// Tests deserializing a basic config file with no archives.
+#[test]
+fn test_deserialize_empty() {
+	const INPUT: &[u8] = br#"{"archives": {}}"#;
+	assert_eq!(
+		serde_json::from_slice::<Config>(INPUT).unwrap(),
+		Config {
+			archives: BTreeMap::new(),
+			repositories: BTreeMap::new(),
+			umask: default_umask(),
+			work_dir: None,
+			cgroup: None,
+			host_check: HostCheck::Off,
+			check_probe: CheckProbe::Info,
+			inherited_passphrase: InheritedPassphrase::Unset,
+			on_complete: None,
+			unknown_keys: Vec::new(),
+		}
+	);
+}
+
+/// Tests deserializing a config file with two complete archive specifications.
+#[test]
+fn test_deserialize_two_archives() {
+	const INPUT: &[u8] = br#"
+		{
+			"archives": {
+				"foo": {
+					"compression": "lzma",
+					"repository": "/path/to/foo/repo",
+					"root": "/path/to/foo/archive/root",
+					"btrfs_snapshot": false
+				},
+				"bar": {
+					"compression": "lzma",
+					"repository": "/path/to/bar/repo",
+					"root": "/path/to/bar/archive/root",
+					"btrfs_snapshot": true,
+					"patterns": [
+						"+pattern1"
+					]
+				}
+			}
+		}"#;
+	assert_eq!(
+		serde_json::from_slice::<Config>(INPUT).unwrap(),
+		Config {
+			archives: [
+				(
+					Cow::Borrowed("foo"),
+					Archive {
+						compression: Compression::Raw(Cow::Borrowed("lzma")),
+						repositories: vec![Cow::Borrowed("/path/to/foo/repo")],
+						root: Cow::Borrowed(Path::new("/path/to/foo/archive/root")),
+						btrfs_snapshot: false,
+						patterns: Vec::new(),
+						..Archive::default()
+					}
+				),
+				(
+					Cow::Borrowed("bar"),
+					Archive {
+						compression: Compression::Raw(Cow::Borrowed("lzma")),
+						repositories: vec![Cow::Borrowed("/path/to/bar/repo")],
+						root: Cow::Borrowed(Path::new("/path/to/bar/archive/root")),
+						btrfs_snapshot: true,
+						patterns: vec![Cow::Borrowed("+pattern1")],
+						..Archive::default()
+					}
+				),
+			]
+			.into_iter()
+			.collect(),
+			repositories: BTreeMap::new(),
+			umask: default_umask(),
+			work_dir: None,
+			cgroup: None,
+			host_check: HostCheck::Off,
+			check_probe: CheckProbe::Info,
+			inherited_passphrase: InheritedPassphrase::Unset,
+			on_complete: None,
+			unknown_keys: Vec::new(),
+		}
+	);
+}
+
+/// Tests deserializing a config file with a complete archive specification, an incomplete archive
+/// specification, and a defaults section that completes the incomplete specification.
+#[test]
+fn test_deserialize_partial_and_complete() {
+	const INPUT: &[u8] = br#"
+		{
+			"defaults": {
+				"compression": "lz4",
+				"repository": "/path/to/default/repo"
+			},
+			"archives": {
+				"foo": {
+					"root": "/path/to/foo/archive/root",
+					"btrfs_snapshot": false
+				},
+				"bar": {
+					"compression": "lzma",
+					"repository": "/path/to/bar/repo",
+					"root": "/path/to/bar/archive/root",
+					"btrfs_snapshot": true,
+					"patterns": [
+						"+pattern1"
+					]
+				}
+			}
+		}"#;
+	assert_eq!(
+		serde_json::from_slice::<Config>(INPUT).unwrap(),
+		Config {
+			archives: [
+				(
+					Cow::Borrowed("foo"),
+					Archive {
+						compression: Compression::Raw(Cow::Borrowed("lz4")),
+						repositories: vec![Cow::Borrowed("/path/to/default/repo")],
+						root: Cow::Borrowed(Path::new("/path/to/foo/archive/root")),
+						btrfs_snapshot: false,
+						patterns: Vec::new(),
+						..Archive::default()
+					}
+				),
+				(
+					Cow::Borrowed("bar"),
+					Archive {
+						compression: Compression::Raw(Cow::Borrowed("lzma")),
+						repositories: vec![Cow::Borrowed("/path/to/bar/repo")],
+						root: Cow::Borrowed(Path::new("/path/to/bar/archive/root")),
+						btrfs_snapshot: true,
+						patterns: vec![Cow::Borrowed("+pattern1")],
+						..Archive::default()
+					}
+				),
+			]
+			.into_iter()
+			.collect(),
+			repositories: BTreeMap::new(),
+			umask: default_umask(),
+			work_dir: None,
+			cgroup: None,
+			host_check: HostCheck::Off,
+			check_probe: CheckProbe::Info,
+			inherited_passphrase: InheritedPassphrase::Unset,
+			on_complete: None,
+			unknown_keys: Vec::new(),
+		}
+	);
+}
+
+/// Tests deserializing a partial archive where the missing information is not provided in the
+/// defaults section.
+///
+/// This should fail because information is completely missing.
+#[test]
+fn test_deserialize_missing_info() {
+	const INPUT: &[u8] = br#"
+		{
+			"defaults": {
+				"compression": "lz4",
+			},
+			"archives": {
+				"foo": {
+					"root": "/path/to/foo/archive/root",
+					"btrfs_snapshot": false
+				}
+			}
+		}"#;
+	assert!(serde_json::from_slice::<Config>(INPUT).is_err());
+}
+
+/// Tests deserializing an archive with an illegal pattern entry.
+#[test]
+fn test_deserialize_bad_pattern() {
+	const INPUT: &[u8] = br#"
+		{
+			"archives": {
+				"foo": {
+					"compression": "lzma",
+					"repository": "/path/to/foo/repo",
+					"root": "/path/to/foo/archive/root",
+					"btrfs_snapshot": false,
+					"patterns": [
+						"X mypattern"
+					]
+				}
+			}
+		}"#;
+	assert!(serde_json::from_slice::<Config>(INPUT).is_err());
+}
+
+/// Tests deserializing an archive which is written to multiple repositories, with a list of
+/// repositories in the defaults section overridden by a single repository in another archive.
+#[test]
+fn test_deserialize_multiple_repositories() {
+	const INPUT: &[u8] = br#"
+		{
+			"defaults": {
+				"compression": "lz4",
+				"repository": ["/path/to/local/repo", "ssh://host/path/to/remote/repo"]
+			},
+			"archives": {
+				"foo": {
+					"root": "/path/to/foo/archive/root"
+				},
+				"bar": {
+					"repository": "/path/to/bar/repo",
+					"root": "/path/to/bar/archive/root"
+				}
+			}
+		}"#;
+	let config = serde_json::from_slice::<Config>(INPUT).unwrap();
+	assert_eq!(
+		config.archives["foo"].repositories,
+		["/path/to/local/repo", "ssh://host/path/to/remote/repo"]
+	);
+	assert_eq!(config.archives["bar"].repositories, ["/path/to/bar/repo"]);
+}
+
+/// Tests deserializing an archive with an empty list of repositories.
+#[test]
+fn test_deserialize_no_repositories() {
+	const INPUT: &[u8] = br#"
+		{
+			"archives": {
+				"foo": {
+					"compression": "lz4",
+					"repository": [],
+					"root": "/path/to/foo/archive/root"
+				}
+			}
+		}"#;
+	assert!(serde_json::from_slice::<Config>(INPUT).is_err());
+}
+
+/// Tests deserializing an archive which allows falling back to a writable snapshot.
+#[test]
+fn test_deserialize_allow_writable_snapshot() {
+	const INPUT: &[u8] = br#"
+		{
+			"archives": {
+				"foo": {
+					"compression": "lz4",
+					"repository": "/path/to/foo/repo",
+					"root": "/path/to/foo/archive/root",
+					"btrfs_snapshot": true,
+					"allow_writable_snapshot": true,
+					"nested_subvolumes": "exclude",
+					"snapshot_delete_failure": "warn",
+					"snapshot_space_retries": 0,
+					"snapshot_collision": "delete"
+				}
+			}
+		}"#;
+	let config = serde_json::from_slice::<Config>(INPUT).unwrap();
+	assert_eq!(
+		config.archives["foo"].snapshot_options,
+		SnapshotOptions {
+			allow_writable: true,
+			nested_subvolumes: NestedSubvolumes::Exclude,
+			delete_failure: SnapshotDeleteFailure::Warn,
+			space_retries: 0,
+			collision: SnapshotCollision::Delete,
+			snapshot_dir: None,
+		}
+	);
+}
+
+/// Tests deserializing success markers, which must name a file.
+#[test]
+fn test_deserialize_success_marker() {
+	const INPUT: &[u8] = br#"
+		{
+			"archives": {
+				"foo": {
+					"compression": "lz4",
+					"repository": "/path/to/repo",
+					"root": "/path/to/root",
+					"success_marker": "/run/borgify/foo.done",
+					"success_marker_on_warnings": true
+				}
+			}
+		}"#;
+	let config = serde_json::from_slice::<Config>(INPUT).unwrap();
+	assert_eq!(
+		config.archives["foo"].success_marker.as_deref(),
+		Some(Path::new("/run/borgify/foo.done"))
+	);
+	assert!(config.archives["foo"].success_marker_on_warnings);
+	const DIRECTORY: &[u8] = br#"{"archives": {"foo": {"compression": "lz4", "repository": "/r", "root": "/", "success_marker": "/run/.."}}}"#;
+	assert!(serde_json::from_slice::<Config>(DIRECTORY).is_err());
+	const ON_WARNINGS_ONLY: &[u8] = br#"{"archives": {"foo": {"compression": "lz4", "repository": "/r", "root": "/", "success_marker_on_warnings": true}}}"#;
+	assert!(serde_json::from_slice::<Config>(ON_WARNINGS_ONLY).is_err());
+}
+
+/// Tests deserializing archives backed up with restic, which cannot use Borg-only settings or
+/// share a repository with Borg archives.
+#[test]
+fn test_deserialize_backend() {
+	const INPUT: &[u8] = br#"
+		{
+			"archives": {
+				"foo": {
+					"compression": "lz4",
+					"repository": "/path/to/borg",
+					"root": "/path/to/root"
+				},
+				"bar": {
+					"compression": "lz4",
+					"repository": "sftp:host:/path/to/restic",
+					"root": "/path/to/root",
+					"backend": "restic"
+				}
+			}
+		}"#;
+	let config = serde_json::from_slice::<Config>(INPUT).unwrap();
+	assert_eq!(config.archives["foo"].backend, Backend::Borg);
+	assert_eq!(config.archives["bar"].backend, Backend::Restic);
+	const PATTERNS: &[u8] = br#"{"archives": {"foo": {"compression": "lz4", "repository": "/r", "root": "/", "backend": "restic", "patterns": ["- foo"]}}}"#;
+	let e = serde_json::from_slice::<Config>(PATTERNS).unwrap_err();
+	assert!(e
+		.to_string()
+		.contains("patterns is not supported by the restic backend"));
+	const PRUNE: &[u8] = br#"{"archives": {"foo": {"compression": "lz4", "repository": "/r", "root": "/", "backend": "restic", "prune": {"keep_daily": 7}}}}"#;
+	assert!(serde_json::from_slice::<Config>(PRUNE).is_err());
+	const COMMA: &[u8] = br#"{"archives": {"foo,bar": {"compression": "lz4", "repository": "/r", "root": "/", "backend": "restic"}}}"#;
+	assert!(serde_json::from_slice::<Config>(COMMA).is_err());
+	const BORG_COMMA: &[u8] =
+		br#"{"archives": {"foo,bar": {"compression": "lz4", "repository": "/r", "root": "/"}}}"#;
+	assert!(serde_json::from_slice::<Config>(BORG_COMMA).is_ok());
+	const SHARED: &[u8] = br#"{"archives": {"foo": {"compression": "lz4", "repository": "/r", "root": "/"}, "bar": {"compression": "lz4", "repository": "/r", "root": "/", "backend": "restic"}}}"#;
+	let e = serde_json::from_slice::<Config>(SHARED).unwrap_err();
+	assert!(e
+		.to_string()
+		.contains("repository /r is used by archives with different backends"));
+}
+
+/// Tests resolving relative archive roots and work directory against the config file’s
+/// directory.
+#[test]
+fn test_resolve_relative_paths() {
+	const INPUT: &[u8] = br#"
+		{
+			"defaults": {
+				"compression": "lz4",
+				"repository": "/path/to/repo",
+				"snapshot_dir": "snapshots"
+			},
+			"archives": {
+				"absolute": {
+					"root": "/path/to/absolute/root",
+					"snapshot_dir": "/snapshots"
+				},
+				"relative": {
+					"root": "relative/root"
+				}
+			},
+			"repositories": {
+				"/path/to/repo": {"passphrase_file": "secret"}
+			},
+			"work_dir": "work"
+		}"#;
+	let mut config = serde_json::from_slice::<Config>(INPUT).unwrap();
+	config.resolve_relative_paths(Path::new("/etc/borgify"));
+	assert_eq!(
+		config.archives["absolute"].root,
+		Path::new("/path/to/absolute/root")
+	);
+	assert_eq!(
+		config.archives["relative"].root,
+		Path::new("/etc/borgify/relative/root")
+	);
+	assert_eq!(
+		config.archives["absolute"]
+			.snapshot_options
+			.snapshot_dir
+			.as_deref(),
+		Some(Path::new("/snapshots"))
+	);
+	assert_eq!(
+		config.archives["relative"]
+			.snapshot_options
+			.snapshot_dir
+			.as_deref(),
+		Some(Path::new("/etc/borgify/snapshots"))
+	);
+	assert_eq!(
+		config.work_dir.as_deref(),
+		Some(Path::new("/etc/borgify/work"))
+	);
+	assert_eq!(
+		config.repositories["/path/to/repo"]
+			.passphrase_file
+			.as_deref(),
+		Some(Path::new("/etc/borgify/secret"))
+	);
+}
+
+/// Tests merging an included config with the including config.
+#[test]
+fn test_merge() {
+	let mut base = serde_json::json!({
+		"defaults": {"compression": "lz4", "repository": "/path/to/repo"},
+		"archives": {"foo": {"root": "/foo"}, "bar": {"root": "/bar"}},
+		"umask": "0077",
+	});
+	let overlay = serde_json::json!({
+		"defaults": {"compression": "lzma"},
+		"archives": {"bar": {"root": "/new/bar"}, "baz": {"root": "/baz"}},
+		"umask": "0027",
+	});
+	let (Value::Object(base_object), Value::Object(overlay)) = (&mut base, overlay) else {
+		unreachable!()
+	};
+	merge(base_object, overlay);
+	assert_eq!(
+		base,
+		serde_json::json!({
+			"defaults": {"compression": "lzma", "repository": "/path/to/repo"},
+			"archives": {
+				"foo": {"root": "/foo"},
+				"bar": {"root": "/new/bar"},
+				"baz": {"root": "/baz"},
+			},
+			"umask": "0027",
+		})
+	);
+}
+
+/// Tests loading config files which include each other in a cycle.
+#[test]
+fn test_load_cycle() {
+	let dir = super::temp_dir::TempDir::new("cycle");
+	std::fs::write(dir.join("a.json"), br#"{"include": ["b.json"]}"#).unwrap();
+	std::fs::write(dir.join("b.json"), br#"{"include": ["a.json"]}"#).unwrap();
+	match load(&dir.join("a.json")) {
+		Err(LoadError::Cycle(_)) => (),
+		other => panic!("unexpected result {other:?}"),
+	}
+}
+
+/// Tests that relative paths in an included file are resolved against that file’s own directory,
+/// and those in the including file against its directory.
+#[test]
+fn test_load_relative_paths() {
+	let dir = super::temp_dir::TempDir::new("relative");
+	std::fs::create_dir(dir.join("shared")).unwrap();
+	std::fs::write(
+		dir.join("a.json"),
+		br#"{"include": ["shared/b.json"], "archives": {"a": {"root": "a"}}}"#,
+	)
+	.unwrap();
+	std::fs::write(
+		dir.join("shared/b.json"),
+		br#"{
+			"defaults": {"snapshot_dir": "snapshots"},
+			"archives": {"b": {"root": "b", "success_marker": "/run/b", "snapshot_dir": 1}},
+			"repositories": {"/r": {"passphrase_file": "secret"}},
+			"work_dir": "work"
+		}"#,
+	)
+	.unwrap();
+	let (config, _) = load(&dir.join("a.json")).unwrap();
+	let path = |pointer: &str| Path::new(config.pointer(pointer).unwrap().as_str().unwrap());
+	assert_eq!(path("/archives/a/root"), dir.join("a"));
+	assert_eq!(path("/archives/b/root"), dir.join("shared/b"));
+	assert_eq!(path("/archives/b/success_marker"), Path::new("/run/b"));
+	assert_eq!(config.pointer("/archives/b/snapshot_dir").unwrap(), 1);
+	assert_eq!(path("/defaults/snapshot_dir"), dir.join("shared/snapshots"));
+	assert_eq!(
+		path("/repositories/~1r/passphrase_file"),
+		dir.join("shared/secret")
+	);
+	assert_eq!(path("/work_dir"), dir.join("shared/work"));
+}
+
+/// Tests that loading a config file reports every file it includes.
+#[test]
+fn test_load_files() {
+	let dir = super::temp_dir::TempDir::new("files");
+	std::fs::write(dir.join("a.json"), br#"{"include": ["b.json"]}"#).unwrap();
+	std::fs::write(dir.join("b.json"), br#"{"include": ["c.json"]}"#).unwrap();
+	std::fs::write(dir.join("c.json"), br#"{"archives": {}}"#).unwrap();
+	let (_, files) = load(&dir.join("a.json")).unwrap();
+	assert_eq!(
+		files,
+		[dir.join("a.json"), dir.join("b.json"), dir.join("c.json")]
+	);
+}
+
+/// Tests deserializing a config file which declares the current format version.
+#[test]
+fn test_deserialize_version() {
+	const INPUT: &[u8] = br#"{"version": 1, "archives": {}}"#;
+	assert!(serde_json::from_slice::<Config>(INPUT).is_ok());
+	const ZERO: &[u8] = br#"{"version": 0, "archives": {}}"#;
+	assert!(serde_json::from_slice::<Config>(ZERO).is_err());
+}
+
+/// Tests loading a config file which declares a newer format version than is supported, along
+/// with a key that this version does not recognize.
+#[test]
+fn test_load_newer_version() {
+	let dir = super::temp_dir::TempDir::new("version");
+	let path = dir.join("borgify.json");
+	std::fs::write(
+		&path,
+		br#"{"version": 999, "archives": {}, "new_key": true}"#,
+	)
+	.unwrap();
+	match load(&path) {
+		Err(LoadError::Version(_, 999)) => (),
+		other => panic!("unexpected result {other:?}"),
+	}
+}
+
+/// Tests deserializing a config file with unrecognized keys at each level.
+#[test]
+fn test_deserialize_unknown_keys() {
+	const INPUT: &[u8] = br#"
+		{
+			"frobnicate": true,
+			"defaults": {
+				"compression": {"algorithm": "zstd", "levle": 3},
+				"repositroy": "/typo",
+				"prune": {"keep_daly": 7, "keep_weekly": 4}
+			},
+			"archives": {
+				"foo": {
+					"repository": "/path/to/foo/repo",
+					"root": "/path/to/foo/archive/root",
+					"btrfs_snaphsot": true,
+					"prune": {"keep_last": 3, "keep_fortnightly": 1}
+				}
+			}
+		}"#;
+	let config = serde_json::from_slice::<Config>(INPUT).unwrap();
+	assert_eq!(
+		config.unknown_keys,
+		[
+			"frobnicate",
+			"defaults.repositroy",
+			"defaults.compression.levle",
+			"defaults.prune.keep_daly",
+			"archives.foo.btrfs_snaphsot",
+			"archives.foo.prune.keep_fortnightly"
+		]
+	);
+	assert!(!config.archives["foo"].btrfs_snapshot);
+}
+
+/// Tests recognizing compression specifications that turn compression off.
+#[test]
+fn test_compression_is_none() {
+	assert!(Compression::Raw(Cow::Borrowed("none")).is_none());
+	assert!(!Compression::Raw(Cow::Borrowed("lz4")).is_none());
+	assert!(!Compression::Raw(Cow::Borrowed("auto,zstd,3")).is_none());
+	let structured = |algorithm| Compression::Structured {
+		algorithm,
+		level: None,
+		auto: false,
+		obfuscate: None,
+	};
+	assert!(structured(CompressionAlgorithm::None).is_none());
+	assert!(!structured(CompressionAlgorithm::Zstd).is_none());
+}
+
+/// Tests deserializing structured compression specifications and rendering them for Borg.
+#[test]
+fn test_deserialize_structured_compression() {
+	const INPUT: &[u8] = br#"
+		{
+			"defaults": {
+				"repository": "/path/to/repo"
+			},
+			"archives": {
+				"plain": {
+					"compression": "zstd,3",
+					"root": "/path/to/plain"
+				},
+				"level": {
+					"compression": {"algorithm": "zstd", "level": 10},
+					"root": "/path/to/level"
+				},
+				"auto": {
+					"compression": {"algorithm": "lzma", "level": 6, "auto": true},
+					"root": "/path/to/auto"
+				},
+				"nolevel": {
+					"compression": {"algorithm": "lz4"},
+					"root": "/path/to/nolevel"
+				},
+				"obfuscate": {
+					"compression": {"algorithm": "zstd", "level": 3, "auto": true, "obfuscate": 110},
+					"root": "/path/to/obfuscate"
+				}
+			}
+		}"#;
+	let config = serde_json::from_slice::<Config>(INPUT).unwrap();
+	assert_eq!(config.archives["plain"].compression.to_string(), "zstd,3");
+	assert_eq!(config.archives["level"].compression.to_string(), "zstd,10");
+	assert_eq!(
+		config.archives["auto"].compression.to_string(),
+		"auto,lzma,6"
+	);
+	assert_eq!(config.archives["nolevel"].compression.to_string(), "lz4");
+	assert_eq!(
+		config.archives["obfuscate"].compression.to_string(),
+		"obfuscate,110,auto,zstd,3"
+	);
+}
+
+/// Tests deserializing timestamp sources, with one taken from the defaults.
+#[test]
+fn test_deserialize_timestamp_source() {
+	const INPUT: &[u8] = br#"
+		{
+			"defaults": {"compression": "lz4", "repository": "/r", "timestamp_source": "snapshot"},
+			"archives": {
+				"foo": {"root": "/foo"},
+				"bar": {"root": "/bar", "timestamp_source": "start"}
+			}
+		}"#;
+	let config = serde_json::from_slice::<Config>(INPUT).unwrap();
+	assert_eq!(
+		config.archives["foo"].timestamp_source,
+		TimestampSource::Snapshot
+	);
+	assert_eq!(
+		config.archives["bar"].timestamp_source,
+		TimestampSource::Start
+	);
+	const INVALID: &[u8] = br#"{"archives": {"foo": {"root": "/foo", "compression": "lz4", "repository": "/r", "timestamp_source": "later"}}}"#;
+	assert!(serde_json::from_slice::<Config>(INVALID).is_err());
+}
+
+/// Tests deserializing upload buffer sizes, with one taken from the defaults, and rejecting zero.
+#[test]
+fn test_deserialize_upload_buffer() {
+	const INPUT: &[u8] = br#"
+		{
+			"defaults": {"compression": "lz4", "repository": "host:r", "upload_buffer": 100},
+			"archives": {
+				"foo": {"root": "/foo"},
+				"bar": {"root": "/bar", "upload_buffer": 25}
+			}
+		}"#;
+	let config = serde_json::from_slice::<Config>(INPUT).unwrap();
+	assert_eq!(config.archives["foo"].upload_buffer, NonZeroU32::new(100));
+	assert_eq!(config.archives["bar"].upload_buffer, NonZeroU32::new(25));
+	const ZERO: &[u8] = br#"{"archives": {"foo": {"root": "/foo", "compression": "lz4", "repository": "/r", "upload_buffer": 0}}}"#;
+	assert!(serde_json::from_slice::<Config>(ZERO).is_err());
+	const NEGATIVE: &[u8] = br#"{"archives": {"foo": {"root": "/foo", "compression": "lz4", "repository": "/r", "upload_buffer": -1}}}"#;
+	assert!(serde_json::from_slice::<Config>(NEGATIVE).is_err());
+}
+
+/// Tests deserializing checkpoint intervals, with one taken from the defaults, and rejecting zero.
+#[test]
+fn test_deserialize_checkpoint_interval() {
+	const INPUT: &[u8] = br#"
+		{
+			"defaults": {"compression": "lz4", "repository": "host:r", "checkpoint_interval": 600},
+			"archives": {
+				"foo": {"root": "/foo"},
+				"bar": {"root": "/bar", "checkpoint_interval": 300}
+			}
+		}"#;
+	let config = serde_json::from_slice::<Config>(INPUT).unwrap();
+	assert_eq!(
+		config.archives["foo"].checkpoint_interval,
+		NonZeroU32::new(600)
+	);
+	assert_eq!(
+		config.archives["bar"].checkpoint_interval,
+		NonZeroU32::new(300)
+	);
+	const ZERO: &[u8] = br#"{"archives": {"foo": {"root": "/foo", "compression": "lz4", "repository": "/r", "checkpoint_interval": 0}}}"#;
+	assert!(serde_json::from_slice::<Config>(ZERO).is_err());
+}
+
+/// Tests deserializing the maximum age of checkpoint archives, which must be an interval.
+#[test]
+fn test_deserialize_checkpoint_max_age() {
+	const INPUT: &[u8] = br#"
+		{
+			"defaults": {"compression": "lz4", "repository": "/r", "checkpoint_max_age": "7d"},
+			"archives": {
+				"foo": {"root": "/foo"},
+				"bar": {"root": "/bar", "checkpoint_max_age": "12H"}
+			}
+		}"#;
+	let config = serde_json::from_slice::<Config>(INPUT).unwrap();
+	assert_eq!(
+		config.archives["foo"].checkpoint_max_age.as_deref(),
+		Some("7d")
+	);
+	assert_eq!(
+		config.archives["bar"].checkpoint_max_age.as_deref(),
+		Some("12H")
+	);
+	const INVALID: &[u8] = br#"{"archives": {"foo": {"root": "/foo", "compression": "lz4", "repository": "/r", "checkpoint_max_age": "7"}}}"#;
+	assert!(serde_json::from_slice::<Config>(INVALID).is_err());
 }
 
-/// Returns the default umask, used if one is not written in the config file.
-const fn default_umask() -> u16 {
-	0o0077
+/// Tests converting intervals into durations.
+#[test]
+fn test_interval_duration() {
+	assert_eq!(interval_duration("36H"), chrono::Duration::try_hours(36));
+	assert_eq!(interval_duration("2d"), chrono::Duration::try_days(2));
+	assert_eq!(interval_duration("1w"), chrono::Duration::try_days(7));
+	assert_eq!(interval_duration("1m"), chrono::Duration::try_days(31));
+	assert_eq!(interval_duration("2y"), chrono::Duration::try_days(730));
+	assert_eq!(interval_duration("d"), None);
+	assert_eq!(interval_duration("2"), None);
+	assert_eq!(interval_duration("-2d"), None);
+	assert_eq!(interval_duration("2é"), None);
+	assert_eq!(interval_duration("99999999999999999999y"), None);
 }
 
-/// Decodes a umask from a three- or four-digit octal string.
-fn deserialize_umask<'de, D: Deserializer<'de>>(d: D) -> Result<u16, D::Error> {
-	use serde::de::{Unexpected, Visitor};
-	use std::fmt::Formatter;
-	struct Vis;
-	impl Visitor<'_> for Vis {
-		type Value = u16;
-
-		fn expecting(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
-			write!(f, "an octal umask ≤777")
-		}
+/// Tests deserializing a cgroup, which must be given as an absolute path.
+#[test]
+fn test_deserialize_cgroup() {
+	const INPUT: &[u8] = br#"{"archives": {}, "cgroup": "/sys/fs/cgroup/borg"}"#;
+	let config = serde_json::from_slice::<Config>(INPUT).unwrap();
+	assert_eq!(
+		config.cgroup.as_deref(),
+		Some(Path::new("/sys/fs/cgroup/borg"))
+	);
+	const RELATIVE: &[u8] = br#"{"archives": {}, "cgroup": "borg"}"#;
+	assert!(serde_json::from_slice::<Config>(RELATIVE).is_err());
+}
 
-		fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<u16, E> {
-			let parsed = u16::from_str_radix(value, 8)
-				.map_err(|_| E::invalid_value(Unexpected::Str(value), &self))?;
-			if parsed <= 0o777 {
-				Ok(parsed)
-			} else {
-				Err(E::invalid_value(Unexpected::Str(value), &self))
+/// Tests deserializing retention rules and turning them into `borg prune` options.
+#[test]
+fn test_deserialize_prune() {
+	const INPUT: &[u8] = br#"
+		{
+			"defaults": {"compression": "lz4", "repository": "/r", "prune": {"keep_daily": 7}},
+			"archives": {
+				"foo": {"root": "/foo"},
+				"bar": {"root": "/bar", "prune": {"keep_within": "2d", "keep_weekly": 4, "keep_monthly": 0}}
 			}
-		}
+		}"#;
+	let config = serde_json::from_slice::<Config>(INPUT).unwrap();
+	assert_eq!(
+		config.archives["foo"].prune.as_ref().unwrap().args(),
+		["--keep-daily=7"]
+	);
+	assert_eq!(
+		config.archives["bar"].prune.as_ref().unwrap().args(),
+		["--keep-within=2d", "--keep-weekly=4", "--keep-monthly=0"]
+	);
+	for prune in [
+		"{}",
+		r#"{"keep_within": "2"}"#,
+		r#"{"keep_within": "d"}"#,
+		r#"{"keep_within": "2x"}"#,
+		r#"{"keep_daily": -1}"#,
+		r#"{"keep_fortnightly": 1}"#,
+	] {
+		let input = format!(
+			r#"{{"archives": {{"foo": {{"prune": {prune}, "compression": "lz4", "repository": "/r", "root": "/x"}}}}}}"#
+		);
+		assert!(
+			serde_json::from_str::<Config>(&input).is_err(),
+			"{prune} should be rejected"
+		);
 	}
-	d.deserialize_str(Vis)
 }
 
-/// The intermediate JSON-parsed form of the config file.
-#[derive(Deserialize)]
-#[serde(deny_unknown_fields)]
-struct ParsedConfig<'raw> {
-	/// The defaults section.
-	#[serde(borrow, default)]
-	defaults: ParsedDefaults<'raw>,
+/// Tests deserializing invalid structured compression specifications.
+#[test]
+fn test_deserialize_invalid_compression() {
+	for compression in [
+		r#"{"algorithm": "lz4", "level": 3}"#,
+		r#"{"algorithm": "zstd", "level": 23}"#,
+		r#"{"algorithm": "zlib", "level": 10}"#,
+		r#"{"algorithm": "none", "auto": true}"#,
+		r#"{"algorithm": "lz4", "obfuscate": 7}"#,
+		r#"{"algorithm": "lz4", "obfuscate": 0}"#,
+		r#"{"algorithm": "brotli"}"#,
+	] {
+		let input = format!(
+			r#"{{"archives": {{"foo": {{"compression": {compression}, "repository": "/r", "root": "/x"}}}}}}"#
+		);
+		assert!(
+			serde_json::from_str::<Config>(&input).is_err(),
+			"{compression} should be rejected"
+		);
+	}
+}
 
-	/// The archives section.
-	#[serde(borrow)]
-	archives: BTreeMap<Cow<'raw, str>, ParsedArchive<'raw>>,
+/// Tests converting an archive to JSON.
+#[test]
+fn test_archive_to_json() {
+	let archive = Archive {
+		compression: Compression::Raw("zstd,3".into()),
+		repositories: vec!["/path/to/repo".into()],
+		root: Cow::Borrowed(Path::new("/home")),
+		btrfs_snapshot: true,
+		patterns: vec!["- **/.cache".into()],
+		..Archive::default()
+	};
+	assert_eq!(
+		archive.to_json(),
+		serde_json::json!({
+			"enabled": true,
+			"compression": "zstd,3",
+			"repository": ["/path/to/repo"],
+			"backend": "borg",
+			"root": "/home",
+			"btrfs_snapshot": true,
+			"allow_writable_snapshot": false,
+			"nested_subvolumes": "warn",
+			"snapshot_delete_failure": "error",
+			"snapshot_space_retries": 3,
+			"snapshot_collision": "warn",
+			"snapshot_dir": null,
+			"patterns": ["- **/.cache"],
+			"tags": [],
+			"comment": null,
+			"verify_after": false,
+			"list_changed": false,
+			"files_cache": null,
+			"timezone": null,
+			"name_date_format": "%FT%T",
+			"timestamp_source": "run",
+			"prune": null,
+			"upload_buffer": null,
+			"checkpoint_interval": null,
+			"checkpoint_max_age": null,
+			"exclude_borg_dirs": true,
+			"path_style": "relative",
+			"require_mount": false,
+			"min_files": null,
+			"min_bytes": null,
+			"success_marker": null,
+			"success_marker_on_warnings": false,
+			"priority": 0,
+		})
+	);
+}
 
-	/// The umask option.
-	#[serde(default = "default_umask", deserialize_with = "deserialize_umask")]
-	umask: u16,
+/// Tests deserializing a disabled archive, which must still be valid.
+#[test]
+fn test_deserialize_disabled() {
+	const INPUT: &[u8] = br#"
+		{
+			"defaults": {"compression": "lz4", "repository": "/repo"},
+			"archives": {
+				"foo": {"root": "/foo", "enabled": false},
+				"bar": {"root": "/bar"}
+			}
+		}"#;
+	let config = serde_json::from_slice::<Config>(INPUT).unwrap();
+	assert!(!config.archives["foo"].enabled);
+	assert!(config.archives["bar"].enabled);
+	const INVALID: &[u8] = br#"
+		{
+			"defaults": {"compression": "lz4", "repository": "/repo"},
+			"archives": {"foo": {"root": "/foo", "enabled": false, "patterns": ["bad"]}}
+		}"#;
+	assert!(serde_json::from_slice::<Config>(INVALID).is_err());
 }
 
-impl<'raw> ParsedConfig<'raw> {
-	/// Converts a `ParsedConfig` into a [`Config`](Config).
-	fn finish<D: Deserializer<'raw>>(self) -> Result<Config<'raw>, D::Error> {
-		Ok(Config {
-			archives: self
-				.archives
-				.into_iter()
-				.map(|(name, archive)| {
-					Ok((name, ParsedArchive::finish::<D>(archive, &self.defaults)?))
-				})
-				.collect::<Result<BTreeMap<Cow<'raw, str>, Archive<'raw>>, D::Error>>()?,
-			umask: self.umask,
-		})
-	}
+/// Tests deserializing archive tags, which must not be empty.
+#[test]
+fn test_deserialize_tags() {
+	const INPUT: &[u8] = br#"
+		{
+			"defaults": {"compression": "lz4", "repository": "/repo"},
+			"archives": {"foo": {"root": "/foo", "tags": ["db", "nightly"]}}
+		}"#;
+	let config = serde_json::from_slice::<Config>(INPUT).unwrap();
+	assert_eq!(config.archives["foo"].tags, ["db", "nightly"]);
+	const EMPTY: &[u8] = br#"
+		{
+			"defaults": {"compression": "lz4", "repository": "/repo"},
+			"archives": {"foo": {"root": "/foo", "tags": [""]}}
+		}"#;
+	assert!(serde_json::from_slice::<Config>(EMPTY).is_err());
 }
 
-/// Tests deserializing a basic config file with no archives.
+/// Tests rejecting patterns with `..` components, except regular expressions, in which `..` is
+/// not a path component.
 #[test]
-fn test_deserialize_empty() {
-	const INPUT: &[u8] = br#"{"archives": {}}"#;
+fn test_deserialize_parent_component_pattern() {
+	let config = |patterns: &str| {
+		let input = format!(
+			r#"{{"archives": {{"foo": {{"compression": "lz4", "repository": "/r", "root": "/foo", "patterns": {patterns}}}}}}}"#
+		);
+		serde_json::from_str::<Config>(&input).map(|_| ())
+	};
+	assert!(config(r#"["+ home/../etc"]"#).is_err());
+	assert!(config(r#"["- pp:/foo/.."]"#).is_err());
+	assert!(config(r#"["! sh:../foo.snapshot*"]"#).is_err());
+	assert!(config(r#"["+ home/..foo", "- re:^home/a..b$"]"#).is_ok());
+	assert!(config(r#"["P re", "- ^home/../x"]"#).is_ok());
+	assert!(config(r#"["P re", "P sh", "- home/../x"]"#).is_err());
+}
+
+/// Tests that the configuration hash ignores the order of keys but not their values.
+#[test]
+fn test_config_hash() {
+	let hash = |input: &str| serde_json::from_str::<Config>(input).unwrap().hash();
+	let base = hash(
+		r#"{"umask": "0027", "archives": {"foo": {"root": "/foo", "compression": "lz4", "repository": "/r"}}}"#,
+	);
+	assert_eq!(base.len(), 64);
 	assert_eq!(
-		serde_json::from_slice::<Config>(INPUT).unwrap(),
-		Config {
-			archives: BTreeMap::new(),
-		}
+		hash(
+			r#"{"archives": {"foo": {"repository": "/r", "compression": "lz4", "root": "/foo"}}, "umask": "0o027"}"#
+		),
+		base
+	);
+	assert_ne!(
+		hash(
+			r#"{"umask": "0027", "archives": {"foo": {"root": "/foo", "compression": "lz4", "repository": "/s"}}}"#
+		),
+		base
 	);
 }
 
-/// Tests deserializing a config file with two complete archive specifications.
+/// Tests that default patterns are appended to each archive’s own patterns and validated.
 #[test]
-fn test_deserialize_two_archives() {
+fn test_deserialize_default_patterns() {
 	const INPUT: &[u8] = br#"
 		{
+			"defaults": {"compression": "lz4", "repository": "/repo", "patterns": ["- **/*.tmp"]},
 			"archives": {
-				"foo": {
-					"compression": "lzma",
-					"repository": "/path/to/foo/repo",
-					"root": "/path/to/foo/archive/root",
-					"btrfs_snapshot": false
-				},
-				"bar": {
-					"compression": "lzma",
-					"repository": "/path/to/bar/repo",
-					"root": "/path/to/bar/archive/root",
-					"btrfs_snapshot": true,
-					"patterns": [
-						"+pattern1"
-					]
-				}
+				"foo": {"root": "/foo", "patterns": ["+ foo/keep.tmp"]},
+				"bar": {"root": "/bar"}
 			}
 		}"#;
+	let config = serde_json::from_slice::<Config>(INPUT).unwrap();
 	assert_eq!(
-		serde_json::from_slice::<Config>(INPUT).unwrap(),
-		Config {
-			archives: [
-				(
-					Cow::Borrowed("foo"),
-					Archive {
-						compression: Cow::Borrowed("lzma"),
-						repository: Cow::Borrowed(Path::new("/path/to/foo/repo")),
-						root: Cow::Borrowed(Path::new("/path/to/foo/archive/root")),
-						btrfs_snapshot: false,
-						patterns: Vec::new(),
-					}
-				),
-				(
-					Cow::Borrowed("bar"),
-					Archive {
-						compression: Cow::Borrowed("lzma"),
-						repository: Cow::Borrowed(Path::new("/path/to/bar/repo")),
-						root: Cow::Borrowed(Path::new("/path/to/bar/archive/root")),
-						btrfs_snapshot: true,
-						patterns: vec![Cow::Borrowed("+pattern1")],
-					}
-				),
-			]
-			.into_iter()
-			.collect(),
-		}
+		config.archives["foo"].patterns,
+		["+ foo/keep.tmp", "- **/*.tmp"]
 	);
+	assert_eq!(config.archives["bar"].patterns, ["- **/*.tmp"]);
+	const INVALID: &[u8] = br#"
+		{
+			"defaults": {"compression": "lz4", "repository": "/repo", "patterns": ["*.tmp"]},
+			"archives": {"foo": {"root": "/foo"}}
+		}"#;
+	assert!(serde_json::from_slice::<Config>(INVALID).is_err());
 }
 
-/// Tests deserializing a config file with a complete archive specification, an incomplete archive
-/// specification, and a defaults section that completes the incomplete specification.
+/// Tests expanding pattern groups into archives’ patterns, and rejecting unknown or invalid
+/// groups.
 #[test]
-fn test_deserialize_partial_and_complete() {
+fn test_deserialize_pattern_groups() {
 	const INPUT: &[u8] = br#"
 		{
-			"defaults": {
-				"compression": "lz4",
-				"repository": "/path/to/default/repo"
-			},
+			"defaults": {"compression": "lz4", "repository": "/repo", "patterns": ["- **/*.tmp"]},
+			"pattern_groups": {"caches": ["- **/.cache"], "vcs": ["- **/.git", "- **/.hg"]},
+			"archives": {"foo": {"root": "/foo", "patterns": ["+ foo"], "use_groups": ["vcs", "caches"]}}
+		}"#;
+	let config = serde_json::from_slice::<Config>(INPUT).unwrap();
+	assert_eq!(
+		config.archives["foo"].patterns,
+		[
+			"+ foo",
+			"- **/.git",
+			"- **/.hg",
+			"- **/.cache",
+			"- **/*.tmp"
+		]
+	);
+	const UNKNOWN: &[u8] = br#"
+		{
+			"defaults": {"compression": "lz4", "repository": "/repo"},
+			"archives": {"foo": {"root": "/foo", "use_groups": ["vcs"]}}
+		}"#;
+	assert!(serde_json::from_slice::<Config>(UNKNOWN).is_err());
+	const INVALID: &[u8] = br#"
+		{
+			"defaults": {"compression": "lz4", "repository": "/repo"},
+			"pattern_groups": {"unused": ["**/.cache"]},
+			"archives": {"foo": {"root": "/foo"}}
+		}"#;
+	assert!(serde_json::from_slice::<Config>(INVALID).is_err());
+}
+
+/// Tests rejecting archive names that Borg would not accept.
+#[test]
+fn test_check_archive_name() {
+	assert!(check_archive_name("home").is_ok());
+	assert!(check_archive_name("home.daily_1 (old)").is_ok());
+	assert!(check_archive_name("").is_err());
+	assert!(check_archive_name("home/user").is_err());
+	assert!(check_archive_name("home\tuser").is_err());
+	assert!(check_archive_name(" home").is_err());
+	assert!(check_archive_name("a:b").is_ok());
+	assert!(check_archive_name("a::b").is_err());
+	assert!(check_archive_name("db{1}").is_err());
+	assert!(check_archive_name("db}").is_err());
+	const INPUT: &[u8] = br#"
+		{
+			"defaults": {"compression": "lz4", "repository": "/repo"},
+			"archives": {"home/user": {"root": "/home/user"}}
+		}"#;
+	let error = serde_json::from_slice::<Config>(INPUT).unwrap_err();
+	assert!(error.to_string().contains("invalid archive name"));
+}
+
+/// Tests validating formats for the timestamp in archive names.
+#[test]
+fn test_valid_name_date_format() {
+	assert!(valid_name_date_format(DEFAULT_NAME_DATE_FORMAT));
+	assert!(valid_name_date_format("%Y%m%d-%H%M%S%z"));
+	assert!(valid_name_date_format("%F_%Hh%M"));
+	assert!(!valid_name_date_format(""));
+	assert!(!valid_name_date_format("%Q"));
+	assert!(!valid_name_date_format("%D"));
+	assert!(!valid_name_date_format("%Y/%m"));
+	assert!(!valid_name_date_format("%F%n"));
+	assert!(!valid_name_date_format("%H::%M"));
+	assert!(!valid_name_date_format("{%F}"));
+	assert!(!valid_name_date_format("%F}"));
+}
+
+/// Tests limiting the length of archive names according to their timestamp formats.
+#[test]
+fn test_archive_name_length() {
+	assert_eq!(max_name_date_len(DEFAULT_NAME_DATE_FORMAT), 19);
+	assert_eq!(max_name_date_len("%B %A"), "September Wednesday".len());
+	let config = |name: &str, format: &str| {
+		let input = format!(
+			r#"{{"defaults": {{"compression": "lz4", "repository": "/r", "name_date_format": "{format}"}}, "archives": {{"{name}": {{"root": "/foo"}}}}}}"#
+		);
+		serde_json::from_str::<Config>(&input).map(|_| ())
+	};
+	assert!(config(&"a".repeat(235), "%FT%T").is_ok());
+	assert!(config(&"a".repeat(236), "%FT%T").is_err());
+	assert!(config(&"a".repeat(236), "%F").is_ok());
+	assert!(config(&"a".repeat(235), "%FT%T_%A").is_err());
+}
+
+/// Tests deserializing archive comments, inherited from the defaults and required to be a single
+/// line.
+#[test]
+fn test_deserialize_comment() {
+	const INPUT: &[u8] = br#"
+		{
+			"defaults": {"compression": "lz4", "repository": "/repo", "comment": "from {hostname}"},
 			"archives": {
-				"foo": {
-					"root": "/path/to/foo/archive/root",
-					"btrfs_snapshot": false
-				},
-				"bar": {
-					"compression": "lzma",
-					"repository": "/path/to/bar/repo",
-					"root": "/path/to/bar/archive/root",
-					"btrfs_snapshot": true,
-					"patterns": [
-						"+pattern1"
-					]
-				}
+				"foo": {"root": "/foo"},
+				"bar": {"root": "/bar", "comment": "tags: {tags}"}
 			}
 		}"#;
+	let config = serde_json::from_slice::<Config>(INPUT).unwrap();
 	assert_eq!(
-		serde_json::from_slice::<Config>(INPUT).unwrap(),
-		Config {
-			archives: [
-				(
-					Cow::Borrowed("foo"),
-					Archive {
-						compression: Cow::Borrowed("lz4"),
-						repository: Cow::Borrowed(Path::new("/path/to/default/repo")),
-						root: Cow::Borrowed(Path::new("/path/to/foo/archive/root")),
-						btrfs_snapshot: false,
-						patterns: Vec::new(),
-					}
-				),
-				(
-					Cow::Borrowed("bar"),
-					Archive {
-						compression: Cow::Borrowed("lzma"),
-						repository: Cow::Borrowed(Path::new("/path/to/bar/repo")),
-						root: Cow::Borrowed(Path::new("/path/to/bar/archive/root")),
-						btrfs_snapshot: true,
-						patterns: vec![Cow::Borrowed("+pattern1")],
-					}
-				),
-			]
-			.into_iter()
-			.collect(),
-		}
+		config.archives["foo"].comment.as_deref(),
+		Some("from {hostname}")
 	);
+	assert_eq!(
+		config.archives["bar"].comment.as_deref(),
+		Some("tags: {tags}")
+	);
+	const MULTILINE: &[u8] = br#"
+		{
+			"defaults": {"compression": "lz4", "repository": "/repo"},
+			"archives": {"foo": {"root": "/foo", "comment": "a\nb"}}
+		}"#;
+	assert!(serde_json::from_slice::<Config>(MULTILINE).is_err());
 }
 
-/// Tests deserializing a partial archive where the missing information is not provided in the
-/// defaults section.
-///
-/// This should fail because information is completely missing.
+/// Tests validating files cache modes.
 #[test]
-fn test_deserialize_missing_info() {
+fn test_valid_files_cache() {
+	assert!(valid_files_cache("ctime,size,inode"));
+	assert!(valid_files_cache("mtime,size"));
+	assert!(valid_files_cache("rechunk,ctime"));
+	assert!(valid_files_cache("disabled"));
+	assert!(!valid_files_cache(""));
+	assert!(!valid_files_cache("ctime,mtime"));
+	assert!(!valid_files_cache("size,size"));
+	assert!(!valid_files_cache("disabled,size"));
+	assert!(!valid_files_cache("atime"));
+}
+
+/// Tests deserializing the path style, which cannot be absolute for a snapshotted archive.
+#[test]
+fn test_deserialize_path_style() {
 	const INPUT: &[u8] = br#"
 		{
-			"defaults": {
-				"compression": "lz4",
-			},
+			"defaults": {"compression": "lz4", "repository": "/repo"},
 			"archives": {
-				"foo": {
-					"root": "/path/to/foo/archive/root",
-					"btrfs_snapshot": false
-				}
+				"foo": {"root": "/foo", "path_style": "absolute"},
+				"bar": {"root": "/bar", "btrfs_snapshot": true}
 			}
 		}"#;
-	assert!(serde_json::from_slice::<Config>(INPUT).is_err());
+	let config = serde_json::from_slice::<Config>(INPUT).unwrap();
+	assert_eq!(config.archives["foo"].path_style, PathStyle::Absolute);
+	assert_eq!(config.archives["bar"].path_style, PathStyle::Relative);
+	const SNAPSHOT: &[u8] = br#"
+		{
+			"defaults": {"compression": "lz4", "repository": "/repo"},
+			"archives": {"foo": {"root": "/foo", "path_style": "absolute", "btrfs_snapshot": true}}
+		}"#;
+	assert!(serde_json::from_slice::<Config>(SNAPSHOT).is_err());
 }
 
-/// Tests deserializing an archive with an illegal pattern entry.
+/// Tests the accepted and rejected forms of the umask.
 #[test]
-fn test_deserialize_bad_pattern() {
+fn test_deserialize_umask() {
+	let umask = |value: serde_json::Value| deserialize_umask(&value).map_err(|e| e.to_string());
+	assert_eq!(umask(serde_json::json!("0027")), Ok(0o027));
+	assert_eq!(umask(serde_json::json!("077")), Ok(0o077));
+	assert_eq!(umask(serde_json::json!("0o022")), Ok(0o022));
+	assert_eq!(
+		umask(serde_json::json!(77)),
+		Err(
+			"umask must be written as an octal string, such as \"0077\", not as the number 77"
+				.to_owned()
+		)
+	);
+	assert!(umask(serde_json::json!(0)).is_err());
+	assert!(umask(serde_json::json!("1000")).is_err());
+	assert!(umask(serde_json::json!("0089")).is_err());
+	assert!(umask(serde_json::json!("+77")).is_err());
+	assert!(umask(serde_json::json!(512)).is_err());
+	assert!(umask(serde_json::json!(-1)).is_err());
+	assert!(umask(serde_json::json!(true)).is_err());
+}
+
+/// Tests deserializing per-repository settings.
+#[test]
+fn test_deserialize_repositories() {
 	const INPUT: &[u8] = br#"
 		{
-			"archives": {
-				"foo": {
-					"compression": "lzma",
-					"repository": "/path/to/foo/repo",
-					"root": "/path/to/foo/archive/root",
-					"btrfs_snapshot": false,
-					"patterns": [
-						"X mypattern"
-					]
-				}
+			"archives": {},
+			"repositories": {
+				"ssh://backup@example.com/./repo": {"passphrase_env": "REPO_PASSPHRASE"}
 			}
 		}"#;
-	assert!(serde_json::from_slice::<Config>(INPUT).is_err());
+	let config = serde_json::from_slice::<Config>(INPUT).unwrap();
+	assert_eq!(
+		config.repositories["ssh://backup@example.com/./repo"],
+		Repository {
+			passphrase_env: Some("REPO_PASSPHRASE".into()),
+			..Repository::default()
+		}
+	);
+	const INVALID: &[u8] =
+		br#"{"archives": {}, "repositories": {"/r": {"passphrase_env": "A=B"}}}"#;
+	assert!(serde_json::from_slice::<Config>(INVALID).is_err());
+	const BOTH: &[u8] = br#"{"archives": {}, "repositories": {"/r": {"passphrase_env": "A", "manage_passphrase": false}}}"#;
+	assert!(serde_json::from_slice::<Config>(BOTH).is_err());
+	const LOCAL: &[u8] = br#"{"archives": {}, "repositories": {"/r": {"create_parent": true}}}"#;
+	assert!(
+		serde_json::from_slice::<Config>(LOCAL)
+			.unwrap()
+			.repositories["/r"]
+			.create_parent
+	);
+	const ENV_AND_FILE: &[u8] = br#"{"archives": {}, "repositories": {"/r": {"passphrase_env": "A", "passphrase_file": "/p"}}}"#;
+	assert!(serde_json::from_slice::<Config>(ENV_AND_FILE).is_err());
+	const ID: &[u8] = br#"{"archives": {}, "repositories": {"/r": {"passphrase_id": "usb"}, "host:r": {"passphrase_id": "usb"}}}"#;
+	assert_eq!(
+		serde_json::from_slice::<Config>(ID).unwrap().repositories["host:r"]
+			.passphrase_id
+			.as_deref(),
+		Some("usb")
+	);
+	const EMPTY_ID: &[u8] = br#"{"archives": {}, "repositories": {"/r": {"passphrase_id": ""}}}"#;
+	assert!(serde_json::from_slice::<Config>(EMPTY_ID).is_err());
+	const ENV_AND_ID: &[u8] = br#"{"archives": {}, "repositories": {"/r": {"passphrase_env": "A", "passphrase_id": "usb"}}}"#;
+	assert!(serde_json::from_slice::<Config>(ENV_AND_ID).is_err());
+	const REMOTE: &[u8] =
+		br#"{"archives": {}, "repositories": {"host:r": {"create_parent": true}}}"#;
+	assert!(serde_json::from_slice::<Config>(REMOTE).is_err());
+	const SYNC: &[u8] = br#"{"archives": {}, "repositories": {"file:///r": {"sync_repo": true}}}"#;
+	assert!(serde_json::from_slice::<Config>(SYNC).unwrap().repositories["file:///r"].sync_repo);
+	const REMOTE_SYNC: &[u8] =
+		br#"{"archives": {}, "repositories": {"host:r": {"sync_repo": true}}}"#;
+	assert!(serde_json::from_slice::<Config>(REMOTE_SYNC).is_err());
+	const REMOTE_PATH: &[u8] = br#"{
+		"defaults": {"remote_path": "borg1"},
+		"archives": {"a": {"compression": "none", "root": "/", "repository": ["host:a", "host:b", "/c"]}},
+		"repositories": {"host:b": {"remote_path": "/opt/borg/bin/borg"}}
+	}"#;
+	let config = serde_json::from_slice::<Config>(REMOTE_PATH).unwrap();
+	assert_eq!(
+		config.repositories["host:a"].remote_path.as_deref(),
+		Some("borg1")
+	);
+	assert_eq!(
+		config.repositories["host:b"].remote_path.as_deref(),
+		Some("/opt/borg/bin/borg")
+	);
+	assert!(!config.repositories.contains_key("/c"));
+	const EMPTY_REMOTE_PATH: &[u8] =
+		br#"{"archives": {}, "repositories": {"host:r": {"remote_path": ""}}}"#;
+	assert!(serde_json::from_slice::<Config>(EMPTY_REMOTE_PATH).is_err());
+	const LOCAL_REMOTE_PATH: &[u8] =
+		br#"{"archives": {}, "repositories": {"/r": {"remote_path": "borg1"}}}"#;
+	assert!(serde_json::from_slice::<Config>(LOCAL_REMOTE_PATH).is_err());
+	const BORG_ENV: &[u8] = br#"{
+		"defaults": {"borg_env": {"BORG_HOSTNAME_IS_UNIQUE": "yes", "BORG_RSH": "ssh -4"}},
+		"archives": {"a": {"compression": "none", "root": "/", "repository": ["/a", "/b"]}},
+		"repositories": {"/b": {"borg_env": {"BORG_RSH": "ssh -6"}}}
+	}"#;
+	let config = serde_json::from_slice::<Config>(BORG_ENV).unwrap();
+	assert_eq!(
+		config.repositories["/a"].borg_env,
+		BorgEnv::from([
+			("BORG_HOSTNAME_IS_UNIQUE".into(), "yes".into()),
+			("BORG_RSH".into(), "ssh -4".into()),
+		])
+	);
+	assert_eq!(
+		config.repositories["/b"].borg_env,
+		BorgEnv::from([
+			("BORG_HOSTNAME_IS_UNIQUE".into(), "yes".into()),
+			("BORG_RSH".into(), "ssh -6".into()),
+		])
+	);
+	const MANAGED_BORG_ENV: &[u8] =
+		br#"{"archives": {}, "repositories": {"/r": {"borg_env": {"BORG_PASSPHRASE_FD": "0"}}}}"#;
+	assert!(serde_json::from_slice::<Config>(MANAGED_BORG_ENV).is_err());
+	const MANAGED_DEFAULT_BORG_ENV: &[u8] =
+		br#"{"defaults": {"borg_env": {"BORG_REPO": "/r"}}, "archives": {}}"#;
+	assert!(serde_json::from_slice::<Config>(MANAGED_DEFAULT_BORG_ENV).is_err());
+	const PASSPHRASE_BORG_ENV: &[u8] =
+		br#"{"archives": {}, "repositories": {"/r": {"borg_env": {"BORG_PASSCOMMAND": "pass"}}}}"#;
+	assert!(serde_json::from_slice::<Config>(PASSPHRASE_BORG_ENV).is_err());
+	const PASSPHRASE_DEFAULT_BORG_ENV: &[u8] =
+		br#"{"defaults": {"borg_env": {"BORG_PASSPHRASE": "x"}}, "archives": {}}"#;
+	assert!(serde_json::from_slice::<Config>(PASSPHRASE_DEFAULT_BORG_ENV).is_err());
+	const INVALID_BORG_ENV: &[u8] =
+		br#"{"archives": {}, "repositories": {"/r": {"borg_env": {"A=B": "1"}}}}"#;
+	assert!(serde_json::from_slice::<Config>(INVALID_BORG_ENV).is_err());
+	const UNKNOWN: &[u8] = br#"{"archives": {}, "repositories": {"/r": {"bogus": 1}}}"#;
+	assert_eq!(
+		serde_json::from_slice::<Config>(UNKNOWN)
+			.unwrap()
+			.unknown_keys,
+		["repositories./r.bogus"]
+	);
 }
@@ -0,0 +1,96 @@
+//! A report on the availability of the programs and system facilities that Borgify depends on,
+//! for diagnosing problems and including in bug reports.
+
+#[cfg(feature = "libbsd")]
+use nix::libc;
+use std::process::{Command, ExitCode, Stdio};
+
+/// Returns the version reported by `borg --version`, or a description of why it could not be
+/// determined.
+fn borg_version() -> Result<String, String> {
+	let output = Command::new("borg")
+		.arg("--version")
+		.stdin(Stdio::null())
+		.stderr(Stdio::null())
+		.output()
+		.map_err(|e| format!("cannot run borg: {e}"))?;
+	if !output.status.success() {
+		return Err(format!("borg --version failed: {}", output.status));
+	}
+	let version = String::from_utf8_lossy(&output.stdout);
+	let version = version.trim();
+	Ok(version.strip_prefix("borg ").unwrap_or(version).to_owned())
+}
+
+/// Returns whether a filesystem type is listed in the contents of `/proc/filesystems`.
+fn filesystem_listed(filesystems: &str, name: &str) -> bool {
+	filesystems
+		.lines()
+		.any(|line| line.split_whitespace().last() == Some(name))
+}
+
+/// Returns whether the kernel supports btrfs, and thus its snapshot ioctls, or a description of
+/// why this could not be determined.
+fn btrfs_available() -> Result<bool, String> {
+	let filesystems = std::fs::read_to_string("/proc/filesystems")
+		.map_err(|e| format!("cannot read /proc/filesystems: {e}"))?;
+	Ok(filesystem_listed(&filesystems, "btrfs"))
+}
+
+/// Returns whether the `readpassphrase` function, used to ask for passphrases, can be found.
+#[cfg(feature = "libbsd")]
+fn readpassphrase_resolved() -> bool {
+	// SAFETY: The symbol name is a valid NUL-terminated string, and RTLD_DEFAULT is a valid handle.
+	!unsafe { libc::dlsym(libc::RTLD_DEFAULT, c"readpassphrase".as_ptr()) }.is_null()
+}
+
+/// Probes each dependency and prints a report.
+///
+/// Returns exit code 2 if anything that every backup needs is missing, 1 if anything needed only
+/// by some configurations is missing, or 0 otherwise.
+pub fn run() -> ExitCode {
+	let mut critical = false;
+	let mut warnings = false;
+	println!("borgify: {}", env!("CARGO_PKG_VERSION"));
+	match borg_version() {
+		Ok(version) => println!("borg: {version}"),
+		Err(e) => {
+			println!("borg: MISSING ({e})");
+			critical = true;
+		}
+	}
+	match btrfs_available() {
+		Ok(true) => println!("btrfs snapshots: available"),
+		Ok(false) => {
+			println!("btrfs snapshots: unavailable (the kernel does not support btrfs)");
+			warnings = true;
+		}
+		Err(e) => {
+			println!("btrfs snapshots: unknown ({e})");
+			warnings = true;
+		}
+	}
+	#[cfg(feature = "libbsd")]
+	if readpassphrase_resolved() {
+		println!("readpassphrase: available");
+	} else {
+		println!("readpassphrase: MISSING");
+		critical = true;
+	}
+	#[cfg(not(feature = "libbsd"))]
+	println!("readpassphrase: not used (built without libbsd)");
+	if critical {
+		2.into()
+	} else {
+		u8::from(warnings).into()
+	}
+}
+
+/// Tests finding a filesystem type in the format of `/proc/filesystems`.
+#[test]
+fn test_filesystem_listed() {
+	const FILESYSTEMS: &str = "nodev\tsysfs\nnodev\ttmpfs\n\text4\n\tbtrfs\n";
+	assert!(filesystem_listed(FILESYSTEMS, "btrfs"));
+	assert!(filesystem_listed(FILESYSTEMS, "tmpfs"));
+	assert!(!filesystem_listed(FILESYSTEMS, "xfs"));
+}
@@ -0,0 +1,107 @@
+//! Reporting of errors as JSON, for consumption by other tools.
+
+use super::{backup, btrfs, check, cli, config};
+use serde_json::{Map, Value};
+
+/// An error type which can describe itself in structured form.
+pub trait Structured: std::error::Error {
+	/// Returns the name of the variant.
+	fn variant(&self) -> &'static str;
+
+	/// Returns the variant’s fields, other than its source error.
+	fn fields(&self) -> Map<String, Value> {
+		Map::new()
+	}
+}
+
+/// Builds a field map from a list of names and values.
+pub fn fields<const N: usize>(entries: [(&str, Value); N]) -> Map<String, Value> {
+	entries
+		.into_iter()
+		.map(|(name, value)| (name.to_owned(), value))
+		.collect()
+}
+
+/// Describes an error if it is of a particular [`Structured`](Structured) type.
+fn structured<T: Structured + 'static>(
+	e: &(dyn std::error::Error + 'static),
+) -> Option<(String, Map<String, Value>)> {
+	e.downcast_ref::<T>()
+		.map(|e| (e.variant().to_owned(), e.fields()))
+}
+
+/// Returns the module, variant name, and fields describing one error.
+fn describe(e: &(dyn std::error::Error + 'static)) -> (&'static str, String, Map<String, Value>) {
+	if let Some((variant, fields)) = structured::<super::Error>(e) {
+		("main", variant, fields)
+	} else if let Some((variant, fields)) = structured::<cli::Error>(e) {
+		("cli", variant, fields)
+	} else if let Some((variant, fields)) = structured::<config::LoadError>(e) {
+		("config", variant, fields)
+	} else if let Some((variant, fields)) = structured::<check::Error>(e) {
+		("check", variant, fields)
+	} else if let Some((variant, fields)) = structured::<backup::Error>(e) {
+		("backup", variant, fields)
+	} else if let Some((variant, fields)) = structured::<btrfs::Error>(e) {
+		("btrfs", variant, fields)
+	} else if let Some(e) = e.downcast_ref::<std::io::Error>() {
+		let fields = match e.raw_os_error() {
+			Some(errno) => fields([("errno", errno.into())]),
+			None => Map::new(),
+		};
+		("io", format!("{:?}", e.kind()), fields)
+	} else if let Some(e) = e.downcast_ref::<serde_json::Error>() {
+		(
+			"json",
+			format!("{:?}", e.classify()),
+			fields([("line", e.line().into()), ("column", e.column().into())]),
+		)
+	} else {
+		("unknown", "Unknown".to_owned(), Map::new())
+	}
+}
+
+/// Converts an error and its chain of sources into a JSON array, outermost error first.
+pub fn to_json(e: &(dyn std::error::Error + 'static)) -> Value {
+	let mut entries = Vec::new();
+	let mut next = Some(e);
+	while let Some(e) = next {
+		let (module, variant, fields) = describe(e);
+		let mut entry = Map::new();
+		entry.insert("message".to_owned(), e.to_string().into());
+		entry.insert("module".to_owned(), module.into());
+		entry.insert("variant".to_owned(), variant.into());
+		if !fields.is_empty() {
+			entry.insert("fields".to_owned(), fields.into());
+		}
+		entries.push(Value::Object(entry));
+		next = e.source();
+	}
+	Value::Array(entries)
+}
+
+/// Tests converting a two-level error chain to JSON.
+#[test]
+fn test_to_json() {
+	let e = super::Error::CheckRepository(
+		"/path/to/repo".to_owned(),
+		check::Error::UnknownExitCode(42),
+	);
+	assert_eq!(
+		to_json(&e),
+		serde_json::json!([
+			{
+				"message": "error checking repository /path/to/repo",
+				"module": "main",
+				"variant": "CheckRepository",
+				"fields": {"repository": "/path/to/repo"},
+			},
+			{
+				"message": "borg returned unknown exit code 42",
+				"module": "check",
+				"variant": "UnknownExitCode",
+				"fields": {"code": 42},
+			},
+		])
+	);
+}
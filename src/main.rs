@@ -1,48 +1,219 @@
 mod backup;
+mod borg_log;
 mod btrfs;
 mod check;
+mod cli;
+mod command_line;
 mod config;
+mod doctor;
+mod json_error;
 mod passphrase;
+mod profile;
+mod progress;
+mod restic;
+mod schema;
+mod tail;
+#[cfg(test)]
+mod temp_dir;
+mod timezone;
+mod usage;
+mod verbosity;
 
+use command_line::CommandLine;
+use json_error::{fields, Structured};
 use nix::libc;
-use std::collections::hash_map::{Entry, HashMap};
+use serde::Deserialize as _;
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ffi::OsString;
 use std::fmt::{Display, Formatter};
+use std::os::unix::ffi::OsStrExt as _;
+use std::os::unix::fs::{DirBuilderExt as _, MetadataExt as _, PermissionsExt as _};
 use std::path::{Path, PathBuf};
-use std::process::ExitCode;
+use std::process::{Command, ExitCode, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+/// The config file used if none is specified on the command line or in the environment.
+const DEFAULT_CONFIG_PATH: &str = "/etc/borgify.json";
+
+/// The environment variable naming the config file, if none is specified on the command line.
+const CONFIG_ENV: &str = "BORGIFY_CONFIG";
 
 /// The errors that can occur in the main application.
 #[derive(Debug)]
 enum Error {
-	/// An error occurred loading the config file.
-	ConfigLoad(std::io::Error),
+	/// The command line is invalid.
+	CommandLine(cli::Error),
+
+	/// An error occurred loading the config file or a file it includes.
+	ConfigLoad(PathBuf, config::LoadError),
 
 	/// An error occurred parsing the config file.
-	ConfigParse(serde_json::Error),
+	ConfigParse(PathBuf, serde_json::Error),
+
+	/// The config file contains unrecognized keys.
+	ConfigUnknownKeys(PathBuf, Vec<String>),
+
+	/// The config file, or a file it includes, can be read or written by users other than its
+	/// owner, and `--strict-permissions` was given.
+	ConfigPermissions(PathBuf, u32),
+
+	/// A repository’s passphrase file can be read or written by users other than its owner, and
+	/// `--strict-permissions` was given; the repository URL, the path, and the mode are included.
+	PassphraseFilePermissions(String, PathBuf, u32),
+
+	/// An archive named on the command line does not exist in the config file.
+	UnknownArchive(String),
 
 	/// An error occurred reading a passphrase from the terminal.
 	ReadPassphrase(std::io::Error),
 
+	/// The environment variable named to hold a repository’s passphrase is not set; the
+	/// repository URL and the variable name are included.
+	PassphraseEnv(String, String),
+
+	/// The file named to hold a repository’s candidate passphrases could not be read; the
+	/// repository URL and the path are included.
+	PassphraseFile(String, PathBuf, std::io::Error),
+
+	/// None of the candidate passphrases in a repository’s passphrase file is correct; the
+	/// repository URL, the path, and the number of candidates are included.
+	PassphraseCandidates(String, PathBuf, usize),
+
 	/// An error occurred checking a repository.
 	CheckRepository(String, check::Error),
 
 	/// An error occurred examining an archive root.
 	CheckArchiveRoot(PathBuf, std::io::Error),
 
+	/// An archive root contains no entries, and `--strict-empty-root` was given.
+	EmptyArchiveRoot(PathBuf),
+
+	/// An archive root which is required to be a mounted filesystem is not one.
+	NotMounted(PathBuf),
+
+	/// The parent directory of a local repository could not be created.
+	CreateRepositoryParent(PathBuf, std::io::Error),
+
+	/// The work directory could not be created or is not a writable directory.
+	WorkDir(PathBuf, std::io::Error),
+
+	/// The environment holds a passphrase for Borg, which the config says to refuse.
+	InheritedPassphrase(&'static str),
+
+	/// The cgroup into which to move Borg cannot be used.
+	Cgroup(PathBuf, std::io::Error),
+
+	/// An error occurred opening or snapshotting an archive root.
+	PrepareSource(PathBuf, backup::Error),
+
 	/// An error occurred performing a backup.
 	Backup(String, backup::Error),
+
+	/// An error occurred pruning an archive’s Borg archives in a repository; the archive name and
+	/// the repository URL are included.
+	Prune(String, String, backup::Error),
+
+	/// An error occurred deleting the snapshot of an archive root.
+	ReleaseSource(PathBuf, backup::Error),
+
+	/// An error occurred looking for the snapshots of an archive’s root.
+	FindSnapshots(String, backup::Error),
+
+	/// An error occurred deleting a snapshot found left behind.
+	DeleteSnapshot(PathBuf, backup::Error),
+
+	/// An error occurred reading the answer to a confirmation prompt.
+	ReadConfirmation(std::io::Error),
 }
 
 impl Display for Error {
 	fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
 		match self {
-			Self::ConfigLoad(_) => "error loading config file".fmt(f),
-			Self::ConfigParse(_) => "error parsing config file".fmt(f),
+			Self::CommandLine(_) => "invalid command line".fmt(f),
+			Self::ConfigLoad(p, _) => write!(f, "error loading config file {}", p.display()),
+			Self::ConfigParse(p, _) => write!(f, "error parsing config file {}", p.display()),
+			Self::ConfigUnknownKeys(p, keys) => write!(
+				f,
+				"config file {} contains unrecognized keys: {} (use --lenient to ignore them)",
+				p.display(),
+				keys.join(", ")
+			),
+			Self::ConfigPermissions(p, mode) => write!(
+				f,
+				"config file {} has unsafe permissions {mode:04o} (it must not be accessible by group or other users)",
+				p.display()
+			),
+			Self::PassphraseFilePermissions(url, p, mode) => write!(
+				f,
+				"passphrase file {} for repository {url} has unsafe permissions {mode:04o} (it must not be accessible by group or other users)",
+				p.display()
+			),
+			Self::UnknownArchive(a) => write!(f, "archive {a} is not defined in the config file"),
 			Self::ReadPassphrase(_) => "error obtaining passphrase from terminal".fmt(f),
+			Self::PassphraseEnv(url, var) => write!(
+				f,
+				"environment variable {var}, which should hold the passphrase for repository {url}, is not set"
+			),
+			Self::PassphraseFile(url, p, _) => write!(
+				f,
+				"error reading passphrase file {} for repository {url}",
+				p.display()
+			),
+			Self::PassphraseCandidates(url, p, 0) => write!(
+				f,
+				"passphrase file {} for repository {url} contains no passphrases",
+				p.display()
+			),
+			Self::PassphraseCandidates(url, p, 1) => write!(
+				f,
+				"the passphrase in {} is not correct for repository {url}",
+				p.display()
+			),
+			Self::PassphraseCandidates(url, p, count) => write!(
+				f,
+				"none of the {count} passphrases in {} is correct for repository {url}",
+				p.display()
+			),
 			Self::CheckRepository(url, _) => write!(f, "error checking repository {url}"),
 			Self::CheckArchiveRoot(p, _) => {
 				write!(f, "error checking archive root directory {}", p.display())
 			}
+			Self::EmptyArchiveRoot(p) => write!(
+				f,
+				"archive root {} is empty (remove --strict-empty-root to back it up anyway)",
+				p.display()
+			),
+			Self::NotMounted(p) => write!(
+				f,
+				"archive root {} is not a mounted filesystem",
+				p.display()
+			),
+			Self::CreateRepositoryParent(p, _) => write!(
+				f,
+				"error creating repository parent directory {}",
+				p.display()
+			),
+			Self::WorkDir(p, _) => write!(f, "error preparing work directory {}", p.display()),
+			Self::InheritedPassphrase(var) => write!(
+				f,
+				"{var} is set in the environment, where Borg would use it instead of the passphrase Borgify passes"
+			),
+			Self::Cgroup(p, _) => write!(f, "cannot move processes into cgroup {}", p.display()),
+			Self::PrepareSource(p, _) => {
+				write!(f, "error preparing archive root {}", p.display())
+			}
 			Self::Backup(a, _) => write!(f, "error backing up archive {a}"),
+			Self::Prune(a, url, _) => write!(f, "error pruning archive {a} in repository {url}"),
+			Self::ReleaseSource(p, _) => {
+				write!(f, "error cleaning up after archive root {}", p.display())
+			}
+			Self::FindSnapshots(a, _) => write!(f, "error finding snapshots of archive {a}"),
+			Self::DeleteSnapshot(p, _) => write!(f, "error deleting snapshot {}", p.display()),
+			Self::ReadConfirmation(_) => "error reading confirmation".fmt(f),
 		}
 	}
 }
@@ -50,38 +221,885 @@ impl Display for Error {
 impl std::error::Error for Error {
 	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
 		match self {
-			Self::ConfigLoad(e) => Some(e),
-			Self::ConfigParse(e) => Some(e),
+			Self::CommandLine(e) => Some(e),
+			Self::ConfigLoad(_, e) => Some(e),
+			Self::ConfigParse(_, e) => Some(e),
+			Self::ConfigUnknownKeys(..)
+			| Self::ConfigPermissions(..)
+			| Self::PassphraseFilePermissions(..)
+			| Self::UnknownArchive(_)
+			| Self::NotMounted(_)
+			| Self::EmptyArchiveRoot(_)
+			| Self::PassphraseEnv(..)
+			| Self::PassphraseCandidates(..)
+			| Self::InheritedPassphrase(_) => None,
+			Self::PassphraseFile(_, _, e) => Some(e),
 			Self::ReadPassphrase(e) => Some(e),
 			Self::CheckRepository(_, e) => Some(e),
 			Self::CheckArchiveRoot(_, e) => Some(e),
-			Self::Backup(_, e) => Some(e),
+			Self::WorkDir(_, e) | Self::Cgroup(_, e) | Self::CreateRepositoryParent(_, e) => {
+				Some(e)
+			}
+			Self::PrepareSource(_, e) => Some(e),
+			Self::Backup(_, e) | Self::Prune(_, _, e) => Some(e),
+			Self::ReleaseSource(_, e) | Self::FindSnapshots(_, e) | Self::DeleteSnapshot(_, e) => {
+				Some(e)
+			}
+			Self::ReadConfirmation(e) => Some(e),
+		}
+	}
+}
+
+impl Structured for Error {
+	fn variant(&self) -> &'static str {
+		match self {
+			Self::CommandLine(_) => "CommandLine",
+			Self::ConfigLoad(..) => "ConfigLoad",
+			Self::ConfigParse(..) => "ConfigParse",
+			Self::ConfigUnknownKeys(..) => "ConfigUnknownKeys",
+			Self::ConfigPermissions(..) => "ConfigPermissions",
+			Self::PassphraseFilePermissions(..) => "PassphraseFilePermissions",
+			Self::UnknownArchive(_) => "UnknownArchive",
+			Self::ReadPassphrase(_) => "ReadPassphrase",
+			Self::PassphraseEnv(..) => "PassphraseEnv",
+			Self::PassphraseFile(..) => "PassphraseFile",
+			Self::PassphraseCandidates(..) => "PassphraseCandidates",
+			Self::CheckRepository(..) => "CheckRepository",
+			Self::CheckArchiveRoot(..) => "CheckArchiveRoot",
+			Self::NotMounted(_) => "NotMounted",
+			Self::EmptyArchiveRoot(_) => "EmptyArchiveRoot",
+			Self::WorkDir(..) => "WorkDir",
+			Self::InheritedPassphrase(_) => "InheritedPassphrase",
+			Self::Cgroup(..) => "Cgroup",
+			Self::CreateRepositoryParent(..) => "CreateRepositoryParent",
+			Self::PrepareSource(..) => "PrepareSource",
+			Self::Backup(..) => "Backup",
+			Self::Prune(..) => "Prune",
+			Self::ReleaseSource(..) => "ReleaseSource",
+			Self::FindSnapshots(..) => "FindSnapshots",
+			Self::DeleteSnapshot(..) => "DeleteSnapshot",
+			Self::ReadConfirmation(_) => "ReadConfirmation",
+		}
+	}
+
+	fn fields(&self) -> Map<String, Value> {
+		match self {
+			Self::ConfigLoad(p, _)
+			| Self::ConfigParse(p, _)
+			| Self::CheckArchiveRoot(p, _)
+			| Self::WorkDir(p, _)
+			| Self::Cgroup(p, _)
+			| Self::CreateRepositoryParent(p, _)
+			| Self::NotMounted(p)
+			| Self::EmptyArchiveRoot(p) => fields([("path", p.to_string_lossy().into())]),
+			Self::ConfigUnknownKeys(p, keys) => fields([
+				("path", p.to_string_lossy().into()),
+				("keys", keys.as_slice().into()),
+			]),
+			Self::ConfigPermissions(p, mode) => fields([
+				("path", p.to_string_lossy().into()),
+				("mode", (*mode).into()),
+			]),
+			Self::PassphraseFilePermissions(url, p, mode) => fields([
+				("repository", url.as_str().into()),
+				("path", p.to_string_lossy().into()),
+				("mode", (*mode).into()),
+			]),
+			Self::CheckRepository(url, _) => fields([("repository", url.as_str().into())]),
+			Self::PassphraseEnv(url, var) => fields([
+				("repository", url.as_str().into()),
+				("variable", var.as_str().into()),
+			]),
+			Self::PassphraseFile(url, p, _) => fields([
+				("repository", url.as_str().into()),
+				("path", p.to_string_lossy().into()),
+			]),
+			Self::PassphraseCandidates(url, p, count) => fields([
+				("repository", url.as_str().into()),
+				("path", p.to_string_lossy().into()),
+				("candidates", (*count).into()),
+			]),
+			Self::PrepareSource(p, _) | Self::ReleaseSource(p, _) | Self::DeleteSnapshot(p, _) => {
+				fields([("path", p.to_string_lossy().into())])
+			}
+			Self::UnknownArchive(a) | Self::Backup(a, _) | Self::FindSnapshots(a, _) => {
+				fields([("archive", a.as_str().into())])
+			}
+			Self::InheritedPassphrase(var) => fields([("variable", (*var).into())]),
+			Self::Prune(a, url, _) => fields([
+				("archive", a.as_str().into()),
+				("repository", url.as_str().into()),
+			]),
+			_ => Map::new(),
 		}
 	}
 }
 
-/// Tries to examine a repository. If a passphrase is needed, asks for the passphrase and
-/// re-examines the repository to verify the passphrase.
-fn check_repository_and_query_passphrase(
+/// Applies a function to each item, running up to `jobs` calls at once on separate threads, and
+/// returns the results in the same order as the items.
+fn parallel_map<T: Sync, R: Send>(items: &[T], jobs: usize, f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+	if jobs <= 1 {
+		return items.iter().map(f).collect();
+	}
+	let next = AtomicUsize::new(0);
+	let results: Vec<Mutex<Option<R>>> = items.iter().map(|_| Mutex::new(None)).collect();
+	std::thread::scope(|scope| {
+		for _ in 0..jobs.min(items.len()) {
+			scope.spawn(|| loop {
+				let i = next.fetch_add(1, Ordering::Relaxed);
+				let Some(item) = items.get(i) else {
+					break;
+				};
+				*results[i].lock().unwrap() = Some(f(item));
+			});
+		}
+	});
+	results
+		.into_iter()
+		.map(|i| i.into_inner().unwrap().expect("every item was processed"))
+		.collect()
+}
+
+/// The result of first examining a repository.
+enum CheckOutcome {
+	/// The repository is usable with the given passphrase, or with none.
+	Ready(Option<Vec<u8>>, check::Info),
+
+	/// The repository needs a passphrase to be typed at the terminal.
+	NeedsPassphrase,
+}
+
+/// The values that the variables named by repositories’ `passphrase_env` settings had at startup.
+static ENV_PASSPHRASES: OnceLock<HashMap<String, OsString>> = OnceLock::new();
+
+/// Moves the variables named by repositories’ `passphrase_env` settings out of the environment into
+/// [`ENV_PASSPHRASES`], so that the programs Borgify runs do not inherit them; Borg receives the
+/// passphrase through a pipe instead.
+///
+/// This must be called before any other threads are started, since it changes the environment.
+fn take_env_passphrases(config: &config::Config<'_>) {
+	let mut taken = HashMap::new();
+	for var in config
+		.repositories
+		.values()
+		.filter_map(|i| i.passphrase_env.as_deref())
+	{
+		if let Some(value) = std::env::var_os(var) {
+			std::env::remove_var(var);
+			taken.insert(var.to_owned(), value);
+		}
+	}
+	ENV_PASSPHRASES
+		.set(taken)
+		.expect("passphrase variables taken only once");
+}
+
+/// Returns the value that environment variable `var` had at startup, if it is named by a
+/// repository’s `passphrase_env` setting and was set.
+fn env_passphrase(var: &str) -> Option<&'static OsString> {
+	ENV_PASSPHRASES.get()?.get(var)
+}
+
+/// Tries to examine a repository, first checking its host if so configured, to find out whether a
+/// passphrase needs to be asked for. Each host is checked only once per `hosts`.
+///
+/// If the repository’s passphrase is configured to come from an environment variable, it is taken
+/// from there instead, and is never asked for. If Borgify is configured not to manage the
+/// passphrase, none is passed to Borg or returned.
+fn check_repository(
 	repository: &str,
-	umask: u16,
-) -> Result<Option<String>, Error> {
-	let mut pw: Option<String> = None;
-	loop {
-		match check::run(repository, pw.as_deref(), umask) {
-			Ok(()) => break Ok(pw),
-			Err(check::Error::Passphrase) => {
-				if pw.is_some() {
-					eprintln!("Passphrase is incorrect.");
+	config: &config::Config<'_>,
+	hosts: &check::HostCache,
+) -> Result<CheckOutcome, Error> {
+	// restic repository URLs are not in a form whose host Borgify can extract.
+	let host_check = config.host_check;
+	if host_check != config::HostCheck::Off
+		&& repository_backend(config, repository) == config::Backend::Borg
+	{
+		check::check_host(repository, host_check == config::HostCheck::Connect, hosts)
+			.map_err(|e| Error::CheckRepository(repository.to_owned(), e))?;
+	}
+	let settings = config.repositories.get(repository);
+	if settings.is_some_and(|i| i.create_parent) {
+		if let Some(parent) = check::local_path(repository).and_then(Path::parent) {
+			if !parent.as_os_str().is_empty() && !parent.exists() {
+				std::fs::create_dir_all(parent)
+					.map_err(|e| Error::CreateRepositoryParent(parent.to_owned(), e))?;
+				if !verbosity::quiet() {
+					println!(
+						"Created parent directory {} for repository {repository}",
+						parent.display()
+					);
 				}
-				pw = Some(
-					passphrase::read(&format!("Passphrase for repository {repository}:"))
-						.map_err(Error::ReadPassphrase)?,
+			}
+		}
+	}
+	if settings.is_some_and(|i| !i.manage_passphrase) {
+		let info = probe_repository(config, repository, None, false)
+			.map_err(|e| Error::CheckRepository(repository.to_owned(), e))?;
+		return Ok(CheckOutcome::Ready(None, info));
+	}
+	if let Some(var) = settings.and_then(|i| i.passphrase_env.as_deref()) {
+		let pw = env_passphrase(var)
+			.ok_or_else(|| Error::PassphraseEnv(repository.to_owned(), var.to_owned()))?
+			.as_bytes()
+			.to_vec();
+		return match probe_repository(config, repository, Some(&pw), true) {
+			Ok(info) => Ok(CheckOutcome::Ready(Some(pw), info)),
+			Err(e) => Err(Error::CheckRepository(repository.to_owned(), e)),
+		};
+	}
+	if let Some(path) = settings.and_then(|i| i.passphrase_file.as_deref()) {
+		let contents = std::fs::read(path)
+			.map_err(|e| Error::PassphraseFile(repository.to_owned(), path.to_owned(), e))?;
+		let candidates = passphrase_candidates(&contents);
+		for candidate in &candidates {
+			match probe_repository(config, repository, Some(candidate), true) {
+				Ok(info) => return Ok(CheckOutcome::Ready(Some(candidate.to_vec()), info)),
+				Err(check::Error::Passphrase) => (),
+				Err(e) => return Err(Error::CheckRepository(repository.to_owned(), e)),
+			}
+		}
+		return Err(Error::PassphraseCandidates(
+			repository.to_owned(),
+			path.to_owned(),
+			candidates.len(),
+		));
+	}
+	match probe_repository(config, repository, None, true) {
+		Ok(info) => Ok(CheckOutcome::Ready(None, info)),
+		Err(check::Error::Passphrase) => Ok(CheckOutcome::NeedsPassphrase),
+		Err(e) => Err(Error::CheckRepository(repository.to_owned(), e)),
+	}
+}
+
+/// Splits the contents of a passphrase file into candidate passphrases, one per line, ignoring
+/// empty lines and the carriage returns of files with DOS line endings.
+///
+/// Usually there is just one, but keeping both the old and the new passphrase during a rotation
+/// lets backups carry on whichever one a repository currently has.
+fn passphrase_candidates(contents: &[u8]) -> Vec<&[u8]> {
+	contents
+		.split(|&b| b == b'\n')
+		.map(|i| i.strip_suffix(b"\r").unwrap_or(i))
+		.filter(|i| !i.is_empty())
+		.collect()
+}
+
+/// Returns the passphrase ID of a repository, if it has one.
+fn passphrase_id<'c>(config: &'c config::Config<'_>, repository: &str) -> Option<&'c str> {
+	config
+		.repositories
+		.get(repository)
+		.and_then(|i| i.passphrase_id.as_deref())
+}
+
+/// Returns the backup program that writes to a repository.
+///
+/// Every archive using a repository has the same backend, as checked when the config is loaded.
+fn repository_backend(config: &config::Config<'_>, repository: &str) -> config::Backend {
+	config
+		.archives
+		.values()
+		.find(|i| i.repositories.iter().any(|i| i == repository))
+		.map_or(config::Backend::Borg, |i| i.backend)
+}
+
+/// Tries to examine a repository with the lightweight check of its backend, as described for
+/// [`check::run`](check::run) and [`restic::check`](restic::check).
+fn probe_repository(
+	config: &config::Config<'_>,
+	repository: &str,
+	passphrase: Option<&[u8]>,
+	manage_passphrase: bool,
+) -> Result<check::Info, check::Error> {
+	match repository_backend(config, repository) {
+		config::Backend::Borg => check::run(
+			repository,
+			passphrase,
+			manage_passphrase,
+			remote_path(config, repository),
+			borg_env(config, repository),
+			config.check_probe,
+			config.umask,
+		),
+		config::Backend::Restic => restic::check(
+			repository,
+			passphrase,
+			manage_passphrase,
+			borg_env(config, repository),
+		),
+	}
+}
+
+/// Returns the path of the Borg executable on the server of a repository, if one is configured.
+fn remote_path<'c>(config: &'c config::Config<'_>, repository: &str) -> Option<&'c str> {
+	config
+		.repositories
+		.get(repository)
+		.and_then(|i| i.remote_path.as_deref())
+}
+
+/// Returns the additional environment variables to set for Borg for a repository, if it has
+/// settings.
+fn borg_env<'c>(
+	config: &'c config::Config<'_>,
+	repository: &str,
+) -> Option<&'c config::BorgEnv<'c>> {
+	config.repositories.get(repository).map(|i| &i.borg_env)
+}
+
+/// Returns the first environment variable that would give Borg a passphrase in preference to the
+/// one Borgify passes, if any is set and any enabled archive uses a repository whose passphrase
+/// Borgify manages.
+fn inherited_passphrase(config: &config::Config<'_>) -> Option<&'static str> {
+	let managed = config
+		.archives
+		.values()
+		.filter(|i| i.enabled)
+		.flat_map(|i| &i.repositories)
+		.any(|url| {
+			config
+				.repositories
+				.get(url)
+				.is_none_or(|i| i.manage_passphrase)
+		});
+	passphrase::OVERRIDING_ENV
+		.into_iter()
+		.find(|var| managed && std::env::var_os(var).is_some())
+}
+
+/// Returns the prompt for the passphrase of a repository, naming every repository sharing it if it
+/// has a passphrase ID.
+fn passphrase_prompt(id: Option<&str>, repository: &str, sharing: &[&str]) -> String {
+	match id {
+		Some(id) if sharing.len() > 1 => {
+			format!("Passphrase {id} for repositories {}:", sharing.join(", "))
+		}
+		Some(id) => format!("Passphrase {id} for repository {repository}:"),
+		None => format!("Passphrase for repository {repository}:"),
+	}
+}
+
+/// A repository that has been checked successfully.
+struct CheckedRepository {
+	/// The passphrase to give to Borg, if any.
+	passphrase: Option<Vec<u8>>,
+
+	/// The information learned about the repository.
+	info: check::Info,
+}
+
+/// Checks each repository, running up to `jobs` checks at once, and asks for the passphrase of
+/// each one that needs it.
+///
+/// If `failures` is given, errors checking repositories are collected there and the repositories
+/// concerned are left out of the result, rather than the first error being returned.
+///
+/// The repositories are first examined concurrently to find which need a passphrase, checking each
+/// host only once, however many of the repositories are on it. Passphrases are then asked for one
+/// at a time, and all those entered are verified concurrently, asking again for any that are
+/// incorrect.
+fn check_repositories_and_query_passphrases<'a>(
+	repositories: &[&'a str],
+	config: &config::Config<'_>,
+	jobs: usize,
+	mut failures: Option<&mut Vec<Error>>,
+) -> Result<HashMap<&'a str, CheckedRepository>, Error> {
+	let mut checked: HashMap<&str, CheckedRepository> = HashMap::new();
+	let mut pending: Vec<&str> = Vec::new();
+	let hosts = check::HostCache::default();
+	for (&repository, outcome) in
+		repositories
+			.iter()
+			.zip(parallel_map(repositories, jobs, |repository| {
+				check_repository(repository, config, &hosts)
+			})) {
+		match outcome {
+			Ok(CheckOutcome::Ready(passphrase, info)) => {
+				checked.insert(repository, CheckedRepository { passphrase, info });
+			}
+			Ok(CheckOutcome::NeedsPassphrase) => pending.push(repository),
+			Err(e) => match &mut failures {
+				Some(failures) => failures.push(e),
+				None => return Err(e),
+			},
+		}
+	}
+	let mut retry = false;
+	while !pending.is_empty() {
+		let asking = std::mem::take(&mut pending);
+		let mut entered: Vec<(&str, Vec<u8>)> = Vec::new();
+		for &repository in &asking {
+			if retry {
+				eprintln!("Passphrase for repository {repository} is incorrect.");
+			}
+			// Repositories sharing a passphrase ID are asked about together, only once.
+			let id = passphrase_id(config, repository);
+			let shared = id.and_then(|id| {
+				entered
+					.iter()
+					.find(|(other, _)| passphrase_id(config, other) == Some(id))
+			});
+			let pw = match shared {
+				Some((_, pw)) => pw.clone(),
+				None => {
+					let sharing: Vec<&str> = asking
+						.iter()
+						.copied()
+						.filter(|&other| id.is_some() && passphrase_id(config, other) == id)
+						.collect();
+					passphrase::read(&passphrase_prompt(id, repository, &sharing))
+						.map_err(Error::ReadPassphrase)?
+				}
+			};
+			entered.push((repository, pw));
+		}
+		for ((repository, pw), result) in
+			entered
+				.iter()
+				.zip(parallel_map(&entered, jobs, |(repository, pw)| {
+					probe_repository(config, repository, Some(pw), true)
+				})) {
+			match result {
+				Ok(info) => {
+					checked.insert(
+						repository,
+						CheckedRepository {
+							passphrase: Some(pw.clone()),
+							info,
+						},
+					);
+				}
+				Err(check::Error::Passphrase) => pending.push(repository),
+				Err(e) => {
+					let e = Error::CheckRepository((*repository).to_owned(), e);
+					match &mut failures {
+						Some(failures) => failures.push(e),
+						None => return Err(e),
+					}
+				}
+			}
+		}
+		retry = true;
+	}
+	Ok(checked)
+}
+
+/// Returns the distinct repositories used by a list of archives, in order of first use.
+fn unique_repositories<'a>(archives: &[(&str, &'a config::Archive<'_>)]) -> Vec<&'a str> {
+	let mut repositories: Vec<&str> = Vec::new();
+	for repository in archives.iter().flat_map(|(_, i)| &i.repositories) {
+		if !repositories.contains(&&**repository) {
+			repositories.push(repository);
+		}
+	}
+	repositories
+}
+
+/// Checks each repository used by an enabled archive and prints its encryption mode, either as
+/// human-readable text or as a JSON object keyed by repository URL.
+///
+/// Passphrases are asked for as needed, because Borg cannot report the encryption mode of an
+/// encrypted repository without its key.
+fn show_encryption(config: &config::Config<'_>, options: &cli::Options) -> Result<ExitCode, Error> {
+	let archives: Vec<(&str, &config::Archive<'_>)> = config
+		.archives
+		.iter()
+		.filter(|(_, archive)| archive.enabled)
+		.map(|(name, archive)| (&**name, archive))
+		.collect();
+	let repositories = unique_repositories(&archives);
+	let checked =
+		check_repositories_and_query_passphrases(&repositories, config, options.check_jobs, None)?;
+	if options.json {
+		let modes: Map<String, Value> = repositories
+			.iter()
+			.map(|&repository| {
+				(
+					repository.to_owned(),
+					checked[repository].info.encryption_mode.as_str().into(),
 				)
+			})
+			.collect();
+		println!("{}", Value::Object(modes));
+	} else {
+		for repository in repositories {
+			println!(
+				"Repository {repository}: encryption mode {}",
+				checked[repository].info.encryption_mode
+			);
+		}
+	}
+	Ok(ExitCode::SUCCESS)
+}
+
+/// Returns where a repository’s passphrase comes from, as named in `--passphrase-status` output,
+/// and whether that source is available, if Borgify can tell without asking for anything.
+///
+/// A passphrase asked for at the terminal, or one that Borg obtains itself, has no availability
+/// that can be checked in advance.
+fn passphrase_source(settings: Option<&config::Repository<'_>>) -> (&'static str, Option<bool>) {
+	match settings {
+		Some(settings) if !settings.manage_passphrase => ("borg", None),
+		Some(config::Repository {
+			passphrase_env: Some(var),
+			..
+		}) => ("env", Some(env_passphrase(var).is_some())),
+		Some(config::Repository {
+			passphrase_file: Some(path),
+			..
+		}) => ("file", Some(std::fs::File::open(path).is_ok())),
+		_ => ("prompt", None),
+	}
+}
+
+/// Checks each repository used by an enabled archive, without any passphrase and without asking
+/// for one, and prints whether it needs a passphrase and where that would come from, either as
+/// human-readable text or as a JSON object keyed by repository URL.
+///
+/// Returns exit code 2 if any repository could not be checked, or 0 otherwise.
+fn passphrase_status(config: &config::Config<'_>, options: &cli::Options) -> ExitCode {
+	let archives: Vec<(&str, &config::Archive<'_>)> = config
+		.archives
+		.iter()
+		.filter(|(_, archive)| archive.enabled)
+		.map(|(name, archive)| (&**name, archive))
+		.collect();
+	let repositories = unique_repositories(&archives);
+	let results = parallel_map(&repositories, options.check_jobs, |repository| {
+		let manage_passphrase = config
+			.repositories
+			.get(*repository)
+			.is_none_or(|i| i.manage_passphrase);
+		// With no passphrase, an encrypted repository whose passphrase Borgify manages fails the
+		// check, while one whose passphrase Borg obtains itself reports its mode if it can.
+		match probe_repository(config, repository, None, manage_passphrase) {
+			Ok(info) => Ok((info.encryption_mode != "none", Some(info.encryption_mode))),
+			Err(check::Error::Passphrase) => Ok((true, None)),
+			Err(e) => Err(Error::CheckRepository((*repository).to_owned(), e)),
+		}
+	});
+	let mut any_failures = false;
+	let mut statuses = Map::new();
+	for (repository, result) in repositories.iter().zip(results) {
+		let (source, available) = passphrase_source(config.repositories.get(*repository));
+		let (required, mode, error) = match result {
+			Ok((required, mode)) => (Some(required), mode, None),
+			Err(e) => {
+				any_failures = true;
+				if !options.json {
+					report_error(&e, options.json_errors);
+				}
+				(None, None, Some(e.to_string()))
 			}
-			Err(e) => break Err(Error::CheckRepository(repository.to_owned(), e)),
+		};
+		if options.json {
+			statuses.insert(
+				(*repository).to_owned(),
+				serde_json::json!({
+					"passphrase_required": required,
+					"encryption_mode": mode,
+					"source": source,
+					"source_available": available,
+					"error": error,
+				}),
+			);
+		} else if let Some(required) = required {
+			let availability = match available {
+				Some(true) => ", available",
+				Some(false) => ", not available",
+				None => "",
+			};
+			if required {
+				println!(
+					"Repository {repository}: passphrase required (source: {source}{availability})"
+				);
+			} else {
+				println!("Repository {repository}: no passphrase required");
+			}
+		}
+	}
+	if options.json {
+		println!("{}", Value::Object(statuses));
+	}
+	if any_failures {
+		2.into()
+	} else {
+		ExitCode::SUCCESS
+	}
+}
+
+/// Returns the directory in which an archive’s snapshots are placed if it has a `snapshot_dir`, or
+/// `None` if they are placed next to its root.
+///
+/// Archives sharing a root and snapshot settings share a snapshot, placed in a subdirectory named
+/// after the first of them.
+fn snapshot_dir(
+	config: &config::Config<'_>,
+	name: &str,
+	archive: &config::Archive<'_>,
+) -> Option<PathBuf> {
+	let first = config
+		.archives
+		.iter()
+		.find(|(_, other)| {
+			other.enabled
+				&& other.root == archive.root
+				&& other.btrfs_snapshot
+				&& other.snapshot_options == archive.snapshot_options
+		})
+		.map_or(name, |(name, _)| &**name);
+	archive
+		.snapshot_options
+		.snapshot_dir
+		.as_deref()
+		.map(|dir| dir.join(first))
+}
+
+/// Lists the snapshots of an archive’s root that Borgify created and that still exist, such as
+/// those left behind by an interrupted run, with their creation times, and deletes them if
+/// `delete` is set.
+///
+/// Deleting asks for confirmation unless `--yes` was given, and skips snapshots that a backup
+/// running concurrently is using. Returns exit code 2 if any snapshot could not be deleted, 1 if
+/// any was skipped, or 0 otherwise.
+fn snapshots(
+	config: &config::Config<'_>,
+	options: &cli::Options,
+	name: &str,
+	delete: bool,
+) -> Result<ExitCode, Error> {
+	let (name, archive) = config
+		.archives
+		.get_key_value(name)
+		.map(|(name, archive)| (&**name, archive))
+		.ok_or_else(|| Error::UnknownArchive(name.to_owned()))?;
+	let found = backup::find_snapshots(
+		&archive.root,
+		snapshot_dir(config, name, archive).as_deref(),
+	)
+	.map_err(|e| Error::FindSnapshots(name.to_owned(), e))?;
+	if found.is_empty() {
+		println!("No snapshots of archive {name} found");
+		return Ok(ExitCode::SUCCESS);
+	}
+	for snapshot in &found {
+		println!(
+			"{}  {}",
+			chrono::DateTime::<chrono::Local>::from(snapshot.created).format("%FT%T"),
+			snapshot.path.display()
+		);
+	}
+	if !delete {
+		return Ok(ExitCode::SUCCESS);
+	}
+	if !options.yes {
+		eprint!(
+			"Delete {} snapshot{} of archive {name}? [y/N] ",
+			found.len(),
+			if found.len() == 1 { "" } else { "s" }
+		);
+		let mut answer = String::new();
+		std::io::stdin()
+			.read_line(&mut answer)
+			.map_err(Error::ReadConfirmation)?;
+		if !matches!(answer.trim(), "y" | "Y" | "yes") {
+			println!("Not deleting any snapshots");
+			return Ok(ExitCode::SUCCESS);
+		}
+	}
+	let mut any_warnings = false;
+	let mut any_failures = false;
+	for snapshot in found {
+		let path = snapshot.path.clone();
+		match snapshot.delete() {
+			Ok(true) => println!("Deleted {}", path.display()),
+			Ok(false) => {
+				eprintln!(
+					"WARNING: Snapshot {} is in use by a backup running concurrently, so was not deleted",
+					path.display()
+				);
+				any_warnings = true;
+			}
+			Err(e) => {
+				report_error(&Error::DeleteSnapshot(path, e), options.json_errors);
+				any_failures = true;
+			}
+		}
+	}
+	Ok(if any_failures {
+		2.into()
+	} else if any_warnings {
+		1.into()
+	} else {
+		ExitCode::SUCCESS
+	})
+}
+
+/// Prints how an archive would be backed up: its resolved settings, whether and where a snapshot
+/// would be taken, and, for each repository, the Borg command line, the environment variables set
+/// for it, and where the passphrase would come from.
+///
+/// Nothing is run and no snapshot is taken. Passphrases are never read, so only their sources are
+/// shown.
+fn explain(
+	config: &config::Config<'_>,
+	options: &cli::Options,
+	name: &str,
+) -> Result<ExitCode, Error> {
+	let (name, archive) = config
+		.archives
+		.get_key_value(name)
+		.map(|(name, archive)| (&**name, archive))
+		.ok_or_else(|| Error::UnknownArchive(name.to_owned()))?;
+	println!("Archive {name}");
+	if !archive.enabled {
+		println!("Disabled, so skipped when backing up");
+	}
+	println!("Resolved settings: {:#}", archive.to_json());
+
+	// Archives sharing a root and snapshot settings share a snapshot, placed according to the
+	// first of them.
+	let snapshot = archive.btrfs_snapshot && !options.no_snapshot;
+	if snapshot {
+		let dir = snapshot_dir(config, name, archive)
+			.unwrap_or_else(|| archive.root.parent().unwrap_or(&archive.root).to_owned());
+		println!(
+			"Snapshot: {} would be snapshotted into a uniquely named subvolume in {}",
+			archive.root.display(),
+			dir.display()
+		);
+	} else if archive.btrfs_snapshot {
+		println!("Snapshot: none, because of --no-snapshot");
+	} else {
+		println!("Snapshot: none");
+	}
+
+	// Timestamps not fixed by the run are only known once Borg is about to start.
+	let timestamp = options.timestamp.unwrap_or_else(chrono::Utc::now);
+	let timestamp_utc = format!("{}", timestamp.format("%FT%T"));
+	let create_timestamp = match archive.timestamp_source {
+		config::TimestampSource::Run => &*timestamp_utc,
+		_ if options.timestamp.is_some() => &timestamp_utc,
+		config::TimestampSource::Snapshot if snapshot => "<time of snapshot>",
+		config::TimestampSource::Snapshot | config::TimestampSource::Start => "<time of start>",
+	};
+	let archives = [(name, archive)];
+	let (name_timestamps, any_warnings) = name_timestamps(&archives, timestamp);
+	let excludes = borg_dir_excludes(&archives)?;
+	let passphrases = HashMap::new();
+	let context = backup::Context {
+		timestamp_utc: &timestamp_utc,
+		timestamp_given: options.timestamp.is_some(),
+		name_timestamps: &name_timestamps,
+		passphrases: &passphrases,
+		umask: config.umask,
+		excludes: &excludes,
+		progress: None,
+		repositories: &config.repositories,
+		cgroup: None,
+		usage: options.json || verbosity::verbose(),
+	};
+	for repository in &archive.repositories {
+		let command = match archive.backend {
+			config::Backend::Borg => {
+				backup::create_command(&context, name, archive, repository, create_timestamp, &[])
+			}
+			config::Backend::Restic => restic::backup_command(
+				&context,
+				name,
+				archive,
+				repository,
+				create_timestamp,
+				&archive.root,
+				&[],
+			),
+		};
+		let settings = config.repositories.get(&**repository);
+		let (source, _) = passphrase_source(settings);
+		let mut env: Vec<Cow<'_, str>> = command
+			.get_envs()
+			.map(|(key, _)| key.to_string_lossy())
+			.collect();
+		if source != "borg" {
+			env.push(match archive.backend {
+				config::Backend::Borg => {
+					"BORG_PASSPHRASE_FD (if the repository needs a passphrase)".into()
+				}
+				config::Backend::Restic => "RESTIC_PASSWORD_FILE".into(),
+			});
+		}
+		println!("Repository {repository}:");
+		println!("  Command: {}", CommandLine::of(&command));
+		if archive.path_style == config::PathStyle::Relative {
+			println!(
+				"  Working directory: the {}",
+				if snapshot { "snapshot" } else { "archive root" }
+			);
+		}
+		if let Some(cgroup) = &config.cgroup {
+			println!("  Cgroup: {}", cgroup.display());
+		}
+		println!("  Environment: {}", env.join(", "));
+		let detail = match (source, settings) {
+			("env", Some(settings)) => settings.passphrase_env.as_deref().map(Cow::from),
+			("file", Some(settings)) => settings
+				.passphrase_file
+				.as_deref()
+				.map(|i| i.to_string_lossy()),
+			_ => None,
+		};
+		let detail = detail
+			.into_iter()
+			.map(Cow::into_owned)
+			.chain(passphrase_id(config, repository).map(|id| format!("shared as {id}")));
+		let detail: Vec<String> = detail.collect();
+		if detail.is_empty() {
+			println!("  Passphrase source: {source}");
+		} else {
+			println!("  Passphrase source: {source} ({})", detail.join(", "));
 		}
 	}
+	Ok(ExitCode::from(u8::from(any_warnings)))
+}
+
+/// Formats a duration for humans, such as `1h02m03s`, `4m05s`, or `6.7s`.
+fn format_duration(duration: Duration) -> String {
+	let seconds = duration.as_secs();
+	if seconds >= 3600 {
+		format!(
+			"{}h{:02}m{:02}s",
+			seconds / 3600,
+			seconds / 60 % 60,
+			seconds % 60
+		)
+	} else if seconds >= 60 {
+		format!("{}m{:02}s", seconds / 60, seconds % 60)
+	} else {
+		format!("{:.1}s", duration.as_secs_f64())
+	}
+}
+
+/// Returns the path of the config file: the one given on the command line if any, otherwise the
+/// one named by the `BORGIFY_CONFIG` environment variable (whose value is `env`) if it is set and
+/// nonempty, otherwise the default.
+fn config_path(option: Option<&Path>, env: Option<OsString>) -> PathBuf {
+	option.map_or_else(
+		|| {
+			env.filter(|i| !i.is_empty())
+				.map_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH), PathBuf::from)
+		},
+		Path::to_owned,
+	)
+}
+
+/// Returns the permission bits of a config or passphrase file if they allow access by group or
+/// other users, or `None` if they are safe or the file cannot be examined (in which case reading it
+/// will report a more useful error).
+fn unsafe_permissions(path: &Path) -> Option<u32> {
+	let mode = std::fs::metadata(path).ok()?.permissions().mode() & 0o7777;
+	(mode & 0o066 != 0).then_some(mode)
 }
 
 /// Checks that a specified archive root is a directory.
@@ -94,70 +1112,1466 @@ fn check_archive_root(root: &Path) -> std::io::Result<()> {
 	}
 }
 
+/// Checks that an archive’s root exists and is ready to be backed up, printing a warning for
+/// anything suspicious, such as an empty root or, if `--check-patterns` was given, a missing
+/// include pattern path.
+///
+/// On success, returns whether any warnings were generated.
+fn check_archive(
+	name: &str,
+	archive: &config::Archive<'_>,
+	options: &cli::Options,
+) -> Result<bool, Error> {
+	let mut any_warnings = false;
+	check_archive_root(&archive.root)
+		.map_err(|e| Error::CheckArchiveRoot(archive.root.clone().into_owned(), e))?;
+	if archive.require_mount
+		&& !is_mount_point(&archive.root)
+			.map_err(|e| Error::CheckArchiveRoot(archive.root.clone().into_owned(), e))?
+	{
+		return Err(Error::NotMounted(archive.root.clone().into_owned()));
+	}
+	if is_empty_dir(&archive.root)
+		.map_err(|e| Error::CheckArchiveRoot(archive.root.clone().into_owned(), e))?
+	{
+		if options.strict_empty_root {
+			return Err(Error::EmptyArchiveRoot(archive.root.clone().into_owned()));
+		}
+		eprintln!(
+			"WARNING: Archive root {} is empty; it may be an unmounted disk or a wrong path",
+			archive.root.display()
+		);
+		any_warnings = true;
+	}
+	if archive.compression.is_none() && !options.allow_uncompressed_remote {
+		for repository in &archive.repositories {
+			if check::local_path(repository).is_none() {
+				eprintln!(
+					"WARNING: Archive {name} is sent uncompressed to remote repository {repository}; consider compression \"lz4\" or better"
+				);
+				any_warnings = true;
+			}
+		}
+	}
+	if options.check_patterns {
+		let base = match archive.path_style {
+			config::PathStyle::Relative => &*archive.root,
+			config::PathStyle::Absolute => Path::new("/"),
+		};
+		for path in literal_includes(&archive.patterns) {
+			let path = base.join(path);
+			if let Err(e) = std::fs::symlink_metadata(&path) {
+				eprintln!(
+					"WARNING: Include pattern path {} in archive {name} cannot be found: {e}",
+					path.display()
+				);
+				any_warnings = true;
+			}
+		}
+	}
+	Ok(any_warnings)
+}
+
+/// Returns the paths named by the include patterns in a list of Borg patterns that match a literal
+/// path rather than a glob or regular expression, without any leading slash.
+///
+/// Patterns in the `pp:` and `pf:` styles always name a literal path, and those in the `sh:` and
+/// `fm:` styles do if they contain no wildcards. Borg’s default style for `--pattern` is `sh:`,
+/// changed by `P` patterns.
+fn literal_includes<'p>(patterns: &'p [Cow<'_, str>]) -> Vec<&'p str> {
+	config::path_patterns(patterns)
+		.into_iter()
+		.filter(|i| i.kind == '+')
+		.filter(|i| match i.style {
+			"pp" | "pf" => true,
+			"sh" | "fm" => !i.path.contains(['*', '?', '[', '\\']),
+			_ => false,
+		})
+		.map(|i| i.path.trim_start_matches('/'))
+		.filter(|i| !i.is_empty())
+		.collect()
+}
+
+/// Checks whether a directory contains no entries.
+fn is_empty_dir(path: &Path) -> std::io::Result<bool> {
+	Ok(std::fs::read_dir(path)?.next().transpose()?.is_none())
+}
+
+/// Checks whether a directory is the root of a mounted filesystem, by comparing its device number
+/// with that of its parent.
+///
+/// A btrfs subvolume has its own device number and therefore also counts as mounted.
+fn is_mount_point(root: &Path) -> std::io::Result<bool> {
+	let md = std::fs::metadata(root)?;
+	let parent = std::fs::metadata(root.join(".."))?;
+	Ok(md.dev() != parent.dev() || md.ino() == parent.ino())
+}
+
+/// Opens the `cgroup.procs` file of a cgroup for writing, which fails if the cgroup does not exist
+/// or processes cannot be moved into it.
+fn open_cgroup(path: &Path) -> std::io::Result<std::fs::File> {
+	std::fs::File::options()
+		.write(true)
+		.open(path.join("cgroup.procs"))
+}
+
+/// Creates or replaces a marker file, atomically, with the given modification time.
+///
+/// The marker is written under a temporary name in the same directory and then renamed into place,
+/// so that a program watching for it never sees it half-written or with the wrong time.
+fn write_success_marker(path: &Path, time: SystemTime) -> std::io::Result<()> {
+	let name = path
+		.file_name()
+		.ok_or_else(|| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+	let mut temp_name = OsString::from(".");
+	temp_name.push(name);
+	temp_name.push(format!(".{}.tmp", std::process::id()));
+	let temp = path.with_file_name(temp_name);
+	let result = std::fs::File::create(&temp).and_then(|file| {
+		file.set_modified(time)?;
+		file.sync_all()?;
+		std::fs::rename(&temp, path)
+	});
+	if result.is_err() {
+		let _ = std::fs::remove_file(&temp);
+	}
+	result
+}
+
+/// Creates the work directory, accessible only to the current user, if it does not exist, and
+/// checks that it is a directory in which files can be created.
+fn prepare_work_dir(path: &Path) -> std::io::Result<()> {
+	match std::fs::DirBuilder::new().mode(0o700).create(path) {
+		Ok(()) => (),
+		Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => (),
+		Err(e) => return Err(e),
+	}
+	if !std::fs::metadata(path)?.is_dir() {
+		return Err(std::io::Error::from_raw_os_error(libc::ENOTDIR));
+	}
+	let path = std::ffi::CString::new(path.as_os_str().as_bytes())
+		.map_err(|_| std::io::Error::from_raw_os_error(libc::EINVAL))?;
+	// SAFETY: path is a valid NUL-terminated string.
+	if unsafe { libc::access(path.as_ptr(), libc::W_OK | libc::X_OK) } == 0 {
+		Ok(())
+	} else {
+		Err(std::io::Error::last_os_error())
+	}
+}
+
+/// Prints the configured archives and their resolved settings, either as human-readable text or
+/// as a JSON object keyed by archive name.
+fn list_archives(config: &config::Config<'_>, config_hash: &str, json: bool) {
+	if json {
+		let mut value = config.to_json();
+		value["config_hash"] = config_hash.into();
+		println!("{value}");
+		return;
+	}
+	println!("config_hash: {config_hash}");
+	println!("umask: 0{:o}", config.umask);
+	if let Some(work_dir) = &config.work_dir {
+		println!("work_dir: {}", work_dir.display());
+	}
+	if let Some(cgroup) = &config.cgroup {
+		println!("cgroup: {}", cgroup.display());
+	}
+	println!("host_check: {}", config.host_check.name());
+	println!("check_probe: {}", config.check_probe.name());
+	println!(
+		"inherited_passphrase: {}",
+		config.inherited_passphrase.name()
+	);
+	if let Some(command) = &config.on_complete {
+		println!("on_complete: {}", command.join(" "));
+	}
+	for (url, repository) in &config.repositories {
+		println!();
+		println!("Repository {url}:");
+		println!("  manage_passphrase: {}", repository.manage_passphrase);
+		if let Some(var) = &repository.passphrase_env {
+			println!("  passphrase_env: {var}");
+		}
+		if let Some(file) = &repository.passphrase_file {
+			println!("  passphrase_file: {}", file.display());
+		}
+		if let Some(id) = &repository.passphrase_id {
+			println!("  passphrase_id: {id}");
+		}
+		if repository.create_parent {
+			println!("  create_parent: true");
+		}
+		if repository.sync_repo {
+			println!("  sync_repo: true");
+		}
+		if let Some(path) = &repository.remote_path {
+			println!("  remote_path: {path}");
+		}
+	}
+	for (name, archive) in &config.archives {
+		println!();
+		println!("Archive {name}:");
+		if !archive.enabled {
+			println!("  enabled: false");
+		}
+		println!("  compression: {}", archive.compression);
+		for repository in &archive.repositories {
+			println!("  repository: {repository}");
+		}
+		if archive.backend != config::Backend::Borg {
+			println!("  backend: {}", archive.backend.name());
+		}
+		println!("  root: {}", archive.root.display());
+		println!("  path_style: {}", archive.path_style.name());
+		println!("  timestamp_source: {}", archive.timestamp_source.name());
+		if let Some(retention) = &archive.prune {
+			println!("  prune: {}", retention.args().join(" "));
+		}
+		if let Some(size) = archive.upload_buffer {
+			println!("  upload_buffer: {size} MiB");
+		}
+		if let Some(interval) = archive.checkpoint_interval {
+			println!("  checkpoint_interval: {interval} s");
+		}
+		if let Some(age) = &archive.checkpoint_max_age {
+			println!("  checkpoint_max_age: {age}");
+		}
+		if let Some(timezone) = &archive.timezone {
+			println!("  timezone: {timezone}");
+		}
+		println!("  name_date_format: {}", archive.name_date_format);
+		if archive.require_mount {
+			println!("  require_mount: true");
+		}
+		if let Some(min_files) = archive.min_files {
+			println!("  min_files: {min_files}");
+		}
+		if let Some(min_bytes) = archive.min_bytes {
+			println!("  min_bytes: {min_bytes}");
+		}
+		if archive.priority != 0 {
+			println!("  priority: {}", archive.priority);
+		}
+		if let Some(marker) = &archive.success_marker {
+			println!("  success_marker: {}", marker.display());
+			println!(
+				"  success_marker_on_warnings: {}",
+				archive.success_marker_on_warnings
+			);
+		}
+		println!("  btrfs_snapshot: {}", archive.btrfs_snapshot);
+		if archive.btrfs_snapshot {
+			println!(
+				"  allow_writable_snapshot: {}",
+				archive.snapshot_options.allow_writable
+			);
+			println!(
+				"  nested_subvolumes: {}",
+				archive.snapshot_options.nested_subvolumes.name()
+			);
+			println!(
+				"  snapshot_delete_failure: {}",
+				archive.snapshot_options.delete_failure.name()
+			);
+			println!(
+				"  snapshot_space_retries: {}",
+				archive.snapshot_options.space_retries
+			);
+			println!(
+				"  snapshot_collision: {}",
+				archive.snapshot_options.collision.name()
+			);
+			if let Some(dir) = &archive.snapshot_options.snapshot_dir {
+				println!("  snapshot_dir: {}", dir.display());
+			}
+		}
+		for pattern in &archive.patterns {
+			println!("  pattern: {pattern}");
+		}
+		for tag in &archive.tags {
+			println!("  tag: {tag}");
+		}
+	}
+}
+
+/// Checks every repository used by an enabled archive, obtaining its passphrase as a backup would,
+/// and every enabled archive’s root, without backing up or taking snapshots.
+///
+/// With `--keep-going`, every problem found is reported and the exit code is 2 if there were any;
+/// otherwise the first problem is returned as an error. If there were none, the exit code is 1 if
+/// any warnings were generated, here or by `any_warnings`, or 0 otherwise.
+fn validate(
+	config: &config::Config<'_>,
+	options: &cli::Options,
+	mut any_warnings: bool,
+) -> Result<ExitCode, Error> {
+	let archives: Vec<(&str, &config::Archive<'_>)> = config
+		.archives
+		.iter()
+		.filter(|(_, archive)| archive.enabled)
+		.map(|(name, archive)| (&**name, archive))
+		.collect();
+	let mut failures = Vec::new();
+	let repositories = unique_repositories(&archives);
+	check_repositories_and_query_passphrases(
+		&repositories,
+		config,
+		options.check_jobs,
+		options.keep_going.then_some(&mut failures),
+	)?;
+	if let Some(path) = &config.cgroup {
+		match open_cgroup(path) {
+			Ok(_) => (),
+			Err(e) if options.keep_going => failures.push(Error::Cgroup(path.to_path_buf(), e)),
+			Err(e) => return Err(Error::Cgroup(path.to_path_buf(), e)),
+		}
+	}
+	for &(name, archive) in &archives {
+		match check_archive(name, archive, options) {
+			Ok(warnings) => any_warnings |= warnings,
+			Err(e) if options.keep_going => failures.push(e),
+			Err(e) => return Err(e),
+		}
+	}
+	if !failures.is_empty() {
+		for e in &failures {
+			report_error(e, options.json_errors);
+		}
+		return Ok(2.into());
+	}
+	if !verbosity::quiet() {
+		println!(
+			"validation OK: {} repositories and {} archives checked",
+			repositories.len(),
+			archives.len()
+		);
+	}
+	Ok(u8::from(any_warnings).into())
+}
+
+/// Prunes the Borg archives of each enabled archive that has retention rules, in each of its
+/// repositories, without backing up.
+///
+/// Passphrases are obtained as they would be for a backup. Every archive and repository is
+/// attempted even if an earlier one fails. Returns exit code 2 if any pruning failed, 1 if any
+/// warnings were generated, or 0 otherwise.
+fn prune(config: &config::Config<'_>, options: &cli::Options) -> Result<ExitCode, Error> {
+	let mut archives: Vec<(&str, &config::Archive<'_>)> = Vec::new();
+	for (name, archive) in &config.archives {
+		match &archive.prune {
+			_ if !archive.enabled => println!("Archive {name}: skipped (disabled)"),
+			None => println!("Archive {name}: skipped (prune is not configured)"),
+			Some(_) => archives.push((name, archive)),
+		}
+	}
+	let repositories = unique_repositories(&archives);
+	let checked =
+		check_repositories_and_query_passphrases(&repositories, config, options.check_jobs, None)?;
+	let mut any_warnings = false;
+	let mut any_failures = false;
+	for (name, archive) in archives {
+		for repository in &archive.repositories {
+			if !verbosity::quiet() {
+				println!("===== Pruning archive {name} in repository {repository} =====");
+			}
+			let passphrase = checked[&**repository].passphrase.as_deref();
+			match backup::prune(
+				name,
+				archive,
+				repository,
+				passphrase,
+				remote_path(config, repository),
+				borg_env(config, repository),
+				config.umask,
+			) {
+				Ok(warnings) => any_warnings |= warnings,
+				Err(e) => {
+					report_error(
+						&Error::Prune(name.to_owned(), repository.clone().into_owned(), e),
+						options.json_errors,
+					);
+					any_failures = true;
+				}
+			}
+		}
+	}
+	Ok(if any_failures {
+		2.into()
+	} else {
+		u8::from(any_warnings).into()
+	})
+}
+
+/// Creates and immediately deletes a snapshot for each archive that uses btrfs snapshots, without
+/// running Borg, to prove that snapshots can be taken with the current kernel and privileges.
+///
+/// Returns exit code 2 if any snapshot could not be created or deleted, 1 if any warnings were
+/// generated, or 0 otherwise.
+fn test_snapshots(config: &config::Config<'_>) -> ExitCode {
+	let mut any_warnings = false;
+	let mut any_failures = false;
+	for (name, archive) in &config.archives {
+		if !archive.enabled {
+			println!("Archive {name}: skipped (disabled)");
+			continue;
+		}
+		if !archive.btrfs_snapshot {
+			println!("Archive {name}: skipped (btrfs_snapshot is not enabled)");
+			continue;
+		}
+		let result = backup::Source::open(&archive.root, Some(&archive.snapshot_options), &[name])
+			.map_err(|e| Error::PrepareSource(archive.root.clone().into_owned(), e))
+			.and_then(|source| {
+				let warnings = source.warnings();
+				source
+					.close()
+					.map_err(|e| Error::ReleaseSource(archive.root.clone().into_owned(), e))?;
+				Ok(warnings)
+			});
+		match result {
+			Ok(warnings) => {
+				println!("Archive {name}: snapshot created and deleted successfully");
+				any_warnings |= warnings;
+			}
+			Err(e) => {
+				println!("Archive {name}: snapshot test failed");
+				show_error_stack(&e, true);
+				any_failures = true;
+			}
+		}
+	}
+	if any_failures {
+		2.into()
+	} else {
+		u8::from(any_warnings).into()
+	}
+}
+
+/// Creates and keeps a snapshot for each archive that uses btrfs snapshots, without running Borg,
+/// and prints their paths, either as a line per archive or as a JSON object keyed by archive name.
+///
+/// Archives are grouped as for a backup, so that archives sharing a root and snapshot settings
+/// share a snapshot, placed where a backup would place it.
+///
+/// Returns exit code 2 if any snapshot could not be created, 1 if any warnings were generated, or
+/// 0 otherwise.
+fn only_snapshots(config: &config::Config<'_>, options: &cli::Options) -> ExitCode {
+	let mut any_warnings = false;
+	let mut any_failures = false;
+	let mut groups: Vec<(&Path, &config::SnapshotOptions<'_>, Vec<&str>)> = Vec::new();
+	for (name, archive) in &config.archives {
+		if !archive.enabled {
+			if !options.json {
+				println!("Archive {name}: skipped (disabled)");
+			}
+		} else if !archive.btrfs_snapshot {
+			if !options.json {
+				println!("Archive {name}: skipped (btrfs_snapshot is not enabled)");
+			}
+		} else {
+			match groups.iter_mut().find(|(root, snapshot_options, _)| {
+				*root == &*archive.root && *snapshot_options == &archive.snapshot_options
+			}) {
+				Some((_, _, names)) => names.push(name),
+				None => groups.push((&archive.root, &archive.snapshot_options, vec![name])),
+			}
+		}
+	}
+	let mut paths = Map::new();
+	for (root, snapshot_options, names) in groups {
+		match backup::Source::open(root, Some(snapshot_options), &names) {
+			Ok(source) => {
+				any_warnings |= source.warnings();
+				let path = source.keep().expect("a snapshot was requested");
+				for name in names {
+					if options.json {
+						paths.insert(name.to_owned(), path.to_string_lossy().into());
+					} else {
+						println!("Archive {name}: snapshot {}", path.display());
+					}
+				}
+			}
+			Err(e) => {
+				for name in &names {
+					eprintln!("Archive {name}: snapshot creation failed");
+				}
+				show_error_stack(&Error::PrepareSource(root.to_owned(), e), true);
+				any_failures = true;
+			}
+		}
+	}
+	if options.json {
+		println!("{}", Value::Object(paths));
+	}
+	if any_failures {
+		2.into()
+	} else {
+		u8::from(any_warnings).into()
+	}
+}
+
 /// The top-level application logic.
-fn run() -> Result<ExitCode, Error> {
-	// Load the config file.
-	let config = std::fs::read("/etc/borgify.json").map_err(Error::ConfigLoad)?;
-	let config: config::Config = serde_json::from_slice(&config).map_err(Error::ConfigParse)?;
+fn run(options: &cli::Options) -> Result<ExitCode, Error> {
+	verbosity::set(options.verbosity);
+
+	// If only diagnosing the environment, do that and nothing else; no config file is needed.
+	if options.doctor {
+		return Ok(doctor::run());
+	}
+
+	// If only describing the config format, do that and nothing else; no config file is needed.
+	if options.schema {
+		println!("{:#}", schema::schema());
+		return Ok(ExitCode::SUCCESS);
+	}
+
+	// Load the config file, and resolve relative paths within it against its directory.
+	let config_path = config_path(options.config.as_deref(), std::env::var_os(CONFIG_ENV));
+	let config_path = std::path::absolute(&config_path).map_err(|e| {
+		Error::ConfigLoad(
+			config_path.to_owned(),
+			config::LoadError::Read(config_path.to_owned(), e),
+		)
+	})?;
+	let (config, config_files) =
+		config::load(&config_path).map_err(|e| Error::ConfigLoad(config_path.clone(), e))?;
+	let mut any_warnings = false;
+	for path in config_files {
+		if let Some(mode) = unsafe_permissions(&path) {
+			if options.strict_permissions {
+				return Err(Error::ConfigPermissions(path, mode));
+			}
+			eprintln!(
+				"WARNING: Config file {} has unsafe permissions {mode:04o}; it should not be accessible by group or other users",
+				path.display()
+			);
+			any_warnings = true;
+		}
+	}
+	let mut config = config::Config::deserialize(&config)
+		.map_err(|e| Error::ConfigParse(config_path.clone(), e))?;
+	if !config.unknown_keys.is_empty() {
+		if options.lenient {
+			for key in &config.unknown_keys {
+				eprintln!("WARNING: Ignoring unrecognized config key {key}");
+			}
+		} else {
+			return Err(Error::ConfigUnknownKeys(
+				config_path,
+				std::mem::take(&mut config.unknown_keys),
+			));
+		}
+	}
+	config.resolve_relative_paths(
+		config_path
+			.parent()
+			.expect("absolute path to a file has a parent"),
+	);
+
+	// Keep passphrases given in the environment from being inherited by every child process.
+	take_env_passphrases(&config);
+
+	// Passphrase files deserve the same care as the config file.
+	for (url, repository) in &config.repositories {
+		let Some(path) = repository.passphrase_file.as_deref() else {
+			continue;
+		};
+		if let Some(mode) = unsafe_permissions(path) {
+			if options.strict_permissions {
+				return Err(Error::PassphraseFilePermissions(
+					url.to_string(),
+					path.to_owned(),
+					mode,
+				));
+			}
+			eprintln!(
+				"WARNING: Passphrase file {} for repository {url} has unsafe permissions {mode:04o}; it should not be accessible by group or other users",
+				path.display()
+			);
+			any_warnings = true;
+		}
+	}
+
+	// Record the configuration as loaded, before archives are selected, so that the hash changes
+	// only when the config file does.
+	let config_hash = config.hash();
+
+	// Apply the umask to anything Borgify itself creates, such as snapshots. Borg sets its own
+	// umask from the --umask option, so this does not affect it.
+	// SAFETY: umask cannot fail and has no memory safety implications.
+	unsafe { libc::umask(config.umask.into()) };
+
+	// If only validating the config file, that has now been done.
+	if options.config_test {
+		println!("config OK");
+		return Ok(ExitCode::SUCCESS);
+	}
+
+	// Keep only the archives selected on the command line, if any. An archive is selected if it is
+	// named by any --archive option and carries any tag named by a --tag option.
+	if let Some(name) = options
+		.archives
+		.iter()
+		.find(|name| !config.archives.contains_key(name.as_str()))
+	{
+		return Err(Error::UnknownArchive(name.clone()));
+	}
+	config.archives.retain(|name, archive| {
+		(options.archives.is_empty() || options.archives.iter().any(|i| i == name))
+			&& (options.tags.is_empty()
+				|| archive
+					.tags
+					.iter()
+					.any(|tag| options.tags.iter().any(|i| i == tag)))
+	});
+
+	// If only listing the archives, do that and nothing else.
+	if options.list {
+		list_archives(&config, &config_hash, options.json);
+		return Ok(ExitCode::SUCCESS);
+	}
+
+	// A passphrase for Borg in the environment would take precedence over the one Borgify passes,
+	// so it is either refused or kept from Borg wherever Borgify passes one.
+	if let Some(var) = inherited_passphrase(&config) {
+		if config.inherited_passphrase == config::InheritedPassphrase::Error {
+			return Err(Error::InheritedPassphrase(var));
+		}
+		eprintln!(
+			"WARNING: {var} is set in the environment; not passing it to Borg for repositories whose passphrase Borgify manages"
+		);
+		any_warnings = true;
+	}
+
+	// If only showing encryption modes, do that and nothing else.
+	if options.show_encryption {
+		return show_encryption(&config, options);
+	}
+
+	// If only reporting which repositories need passphrases, do that and nothing else.
+	if options.passphrase_status {
+		return Ok(passphrase_status(&config, options));
+	}
+
+	// If only explaining how an archive would be backed up, do that and nothing else.
+	if let Some(name) = &options.explain {
+		return explain(&config, options, name);
+	}
+
+	// If only listing or deleting an archive’s leftover snapshots, do that and nothing else.
+	if let Some(name) = &options.snapshots {
+		return snapshots(&config, options, name, false);
+	}
+	if let Some(name) = &options.delete_snapshots {
+		return snapshots(&config, options, name, true);
+	}
+
+	// If only validating the setup without backing up, do that and nothing else.
+	if options.validate {
+		return validate(&config, options, any_warnings);
+	}
+
+	// If only pruning, do that and nothing else.
+	if options.prune {
+		return prune(&config, options);
+	}
+
+	// If only testing snapshots, do that and nothing else.
+	if options.test_snapshot {
+		return Ok(test_snapshots(&config));
+	}
+
+	// If only creating snapshots for something else to use, do that and nothing else.
+	if options.only_snapshot {
+		return Ok(only_snapshots(&config, options));
+	}
+
+	// Back up the live trees instead of snapshots if asked to.
+	if options.no_snapshot {
+		for (name, archive) in &mut config.archives {
+			if archive.enabled && archive.btrfs_snapshot {
+				eprintln!(
+					"WARNING: Archive {name}: not taking a snapshot because of --no-snapshot; the backup may be inconsistent if files change during it"
+				);
+				archive.btrfs_snapshot = false;
+				any_warnings = true;
+			}
+		}
+	}
+
+	// Back up, then run the completion command, if any, whatever the outcome.
+	if options.profile {
+		profile::enable();
+	}
+	let started = Instant::now();
+	let mut outcomes = Outcomes::default();
+	let result = back_up(options, &config, &mut outcomes).map(|warnings| any_warnings || warnings);
+	let archives = config.archives.values().filter(|i| i.enabled).count();
+	if let Some(command) = &config.on_complete {
+		run_on_complete(
+			command,
+			&result,
+			archives,
+			outcomes.ok + outcomes.warnings,
+			&config_hash,
+		);
+	}
+
+	// Sum up the run, which is worth doing even when quiet if it failed.
+	if !verbosity::quiet() || result.is_err() {
+		println!(
+			"{}",
+			summary(archives, started.elapsed(), outcomes, &config_hash)
+		);
+	}
+
+	// Account for the use of each repository, if asked to, which a script may want even when
+	// quiet, along with the configuration it was backed up with.
+	if options.json {
+		let output = serde_json::json!({
+			"config_hash": config_hash,
+			"repositories": usage::to_json(),
+		});
+		println!("{output}");
+	} else if verbosity::verbose() {
+		usage::print();
+	}
+	profile::print();
+	Ok(ExitCode::from(u8::from(result?)))
+}
+
+/// The number of archives backed up with each outcome.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct Outcomes {
+	/// The number of archives backed up without warnings.
+	ok: usize,
+
+	/// The number of archives backed up with warnings.
+	warnings: usize,
+
+	/// The number of archives that failed to be backed up.
+	failed: usize,
+
+	/// The number of archives not started because the deadline had passed.
+	skipped: usize,
+}
+
+/// Returns the line summing up a run that backed up some of `archives` enabled archives, with
+/// `outcomes`, in `elapsed` time, using the configuration whose hash is `config_hash`.
+///
+/// Archives with no outcome were not attempted, because an earlier failure stopped the run.
+fn summary(archives: usize, elapsed: Duration, outcomes: Outcomes, config_hash: &str) -> String {
+	let not_attempted =
+		archives - (outcomes.ok + outcomes.warnings + outcomes.failed + outcomes.skipped);
+	let counts: Vec<String> = [
+		(outcomes.ok, "ok"),
+		(outcomes.warnings, "with warnings"),
+		(outcomes.failed, "failed"),
+		(outcomes.skipped, "skipped at deadline"),
+		(not_attempted, "not attempted"),
+	]
+	.into_iter()
+	.filter(|&(count, _)| count != 0)
+	.map(|(count, outcome)| format!("{count} {outcome}"))
+	.collect();
+	format!(
+		"Finished {archives} archive{} in {}{}; config hash {config_hash}",
+		if archives == 1 { "" } else { "s" },
+		format_duration(elapsed),
+		if counts.is_empty() {
+			String::new()
+		} else {
+			format!(" ({})", counts.join(", "))
+		}
+	)
+}
+
+/// Finds any of Borg’s own directories within the archive roots, returning the paths to exclude,
+/// relative to the root, for each archive that has any and excludes them.
+fn borg_dir_excludes<'a>(
+	archives: &[(&'a str, &config::Archive<'_>)],
+) -> Result<HashMap<&'a str, Vec<PathBuf>>, Error> {
+	let borg_dirs: Vec<PathBuf> = backup::borg_dirs()
+		.into_iter()
+		.filter_map(|i| i.canonicalize().ok())
+		.collect();
+	let mut excludes = HashMap::new();
+	for &(name, archive) in archives {
+		if !archive.exclude_borg_dirs {
+			continue;
+		}
+		let root = archive
+			.root
+			.canonicalize()
+			.map_err(|e| Error::CheckArchiveRoot(archive.root.clone().into_owned(), e))?;
+		let archive_excludes = backup::excludes_within(&root, &borg_dirs);
+		if !archive_excludes.is_empty() {
+			excludes.insert(name, archive_excludes);
+		}
+	}
+	Ok(excludes)
+}
+
+/// Formats the run timestamp as written in each archive’s name, in the archive’s time zone.
+///
+/// Also returns whether any warnings were generated, which happens if a time zone cannot be used.
+fn name_timestamps<'a>(
+	archives: &[(&'a str, &config::Archive<'_>)],
+	timestamp: chrono::DateTime<chrono::Utc>,
+) -> (HashMap<&'a str, String>, bool) {
+	let mut any_warnings = false;
+	let timestamp_local = timestamp.with_timezone(&chrono::Local).fixed_offset();
+	let mut name_timestamps = HashMap::new();
+	for &(name, archive) in archives {
+		let time = match archive.timezone.as_deref() {
+			Some(zone) => timezone::convert(timestamp, zone).unwrap_or_else(|| {
+				eprintln!(
+					"WARNING: Cannot convert the time to time zone {zone} for archive {name}; using local time"
+				);
+				any_warnings = true;
+				timestamp_local
+			}),
+			None => timestamp_local,
+		};
+		name_timestamps.insert(name, format!("{}", time.format(&archive.name_date_format)));
+	}
+	(name_timestamps, any_warnings)
+}
+
+/// Backs up the enabled archives in a loaded config, counting in `outcomes` how each archive
+/// fared.
+///
+/// On success, returns whether any warnings were generated.
+fn back_up(
+	options: &cli::Options,
+	config: &config::Config<'_>,
+	outcomes: &mut Outcomes,
+) -> Result<bool, Error> {
+	let mut any_warnings = false;
+	let started = Instant::now();
+
+	// Disabled archives are skipped entirely from here on.
+	for name in config
+		.archives
+		.iter()
+		.filter_map(|(name, archive)| (!archive.enabled).then_some(name))
+	{
+		if !verbosity::quiet() {
+			println!("Archive {name}: skipped (disabled)");
+		}
+	}
+	let archives = backup_order(config);
+
+	// Make sure there is somewhere to put temporary files before asking for any passphrases, and
+	// point every program run from here on at it. No other threads exist yet, so changing the
+	// environment is safe.
+	let work_dir = config
+		.work_dir
+		.as_deref()
+		.map_or_else(std::env::temp_dir, Path::to_owned);
+	prepare_work_dir(&work_dir).map_err(|e| Error::WorkDir(work_dir.clone(), e))?;
+	std::env::set_var("TMPDIR", &work_dir);
 
 	// Check all the archives, collecting passwords for each one that needs one.
-	let passphrases: HashMap<&str, Option<String>> = {
-		let mut passphrases: HashMap<&str, Option<String>> = HashMap::new();
-		for archive in config.archives.values() {
-			if let Entry::Vacant(entry) = passphrases.entry(&archive.repository) {
-				entry.insert(check_repository_and_query_passphrase(
-					&archive.repository,
-					config.umask,
-				)?);
+	let passphrases: HashMap<&str, Option<Vec<u8>>> = {
+		let repositories = unique_repositories(&archives);
+		let mut checked = check_repositories_and_query_passphrases(
+			&repositories,
+			config,
+			options.check_jobs,
+			None,
+		)?;
+		if verbosity::verbose() {
+			for repository in &repositories {
+				println!(
+					"Repository {repository} uses encryption mode {}",
+					checked[repository].info.encryption_mode
+				);
 			}
 		}
-		passphrases
+		repositories
+			.into_iter()
+			.map(|i| (i, checked.remove(i).unwrap().passphrase))
+			.collect()
 	};
 
-	// Check that all the repository roots exist.
-	for archive in config.archives.values() {
-		check_archive_root(&archive.root)
-			.map_err(|e| Error::CheckArchiveRoot(archive.root.clone().into_owned(), e))?;
+	// Make sure Borg can be moved into the cgroup, if any.
+	let cgroup = config
+		.cgroup
+		.as_deref()
+		.map(|path| open_cgroup(path).map_err(|e| Error::Cgroup(path.to_owned(), e)))
+		.transpose()?;
+
+	// Check that all the archive roots are ready to be backed up.
+	for &(name, archive) in &archives {
+		any_warnings |= check_archive(name, archive, options)?;
 	}
+	profile::record_check(started.elapsed());
+
+	// Find any of Borg’s own directories within the archive roots, so they can be excluded.
+	let excludes = borg_dir_excludes(&archives)?;
+
+	// Connect to the progress consumer, if any. Progress reporting is not essential, so failing to
+	// connect only warrants a warning.
+	let progress = match &options.progress_socket {
+		Some(path) => match progress::Sink::open(path) {
+			Ok(sink) => Some(sink),
+			Err(e) => {
+				eprintln!("WARNING: Cannot send progress to {}: {e}", path.display());
+				any_warnings = true;
+				None
+			}
+		},
+		None => None,
+	};
 
 	// Run the backup processes.
-	let timestamp_utc = chrono::Utc::now();
-	let timestamp_local = timestamp_utc.with_timezone(&chrono::Local);
-	let timestamp_utc = format!("{}", timestamp_utc.format("%FT%T"));
-	let timestamp_local = format!("{}", timestamp_local.format("%FT%T"));
-	let mut any_warnings = false;
-	for (name, archive) in &config.archives {
-		println!("===== Backing up archive {name} =====");
-		any_warnings |= backup::run(
-			name,
-			archive,
-			&timestamp_utc,
-			&timestamp_local,
-			passphrases
-				.get(&*archive.repository)
-				.expect("passphrase missing from map, but we already examined every repository")
-				.as_deref(),
-			config.umask,
-		)
-		.map_err(|e| Error::Backup(name.clone().into_owned(), e))?;
-		println!();
+	let timestamp = options.timestamp.unwrap_or_else(chrono::Utc::now);
+	let timestamp_utc = format!("{}", timestamp.format("%FT%T"));
+	let (name_timestamps, timezone_warnings) = name_timestamps(&archives, timestamp);
+	any_warnings |= timezone_warnings;
+	let context = backup::Context {
+		timestamp_utc: &timestamp_utc,
+		timestamp_given: options.timestamp.is_some(),
+		name_timestamps: &name_timestamps,
+		passphrases: &passphrases,
+		umask: config.umask,
+		excludes: &excludes,
+		progress: progress.as_ref(),
+		repositories: &config.repositories,
+		cgroup: cgroup.as_ref(),
+		usage: options.json || verbosity::verbose(),
+	};
+
+	// Delete stale checkpoint archives left by interrupted backups. This is only housekeeping, so
+	// failing to do so only warrants a warning.
+	for &(name, archive) in &archives {
+		let Some(max_age) = archive
+			.checkpoint_max_age
+			.as_deref()
+			.and_then(config::interval_duration)
+		else {
+			continue;
+		};
+		for repository in &archive.repositories {
+			match backup::delete_stale_checkpoints(&context, name, archive, repository, max_age) {
+				Ok(warnings) => any_warnings |= warnings,
+				Err(e) => {
+					eprintln!(
+						"WARNING: Archive {name}: cannot delete stale checkpoint archives in repository {repository}: {e}"
+					);
+					any_warnings = true;
+				}
+			}
+		}
 	}
 
-	Ok(ExitCode::from(u8::from(any_warnings)))
+	// Group the archives by root directory and snapshot settings, so that archives backing up the
+	// same snapshotted root share a single snapshot taken at a single point in time.
+	type Group<'a> = (
+		(&'a Path, Option<&'a config::SnapshotOptions<'a>>),
+		Vec<(&'a str, &'a config::Archive<'a>)>,
+	);
+	let mut groups: Vec<Group<'_>> = Vec::new();
+	for &(name, archive) in &archives {
+		let key = (
+			&*archive.root,
+			archive.btrfs_snapshot.then_some(&archive.snapshot_options),
+		);
+		match groups.iter_mut().find(|(k, _)| *k == key) {
+			Some((_, members)) => members.push((name, archive)),
+			None => groups.push((key, vec![(name, archive)])),
+		}
+	}
+
+	// Once the deadline has passed, no more archives are started, and those remaining are reported.
+	let past_deadline = || options.deadline.is_some_and(|i| chrono::Utc::now() >= i);
+	let mut skipped: Vec<&str> = Vec::new();
+	for ((root, snapshot_options), members) in groups {
+		let names: Vec<&str> = members.iter().map(|&(name, _)| name).collect();
+		if past_deadline() {
+			skipped.extend(&names);
+			continue;
+		}
+		let opening = Instant::now();
+		let source = backup::Source::open(root, snapshot_options, &names)
+			.map_err(|e| Error::PrepareSource(root.to_owned(), e))?;
+		if snapshot_options.is_some() {
+			for name in &names {
+				profile::record(name, profile::Phase::SnapshotCreate, opening.elapsed());
+			}
+		}
+		any_warnings |= source.warnings();
+		let mut result = Ok(());
+		for (name, archive) in members {
+			if past_deadline() {
+				skipped.push(name);
+				continue;
+			}
+			if !verbosity::quiet() {
+				println!("===== Backing up archive {name} =====");
+			}
+			let started_at = chrono::Local::now();
+			let started = Instant::now();
+			let archive_result = backup::run(&context, name, archive, &source);
+			if !verbosity::quiet() {
+				println!(
+					"Archive {name} took {} (started {}, finished {})",
+					format_duration(started.elapsed()),
+					started_at.format("%FT%T"),
+					chrono::Local::now().format("%FT%T")
+				);
+			}
+			if let (Ok(warnings), Some(marker)) = (&archive_result, &archive.success_marker) {
+				if !warnings || archive.success_marker_on_warnings {
+					if let Err(e) = write_success_marker(marker, timestamp.into()) {
+						eprintln!(
+							"WARNING: Cannot write success marker {} for archive {name}: {e}",
+							marker.display()
+						);
+						any_warnings = true;
+					}
+				}
+			}
+			match archive_result {
+				Ok(false) => outcomes.ok += 1,
+				Ok(true) => {
+					any_warnings = true;
+					outcomes.warnings += 1;
+				}
+				Err(e) => {
+					outcomes.failed += 1;
+					result = Err(Error::Backup(name.to_owned(), e));
+					break;
+				}
+			}
+			if !verbosity::quiet() {
+				println!();
+			}
+		}
+
+		// Release the source, deleting any snapshot, even if a backup failed. If both failed, the
+		// error from doing the backup is more important. A snapshot that cannot be deleted may be
+		// configured to be left behind with only a warning.
+		let closing = Instant::now();
+		let snapshot_path = source.snapshot_path().map(Path::to_owned);
+		let release_result = match source.close() {
+			Err(e)
+				if snapshot_options
+					.is_some_and(|i| i.delete_failure == config::SnapshotDeleteFailure::Warn) =>
+			{
+				eprintln!(
+					"WARNING: Cannot delete snapshot {}, which has been left behind",
+					snapshot_path.as_deref().unwrap_or(root).display()
+				);
+				show_error_stack(&e, false);
+				any_warnings = true;
+				Ok(())
+			}
+			result => result.map_err(|e| Error::ReleaseSource(root.to_owned(), e)),
+		};
+		if snapshot_options.is_some() {
+			for name in &names {
+				profile::record(name, profile::Phase::SnapshotDelete, closing.elapsed());
+			}
+		}
+		result?;
+		release_result?;
+	}
+	if !skipped.is_empty() {
+		eprintln!(
+			"WARNING: The deadline passed before backing up archive{} {}",
+			if skipped.len() == 1 { "" } else { "s" },
+			skipped.join(", ")
+		);
+		outcomes.skipped = skipped.len();
+		any_warnings = true;
+	}
+
+	Ok(any_warnings)
+}
+
+/// Returns the enabled archives in the order to back them up: by priority, lowest first, and then
+/// by name.
+fn backup_order<'c>(config: &'c config::Config<'_>) -> Vec<(&'c str, &'c config::Archive<'c>)> {
+	let mut archives: Vec<(&str, &config::Archive<'_>)> = config
+		.archives
+		.iter()
+		.filter(|(_, archive)| archive.enabled)
+		.map(|(name, archive)| (&**name, archive))
+		.collect();
+	// The map is ordered by name and the sort is stable, so sorting by priority alone suffices.
+	archives.sort_by_key(|(_, archive)| archive.priority);
+	archives
+}
+
+/// Returns the environment variables describing the outcome of a run, to pass to the completion
+/// command.
+fn on_complete_env(
+	result: &Result<bool, Error>,
+	archives: usize,
+	succeeded: usize,
+	config_hash: &str,
+) -> Vec<(&'static str, String)> {
+	let (outcome, code) = match result {
+		Ok(false) => ("ok", 0),
+		Ok(true) => ("warnings", 1),
+		Err(_) => ("error", 2),
+	};
+	let mut env = vec![
+		("BORGIFY_RESULT", outcome.to_owned()),
+		("BORGIFY_EXIT_CODE", code.to_string()),
+		("BORGIFY_ARCHIVES", archives.to_string()),
+		("BORGIFY_SUCCEEDED", succeeded.to_string()),
+		("BORGIFY_CONFIG_HASH", config_hash.to_owned()),
+	];
+	if let Err(e) = result {
+		env.push(("BORGIFY_ERROR", e.to_string()));
+	}
+	env
+}
+
+/// Runs the completion command, reporting but otherwise ignoring any failure, so that it cannot
+/// change the outcome of the run.
+fn run_on_complete(
+	command: &[Cow<'_, str>],
+	result: &Result<bool, Error>,
+	archives: usize,
+	succeeded: usize,
+	config_hash: &str,
+) {
+	let (program, args) = command
+		.split_first()
+		.expect("on_complete was checked to be nonempty when the config was loaded");
+	let status = Command::new(&**program)
+		.args(args.iter().map(|i| &**i))
+		.envs(on_complete_env(result, archives, succeeded, config_hash))
+		.stdin(Stdio::null())
+		.status();
+	match status {
+		Ok(status) if status.success() => (),
+		Ok(status) => eprintln!("WARNING: on_complete command {program} failed: {status}"),
+		Err(e) => eprintln!("WARNING: Cannot run on_complete command {program}: {e}"),
+	}
+}
+
+/// Prints an error and its chain of sources on standard error in human-readable form.
+fn show_error_stack(e: &(dyn std::error::Error + 'static), first: bool) {
+	eprintln!("{}{e}", if first { "" } else { "caused by: " });
+	if let Some(source) = e.source() {
+		show_error_stack(source, false);
+	}
+}
+
+/// Reports a fatal error on standard error, either as human-readable text or as JSON.
+fn report_error(e: &Error, json: bool) {
+	if json {
+		eprintln!("{}", json_error::to_json(e));
+	} else {
+		show_error_stack(e, true);
+	}
 }
 
 fn main() -> ExitCode {
-	match run() {
+	let args: Vec<OsString> = std::env::args_os().skip(1).collect();
+	let options = match cli::parse(args.iter().cloned()) {
+		Ok(options) => options,
+		Err(e) => {
+			// The command line could not be parsed, so look for the JSON option by hand.
+			report_error(
+				&Error::CommandLine(e),
+				args.iter().any(|i| i == "--json-errors"),
+			);
+			return 2.into();
+		}
+	};
+	match run(&options) {
 		Ok(code) => code,
 		Err(e) => {
-			fn show_error_stack(e: &(dyn std::error::Error + 'static), first: bool) {
-				eprintln!("{}{e}", if first { "" } else { "caused by: " });
-				if let Some(source) = e.source() {
-					show_error_stack(source, false);
-				}
-			}
-			show_error_stack(&e, true);
+			report_error(&e, options.json_errors);
 			2.into()
 		}
 	}
 }
+
+/// Tests formatting durations of various magnitudes.
+#[test]
+fn test_format_duration() {
+	assert_eq!(format_duration(Duration::from_millis(6_700)), "6.7s");
+	assert_eq!(format_duration(Duration::from_secs(245)), "4m05s");
+	assert_eq!(format_duration(Duration::from_secs(3_723)), "1h02m03s");
+}
+
+/// Tests summing up a run.
+#[test]
+fn test_summary() {
+	let outcomes = Outcomes {
+		ok: 3,
+		warnings: 1,
+		failed: 1,
+		skipped: 0,
+	};
+	assert_eq!(
+		summary(7, Duration::from_secs(754), outcomes, "0123"),
+		"Finished 7 archives in 12m34s (3 ok, 1 with warnings, 1 failed, 2 not attempted); config hash 0123"
+	);
+	let outcomes = Outcomes {
+		ok: 2,
+		skipped: 2,
+		..Outcomes::default()
+	};
+	assert_eq!(
+		summary(4, Duration::from_secs(60), outcomes, "0123"),
+		"Finished 4 archives in 1m00s (2 ok, 2 skipped at deadline); config hash 0123"
+	);
+	let outcomes = Outcomes {
+		ok: 1,
+		..Outcomes::default()
+	};
+	assert_eq!(
+		summary(1, Duration::from_millis(2_500), outcomes, "0123"),
+		"Finished 1 archive in 2.5s (1 ok); config hash 0123"
+	);
+	assert_eq!(
+		summary(0, Duration::ZERO, Outcomes::default(), "0123"),
+		"Finished 0 archives in 0.0s; config hash 0123"
+	);
+}
+
+/// Tests choosing the config file from the command line, the environment, or the default, in that
+/// order of precedence.
+#[test]
+fn test_config_path() {
+	let env = || Some(OsString::from("/env.json"));
+	assert_eq!(
+		config_path(Some(Path::new("/cli.json")), env()),
+		Path::new("/cli.json")
+	);
+	assert_eq!(config_path(None, env()), Path::new("/env.json"));
+	assert_eq!(
+		config_path(None, Some(OsString::new())),
+		Path::new(DEFAULT_CONFIG_PATH)
+	);
+	assert_eq!(config_path(None, None), Path::new(DEFAULT_CONFIG_PATH));
+}
+
+/// Tests detecting a config file accessible by group or other users.
+#[test]
+fn test_unsafe_permissions() {
+	let dir = temp_dir::TempDir::new("perms");
+	let path = dir.join("borgify.json");
+	std::fs::write(&path, b"{}").unwrap();
+	std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+	let safe = unsafe_permissions(&path);
+	std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+	let readable = unsafe_permissions(&path);
+	std::fs::remove_file(&path).unwrap();
+	assert_eq!(safe, None);
+	assert_eq!(readable, Some(0o644));
+	assert_eq!(unsafe_permissions(&path), None);
+}
+
+/// Tests creating a work directory, and rejecting one that is not a directory.
+#[test]
+fn test_prepare_work_dir() {
+	let dir = temp_dir::TempDir::new("work");
+	let path = dir.join("work");
+	prepare_work_dir(&path).unwrap();
+	let mode = std::fs::metadata(&path).unwrap().permissions().mode() & 0o7777;
+	prepare_work_dir(&path).unwrap();
+	std::fs::remove_dir(&path).unwrap();
+	std::fs::write(&path, b"").unwrap();
+	let file = prepare_work_dir(&path);
+	assert_eq!(mode & 0o077, 0);
+	assert_eq!(file.unwrap_err().raw_os_error(), Some(libc::ENOTDIR));
+}
+
+/// Tests writing a success marker with a given modification time, replacing any existing one.
+#[test]
+fn test_write_success_marker() {
+	let dir = temp_dir::TempDir::new("marker");
+	let path = dir.join("marker");
+	std::fs::write(&path, b"old").unwrap();
+	let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+	write_success_marker(&path, time).unwrap();
+	let metadata = std::fs::metadata(&path).unwrap();
+	assert_eq!(metadata.len(), 0);
+	assert_eq!(metadata.modified().unwrap(), time);
+	assert!(write_success_marker(&path.join("missing/marker"), time).is_err());
+}
+
+/// Tests ordering archives for backing up by priority and then by name, skipping disabled ones.
+#[test]
+fn test_backup_order() {
+	const INPUT: &[u8] = br#"
+		{
+			"defaults": {"compression": "lz4", "repository": "/r"},
+			"archives": {
+				"a": {"root": "/a"},
+				"b": {"root": "/b", "priority": -1},
+				"c": {"root": "/c", "priority": 0},
+				"d": {"root": "/d", "priority": -5, "enabled": false},
+				"e": {"root": "/e", "priority": 2},
+				"f": {"root": "/f", "priority": -1}
+			}
+		}"#;
+	let config = serde_json::from_slice::<config::Config>(INPUT).unwrap();
+	let order: Vec<&str> = backup_order(&config)
+		.into_iter()
+		.map(|(name, _)| name)
+		.collect();
+	assert_eq!(order, ["b", "f", "a", "c", "e"]);
+}
+
+/// Tests detecting mount points.
+#[test]
+fn test_is_mount_point() {
+	assert!(is_mount_point(Path::new("/")).unwrap());
+	let dir = temp_dir::TempDir::new("mount");
+	assert!(!is_mount_point(dir.path()).unwrap());
+}
+
+/// Tests detecting empty directories.
+#[test]
+fn test_is_empty_dir() {
+	let dir = temp_dir::TempDir::new("empty");
+	assert!(is_empty_dir(dir.path()).unwrap());
+	std::fs::write(dir.join("file"), b"").unwrap();
+	assert!(!is_empty_dir(dir.path()).unwrap());
+}
+
+/// Tests that a parallel map preserves the order of its results.
+#[test]
+fn test_parallel_map() {
+	let items: Vec<u32> = (0..20).collect();
+	let expected: Vec<u32> = items.iter().map(|i| i * 2).collect();
+	assert_eq!(parallel_map(&items, 1, |i| i * 2), expected);
+	assert_eq!(parallel_map(&items, 4, |i| i * 2), expected);
+	assert_eq!(parallel_map(&items, 100, |i| i * 2), expected);
+	assert!(parallel_map(&[] as &[u32], 4, |i| i * 2).is_empty());
+}
+
+/// Tests picking out the literal paths of include patterns.
+#[test]
+fn test_literal_includes() {
+	let patterns: Vec<Cow<'_, str>> = [
+		"+ home/alice",
+		"+ sh:home/*/notes",
+		"- home/alice/.cache",
+		"+ /etc/fstab",
+		"+ re:^var/log",
+		"+ fm:srv/[ab]",
+		"P pp",
+		"+ srv/www/*",
+		"+ sh:opt",
+		"+ pp:/",
+		"R /",
+	]
+	.into_iter()
+	.map(Cow::Borrowed)
+	.collect();
+	assert_eq!(
+		literal_includes(&patterns),
+		["home/alice", "etc/fstab", "srv/www/*", "opt"]
+	);
+}
+
+/// Tests the passphrase prompts for repositories with and without passphrase IDs.
+#[test]
+fn test_passphrase_prompt() {
+	assert_eq!(
+		passphrase_prompt(None, "/r", &[]),
+		"Passphrase for repository /r:"
+	);
+	assert_eq!(
+		passphrase_prompt(Some("usb"), "/r", &["/r"]),
+		"Passphrase usb for repository /r:"
+	);
+	assert_eq!(
+		passphrase_prompt(Some("usb"), "/r", &["/r", "host:r"]),
+		"Passphrase usb for repositories /r, host:r:"
+	);
+}
+
+/// Tests splitting a passphrase file into candidates.
+#[test]
+fn test_passphrase_candidates() {
+	assert_eq!(passphrase_candidates(b"only\n"), [b"only"]);
+	assert_eq!(passphrase_candidates(b"only"), [b"only"]);
+	assert_eq!(
+		passphrase_candidates(b"new pass\n\nold pass\n"),
+		[&b"new pass"[..], b"old pass"]
+	);
+	assert!(passphrase_candidates(b"\n").is_empty());
+	assert_eq!(
+		passphrase_candidates(b"new pass\r\n\r\nold pass\r\n"),
+		[&b"new pass"[..], b"old pass"]
+	);
+	assert!(passphrase_candidates(b"\r\n").is_empty());
+}
+
+/// Tests describing the outcome of a run to the completion command.
+#[test]
+fn test_on_complete_env() {
+	assert_eq!(
+		on_complete_env(&Ok(true), 3, 3, "abc"),
+		[
+			("BORGIFY_RESULT", "warnings".to_owned()),
+			("BORGIFY_EXIT_CODE", "1".to_owned()),
+			("BORGIFY_ARCHIVES", "3".to_owned()),
+			("BORGIFY_SUCCEEDED", "3".to_owned()),
+			("BORGIFY_CONFIG_HASH", "abc".to_owned()),
+		]
+	);
+	let env = on_complete_env(&Err(Error::UnknownArchive("foo".to_owned())), 2, 0, "abc");
+	assert_eq!(env[0], ("BORGIFY_RESULT", "error".to_owned()));
+	assert_eq!(
+		env.last().unwrap(),
+		&(
+			"BORGIFY_ERROR",
+			"archive foo is not defined in the config file".to_owned()
+		)
+	);
+}
+
+/// Tests naming the source of a repository’s passphrase and checking whether it is available.
+#[test]
+fn test_passphrase_source() {
+	let dir = temp_dir::TempDir::new("source");
+	let path = dir.join("passphrase");
+	std::fs::write(&path, b"secret\n").unwrap();
+	let file = config::Repository {
+		passphrase_file: Some(path.clone().into()),
+		..config::Repository::default()
+	};
+	assert_eq!(passphrase_source(Some(&file)), ("file", Some(true)));
+	std::fs::remove_file(&path).unwrap();
+	assert_eq!(passphrase_source(Some(&file)), ("file", Some(false)));
+	let borg = config::Repository {
+		manage_passphrase: false,
+		..config::Repository::default()
+	};
+	assert_eq!(passphrase_source(Some(&borg)), ("borg", None));
+	assert_eq!(
+		passphrase_source(Some(&config::Repository::default())),
+		("prompt", None)
+	);
+	assert_eq!(passphrase_source(None), ("prompt", None));
+}
+
+/// Tests listing the distinct repositories of several archives in order of first use.
+#[test]
+fn test_unique_repositories() {
+	let foo = config::Archive {
+		repositories: vec!["/b".into(), "/a".into()],
+		..config::Archive::default()
+	};
+	let bar = config::Archive {
+		repositories: vec!["/a".into(), "/c".into()],
+		..config::Archive::default()
+	};
+	assert_eq!(
+		unique_repositories(&[("foo", &foo), ("bar", &bar)]),
+		["/b", "/a", "/c"]
+	);
+}
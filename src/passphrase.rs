@@ -1,13 +1,20 @@
 //! Support for reading a passphrase from the terminal with echoing disabled.
 
 use nix::libc::{self, fcntl};
+#[cfg(feature = "libbsd")]
 use std::ffi::{c_char, c_int, CString};
 use std::io::Write as _;
 use std::os::unix::io::{AsFd as _, AsRawFd as _};
 
 /// Fail if there is no tty.
+#[cfg(feature = "libbsd")]
 const RPP_REQUIRE_TTY: c_int = 0x02;
 
+/// The length, in bytes, of the longest passphrase that can be read.
+#[cfg(feature = "libbsd")]
+const MAX_LEN: usize = 8192;
+
+#[cfg(feature = "libbsd")]
 #[link(name = ":libbsd.so.0")]
 extern "C" {
 	fn readpassphrase(
@@ -18,13 +25,18 @@ extern "C" {
 	) -> *mut c_char;
 }
 
-/// Reads a passphrase from the terminal.
+/// Reads a passphrase from the terminal, using libbsd’s `readpassphrase`.
+///
+/// The passphrase is returned as raw bytes, since it need not be valid UTF-8.
 ///
 /// # Panics
 /// This function panics of `prompt` contains an embedded NUL.
-pub fn read(prompt: &str) -> std::io::Result<String> {
+#[cfg(feature = "libbsd")]
+pub fn read(prompt: &str) -> std::io::Result<Vec<u8>> {
 	let prompt = CString::new(prompt).expect("prompt contains embedded NUL");
-	let mut buffer = vec![0_u8; 1024];
+	// Leave room for one byte beyond the maximum as well as the NUL, so that a passphrase which
+	// readpassphrase() silently truncated can be detected.
+	let mut buffer = vec![0_u8; MAX_LEN + 2];
 	// SAFETY: Prompt is a valid CString. Buffer and its length are passed properly.
 	let ret = unsafe {
 		readpassphrase(
@@ -37,24 +49,100 @@ pub fn read(prompt: &str) -> std::io::Result<String> {
 	if ret.is_null() {
 		Err(std::io::Error::last_os_error())
 	} else {
-		// Panic-soundness: readpassphrase(), on success, promises to write a NUL into the buffer.
-		let nul_pos = buffer
-			.iter()
-			.position(|&b| b == 0)
-			.expect("readpassphrase() did not write NUL into buffer");
-		buffer.resize(nul_pos, 0_u8);
-		String::from_utf8(buffer)
-			.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+		from_buffer(buffer)
+	}
+}
+
+/// Extracts the passphrase from the buffer filled in by `readpassphrase`, failing if it filled the
+/// whole buffer and therefore may have truncated the passphrase.
+#[cfg(feature = "libbsd")]
+fn from_buffer(mut buffer: Vec<u8>) -> std::io::Result<Vec<u8>> {
+	// Panic-soundness: readpassphrase(), on success, promises to write a NUL into the buffer.
+	let nul_pos = buffer
+		.iter()
+		.position(|&b| b == 0)
+		.expect("readpassphrase() did not write NUL into buffer");
+	if nul_pos > MAX_LEN {
+		buffer.fill(0);
+		return Err(std::io::Error::new(
+			std::io::ErrorKind::InvalidInput,
+			format!("passphrase is longer than the maximum of {MAX_LEN} bytes"),
+		));
+	}
+	buffer.truncate(nul_pos);
+	Ok(buffer)
+}
+
+/// Removes the line terminator, and anything after it, from a line read from the terminal, as
+/// `readpassphrase` does.
+#[cfg_attr(feature = "libbsd", allow(dead_code))]
+fn trim_line(mut line: Vec<u8>) -> Vec<u8> {
+	if let Some(pos) = line.iter().position(|&b| b == b'\n' || b == b'\r') {
+		line.truncate(pos);
+	}
+	line
+}
+
+/// Reads a passphrase from the terminal, disabling echo with termios directly.
+///
+/// The passphrase is returned as raw bytes, since it need not be valid UTF-8. Like
+/// `readpassphrase` with `RPP_REQUIRE_TTY`, this fails if there is no controlling terminal, rather
+/// than reading from standard input.
+#[cfg(not(feature = "libbsd"))]
+pub fn read(prompt: &str) -> std::io::Result<Vec<u8>> {
+	use std::io::Read as _;
+	let mut tty = std::fs::File::options()
+		.read(true)
+		.write(true)
+		.open("/dev/tty")?;
+	let fd = tty.as_raw_fd();
+	let mut original = std::mem::MaybeUninit::<libc::termios>::uninit();
+	// SAFETY: fd is a valid file descriptor, and original points to space for a termios.
+	if unsafe { libc::tcgetattr(fd, original.as_mut_ptr()) } < 0 {
+		return Err(std::io::Error::last_os_error());
+	}
+	// SAFETY: On success, tcgetattr() promises to fill the termios.
+	let original = unsafe { original.assume_init() };
+	let mut silent = original;
+	silent.c_lflag &= !libc::ECHO;
+	silent.c_lflag |= libc::ECHONL;
+	tty.write_all(prompt.as_bytes())?;
+	// SAFETY: fd is a valid file descriptor, and silent is a valid termios.
+	if unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &silent) } < 0 {
+		return Err(std::io::Error::last_os_error());
 	}
+	let mut line = Vec::new();
+	let mut byte = [0_u8];
+	let result = loop {
+		match tty.read(&mut byte) {
+			Ok(0) => break Ok(()),
+			Ok(_) if byte[0] == b'\n' => break Ok(()),
+			Ok(_) => line.push(byte[0]),
+			Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+			Err(e) => break Err(e),
+		}
+	};
+	// SAFETY: fd is a valid file descriptor, and original is the termios read from it earlier.
+	let restore = unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &original) };
+	result?;
+	if restore < 0 {
+		return Err(std::io::Error::last_os_error());
+	}
+	Ok(trim_line(line))
 }
 
+/// The environment variables from which Borg takes a passphrase in preference to the pipe named by
+/// `BORG_PASSPHRASE_FD`, and which are therefore removed from Borg’s environment whenever Borgify
+/// passes it a passphrase.
+pub const OVERRIDING_ENV: [&str; 2] = ["BORG_PASSPHRASE", "BORG_PASSCOMMAND"];
+
 /// Creates an inheritable pipe with a passphrase inside it.
-pub fn send_to_inheritable_pipe(passphrase: &str) -> std::io::Result<os_pipe::PipeReader> {
+pub fn send_to_inheritable_pipe(passphrase: &[u8]) -> std::io::Result<os_pipe::PipeReader> {
 	// Create the pipe.
 	let (reader, mut writer) = os_pipe::pipe()?;
 
 	// Write the passphrase into the writer end.
-	writer.write_all(passphrase.as_bytes())?;
+	writer.write_all(passphrase)?;
 
 	// Make the reader end inheritable.
 	let fd = reader.as_fd().as_raw_fd();
@@ -75,9 +163,44 @@ pub fn send_to_inheritable_pipe(passphrase: &str) -> std::io::Result<os_pipe::Pi
 #[test]
 fn test_send_to_inheritable_pipe() {
 	use std::io::Read as _;
-	const PASSPHRASE: &'static str = "hello world";
+	const PASSPHRASE: &[u8] = b"hello world";
+	let mut reader = send_to_inheritable_pipe(PASSPHRASE).expect("send_to_inheritable_pipe failed");
+	let mut buffer = vec![];
+	reader.read_to_end(&mut buffer).expect("read failed");
+	assert_eq!(buffer, PASSPHRASE);
+}
+
+/// Tests sending a passphrase which is not valid UTF-8 to a pipe.
+#[test]
+fn test_send_to_inheritable_pipe_non_utf8() {
+	use std::io::Read as _;
+	const PASSPHRASE: &[u8] = b"caf\xe9 \xff\x00\x80";
 	let mut reader = send_to_inheritable_pipe(PASSPHRASE).expect("send_to_inheritable_pipe failed");
 	let mut buffer = vec![];
-	let actual = reader.read_to_end(&mut buffer).expect("read failed");
-	assert_eq!(buffer, PASSPHRASE.as_bytes());
+	reader.read_to_end(&mut buffer).expect("read failed");
+	assert_eq!(buffer, PASSPHRASE);
+}
+
+/// Tests removing the line terminator from a line read from the terminal.
+#[test]
+fn test_trim_line() {
+	assert_eq!(trim_line(b"secret".to_vec()), b"secret");
+	assert_eq!(trim_line(b"secret\n".to_vec()), b"secret");
+	assert_eq!(trim_line(b"secret\r\n".to_vec()), b"secret");
+	assert_eq!(trim_line(b"".to_vec()), b"");
+}
+
+/// Tests extracting passphrases of the maximum length and longer from a `readpassphrase` buffer.
+#[cfg(feature = "libbsd")]
+#[test]
+fn test_from_buffer() {
+	let mut buffer = vec![b'x'; MAX_LEN + 2];
+	buffer[MAX_LEN] = 0;
+	assert_eq!(from_buffer(buffer.clone()).unwrap(), vec![b'x'; MAX_LEN]);
+	buffer[MAX_LEN] = b'x';
+	buffer[MAX_LEN + 1] = 0;
+	assert_eq!(
+		from_buffer(buffer).unwrap_err().kind(),
+		std::io::ErrorKind::InvalidInput
+	);
 }
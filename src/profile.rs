@@ -0,0 +1,171 @@
+//! Optional recording of how long each phase of a run takes, for `--profile`.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A phase of backing up an archive whose duration is recorded.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Phase {
+	/// Creating the btrfs snapshot of the archive root, including looking for nested subvolumes.
+	SnapshotCreate,
+
+	/// Running `borg create`, summed over all the archive’s repositories.
+	Create,
+
+	/// Verifying the created archive, summed over all the archive’s repositories.
+	Verify,
+
+	/// Flushing repositories to disk, summed over all the archive’s repositories.
+	Sync,
+
+	/// Deleting the btrfs snapshot of the archive root.
+	SnapshotDelete,
+}
+
+impl Phase {
+	/// All the phases, in the order they happen and are printed.
+	const ALL: [Self; 5] = [
+		Self::SnapshotCreate,
+		Self::Create,
+		Self::Verify,
+		Self::Sync,
+		Self::SnapshotDelete,
+	];
+
+	/// Returns the column heading for the phase.
+	fn heading(self) -> &'static str {
+		match self {
+			Self::SnapshotCreate => "snapshot",
+			Self::Create => "create",
+			Self::Verify => "verify",
+			Self::Sync => "sync",
+			Self::SnapshotDelete => "delete",
+		}
+	}
+}
+
+/// The durations recorded so far.
+#[derive(Debug, Default, Eq, PartialEq)]
+struct Profile {
+	/// The time spent checking repositories and archive roots before backing up.
+	check: Duration,
+
+	/// The time spent in each phase, for each archive, in the order the archives were first seen.
+	archives: Vec<(String, [Option<Duration>; Phase::ALL.len()])>,
+}
+
+impl Profile {
+	/// Adds the time spent by an archive in a phase.
+	fn add(&mut self, archive: &str, phase: Phase, duration: Duration) {
+		let index = self.archives.iter().position(|(name, _)| name == archive);
+		let index = index.unwrap_or_else(|| {
+			self.archives
+				.push((archive.to_owned(), [None; Phase::ALL.len()]));
+			self.archives.len() - 1
+		});
+		let slot = &mut self.archives[index].1[phase as usize];
+		*slot = Some(slot.unwrap_or_default() + duration);
+	}
+
+	/// Formats the recorded durations as a table, with a column per phase and a row per archive.
+	fn table(&self) -> String {
+		let headings = std::iter::once("archive").chain(Phase::ALL.into_iter().map(Phase::heading));
+		let rows = self.archives.iter().map(|(name, durations)| {
+			std::iter::once(name.clone())
+				.chain(
+					durations
+						.iter()
+						.map(|i| i.map_or_else(|| "-".to_owned(), super::format_duration)),
+				)
+				.collect::<Vec<String>>()
+		});
+		let rows: Vec<Vec<String>> = std::iter::once(headings.map(str::to_owned).collect())
+			.chain(rows)
+			.collect();
+		let widths: Vec<usize> = (0..rows[0].len())
+			.map(|column| {
+				rows.iter()
+					.map(|row| row[column].chars().count())
+					.max()
+					.unwrap_or(0)
+			})
+			.collect();
+		let mut table = format!("checks: {}\n", super::format_duration(self.check));
+		for row in rows {
+			let cells: Vec<String> = row
+				.iter()
+				.zip(&widths)
+				.enumerate()
+				.map(|(column, (cell, &width))| {
+					if column == 0 {
+						format!("{cell:<width$}")
+					} else {
+						format!("{cell:>width$}")
+					}
+				})
+				.collect();
+			table.push_str(cells.join("  ").trim_end());
+			table.push('\n');
+		}
+		table
+	}
+}
+
+/// The durations recorded so far, or `None` if profiling is not enabled.
+static PROFILE: Mutex<Option<Profile>> = Mutex::new(None);
+
+/// Starts recording durations.
+pub fn enable() {
+	*PROFILE.lock().unwrap() = Some(Profile::default());
+}
+
+/// Records the time spent checking repositories and archive roots, if profiling is enabled.
+pub fn record_check(duration: Duration) {
+	if let Some(profile) = &mut *PROFILE.lock().unwrap() {
+		profile.check += duration;
+	}
+}
+
+/// Records time spent by an archive in a phase, if profiling is enabled.
+pub fn record(archive: &str, phase: Phase, duration: Duration) {
+	if let Some(profile) = &mut *PROFILE.lock().unwrap() {
+		profile.add(archive, phase, duration);
+	}
+}
+
+/// Runs `f`, recording the time it takes as spent by an archive in a phase, if profiling is
+/// enabled.
+pub fn time<T>(archive: &str, phase: Phase, f: impl FnOnce() -> T) -> T {
+	let started = Instant::now();
+	let result = f();
+	record(archive, phase, started.elapsed());
+	result
+}
+
+/// Prints the table of recorded durations, if profiling is enabled.
+pub fn print() {
+	if let Some(profile) = &*PROFILE.lock().unwrap() {
+		print!("{}", profile.table());
+	}
+}
+
+/// Tests accumulating durations and formatting them as a table.
+#[test]
+fn test_table() {
+	let mut profile = Profile {
+		check: Duration::from_millis(1_200),
+		..Profile::default()
+	};
+	profile.add("home", Phase::SnapshotCreate, Duration::from_millis(300));
+	profile.add("home", Phase::Create, Duration::from_secs(200));
+	profile.add("home", Phase::Create, Duration::from_secs(45));
+	profile.add("etc", Phase::Create, Duration::from_millis(2_500));
+	assert_eq!(
+		profile.table(),
+		"checks: 1.2s
+archive  snapshot  create  verify  sync  delete
+home         0.3s   4m05s       -     -       -
+etc             -    2.5s       -     -       -
+"
+	);
+}
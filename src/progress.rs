@@ -0,0 +1,173 @@
+//! Forwarding of Borg’s progress reports to a Unix socket or named pipe, for consumption by
+//! another program.
+
+use nix::libc;
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::{FileTypeExt as _, OpenOptionsExt as _};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+/// The state of a connection to a progress consumer.
+struct State {
+	/// The connection, or `None` if the consumer has disconnected.
+	writer: Option<Box<dyn Write>>,
+
+	/// The part of the most recent report that has not yet been written.
+	pending: Vec<u8>,
+}
+
+/// A destination for progress reports, written as newline-delimited JSON.
+///
+/// Writes never block: if the consumer falls behind, reports are dropped rather than holding up
+/// the backup, and a report is only ever dropped as a whole, so every line received is complete.
+/// If the consumer disconnects, reporting stops, but the backup carries on.
+pub struct Sink {
+	/// The path of the socket or named pipe.
+	path: PathBuf,
+
+	/// The connection.
+	state: RefCell<State>,
+}
+
+impl Sink {
+	/// Connects to a Unix stream socket, or opens a named pipe, at `path`.
+	///
+	/// A named pipe must already be open for reading by the consumer.
+	pub fn open(path: &Path) -> std::io::Result<Self> {
+		let file_type = std::fs::metadata(path)?.file_type();
+		let writer: Box<dyn Write> = if file_type.is_socket() {
+			let stream = UnixStream::connect(path)?;
+			stream.set_nonblocking(true)?;
+			Box::new(stream)
+		} else if file_type.is_fifo() {
+			// Opening a named pipe for writing without a reader fails with ENXIO when nonblocking,
+			// rather than waiting for one to arrive.
+			match OpenOptions::new()
+				.write(true)
+				.custom_flags(libc::O_NONBLOCK)
+				.open(path)
+			{
+				Ok(file) => Box::new(file),
+				Err(e) if e.raw_os_error() == Some(libc::ENXIO) => {
+					return Err(std::io::Error::new(
+						ErrorKind::NotConnected,
+						"no process has the named pipe open for reading",
+					))
+				}
+				Err(e) => return Err(e),
+			}
+		} else {
+			return Err(std::io::Error::new(
+				ErrorKind::InvalidInput,
+				"not a socket or named pipe",
+			));
+		};
+		Ok(Self::new(path.to_owned(), writer))
+	}
+
+	/// Wraps an already-open, nonblocking connection.
+	fn new(path: PathBuf, writer: Box<dyn Write>) -> Self {
+		Self {
+			path,
+			state: RefCell::new(State {
+				writer: Some(writer),
+				pending: Vec::new(),
+			}),
+		}
+	}
+
+	/// Sends a progress report, given as the JSON line Borg printed, adding the name of the archive
+	/// and the repository being backed up to.
+	///
+	/// The report is dropped if the consumer has not yet accepted all of the previous one.
+	pub fn send(&self, line: &str, archive: &str, repository: &str) {
+		let mut state = self.state.borrow_mut();
+		let State { writer, pending } = &mut *state;
+		let Some(stream) = writer else {
+			return;
+		};
+		if pending.is_empty() {
+			let Ok(mut report) = serde_json::from_str::<Map<String, Value>>(line) else {
+				return;
+			};
+			report.insert("archive".to_owned(), archive.into());
+			report.insert("repository".to_owned(), repository.into());
+			serde_json::to_writer(&mut *pending, &report)
+				.expect("serializing a JSON map to a Vec cannot fail");
+			pending.push(b'\n');
+		}
+		while !pending.is_empty() {
+			match stream.write(pending) {
+				Ok(0) => {
+					self.disconnected(writer, ErrorKind::WriteZero.into());
+					return;
+				}
+				Ok(n) => {
+					pending.drain(..n);
+				}
+				Err(e) if e.kind() == ErrorKind::Interrupted => (),
+				Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+				Err(e) => {
+					self.disconnected(writer, e);
+					return;
+				}
+			}
+		}
+	}
+
+	/// Stops sending reports after the consumer has gone away.
+	fn disconnected(&self, writer: &mut Option<Box<dyn Write>>, error: std::io::Error) {
+		*writer = None;
+		if !super::verbosity::quiet() {
+			eprintln!(
+				"Stopped sending progress to {}: {error}",
+				self.path.display()
+			);
+		}
+	}
+}
+
+/// Tests that reports arrive as complete JSON lines with the archive and repository added, and
+/// that a consumer disconnecting is tolerated.
+#[test]
+fn test_send() {
+	use std::io::{BufRead as _, BufReader};
+	let (ours, theirs) = UnixStream::pair().unwrap();
+	ours.set_nonblocking(true).unwrap();
+	let sink = Sink::new(PathBuf::from("test"), Box::new(ours));
+	sink.send(
+		r#"{"type": "progress_percent", "current": 1, "total": 4}"#,
+		"home",
+		"/backup",
+	);
+	sink.send("not json", "home", "/backup");
+	let mut reader = BufReader::new(theirs);
+	let mut line = String::new();
+	reader.read_line(&mut line).unwrap();
+	let report: Value = serde_json::from_str(&line).unwrap();
+	assert_eq!(
+		report,
+		serde_json::json!({"type": "progress_percent", "current": 1, "total": 4, "archive": "home", "repository": "/backup"})
+	);
+	drop(reader);
+	sink.send(r#"{"type": "progress_percent"}"#, "home", "/backup");
+	assert!(sink.state.borrow().writer.is_none());
+}
+
+/// Tests that opening a named pipe with no reader fails rather than blocking.
+#[test]
+fn test_open_fifo_without_reader() {
+	use std::os::unix::ffi::OsStrExt as _;
+	let dir = super::temp_dir::TempDir::new("progress-fifo");
+	let path = dir.join("fifo");
+	let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+	// SAFETY: c_path is a valid NUL-terminated string.
+	assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+	assert_eq!(
+		Sink::open(&path).err().unwrap().kind(),
+		ErrorKind::NotConnected
+	);
+}
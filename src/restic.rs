@@ -0,0 +1,454 @@
+//! Support for restic repositories, as an alternative to Borg.
+//!
+//! Everything around the backup itself, such as snapshots, passphrases, and the order of archives,
+//! is shared with Borg; only the restic command lines and the reading of restic’s output differ.
+
+use super::backup::{self, Context};
+use super::check;
+use super::command_line::CommandLine;
+use super::config::{self, BorgEnv};
+use super::tail::Tail;
+use super::usage;
+use serde::Deserialize;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::io::{AsFd as _, AsRawFd as _};
+use std::os::unix::process::ExitStatusExt as _;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// The environment variables from which restic takes a password in preference to, or in conflict
+/// with, the file named by `RESTIC_PASSWORD_FILE`, and which are therefore removed from restic’s
+/// environment whenever Borgify passes it a password.
+const OVERRIDING_ENV: [&str; 2] = ["RESTIC_PASSWORD", "RESTIC_PASSWORD_COMMAND"];
+
+/// The exit code with which restic 0.17 and later report an incorrect password.
+const WRONG_PASSWORD_CODE: i32 = 12;
+
+/// The message with which restic reports an incorrect password, for versions before 0.17, which
+/// have no dedicated exit code.
+const WRONG_PASSWORD_MESSAGE: &str = "wrong password or no key found";
+
+/// The exit code with which `restic backup` reports that it created a snapshot, but could not
+/// read some of the files.
+const INCOMPLETE_CODE: i32 = 3;
+
+/// The encryption mode reported for restic repositories, which are always encrypted.
+const ENCRYPTION_MODE: &str = "restic";
+
+/// Arranges for a restic command to read its password from an inheritable pipe, returning the
+/// pipe, which must be kept open until the command has been spawned.
+fn pass_password(command: &mut Command, password: &[u8]) -> std::io::Result<os_pipe::PipeReader> {
+	let reader = super::passphrase::send_to_inheritable_pipe(password)?;
+	for var in OVERRIDING_ENV {
+		command.env_remove(var);
+	}
+	command.env(
+		"RESTIC_PASSWORD_FILE",
+		format!("/dev/fd/{}", reader.as_fd().as_raw_fd()),
+	);
+	Ok(reader)
+}
+
+/// Returns the message of the last fatal error restic printed to standard error, if any.
+fn fatal_message(stderr: &str) -> Option<&str> {
+	stderr
+		.lines()
+		.rev()
+		.find_map(|i| i.strip_prefix("Fatal: "))
+		.map(str::trim_end)
+}
+
+/// Returns the error for a repository check that restic failed with a given exit code and
+/// standard error.
+fn check_error(code: i32, stderr: &str) -> check::Error {
+	if code == WRONG_PASSWORD_CODE || stderr.contains(WRONG_PASSWORD_MESSAGE) {
+		check::Error::Passphrase
+	} else {
+		check::Error::Repository(match fatal_message(stderr) {
+			Some(message) => message.to_owned(),
+			None => format!("restic returned exit code {code}"),
+		})
+	}
+}
+
+/// Tries to open a restic repository and verify that it is accessible with a given password, by
+/// listing its most recent snapshot.
+///
+/// As with [`check::run`](check::run), an arbitrary password is passed if none is provided, so
+/// that a repository needing one fails with [`check::Error::Passphrase`]; restic repositories are
+/// always encrypted, so one always does. If `manage_passphrase` is false, no password is passed at
+/// all, leaving restic to obtain one itself. The variables in `env`, if any, are set for restic.
+pub fn check(
+	repository: &str,
+	passphrase: Option<&[u8]>,
+	manage_passphrase: bool,
+	env: Option<&BorgEnv<'_>>,
+) -> Result<check::Info, check::Error> {
+	let mut command = Command::new("restic");
+	command
+		.args(["--no-lock", "snapshots", "--json", "--latest", "1"])
+		.envs(env.into_iter().flatten().map(|(k, v)| (&**k, &**v)))
+		.env("RESTIC_REPOSITORY", repository)
+		.stdin(Stdio::null())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped());
+	let password_pipe_reader = if manage_passphrase {
+		let password = passphrase.unwrap_or(b"f1ba7f94-7bb5-4a55-8877-7afe3b280f4b");
+		Some(pass_password(&mut command, password)?)
+	} else {
+		None
+	};
+	let child = command
+		.spawn()
+		.map_err(|e| check::Error::Launch(CommandLine::of(&command), e))?;
+
+	// Drop the pipe reader now that the child has a copy of it, ensuring we don’t keep open FDs
+	// around longer than necessary.
+	drop(password_pipe_reader);
+
+	let output = child.wait_with_output()?;
+	match output.status.code() {
+		Some(0) => Ok(check::Info {
+			encryption_mode: ENCRYPTION_MODE.to_owned(),
+		}),
+		Some(code) => Err(check_error(code, &String::from_utf8_lossy(&output.stderr))),
+		None => Err(output
+			.status
+			.signal()
+			.map_or(check::Error::Unknown, check::Error::Signal)),
+	}
+}
+
+/// Converts a time in UTC, written as Borg’s `--timestamp` option expects, into local time, as
+/// restic’s `--time` option expects, or returns `None` if it is not such a time.
+fn local_time(timestamp: &str) -> Option<String> {
+	let time = chrono::NaiveDateTime::parse_from_str(timestamp, "%FT%T").ok()?;
+	Some(
+		time.and_utc()
+			.with_timezone(&chrono::Local)
+			.format("%F %T")
+			.to_string(),
+	)
+}
+
+/// Escapes the characters that restic treats specially in an exclude pattern, so that the pattern
+/// matches only the literal path.
+fn escape_pattern(path: &Path) -> String {
+	let path = path.to_string_lossy();
+	let mut ret = String::with_capacity(path.len());
+	for c in path.chars() {
+		if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+			ret.push('\\');
+		}
+		ret.push(c);
+	}
+	ret
+}
+
+/// Builds the `restic backup` command that backs up an archive to one repository, without
+/// arranging for it to run in the archive root.
+///
+/// `timestamp` is the creation time to record, in UTC, written as for Borg, and `source_excludes`
+/// lists any further paths, relative to `dir`, the directory actually read, to exclude, such as
+/// nested subvolumes missing from a snapshot.
+///
+/// restic has no archive names, so each snapshot is tagged with the archive name instead, and the
+/// previous snapshot with the same host and tags is used as its parent; this matters because the
+/// path of a btrfs snapshot differs on every run.
+pub fn backup_command(
+	context: &Context<'_>,
+	archive_name: &str,
+	archive: &config::Archive,
+	repository: &str,
+	timestamp: &str,
+	dir: &Path,
+	source_excludes: &[PathBuf],
+) -> Command {
+	// As with Borg, restic runs in the archive root for relative paths, and is given the archive
+	// root’s path for absolute ones.
+	let path = match archive.path_style {
+		config::PathStyle::Relative => Path::new("."),
+		config::PathStyle::Absolute => &*archive.root,
+	};
+	let mut child = Command::new("restic");
+	child
+		.args(["--json", "backup", "--exclude-caches"])
+		.args(["--tag", archive_name, "--group-by", "host,tags"])
+		.args([
+			"--time",
+			&local_time(timestamp).unwrap_or_else(|| timestamp.to_owned()),
+		])
+		.args(archive.compression.is_none().then_some("--compression=off"))
+		.args(archive.btrfs_snapshot.then_some("--ignore-inode"))
+		.args(
+			source_excludes
+				.iter()
+				.map(|i| format!("--exclude={}", escape_pattern(&dir.join(i)))),
+		)
+		.arg(path)
+		.envs(context.borg_env(repository))
+		.env("RESTIC_REPOSITORY", repository);
+	child
+}
+
+/// The parts of the `summary` message of `restic backup --json` that Borgify uses.
+#[derive(Debug, Deserialize)]
+struct Summary {
+	/// The number of files in the snapshot.
+	total_files_processed: u64,
+
+	/// The total size of the files in the snapshot.
+	total_bytes_processed: u64,
+
+	/// The size of the data added to the repository, after deduplication but before compression.
+	data_added: u64,
+
+	/// The size of the data added to the repository, after deduplication and compression, which
+	/// restic 0.16 and later report.
+	#[serde(default)]
+	data_added_packed: Option<u64>,
+}
+
+/// Returns the statistics of the snapshot created, from the `summary` message of
+/// `restic backup --json`.
+///
+/// restic does not report the size after compression but before deduplication, so the original
+/// size is given in its place.
+fn summary_stats(summary: &[u8]) -> Result<usage::Stats, serde_json::Error> {
+	let summary: Summary = serde_json::from_slice(summary)?;
+	Ok(usage::Stats {
+		original_size: summary.total_bytes_processed,
+		compressed_size: summary.total_bytes_processed,
+		deduplicated_size: summary.data_added_packed.unwrap_or(summary.data_added),
+		nfiles: summary.total_files_processed,
+	})
+}
+
+/// A message written by `restic backup --json` to standard output.
+#[derive(Debug, Deserialize)]
+struct Message {
+	/// The kind of message, such as `status`, `error`, or `summary`.
+	message_type: String,
+
+	/// The error, for an `error` message.
+	#[serde(default)]
+	error: Option<MessageError>,
+
+	/// The path the error concerns, for an `error` message.
+	#[serde(default)]
+	item: Option<String>,
+}
+
+/// The error in an `error` message written by `restic backup --json`.
+#[derive(Debug, Deserialize)]
+struct MessageError {
+	/// The error message, which restic versions before 0.17 do not include.
+	#[serde(default)]
+	message: Option<String>,
+}
+
+/// Handles the JSON messages that `restic backup --json` writes to standard output, returning the
+/// `summary` message, or an empty buffer if there was none.
+///
+/// Status messages are passed to `on_progress`. Errors, which concern individual files, are
+/// written to `out` in human-readable form, as are any lines that are not valid JSON.
+fn handle_stdout(
+	mut stdout: impl BufRead,
+	mut out: impl Write,
+	mut on_progress: impl FnMut(&str),
+) -> std::io::Result<Vec<u8>> {
+	let mut line_buffer = Vec::new();
+	let mut summary = Vec::new();
+	loop {
+		line_buffer.clear();
+		if stdout.read_until(b'\n', &mut line_buffer)? == 0 {
+			break;
+		}
+		let text = String::from_utf8_lossy(&line_buffer);
+		let text = text.trim_end();
+		match serde_json::from_str::<Message>(text) {
+			Ok(message) => match &*message.message_type {
+				"status" => on_progress(text),
+				"summary" => summary = text.as_bytes().to_owned(),
+				"error" => writeln!(
+					out,
+					"{}: {}",
+					message.item.as_deref().unwrap_or("restic"),
+					message
+						.error
+						.and_then(|i| i.message)
+						.as_deref()
+						.unwrap_or("error")
+				)?,
+				_ => (),
+			},
+			Err(_) if text.is_empty() => (),
+			Err(_) => writeln!(out, "{text}")?,
+		}
+	}
+	Ok(summary)
+}
+
+/// The result of a `restic backup` command that did not fail.
+#[derive(Debug)]
+pub struct Backup {
+	/// Whether restic could not read some of the files.
+	pub warnings: bool,
+
+	/// The statistics of the snapshot created, or why they could not be read.
+	pub stats: Result<usage::Stats, serde_json::Error>,
+}
+
+/// Runs a `restic backup` command to completion, passing it the repository password if there is
+/// one.
+///
+/// restic’s standard error is passed through to ours as it arrives. Status messages are passed to
+/// `on_progress`.
+pub fn run_backup(
+	mut child: Command,
+	passphrase: Option<&[u8]>,
+	on_progress: impl FnMut(&str),
+) -> Result<Backup, backup::Error> {
+	let password_pipe_reader = passphrase
+		.map(|i| pass_password(&mut child, i))
+		.transpose()
+		.map_err(backup::Error::Spawn)?;
+	let mut child = child
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.spawn()
+		.map_err(|e| backup::Error::Launch(CommandLine::of(&child), e))?;
+
+	// Drop the pipe reader now that the child has a copy of it, ensuring we don’t keep open FDs
+	// around longer than necessary.
+	drop(password_pipe_reader);
+
+	// Pass standard error through on another thread, keeping its last few lines for an error
+	// message, so that neither pipe can fill up and block restic while the other is being read.
+	let mut stderr = BufReader::new(child.stderr.take().unwrap());
+	let stderr = std::thread::spawn(move || {
+		let mut tail = Tail::default();
+		let mut line_buffer = Vec::new();
+		loop {
+			line_buffer.clear();
+			if stderr.read_until(b'\n', &mut line_buffer)? == 0 {
+				break;
+			}
+			let text = String::from_utf8_lossy(&line_buffer);
+			eprintln!("{}", text.trim_end());
+			tail.push_line(&text);
+		}
+		Ok::<_, std::io::Error>(tail.into_lines())
+	});
+
+	// Handle standard output until the child closes it. If that fails, try to clean up by killing
+	// the child, which may not have finished yet.
+	let summary = match handle_stdout(
+		BufReader::new(child.stdout.take().unwrap()),
+		std::io::stderr().lock(),
+		on_progress,
+	) {
+		Ok(summary) => summary,
+		Err(e) => {
+			// Best effort; the child may already have died, in which case there is nothing to do.
+			let _ = child.kill();
+			let _ = child.wait();
+			return Err(backup::Error::Spawn(e));
+		}
+	};
+
+	// Wait and collect exit status.
+	let status = child.wait().map_err(backup::Error::Spawn)?;
+	let tail = stderr
+		.join()
+		.expect("standard error reader panicked")
+		.map_err(backup::Error::Spawn)?;
+	match status.code() {
+		Some(code @ (0 | INCOMPLETE_CODE)) => Ok(Backup {
+			warnings: code == INCOMPLETE_CODE,
+			stats: summary_stats(&summary),
+		}),
+		Some(code) => Err(backup::Error::Restic(code, tail)),
+		None => Err(status
+			.signal()
+			.map_or(backup::Error::Unknown, backup::Error::Signal)),
+	}
+}
+
+/// Tests recognizing an incorrect password and other errors when checking a repository.
+#[test]
+fn test_check_error() {
+	assert!(matches!(
+		check_error(12, "Fatal: wrong password or no key found\n"),
+		check::Error::Passphrase
+	));
+	assert!(matches!(
+		check_error(1, "Fatal: wrong password or no key found\n"),
+		check::Error::Passphrase
+	));
+	assert!(matches!(
+		check_error(10, "Fatal: repository does not exist: unable to open config file\n"),
+		check::Error::Repository(message) if message == "repository does not exist: unable to open config file"
+	));
+	assert!(matches!(
+		check_error(1, ""),
+		check::Error::Repository(message) if message == "restic returned exit code 1"
+	));
+}
+
+/// Tests converting a timestamp into the form restic expects.
+#[test]
+fn test_local_time() {
+	let expected = chrono::DateTime::parse_from_rfc3339("2023-01-08T10:00:00Z")
+		.unwrap()
+		.with_timezone(&chrono::Local)
+		.format("%F %T")
+		.to_string();
+	assert_eq!(local_time("2023-01-08T10:00:00"), Some(expected));
+	assert_eq!(local_time("<time of start>"), None);
+}
+
+/// Tests escaping paths for use as restic exclude patterns.
+#[test]
+fn test_escape_pattern() {
+	assert_eq!(escape_pattern(Path::new("/a/b")), "/a/b");
+	assert_eq!(
+		escape_pattern(Path::new("/a/[x]*?\\")),
+		"/a/\\[x\\]\\*\\?\\\\"
+	);
+}
+
+/// Tests handling the messages of `restic backup --json`.
+#[test]
+fn test_handle_stdout() {
+	let input = concat!(
+		"{\"message_type\":\"status\",\"percent_done\":0.5}\n",
+		"{\"message_type\":\"error\",\"error\":{\"message\":\"permission denied\"},\"during\":\"archival\",\"item\":\"/a/b\"}\n",
+		"not JSON\n",
+		"{\"message_type\":\"summary\",\"files_new\":2,\"total_files_processed\":10,\"total_bytes_processed\":4096,\"data_added\":2048,\"data_added_packed\":1024}\n",
+	);
+	let mut out = Vec::new();
+	let mut progress = Vec::new();
+	let summary = handle_stdout(input.as_bytes(), &mut out, |line| {
+		progress.push(line.to_owned())
+	})
+	.unwrap();
+	assert_eq!(
+		progress,
+		["{\"message_type\":\"status\",\"percent_done\":0.5}"]
+	);
+	assert_eq!(
+		String::from_utf8(out).unwrap(),
+		"/a/b: permission denied\nnot JSON\n"
+	);
+	assert_eq!(
+		summary_stats(&summary).unwrap(),
+		usage::Stats {
+			original_size: 4096,
+			compressed_size: 4096,
+			deduplicated_size: 1024,
+			nfiles: 10,
+		}
+	);
+	assert!(summary_stats(b"").is_err());
+}
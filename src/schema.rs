@@ -0,0 +1,258 @@
+//! A JSON Schema describing the config file, for editors to offer completion and validation.
+//!
+//! The schema is written by hand, so it cannot express every rule that loading the config
+//! enforces (such as which compression algorithms take a level), but the tests check that it
+//! names every key the config types accept.
+
+use serde_json::{json, Value};
+
+/// Returns the schema for a string that is a valid umask.
+fn umask() -> Value {
+	json!({
+		"description": "The umask for Borg and Borgify, as an octal string such as \"0027\".",
+		"type": "string",
+		"pattern": "^(0o)?[0-7]{1,4}$"
+	})
+}
+
+/// Returns the schema for an array of Borg pattern specifications.
+fn patterns(description: &str) -> Value {
+	json!({
+		"description": description,
+		"type": "array",
+		"items": {"type": "string", "pattern": "^[-+!P]"}
+	})
+}
+
+/// Returns the schema for a compression specification.
+fn compression() -> Value {
+	json!({
+		"description": "The compression method, as a string in Borg’s --compression syntax or as a structured object.",
+		"oneOf": [
+			{"type": "string"},
+			{
+				"type": "object",
+				"properties": {
+					"algorithm": {"enum": ["none", "lz4", "zstd", "zlib", "lzma"]},
+					"level": {"type": "integer", "minimum": 0, "maximum": 22},
+					"auto": {"type": "boolean"},
+					"obfuscate": {
+						"anyOf": [
+							{"type": "integer", "minimum": 1, "maximum": 6},
+							{"type": "integer", "minimum": 110, "maximum": 123},
+							{"const": 250}
+						]
+					}
+				},
+				"required": ["algorithm"],
+				"additionalProperties": false
+			}
+		]
+	})
+}
+
+/// Returns the schema for one repository URL or a nonempty list of them.
+fn repository() -> Value {
+	json!({
+		"description": "The URL of the repository, or a list of URLs to back up to each in turn.",
+		"oneOf": [
+			{"type": "string"},
+			{"type": "array", "items": {"type": "string"}, "minItems": 1}
+		]
+	})
+}
+
+/// Returns the schema for the path of the Borg executable on a repository’s server.
+fn remote_path() -> Value {
+	json!({
+		"description": "The Borg executable on the server of a remote repository, passed as --remote-path.",
+		"type": "string",
+		"minLength": 1
+	})
+}
+
+/// Returns the schema for environment variables to set for Borg.
+fn borg_env() -> Value {
+	json!({
+		"description": "Additional environment variables to set for Borg, by name.",
+		"type": "object",
+		"propertyNames": {"minLength": 1, "not": {"enum": ["BORG_REPO", "BORG_PASSPHRASE_FD", "BORG_FILES_CACHE_SUFFIX"]}},
+		"additionalProperties": {"type": "string"}
+	})
+}
+
+/// Returns the schema for retention rules.
+fn prune() -> Value {
+	let count = json!({"type": "integer", "minimum": 0});
+	json!({
+		"description": "Which Borg archives to keep when pruning with --prune, as borg prune’s --keep-* options.",
+		"type": "object",
+		"properties": {
+			"keep_within": {"type": "string", "pattern": "^[0-9]+[Hdwmy]$"},
+			"keep_last": count,
+			"keep_minutely": count,
+			"keep_hourly": count,
+			"keep_daily": count,
+			"keep_weekly": count,
+			"keep_monthly": count,
+			"keep_yearly": count
+		},
+		"minProperties": 1,
+		"additionalProperties": false
+	})
+}
+
+/// Returns the properties that may appear both in an archive and in the defaults section.
+fn defaultable_properties() -> serde_json::Map<String, Value> {
+	let Value::Object(properties) = json!({
+		"compression": compression(),
+		"repository": repository(),
+		"comment": {"type": "string", "description": "The archive comment, with placeholders such as {archive} expanded."},
+		"files_cache": {"type": "string", "description": "The mode of Borg’s files cache, such as ctime,size."},
+		"timezone": {"type": "string", "description": "The IANA time zone for the timestamp in archive names."},
+		"name_date_format": {"type": "string", "description": "The strftime format of the timestamp in archive names."},
+		"snapshot_dir": {"type": "string", "description": "The directory under which to place btrfs snapshots, in a subdirectory per archive."},
+		"timestamp_source": {"enum": ["run", "snapshot", "start"], "description": "Which time Borg records as the archive’s creation time."},
+		"prune": prune(),
+		"upload_buffer": {"type": "integer", "minimum": 1, "maximum": u32::MAX, "description": "The size in MiB of Borg’s upload buffer, passed as --upload-buffer."},
+		"checkpoint_interval": {"type": "integer", "minimum": 1, "maximum": u32::MAX, "description": "The seconds between Borg’s checkpoints, passed as --checkpoint-interval."},
+		"checkpoint_max_age": {"type": "string", "pattern": "^[0-9]+[Hdwmy]$", "description": "The age, such as 7d, beyond which checkpoint archives are deleted before backing up."}
+	}) else {
+		unreachable!()
+	};
+	properties
+}
+
+/// Returns the schema for an archive.
+fn archive() -> Value {
+	let mut properties = defaultable_properties();
+	let Value::Object(own) = json!({
+		"enabled": {"type": "boolean"},
+		"backend": {"enum": ["borg", "restic"], "description": "The backup program that writes to the repositories."},
+		"root": {"type": "string", "description": "The directory to back up."},
+		"btrfs_snapshot": {"type": "boolean"},
+		"allow_writable_snapshot": {"type": "boolean"},
+		"nested_subvolumes": {"enum": ["warn", "exclude", "ignore"]},
+		"snapshot_delete_failure": {"enum": ["error", "warn"]},
+		"snapshot_space_retries": {"type": "integer", "minimum": 0, "maximum": u32::MAX},
+		"snapshot_collision": {"enum": ["warn", "info", "delete"]},
+		"patterns": patterns("Borg patterns, passed via --pattern."),
+		"use_groups": {"type": "array", "items": {"type": "string"}, "description": "Names of pattern groups whose patterns follow the archive’s own."},
+		"tags": {"type": "array", "items": {"type": "string", "minLength": 1}},
+		"verify_after": {"type": "boolean"},
+		"list_changed": {"type": "boolean"},
+		"exclude_borg_dirs": {"type": "boolean"},
+		"path_style": {"enum": ["relative", "absolute"]},
+		"require_mount": {"type": "boolean"},
+		"min_files": {"type": "integer", "minimum": 0},
+		"min_bytes": {"type": "integer", "minimum": 0},
+		"success_marker": {"type": "string", "minLength": 1},
+		"success_marker_on_warnings": {"type": "boolean"},
+		"priority": {"type": "integer", "minimum": i32::MIN, "maximum": i32::MAX}
+	}) else {
+		unreachable!()
+	};
+	properties.extend(own);
+	json!({
+		"type": "object",
+		"properties": properties,
+		"required": ["root"],
+		"additionalProperties": false
+	})
+}
+
+/// Returns the schema for the defaults section.
+fn defaults() -> Value {
+	let mut properties = defaultable_properties();
+	properties.insert(
+		"patterns".to_owned(),
+		patterns("Borg patterns appended to every archive’s own."),
+	);
+	properties.insert("remote_path".to_owned(), remote_path());
+	properties.insert("borg_env".to_owned(), borg_env());
+	json!({
+		"type": "object",
+		"properties": properties,
+		"additionalProperties": false
+	})
+}
+
+/// Returns the schema for a repository’s settings.
+fn repository_settings() -> Value {
+	json!({
+		"type": "object",
+		"properties": {
+			"manage_passphrase": {"type": "boolean"},
+			"passphrase_env": {"type": "string", "minLength": 1},
+			"passphrase_file": {"type": "string"},
+			"passphrase_id": {"type": "string", "minLength": 1},
+			"create_parent": {"type": "boolean"},
+			"sync_repo": {"type": "boolean"},
+			"remote_path": remote_path(),
+			"borg_env": borg_env()
+		},
+		"additionalProperties": false
+	})
+}
+
+/// Returns the JSON Schema describing the config file.
+pub fn schema() -> Value {
+	json!({
+		"$schema": "https://json-schema.org/draft/2020-12/schema",
+		"title": "Borgify configuration",
+		"type": "object",
+		"properties": {
+			"version": {"type": "integer", "minimum": 1, "maximum": super::config::VERSION},
+			"include": {"type": "array", "items": {"type": "string"}},
+			"defaults": defaults(),
+			"archives": {"type": "object", "additionalProperties": archive()},
+			"repositories": {"type": "object", "additionalProperties": repository_settings()},
+			"pattern_groups": {
+				"type": "object",
+				"additionalProperties": patterns("A reusable group of Borg patterns.")
+			},
+			"umask": umask(),
+			"work_dir": {"type": "string"},
+			"cgroup": {"type": "string", "description": "An absolute path to a cgroup directory into which Borg is moved when backing up."},
+			"host_check": {"enum": ["off", "resolve", "connect"]},
+			"check_probe": {"enum": ["info", "list"]},
+			"inherited_passphrase": {"enum": ["unset", "error"]},
+			"on_complete": {"type": "array", "items": {"type": "string"}, "minItems": 1}
+		},
+		"required": ["archives"],
+		"additionalProperties": false
+	})
+}
+
+/// Tests that the schema names exactly the keys of archives and repositories as the config types
+/// report them, and of the defaults section and the top level as the parsed config types declare
+/// them, plus the keys that are consumed while loading the config.
+#[test]
+fn test_schema_matches_config() {
+	use super::config::{Archive, Repository};
+	// Returns the sorted names of the properties of an object schema, or of an object.
+	let keys = |value: &Value| {
+		let object = value.get("properties").unwrap_or(value);
+		let mut keys: Vec<String> = object.as_object().unwrap().keys().cloned().collect();
+		keys.sort_unstable();
+		keys
+	};
+	let schema = schema();
+	let mut archive_keys = keys(&Archive::default().to_json());
+	archive_keys.push("use_groups".to_owned());
+	archive_keys.sort_unstable();
+	assert_eq!(
+		keys(&schema["properties"]["archives"]["additionalProperties"]),
+		archive_keys
+	);
+	assert_eq!(
+		keys(&schema["properties"]["repositories"]["additionalProperties"]),
+		keys(&Repository::default().to_json())
+	);
+	let (mut defaults_keys, mut config_keys) = super::config::parsed_keys();
+	defaults_keys.sort_unstable();
+	assert_eq!(keys(&schema["properties"]["defaults"]), defaults_keys);
+	config_keys.push("include");
+	config_keys.sort_unstable();
+	assert_eq!(keys(&schema), config_keys);
+}
@@ -0,0 +1,60 @@
+//! Retention of the last few lines of a child process’s output, for use in error messages.
+
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+
+/// The number of lines retained.
+const CAPACITY: usize = 10;
+
+/// The last few nonempty lines of some output.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Tail {
+	/// The lines, oldest first.
+	lines: VecDeque<String>,
+}
+
+impl Tail {
+	/// Adds a complete line, discarding the oldest line if the buffer is full.
+	pub fn push_line(&mut self, line: &str) {
+		let line = line.trim_end();
+		if line.is_empty() {
+			return;
+		}
+		if self.lines.len() == CAPACITY {
+			self.lines.pop_front();
+		}
+		self.lines.push_back(line.to_owned());
+	}
+
+	/// Returns the retained lines, oldest first.
+	pub fn into_lines(self) -> Vec<String> {
+		self.lines.into()
+	}
+}
+
+/// A list of lines of output, which formats as an indented block for inclusion in an error
+/// message.
+#[derive(Clone, Copy, Debug)]
+pub struct Lines<'a>(pub &'a [String]);
+
+impl Display for Lines<'_> {
+	fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+		for line in self.0 {
+			write!(f, "\n  {line}")?;
+		}
+		Ok(())
+	}
+}
+
+/// Tests that only the last lines are retained.
+#[test]
+fn test_tail_capacity() {
+	let mut tail = Tail::default();
+	for i in 0..CAPACITY + 5 {
+		tail.push_line(&format!("line {i}"));
+	}
+	let lines = tail.into_lines();
+	assert_eq!(lines.len(), CAPACITY);
+	assert_eq!(lines[0], "line 5");
+	assert_eq!(lines[CAPACITY - 1], format!("line {}", CAPACITY + 4));
+}
@@ -0,0 +1,58 @@
+//! A scratch directory for tests, removed with its contents when dropped.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A counter distinguishing the directories created by one test process.
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// A newly created, empty directory under the system temporary directory, which is deleted along
+/// with everything in it when the value is dropped, including when a test panics.
+#[derive(Debug)]
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+	/// Creates a directory whose name includes `name`, the process ID, and a counter, so that tests
+	/// running in parallel, or in concurrent test processes, never share one.
+	pub fn new(name: &str) -> Self {
+		let path = std::env::temp_dir().join(format!(
+			"borgify-test-{name}-{}-{}",
+			std::process::id(),
+			NEXT.fetch_add(1, Ordering::Relaxed)
+		));
+		std::fs::create_dir(&path).expect("failed to create temporary directory");
+		Self(path)
+	}
+
+	/// Returns the path of the directory.
+	pub fn path(&self) -> &Path {
+		&self.0
+	}
+
+	/// Returns the path of an entry within the directory.
+	pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+		self.0.join(path)
+	}
+}
+
+impl Drop for TempDir {
+	fn drop(&mut self) {
+		// Best effort; a test may already have removed the directory or made it unremovable.
+		let _ = std::fs::remove_dir_all(&self.0);
+	}
+}
+
+/// Tests that the directory exists while the value lives and is removed, contents and all, when it
+/// is dropped.
+#[test]
+fn test_temp_dir() {
+	let dir = TempDir::new("temp-dir");
+	let other = TempDir::new("temp-dir");
+	assert_ne!(dir.path(), other.path());
+	std::fs::create_dir(dir.join("sub")).unwrap();
+	std::fs::write(dir.join("sub/file"), b"").unwrap();
+	let path = dir.path().to_owned();
+	drop(dir);
+	assert!(!path.exists());
+	assert!(other.path().is_dir());
+}
@@ -0,0 +1,428 @@
+//! Conversion of times to named time zones, using the system’s time zone database.
+
+use chrono::{DateTime, Datelike as _, FixedOffset, NaiveDate, NaiveTime, Utc};
+use std::io::Read as _;
+use std::path::{Component, Path, PathBuf};
+
+/// Returns the directory holding the system’s time zone database.
+fn database_dir() -> PathBuf {
+	std::env::var_os("TZDIR")
+		.filter(|i| !i.is_empty())
+		.map_or_else(|| PathBuf::from("/usr/share/zoneinfo"), PathBuf::from)
+}
+
+/// Checks whether `name` is an IANA time zone name, such as `Europe/Berlin`, present in the time
+/// zone database in `dir`.
+fn exists_in(dir: &Path, name: &str) -> bool {
+	let relative = Path::new(name);
+	if name.is_empty()
+		|| !relative
+			.components()
+			.all(|i| matches!(i, Component::Normal(_)))
+	{
+		return false;
+	}
+	let mut magic = [0_u8; 4];
+	std::fs::File::open(dir.join(relative))
+		.and_then(|mut f| f.read_exact(&mut magic))
+		.is_ok()
+		&& &magic == b"TZif"
+}
+
+/// Checks whether `name` is an IANA time zone name present in the system’s time zone database.
+pub fn exists(name: &str) -> bool {
+	exists_in(&database_dir(), name)
+}
+
+/// Converts a time to the time zone `name`, which should have been checked with
+/// [`exists`](exists).
+///
+/// The zone’s file is read and interpreted directly, rather than by pointing the C library at it
+/// through the `TZ` environment variable, which other threads may be reading at the same time.
+pub fn convert(time: DateTime<Utc>, name: &str) -> Option<DateTime<FixedOffset>> {
+	let dir = database_dir();
+	if !exists_in(&dir, name) {
+		return None;
+	}
+	let data = std::fs::read(dir.join(name)).ok()?;
+	let offset = FixedOffset::east_opt(offset_at(&data, time.timestamp())?)?;
+	Some(time.with_timezone(&offset))
+}
+
+/// The length of a TZif header.
+const HEADER_LEN: usize = 44;
+
+/// The counts of each kind of record in one data block of a TZif file, as described in RFC 8536.
+struct Counts {
+	/// The number of UT/local indicators.
+	isutcnt: usize,
+
+	/// The number of standard/wall indicators.
+	isstdcnt: usize,
+
+	/// The number of leap second records.
+	leapcnt: usize,
+
+	/// The number of transition times.
+	timecnt: usize,
+
+	/// The number of local time type records.
+	typecnt: usize,
+
+	/// The number of bytes of time zone abbreviations.
+	charcnt: usize,
+}
+
+impl Counts {
+	/// Parses the header at the start of `data`, returning the version byte and the counts.
+	fn parse(data: &[u8]) -> Option<(u8, Self)> {
+		if data.len() < HEADER_LEN || &data[..4] != b"TZif" {
+			return None;
+		}
+		let count = |i: usize| {
+			let start = 20 + 4 * i;
+			u32::from_be_bytes(data[start..start + 4].try_into().unwrap()) as usize
+		};
+		Some((
+			data[4],
+			Self {
+				isutcnt: count(0),
+				isstdcnt: count(1),
+				leapcnt: count(2),
+				timecnt: count(3),
+				typecnt: count(4),
+				charcnt: count(5),
+			},
+		))
+	}
+
+	/// Returns the length of the data block following the header, given the size of each
+	/// transition time in bytes.
+	fn block_len(&self, time_size: usize) -> usize {
+		self.timecnt * (time_size + 1)
+			+ self.typecnt * 6
+			+ self.charcnt
+			+ self.leapcnt * (time_size + 4)
+			+ self.isstdcnt
+			+ self.isutcnt
+	}
+}
+
+/// Returns the offset from UTC, in seconds east, in effect at `time`, in seconds since the epoch,
+/// in the zone described by the TZif file `data`.
+///
+/// Times after the last transition in the file are governed by the POSIX `TZ` string in its
+/// footer, if it has one.
+fn offset_at(data: &[u8], time: i64) -> Option<i32> {
+	let (version, counts) = Counts::parse(data)?;
+	// Version 2 and later files repeat the data with 64-bit times after the version 1 block.
+	let (time_size, counts, block) = if version >= b'2' {
+		let rest = data.get(HEADER_LEN + counts.block_len(4)..)?;
+		let (_, counts) = Counts::parse(rest)?;
+		(8, counts, &rest[HEADER_LEN..])
+	} else {
+		(4, counts, &data[HEADER_LEN..])
+	};
+	if block.len() < counts.block_len(time_size) || counts.typecnt == 0 {
+		return None;
+	}
+	let (times, block) = block.split_at(counts.timecnt * time_size);
+	let (indices, block) = block.split_at(counts.timecnt);
+	let times: Vec<i64> = times
+		.chunks_exact(time_size)
+		.map(|i| match time_size {
+			8 => i64::from_be_bytes(i.try_into().unwrap()),
+			_ => i32::from_be_bytes(i.try_into().unwrap()).into(),
+		})
+		.collect();
+	let passed = times.partition_point(|&i| i <= time);
+	if passed == times.len() && version >= b'2' {
+		let footer = &block[counts.block_len(time_size) - counts.timecnt * (time_size + 1)..];
+		let footer = footer.strip_prefix(b"\n")?;
+		let footer = &footer[..footer.iter().position(|&i| i == b'\n')?];
+		if !footer.is_empty() {
+			return Rule::parse(std::str::from_utf8(footer).ok()?)?.offset_at(time);
+		}
+	}
+	// Before the first transition, the first local time type is in effect.
+	let index = match passed.checked_sub(1) {
+		Some(i) => usize::from(indices[i]),
+		None => 0,
+	};
+	let record = block.get(index * 6..index * 6 + 4)?;
+	Some(i32::from_be_bytes(record.try_into().unwrap()))
+}
+
+/// A day in a POSIX `TZ` string on which daylight saving time starts or ends.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Day {
+	/// `Jn`: the day of the year from 1 to 365, never counting February 29.
+	Julian(u32),
+
+	/// `n`: the day of the year from 0 to 365, counting February 29 in leap years.
+	Zero(u32),
+
+	/// `Mm.w.d`: day `d` of the week (0 is Sunday) in week `w` (1 to 5, 5 meaning the last) of
+	/// month `m`.
+	Weekday(u32, u32, u32),
+}
+
+impl Day {
+	/// Returns the date of this day in `year`.
+	fn in_year(self, year: i32) -> Option<NaiveDate> {
+		match self {
+			Self::Julian(n) => {
+				let leap = NaiveDate::from_ymd_opt(year, 2, 29).is_some();
+				NaiveDate::from_yo_opt(year, n + u32::from(leap && n >= 60))
+			}
+			Self::Zero(n) => NaiveDate::from_yo_opt(year, n + 1),
+			Self::Weekday(month, week, weekday) => {
+				let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+				let first_weekday = first.weekday().num_days_from_sunday();
+				let day = 1 + (weekday + 7 - first_weekday) % 7 + (week - 1) * 7;
+				// Week 5 means the last such weekday, which may be in the fourth week.
+				NaiveDate::from_ymd_opt(year, month, day)
+					.or_else(|| NaiveDate::from_ymd_opt(year, month, day - 7))
+			}
+		}
+	}
+}
+
+/// A day, and a local time of day in seconds, at which daylight saving time starts or ends.
+type Transition = (Day, i32);
+
+/// The offsets and daylight saving rules of a POSIX `TZ` string, such as
+/// `CET-1CEST,M3.5.0,M10.5.0/3`, which a TZif file’s footer uses to describe times after its last
+/// transition.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Rule {
+	/// The offset from UTC of standard time, in seconds east.
+	standard: i32,
+
+	/// The offset from UTC of daylight saving time, in seconds east, and the day and local time of
+	/// day at which daylight saving time starts and ends, if it is observed.
+	daylight: Option<(i32, Transition, Transition)>,
+}
+
+impl Rule {
+	/// Parses a POSIX `TZ` string, with the RFC 8536 extension allowing times of day from −167 to
+	/// 167 hours.
+	///
+	/// Daylight saving time without an explicit rule is not supported, since TZif files never use
+	/// it.
+	fn parse(s: &str) -> Option<Self> {
+		let mut s = s.as_bytes();
+		skip_name(&mut s)?;
+		let standard = -parse_time(&mut s)?;
+		if s.is_empty() {
+			return Some(Self {
+				standard,
+				daylight: None,
+			});
+		}
+		skip_name(&mut s)?;
+		let daylight = if s.first() == Some(&b',') {
+			standard + 3600
+		} else {
+			-parse_time(&mut s)?
+		};
+		let start = parse_transition(&mut s)?;
+		let end = parse_transition(&mut s)?;
+		s.is_empty().then_some(Self {
+			standard,
+			daylight: Some((daylight, start, end)),
+		})
+	}
+
+	/// Returns the offset from UTC, in seconds east, in effect at `time`, in seconds since the
+	/// epoch.
+	fn offset_at(&self, time: i64) -> Option<i32> {
+		let Some((daylight, start, end)) = self.daylight else {
+			return Some(self.standard);
+		};
+		// Daylight saving time starts at a local standard time and ends at a local daylight time.
+		let year = DateTime::from_timestamp(time + i64::from(self.standard), 0)?.year();
+		let instant = |(day, seconds): Transition, offset: i32| {
+			Some(
+				day.in_year(year)?
+					.and_time(NaiveTime::MIN)
+					.and_utc()
+					.timestamp() + i64::from(seconds - offset),
+			)
+		};
+		let start = instant(start, self.standard)?;
+		let end = instant(end, daylight)?;
+		let in_daylight = if start < end {
+			start <= time && time < end
+		} else {
+			// In the southern hemisphere, daylight saving time spans the new year.
+			!(end <= time && time < start)
+		};
+		Some(if in_daylight { daylight } else { self.standard })
+	}
+}
+
+/// Skips a time zone abbreviation, either alphabetic or enclosed in angle brackets, at the start
+/// of `s`.
+fn skip_name(s: &mut &[u8]) -> Option<()> {
+	let len = if s.first() == Some(&b'<') {
+		s.iter().position(|&i| i == b'>')? + 1
+	} else {
+		s.iter().take_while(|i| i.is_ascii_alphabetic()).count()
+	};
+	if len == 0 {
+		return None;
+	}
+	*s = &s[len..];
+	Some(())
+}
+
+/// Parses a number at the start of `s`.
+fn parse_number(s: &mut &[u8]) -> Option<u32> {
+	let len = s.iter().take_while(|i| i.is_ascii_digit()).count();
+	if len == 0 || len > 3 {
+		return None;
+	}
+	let value = std::str::from_utf8(&s[..len]).ok()?.parse().ok()?;
+	*s = &s[len..];
+	Some(value)
+}
+
+/// Parses a signed `hh[:mm[:ss]]` time at the start of `s`, returning a number of seconds.
+fn parse_time(s: &mut &[u8]) -> Option<i32> {
+	let sign = match s.first() {
+		Some(b'-') => -1,
+		Some(b'+') => 1,
+		_ => 0,
+	};
+	if sign != 0 {
+		*s = &s[1..];
+	}
+	let mut seconds = parse_number(s)? * 3600;
+	for unit in [60, 1] {
+		match s.strip_prefix(b":") {
+			Some(rest) => {
+				*s = rest;
+				seconds += parse_number(s)? * unit;
+			}
+			None => break,
+		}
+	}
+	Some(if sign < 0 { -1 } else { 1 } * i32::try_from(seconds).ok()?)
+}
+
+/// Parses a `,day[/time]` daylight saving transition at the start of `s`.
+fn parse_transition(s: &mut &[u8]) -> Option<Transition> {
+	*s = s.strip_prefix(b",")?;
+	let day = match s.first()? {
+		b'J' => {
+			*s = &s[1..];
+			let n = parse_number(s)?;
+			(1..=365).contains(&n).then_some(Day::Julian(n))?
+		}
+		b'M' => {
+			*s = &s[1..];
+			let month = parse_number(s)?;
+			*s = s.strip_prefix(b".")?;
+			let week = parse_number(s)?;
+			*s = s.strip_prefix(b".")?;
+			let weekday = parse_number(s)?;
+			((1..=12).contains(&month) && (1..=5).contains(&week) && weekday <= 6)
+				.then_some(Day::Weekday(month, week, weekday))?
+		}
+		_ => {
+			let n = parse_number(s)?;
+			(n <= 365).then_some(Day::Zero(n))?
+		}
+	};
+	let time = match s.strip_prefix(b"/") {
+		Some(rest) => {
+			*s = rest;
+			parse_time(s)?
+		}
+		None => 2 * 3600,
+	};
+	Some((day, time))
+}
+
+/// Tests recognizing time zone names in a database, and rejecting names that escape it.
+#[test]
+fn test_exists_in() {
+	let temp = super::temp_dir::TempDir::new("zoneinfo");
+	let dir = temp.path();
+	std::fs::create_dir(dir.join("Europe")).unwrap();
+	std::fs::write(dir.join("Europe/Berlin"), b"TZif2").unwrap();
+	std::fs::write(dir.join("zone.tab"), b"# tab").unwrap();
+	assert!(exists_in(dir, "Europe/Berlin"));
+	assert!(!exists_in(dir, "Europe"));
+	assert!(!exists_in(dir, "Europe/Paris"));
+	assert!(!exists_in(dir, "zone.tab"));
+	assert!(!exists_in(dir, ""));
+	assert!(!exists_in(dir, "Europe/../Europe/Berlin"));
+	assert!(!exists_in(
+		dir,
+		&dir.join("Europe/Berlin").to_string_lossy()
+	));
+}
+
+/// Tests evaluating POSIX `TZ` strings on either side of their daylight saving transitions.
+#[test]
+fn test_rule_offset_at() {
+	let offset = |rule: &str, time: &str| {
+		Rule::parse(rule)
+			.unwrap()
+			.offset_at(time.parse::<DateTime<Utc>>().unwrap().timestamp())
+			.unwrap()
+	};
+	const BERLIN: &str = "CET-1CEST,M3.5.0,M10.5.0/3";
+	assert_eq!(offset(BERLIN, "2024-03-31T00:59:59Z"), 3600);
+	assert_eq!(offset(BERLIN, "2024-03-31T01:00:00Z"), 7200);
+	assert_eq!(offset(BERLIN, "2024-10-27T00:59:59Z"), 7200);
+	assert_eq!(offset(BERLIN, "2024-10-27T01:00:00Z"), 3600);
+	const SYDNEY: &str = "AEST-10AEDT,M10.1.0,M4.1.0/3";
+	assert_eq!(offset(SYDNEY, "2024-01-15T00:00:00Z"), 39600);
+	assert_eq!(offset(SYDNEY, "2024-07-01T00:00:00Z"), 36000);
+	assert_eq!(offset(SYDNEY, "2024-12-31T23:00:00Z"), 39600);
+	const NEW_YORK: &str = "EST5EDT,M3.2.0,M11.1.0";
+	assert_eq!(offset(NEW_YORK, "2024-03-10T06:59:59Z"), -18000);
+	assert_eq!(offset(NEW_YORK, "2024-03-10T07:00:00Z"), -14400);
+	assert_eq!(offset("<+0530>-5:30", "2024-07-01T00:00:00Z"), 19800);
+	assert_eq!(
+		offset("<-03>3<-02>,J1/0,J365/25", "2024-07-01T00:00:00Z"),
+		-7200
+	);
+	assert_eq!(Rule::parse("CET-1CEST"), None);
+	assert_eq!(Rule::parse("CET-1CEST,M3.5.0,M13.5.0"), None);
+	assert_eq!(Rule::parse("-1"), None);
+}
+
+/// Tests finding the offset in effect at a time from a TZif file’s transitions and footer.
+#[test]
+fn test_offset_at() {
+	// A version 2 file with an empty version 1 block, transitions at 0 and 1000 seconds, and two
+	// local time types.
+	let mut data = Vec::new();
+	data.extend_from_slice(b"TZif2");
+	data.extend_from_slice(&[0; HEADER_LEN - 5]);
+	data.extend_from_slice(b"TZif2");
+	data.extend_from_slice(&[0; 15]);
+	for count in [0_u32, 0, 0, 2, 2, 0] {
+		data.extend_from_slice(&count.to_be_bytes());
+	}
+	for time in [0_i64, 1000] {
+		data.extend_from_slice(&time.to_be_bytes());
+	}
+	data.extend_from_slice(&[1, 0]);
+	for offset in [3600_i32, 7200] {
+		data.extend_from_slice(&offset.to_be_bytes());
+		data.extend_from_slice(&[0, 0]);
+	}
+	data.extend_from_slice(b"\nCET-1CEST,M3.5.0,M10.5.0/3\n");
+	assert_eq!(offset_at(&data, -1), Some(3600));
+	assert_eq!(offset_at(&data, 0), Some(7200));
+	assert_eq!(offset_at(&data, 999), Some(7200));
+	assert_eq!(offset_at(&data, 1000), Some(3600));
+	assert_eq!(offset_at(&data, 1_720_000_000), Some(7200));
+	assert_eq!(offset_at(&data[..data.len() - 40], 0), None);
+	assert_eq!(offset_at(b"TZif2", 0), None);
+}
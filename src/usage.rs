@@ -0,0 +1,165 @@
+//! Accounting of how much each repository was used during a run, for attributing usage of a
+//! shared backup server.
+
+use super::borg_log::Size;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The statistics Borg reports for a newly created archive.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+pub struct Stats {
+	/// The total size of the files in the archive.
+	pub original_size: u64,
+
+	/// The size of the files in the archive, after compression.
+	pub compressed_size: u64,
+
+	/// The size of the data added to the repository, after compression and deduplication.
+	pub deduplicated_size: u64,
+
+	/// The number of files in the archive.
+	pub nfiles: u64,
+}
+
+impl Stats {
+	/// Formats the statistics for humans, such as `12 files, 1.00 MiB original, 512.00 KiB
+	/// compressed, 2.00 KiB deduplicated`.
+	pub fn describe(&self) -> String {
+		format!(
+			"{} files, {} original, {} compressed, {} deduplicated",
+			self.nfiles,
+			Size(self.original_size),
+			Size(self.compressed_size),
+			Size(self.deduplicated_size)
+		)
+	}
+}
+
+/// The usage of one repository, summed over the archives created in it.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct Usage {
+	/// The number of archives created.
+	archives: u64,
+
+	/// The statistics of the archives, summed.
+	stats: Stats,
+
+	/// The time spent running `borg create`.
+	duration: Duration,
+}
+
+/// The usage recorded so far, for each repository, in the order the repositories were first seen.
+#[derive(Debug, Default, Eq, PartialEq)]
+struct Accounts(Vec<(String, Usage)>);
+
+impl Accounts {
+	/// Adds an archive created in a repository.
+	fn add(&mut self, repository: &str, stats: &Stats, duration: Duration) {
+		let index = self.0.iter().position(|(url, _)| url == repository);
+		let index = index.unwrap_or_else(|| {
+			self.0.push((repository.to_owned(), Usage::default()));
+			self.0.len() - 1
+		});
+		let usage = &mut self.0[index].1;
+		usage.archives += 1;
+		usage.stats.original_size += stats.original_size;
+		usage.stats.compressed_size += stats.compressed_size;
+		usage.stats.deduplicated_size += stats.deduplicated_size;
+		usage.stats.nfiles += stats.nfiles;
+		usage.duration += duration;
+	}
+
+	/// Formats the usage for humans, with a line per repository.
+	fn lines(&self) -> Vec<String> {
+		self.0
+			.iter()
+			.map(|(url, usage)| {
+				format!(
+					"Repository {url}: {} archive{}, {}, in {}",
+					usage.archives,
+					if usage.archives == 1 { "" } else { "s" },
+					usage.stats.describe(),
+					super::format_duration(usage.duration)
+				)
+			})
+			.collect()
+	}
+
+	/// Returns the usage as a JSON object keyed by repository URL.
+	fn to_json(&self) -> Value {
+		let accounts: Map<String, Value> = self
+			.0
+			.iter()
+			.map(|(url, usage)| {
+				(
+					url.clone(),
+					serde_json::json!({
+						"archives": usage.archives,
+						"original_size": usage.stats.original_size,
+						"compressed_size": usage.stats.compressed_size,
+						"deduplicated_size": usage.stats.deduplicated_size,
+						"nfiles": usage.stats.nfiles,
+						"seconds": usage.duration.as_secs_f64(),
+					}),
+				)
+			})
+			.collect();
+		Value::Object(accounts)
+	}
+}
+
+/// The usage recorded so far.
+static ACCOUNTS: Mutex<Accounts> = Mutex::new(Accounts(Vec::new()));
+
+/// Records an archive created in a repository, with the statistics Borg reported for it and the
+/// time spent creating it.
+pub fn record(repository: &str, stats: &Stats, duration: Duration) {
+	ACCOUNTS.lock().unwrap().add(repository, stats, duration);
+}
+
+/// Prints the usage of each repository, as a line per repository.
+pub fn print() {
+	for line in ACCOUNTS.lock().unwrap().lines() {
+		println!("{line}");
+	}
+}
+
+/// Returns the usage of each repository as a JSON object keyed by repository URL.
+pub fn to_json() -> Value {
+	ACCOUNTS.lock().unwrap().to_json()
+}
+
+/// Tests summing the usage of archives by repository and formatting it.
+#[test]
+fn test_accounts() {
+	let mut accounts = Accounts::default();
+	let stats = Stats {
+		original_size: 4096,
+		compressed_size: 2048,
+		deduplicated_size: 1024,
+		nfiles: 10,
+	};
+	accounts.add("/a", &stats, Duration::from_secs(2));
+	accounts.add("host:b", &stats, Duration::from_millis(500));
+	accounts.add("/a", &stats, Duration::from_secs(3));
+	assert_eq!(
+		accounts.lines(),
+		[
+			"Repository /a: 2 archives, 20 files, 8.00 KiB original, 4.00 KiB compressed, 2.00 KiB deduplicated, in 5.0s",
+			"Repository host:b: 1 archive, 10 files, 4.00 KiB original, 2.00 KiB compressed, 1.00 KiB deduplicated, in 0.5s",
+		]
+	);
+	assert_eq!(
+		accounts.to_json()["/a"],
+		serde_json::json!({
+			"archives": 2,
+			"original_size": 8192,
+			"compressed_size": 4096,
+			"deduplicated_size": 2048,
+			"nfiles": 20,
+			"seconds": 5.0,
+		})
+	);
+}
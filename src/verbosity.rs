@@ -0,0 +1,22 @@
+//! The global level of output verbosity.
+
+use std::sync::atomic::{AtomicI8, Ordering};
+
+/// The verbosity level: zero is normal, higher values print more information, and negative values
+/// print only errors and warnings.
+static LEVEL: AtomicI8 = AtomicI8::new(0);
+
+/// Sets the verbosity level.
+pub fn set(level: i8) {
+	LEVEL.store(level, Ordering::Relaxed);
+}
+
+/// Returns whether progress and informational output should be suppressed.
+pub fn quiet() -> bool {
+	LEVEL.load(Ordering::Relaxed) < 0
+}
+
+/// Returns whether extra diagnostic information should be printed.
+pub fn verbose() -> bool {
+	LEVEL.load(Ordering::Relaxed) > 0
+}